@@ -0,0 +1,613 @@
+//! Backups of a whole image directory as a single zip archive, for the `backup` subcommand
+
+use std::collections::BTreeMap;
+use std::io::{Read, Write};
+
+use crate::image::TEMP_SUFFIX;
+use crate::slots::*;
+
+/// Name of the entry of a backup archive that describes the backup
+pub const MANIFEST_NAME: &str = "manifest.json";
+
+/// Description of a backup, which is stored in its archive as [`MANIFEST_NAME`]
+#[derive(Debug, Clone, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+pub struct Manifest {
+    /// Version of the server that wrote the backup
+    pub version: String,
+    /// Time at which the backup was written, in milliseconds since the UNIX epoch
+    pub created_ms: u64,
+    /// Every file of the image directory that is in the archive, in the order they were archived
+    pub files: Vec<ManifestFile>,
+}
+
+/// A file of the image directory that is in a backup archive
+#[derive(Debug, Clone, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+pub struct ManifestFile {
+    /// Path of the file relative to the image directory, which is also the name of its entry
+    pub path: String,
+    /// Size of the file, in bytes
+    pub bytes: u64,
+    /// Time at which the file was last modified, in milliseconds since the UNIX epoch
+    pub modified_ms: u64,
+}
+
+/// Gets the number of milliseconds since the UNIX epoch of a point in time (0 for earlier times)
+fn millis_since_epoch(time: std::time::SystemTime) -> u64 {
+    time.duration_since(std::time::UNIX_EPOCH)
+        .map_or(0, |duration| duration.as_millis() as u64)
+}
+
+/// Locks every slot of a directory and of its subdirectories, and finds every file in them
+///
+/// Lock files and temporary files are left out, since they only exist while an image is written.
+///
+/// # Arguments
+///
+/// * `dir` - Directory to search
+/// * `prefix` - Path of the directory relative to the image directory (empty for the image
+///   directory itself, and otherwise ending with a slash)
+/// * `files` - Pairs of the path of every file found and its path relative to the image directory
+/// * `locks` - Locks of every slot found, which are held until the files have been archived
+///
+fn collect_files(
+    dir: &str,
+    prefix: &str,
+    files: &mut Vec<(String, String)>,
+    locks: &mut Vec<SlotLock>,
+) -> std::io::Result<()> {
+    // the slots are locked before their files are found, so no file is replaced after it is found
+    for slot in list_slots(dir) {
+        match lock_slot(dir, &slot) {
+            Ok(lock) => locks.push(lock),
+            Err(err) if err.kind() == std::io::ErrorKind::AlreadyExists => {
+                return Err(std::io::Error::new(
+                    std::io::ErrorKind::AlreadyExists,
+                    format!(
+                        "{prefix}image_{slot}.bmp is being saved by the server, try again once it is done"
+                    ),
+                ));
+            }
+            Err(err) => return Err(err),
+        }
+    }
+
+    let mut entries = std::fs::read_dir(dir)?.collect::<std::io::Result<Vec<_>>>()?;
+    entries.sort_by_key(|entry| entry.file_name());
+    for entry in entries {
+        let name = entry.file_name().to_string_lossy().into_owned();
+        let path = entry.path().to_string_lossy().into_owned();
+        if entry.file_type()?.is_dir() {
+            collect_files(&path, &format!("{prefix}{name}/"), files, locks)?;
+        } else if !name.ends_with(LOCK_SUFFIX) && !name.ends_with(TEMP_SUFFIX) {
+            files.push((path, format!("{prefix}{name}")));
+        }
+    }
+    Ok(())
+}
+
+/// Writes every file of an image directory (images, their backups, metadata, checksums,
+/// thumbnails and history, including those of every device) into a zip archive, along with a
+/// manifest of the files
+///
+/// Every slot is locked while it is archived, so an image that is being saved (by a server in
+/// another process, for example) is never archived half written. The archive is replaced
+/// atomically, so an existing backup is never left partially overwritten.
+///
+/// # Arguments
+///
+/// * `dir` - Directory where images are stored
+/// * `out` - Path of the archive to write
+///
+/// # Errors
+///
+/// * With [`std::io::ErrorKind::AlreadyExists`] when a slot is being saved, so it can not be locked
+/// * When the directory can not be read, or the archive can not be written
+///
+pub fn backup(dir: &str, out: &str) -> std::io::Result<Manifest> {
+    let mut files = Vec::new();
+    let mut locks = Vec::new();
+    collect_files(dir, "", &mut files, &mut locks)?;
+
+    let mut manifest = Manifest {
+        version: env!("CARGO_PKG_VERSION").to_string(),
+        created_ms: millis_since_epoch(std::time::SystemTime::now()),
+        files: Vec::with_capacity(files.len()),
+    };
+
+    let temp = format!("{out}{TEMP_SUFFIX}");
+    let result = (|| {
+        let mut zip = zip::ZipWriter::new(std::fs::File::create(&temp)?);
+        let options = zip::write::SimpleFileOptions::default()
+            .compression_method(zip::CompressionMethod::Deflated);
+
+        for (path, archived) in files {
+            let contents = std::fs::read(&path)?;
+            let modified = std::fs::metadata(&path)?.modified()?;
+            zip.start_file(archived.as_str(), options)?;
+            zip.write_all(&contents)?;
+            manifest.files.push(ManifestFile {
+                path: archived,
+                bytes: contents.len() as u64,
+                modified_ms: millis_since_epoch(modified),
+            });
+        }
+
+        zip.start_file(MANIFEST_NAME, options)?;
+        serde_json::to_writer_pretty(&mut zip, &manifest)?;
+        zip.finish()?.sync_all()?;
+        std::fs::rename(&temp, out)
+    })();
+    if result.is_err() {
+        let _ = std::fs::remove_file(&temp);
+    }
+    drop(locks);
+
+    result.map(|()| manifest)
+}
+
+/// What was done with the files of a slot when restoring a backup
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RestoreOutcome {
+    /// The files of the slot were written into the image directory
+    Restored,
+    /// The slot already had an image, so its files were left alone
+    Skipped,
+}
+
+/// Slots that have been restored from a backup so far, which tells what landed in the image
+/// directory even if the restore failed part of the way through
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct RestoreReport {
+    /// Every slot that was handled (as the path of its image file relative to the image
+    /// directory), how it was handled and how many of its files were written, in order
+    pub slots: Vec<(String, RestoreOutcome, usize)>,
+    /// Number of files that do not belong to any slot, which were written
+    pub other_files: usize,
+}
+
+/// Checks that the path of an entry of an archive stays inside the directory it is extracted
+/// into (so that an archive can not replace files elsewhere)
+fn is_contained(path: &str) -> bool {
+    !path.is_empty()
+        && !path.contains('\\')
+        && !path.contains(':')
+        && path
+            .split('/')
+            .all(|component| !component.is_empty() && component != "." && component != "..")
+}
+
+/// Finds the slot that a file of an image directory belongs to, as the path of the directory of
+/// the slot relative to the image directory (empty, or ending with a slash) and the slot
+///
+/// The image, its backup, metadata and checksum (`image_{slot}.*`), its thumbnail
+/// (`thumbnails/image_{slot}.png`) and its history (`history/{slot}/*`) belong to the slot.
+fn slot_of(path: &str) -> Option<(String, Slot)> {
+    let components: Vec<&str> = path.split('/').collect();
+    let (file, parents) = components.split_last()?;
+    let prefix = |parents: &[&str]| parents.iter().map(|parent| format!("{parent}/")).collect();
+
+    if let [rest @ .., "history", slot] = parents {
+        return Some((prefix(rest), Slot::named(slot.as_bytes()).ok()?));
+    }
+    let name = file.strip_prefix("image_")?.split('.').next()?;
+    let slot = Slot::named(name.as_bytes()).ok()?;
+    match parents {
+        [rest @ .., "thumbnails"] => Some((prefix(rest), slot)),
+        _ => Some((prefix(parents), slot)),
+    }
+}
+
+/// Reads a backup archive and checks it against its manifest
+///
+/// # Errors
+///
+/// * With [`std::io::ErrorKind::InvalidData`] when the archive has no valid manifest, when an
+///   entry is not listed by the manifest (or the other way round) or has a different size, or when
+///   any path would be extracted outside of the image directory
+/// * When the archive can not be read
+///
+fn open_backup(from: &str) -> std::io::Result<(zip::ZipArchive<std::fs::File>, Manifest)> {
+    let invalid = |message: String| std::io::Error::new(std::io::ErrorKind::InvalidData, message);
+
+    let mut zip = zip::ZipArchive::new(std::fs::File::open(from)?)?;
+    let mut names = zip
+        .file_names()
+        .map(|name| name.map(|name| name.into_owned()))
+        .collect::<Result<Vec<_>, _>>()?;
+    if let Some(name) = names.iter().find(|name| !is_contained(name)) {
+        return Err(invalid(format!("{name} is outside of the image directory")));
+    }
+
+    let mut json = String::new();
+    zip.by_name(MANIFEST_NAME)
+        .map_err(|_| invalid(format!("{from} has no {MANIFEST_NAME}")))?
+        .read_to_string(&mut json)?;
+    let manifest: Manifest = serde_json::from_str(&json)
+        .map_err(|err| invalid(format!("{MANIFEST_NAME} is not valid: {err}")))?;
+
+    names.retain(|name| name != MANIFEST_NAME);
+    names.sort();
+    let mut listed: Vec<&str> = manifest
+        .files
+        .iter()
+        .map(|file| file.path.as_str())
+        .collect();
+    listed.sort();
+    if names != listed {
+        return Err(invalid(format!(
+            "the entries of {from} do not match its {MANIFEST_NAME}"
+        )));
+    }
+    for file in &manifest.files {
+        if zip.by_name(&file.path)?.size() != file.bytes {
+            return Err(invalid(format!(
+                "{} is not the size recorded in {MANIFEST_NAME}",
+                file.path
+            )));
+        }
+    }
+
+    Ok((zip, manifest))
+}
+
+/// Writes an entry of a backup archive to its file in the image directory, keeping the time at
+/// which it was last modified
+///
+/// The file is replaced atomically, so an existing file is never left partially overwritten.
+fn extract(
+    zip: &mut zip::ZipArchive<std::fs::File>,
+    dir: &str,
+    file: &ManifestFile,
+) -> std::io::Result<()> {
+    let path = format!("{dir}/{}", file.path);
+    if let Some(parent) = std::path::Path::new(&path).parent() {
+        std::fs::create_dir_all(parent)?;
+    }
+
+    let mut contents = Vec::new();
+    zip.by_name(&file.path)?.read_to_end(&mut contents)?;
+
+    let temp = format!("{path}{TEMP_SUFFIX}");
+    let result = (|| {
+        let mut temp_file = std::fs::File::create(&temp)?;
+        temp_file.write_all(&contents)?;
+        temp_file.set_modified(
+            std::time::UNIX_EPOCH + std::time::Duration::from_millis(file.modified_ms),
+        )?;
+        temp_file.sync_all()?;
+        std::fs::rename(&temp, &path)
+    })();
+    if result.is_err() {
+        let _ = std::fs::remove_file(&temp);
+    }
+    result
+}
+
+/// Restores the slots of a backup archive written by [`backup`] into an image directory
+///
+/// The whole archive is checked before anything is written, and slots that already have an image
+/// are skipped unless they may be overwritten. Every slot is locked while its files are written.
+/// Files that do not belong to a slot are only written if they do not exist yet (or may be
+/// overwritten).
+///
+/// # Arguments
+///
+/// * `dir` - Directory where images are stored
+/// * `from` - Path of the archive to restore
+/// * `overwrite` - Whether to replace the images of slots that already have one
+/// * `report` - Filled with every slot as it is restored, so that it describes what was restored
+///   before an error
+///
+/// # Errors
+///
+/// * The same errors as [`open_backup`], in which case nothing is restored
+/// * With [`std::io::ErrorKind::AlreadyExists`] when a slot is being saved, so it can not be locked
+/// * When a file can not be written
+///
+pub fn restore_backup(
+    dir: &str,
+    from: &str,
+    overwrite: bool,
+    report: &mut RestoreReport,
+) -> std::io::Result<()> {
+    let (mut zip, manifest) = open_backup(from)?;
+
+    let mut slots: BTreeMap<(String, Slot), Vec<&ManifestFile>> = BTreeMap::new();
+    let mut other_files = Vec::new();
+    for file in &manifest.files {
+        match slot_of(&file.path) {
+            Some(key) => slots.entry(key).or_default().push(file),
+            None => other_files.push(file),
+        }
+    }
+
+    for ((prefix, slot), files) in slots {
+        let slot_dir = format!("{dir}/{prefix}");
+        let slot_dir = slot_dir.trim_end_matches('/');
+        std::fs::create_dir_all(slot_dir)?;
+        let label = format!("{prefix}image_{slot}.bmp");
+
+        let _lock = match lock_slot(slot_dir, &slot) {
+            Ok(lock) => lock,
+            Err(err) if err.kind() == std::io::ErrorKind::AlreadyExists => {
+                return Err(std::io::Error::new(
+                    std::io::ErrorKind::AlreadyExists,
+                    format!("{label} is being saved by the server"),
+                ));
+            }
+            Err(err) => return Err(err),
+        };
+        if !overwrite && std::path::Path::new(&image_path(slot_dir, &slot)).exists() {
+            report.slots.push((label, RestoreOutcome::Skipped, 0));
+            continue;
+        }
+
+        for file in &files {
+            extract(&mut zip, dir, file)?;
+        }
+        // an image stored in another form than the one restored would otherwise be served instead
+        for file in &files {
+            let file_name = file.path.rsplit('/').next().unwrap_or(&file.path);
+            if !file.path.contains("thumbnails/") && parse_image_slot(file_name).is_some() {
+                remove_stale_image(&format!("{dir}/{}", file.path))?;
+            }
+        }
+        report
+            .slots
+            .push((label, RestoreOutcome::Restored, files.len()));
+    }
+
+    for file in other_files {
+        if overwrite || !std::path::Path::new(&format!("{dir}/{}", file.path)).exists() {
+            extract(&mut zip, dir, file)?;
+            report.other_files += 1;
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::image::save_bmp_image;
+    use crate::slots::{archive_slot, Slot};
+    use crate::testing::temp_dir;
+    use std::io::Read;
+
+    #[test]
+    fn backups_contain_every_file() {
+        let dir = temp_dir("backups_contain_every_file");
+        let images = format!("{dir}/images");
+        std::fs::create_dir_all(format!("{images}/thumbnails")).unwrap();
+        save_bmp_image(&[vec![0xF800; 2]], &format!("{images}/image_1")).unwrap();
+        save_bmp_image(&[vec![0x07E0; 3]], &format!("{images}/image_card")).unwrap();
+        archive_slot(&images, &Slot::Number(1)).unwrap();
+        std::fs::write(format!("{images}/image_1.json"), b"{}").unwrap();
+        std::fs::write(format!("{images}/thumbnails/image_1.png"), b"png").unwrap();
+
+        let out = format!("{dir}/backup.zip");
+        let manifest = backup(&images, &out).unwrap();
+        assert_eq!(manifest.version, env!("CARGO_PKG_VERSION"));
+
+        let mut zip = zip::ZipArchive::new(std::fs::File::open(&out).unwrap()).unwrap();
+        let mut names: Vec<String> = zip
+            .file_names()
+            .map(|name| name.unwrap().to_string())
+            .collect();
+        names.sort();
+        let mut expected: Vec<String> = manifest.files.iter().map(|f| f.path.clone()).collect();
+        assert!(expected.iter().any(|path| path.starts_with("history/1/")));
+        assert!(expected.contains(&"thumbnails/image_1.png".to_string()));
+        expected.push(MANIFEST_NAME.to_string());
+        expected.sort();
+        assert_eq!(names, expected);
+
+        for file in &manifest.files {
+            let mut contents = Vec::new();
+            zip.by_name(&file.path)
+                .unwrap()
+                .read_to_end(&mut contents)
+                .unwrap();
+            assert_eq!(
+                contents,
+                std::fs::read(format!("{images}/{}", file.path)).unwrap()
+            );
+            assert_eq!(contents.len() as u64, file.bytes);
+        }
+
+        let mut json = String::new();
+        zip.by_name(MANIFEST_NAME)
+            .unwrap()
+            .read_to_string(&mut json)
+            .unwrap();
+        assert_eq!(json, serde_json::to_string_pretty(&manifest).unwrap());
+
+        // the slots are unlocked afterwards
+        assert!(lock_slot(&images, &Slot::Number(1)).is_ok());
+    }
+
+    #[test]
+    fn slots_being_saved_are_not_backed_up() {
+        let dir = temp_dir("slots_being_saved_are_not_backed_up");
+        save_bmp_image(&[vec![0xF800; 2]], &format!("{dir}/image_1")).unwrap();
+        save_bmp_image(&[vec![0xF800; 2]], &format!("{dir}/image_2")).unwrap();
+
+        let out = format!("{dir}/backup.zip");
+        let lock = lock_slot(&dir, &Slot::Number(2)).unwrap();
+        let err = backup(&dir, &out).unwrap_err();
+        assert_eq!(err.kind(), std::io::ErrorKind::AlreadyExists);
+        assert!(!std::path::Path::new(&out).exists());
+
+        // the slots that were locked by the backup are released again
+        assert!(lock_slot(&dir, &Slot::Number(1)).is_ok());
+        drop(lock);
+        assert!(backup(&dir, &out).is_ok());
+    }
+
+    /// Reads every file of a directory (except lock files) and the time at which it was last
+    /// modified (to the millisecond), by their paths relative to it
+    fn read_tree(dir: &str) -> BTreeMap<String, (Vec<u8>, u64)> {
+        let mut files = Vec::new();
+        collect_files(dir, "", &mut files, &mut Vec::new()).unwrap();
+        files
+            .into_iter()
+            .map(|(path, relative)| {
+                let modified = std::fs::metadata(&path).unwrap().modified().unwrap();
+                (
+                    relative,
+                    (std::fs::read(&path).unwrap(), millis_since_epoch(modified)),
+                )
+            })
+            .collect()
+    }
+
+    /// Creates an image directory with a few slots, their sidecars and a device subdirectory
+    fn populate(images: &str) {
+        std::fs::create_dir_all(format!("{images}/thumbnails")).unwrap();
+        std::fs::create_dir_all(format!("{images}/7")).unwrap();
+        save_bmp_image(&[vec![0xF800; 2]], &format!("{images}/image_1")).unwrap();
+        archive_slot(images, &Slot::Number(1)).unwrap();
+        save_bmp_image(&[vec![0x001F; 2]], &format!("{images}/image_1.bak")).unwrap();
+        std::fs::write(format!("{images}/image_1.json"), b"{\"height\":1}").unwrap();
+        std::fs::write(format!("{images}/thumbnails/image_1.png"), b"png").unwrap();
+        save_bmp_image(&[vec![0x07E0; 3]], &format!("{images}/image_card")).unwrap();
+        save_bmp_image(&[vec![0xFFFF; 4]], &format!("{images}/7/image_2")).unwrap();
+        std::fs::write(format!("{images}/notes.txt"), b"notes").unwrap();
+    }
+
+    #[test]
+    fn backups_are_restored_exactly() {
+        let dir = temp_dir("backups_are_restored_exactly");
+        let images = format!("{dir}/images");
+        populate(&images);
+        let out = format!("{dir}/backup.zip");
+        backup(&images, &out).unwrap();
+        let before = read_tree(&images);
+
+        std::fs::remove_dir_all(&images).unwrap();
+        let mut report = RestoreReport::default();
+        restore_backup(&images, &out, false, &mut report).unwrap();
+        assert_eq!(read_tree(&images), before);
+        assert_eq!(
+            report.slots,
+            [
+                ("image_1.bmp".to_string(), RestoreOutcome::Restored, 5),
+                ("image_card.bmp".to_string(), RestoreOutcome::Restored, 1),
+                ("7/image_2.bmp".to_string(), RestoreOutcome::Restored, 1),
+            ]
+        );
+        assert_eq!(report.other_files, 1);
+        assert_eq!(
+            list_slots(&images),
+            [Slot::Number(1), Slot::Name("card".into())]
+        );
+    }
+
+    #[test]
+    fn existing_slots_are_only_restored_with_overwrite() {
+        let dir = temp_dir("existing_slots_are_only_restored_with_overwrite");
+        let images = format!("{dir}/images");
+        populate(&images);
+        let out = format!("{dir}/backup.zip");
+        backup(&images, &out).unwrap();
+        let backed_up = std::fs::read(format!("{images}/image_1.bmp")).unwrap();
+
+        // the slot now has an image in another form, which is newer than the backed up one
+        std::fs::remove_file(format!("{images}/image_1.bmp")).unwrap();
+        crate::image::save_zstd_bmp_image_as(
+            &[vec![0x0000; 2]],
+            &format!("{images}/image_1"),
+            crate::image::ColorFormat::Rgb565,
+            1,
+        )
+        .unwrap();
+        std::fs::remove_file(format!("{images}/image_card.bmp")).unwrap();
+
+        let mut report = RestoreReport::default();
+        restore_backup(&images, &out, false, &mut report).unwrap();
+        assert_eq!(
+            report.slots[0],
+            ("image_1.bmp".into(), RestoreOutcome::Skipped, 0)
+        );
+        assert_eq!(report.slots[1].1, RestoreOutcome::Restored);
+        assert!(std::path::Path::new(&format!("{images}/image_1.bmp.zst")).exists());
+
+        let mut report = RestoreReport::default();
+        restore_backup(&images, &out, true, &mut report).unwrap();
+        assert_eq!(report.slots[0].1, RestoreOutcome::Restored);
+        assert!(!std::path::Path::new(&format!("{images}/image_1.bmp.zst")).exists());
+        assert_eq!(
+            std::fs::read(image_path(&images, &Slot::Number(1))).unwrap(),
+            backed_up
+        );
+    }
+
+    #[test]
+    fn entries_outside_the_image_directory_are_refused() {
+        let dir = temp_dir("entries_outside_the_image_directory_are_refused");
+        let images = format!("{dir}/images");
+        let out = format!("{dir}/backup.zip");
+
+        let manifest = Manifest {
+            version: env!("CARGO_PKG_VERSION").to_string(),
+            created_ms: 0,
+            files: vec![
+                ManifestFile {
+                    path: "image_1.bmp".to_string(),
+                    bytes: 2,
+                    modified_ms: 0,
+                },
+                ManifestFile {
+                    path: "../escaped.bmp".to_string(),
+                    bytes: 2,
+                    modified_ms: 0,
+                },
+            ],
+        };
+        let mut zip = zip::ZipWriter::new(std::fs::File::create(&out).unwrap());
+        let options = zip::write::SimpleFileOptions::default();
+        for file in &manifest.files {
+            zip.start_file(file.path.as_str(), options).unwrap();
+            zip.write_all(b"BM").unwrap();
+        }
+        zip.start_file(MANIFEST_NAME, options).unwrap();
+        serde_json::to_writer(&mut zip, &manifest).unwrap();
+        zip.finish().unwrap();
+
+        let mut report = RestoreReport::default();
+        let err = restore_backup(&images, &out, true, &mut report).unwrap_err();
+        assert_eq!(err.kind(), std::io::ErrorKind::InvalidData);
+        assert_eq!(report, RestoreReport::default());
+        assert!(!std::path::Path::new(&images).exists());
+        assert!(!std::path::Path::new(&format!("{dir}/escaped.bmp")).exists());
+
+        for path in ["/etc/passwd", "a\\..\\b", "C:x", "a//b", "./a", ""] {
+            assert!(!is_contained(path), "{path:?}");
+        }
+        assert!(is_contained("7/history/1/12.bmp"));
+    }
+
+    #[test]
+    fn restores_stop_at_slots_being_saved() {
+        let dir = temp_dir("restores_stop_at_slots_being_saved");
+        let images = format!("{dir}/images");
+        populate(&images);
+        let out = format!("{dir}/backup.zip");
+        backup(&images, &out).unwrap();
+
+        std::fs::remove_dir_all(&images).unwrap();
+        std::fs::create_dir_all(&images).unwrap();
+        let _lock = lock_slot(&images, &Slot::Name("card".into())).unwrap();
+        let mut report = RestoreReport::default();
+        let err = restore_backup(&images, &out, false, &mut report).unwrap_err();
+        assert_eq!(err.kind(), std::io::ErrorKind::AlreadyExists);
+
+        // only the slot before the locked one was restored
+        assert_eq!(
+            report.slots,
+            [("image_1.bmp".to_string(), RestoreOutcome::Restored, 5)]
+        );
+        assert_eq!(list_slots(&images), [Slot::Number(1)]);
+        assert!(list_slots(&format!("{images}/7")).is_empty());
+    }
+}
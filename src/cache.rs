@@ -0,0 +1,335 @@
+//! Cache of the most recently loaded images, so that clients flipping between slots do not have
+//! to wait for every image to be read from the disk (and decoded) again
+
+use std::collections::VecDeque;
+use std::sync::{Arc, Mutex};
+use std::time::SystemTime;
+
+use crate::image::{LoadError, STORED_SUFFIXES};
+use crate::slots::{image_path, Slot};
+use crate::store::Store;
+
+/// Modification time and size of a file, which change whenever the file is replaced
+pub type Stamp = (SystemTime, u64);
+
+/// An image that was loaded from (or saved to) the disk
+struct Entry {
+    /// Path of the image file, which also identifies the slot (and its device)
+    path: String,
+    /// Modification time and size of the file when the image was read, to notice when it changes
+    /// (pinned images may not have a file)
+    stamp: Option<Stamp>,
+    img: Arc<Vec<Vec<u16>>>,
+    /// The image was preloaded (or saved with `--preload`), so it is never evicted, and is served
+    /// even if its file changes or disappears
+    pinned: bool,
+}
+
+/// Cached images, from the most recently used to the least recently used
+static ENTRIES: Mutex<VecDeque<Entry>> = Mutex::new(VecDeque::new());
+
+/// Gets the modification time and size of a file, if it exists
+pub fn stamp(path: &str) -> Option<Stamp> {
+    let metadata = std::fs::metadata(path).ok()?;
+    Some((metadata.modified().ok()?, metadata.len()))
+}
+
+/// Adds an image to the cache as the most recently used one, replacing the previous image of its
+/// slot and evicting the least recently used images beyond the capacity (pinned images do not
+/// count towards it)
+fn insert(entry: Entry, capacity: usize) {
+    let mut entries = ENTRIES.lock().unwrap_or_else(|err| err.into_inner());
+    entries.retain(|other| other.path != entry.path);
+    entries.push_front(entry);
+
+    let mut unpinned = 0;
+    entries.retain(|entry| {
+        unpinned += usize::from(!entry.pinned);
+        entry.pinned || unpinned <= capacity
+    });
+}
+
+/// Loads the image stored in a slot, from the cache if its file has not changed since it was cached
+///
+/// Images are only cached when they are loaded from a BMP file (so never from a database). The file is checked before it is
+/// read, so a file that is replaced while it is being read is read again by the next load. Pinned
+/// images are served without checking their file.
+///
+/// # Arguments
+///
+/// * `dir` - Directory to retrieve the image from
+/// * `name` - The slot of the image
+/// * `expected_width` - Number of columns in the image as expected by the client
+/// * `expected_height` - Number of rows in the image as expected by the client
+/// * `capacity` - Largest number of images to keep cached (0 disables the cache)
+/// * `store` - Store to load the image from, when it is not cached
+///
+/// # Errors
+///
+/// * The same errors as [`Store::read_slot`]
+///
+pub fn load_cached(
+    dir: &str,
+    name: &Slot,
+    expected_width: usize,
+    expected_height: usize,
+    capacity: usize,
+    store: &dyn Store,
+) -> Result<Arc<Vec<Vec<u16>>>, LoadError> {
+    let path = image_path(dir, name);
+    let current = stamp(&path);
+
+    let cached = {
+        let mut entries = ENTRIES.lock().unwrap_or_else(|err| err.into_inner());
+        match entries.iter().position(|entry| entry.path == path) {
+            Some(idx) if entries[idx].pinned || entries[idx].stamp == current => {
+                let entry = entries.remove(idx).unwrap();
+                let img = entry.img.clone();
+                entries.push_front(entry);
+                Some(img)
+            }
+            Some(idx) => {
+                entries.remove(idx);
+                None
+            }
+            None => None,
+        }
+    };
+    if let Some(img) = cached {
+        let (height, width) = (img.len(), img.first().map_or(0, |row| row.len()));
+        if width != expected_width || height != expected_height {
+            return Err(LoadError::DimensionMismatch { width, height });
+        }
+        return Ok(img);
+    }
+
+    let img = Arc::new(store.read_slot(dir, name, expected_width, expected_height)?);
+    if current.is_some() && capacity > 0 {
+        let img = img.clone();
+        let pinned = false;
+        insert(
+            Entry {
+                path,
+                stamp: current,
+                img,
+                pinned,
+            },
+            capacity,
+        );
+    }
+    Ok(img)
+}
+
+/// Caches an image that was just saved to a slot, replacing the image that was cached for it
+///
+/// # Arguments
+///
+/// * `dir` - Directory where images are stored
+/// * `name` - The slot of the image
+/// * `img` - The image, as it was written to the image file of the slot
+/// * `capacity` - Largest number of images to keep cached (0 disables the cache)
+/// * `pinned` - Whether the image is kept until the server stops (as with `--preload`)
+///
+pub fn store(dir: &str, name: &Slot, img: Vec<Vec<u16>>, capacity: usize, pinned: bool) {
+    let path = image_path(dir, name);
+    match stamp(&path) {
+        stamp if (stamp.is_some() && capacity > 0) || pinned => insert(
+            Entry {
+                path,
+                stamp,
+                img: Arc::new(img),
+                pinned,
+            },
+            capacity,
+        ),
+        _ => invalidate(dir, name),
+    }
+}
+
+/// Moves the cached image of a slot to another slot, after its file was moved (which keeps the
+/// modification time and size of the file)
+///
+/// # Arguments
+///
+/// * `dir` - Directory where images are stored
+/// * `from` - The slot that the image was moved from
+/// * `to` - The slot that the image was moved to
+///
+pub fn rename(dir: &str, from: &Slot, to: &Slot) {
+    let path = format!("{dir}/image_{from}.bmp");
+    invalidate(dir, to);
+
+    let mut entries = ENTRIES.lock().unwrap_or_else(|err| err.into_inner());
+    if let Some(entry) = entries
+        .iter_mut()
+        .find(|entry| is_image_of(&entry.path, &path))
+    {
+        entry.path = image_path(dir, to);
+    }
+}
+
+/// Loads every image of a directory into the cache, where they are kept until the server stops
+///
+/// Images that can not be loaded are skipped with a warning.
+///
+/// # Arguments
+///
+/// * `dir` - Directory where images are stored
+/// * `store` - Store to load the images from
+///
+/// # Returns
+///
+/// The number of images that were loaded, and the number of bytes that their pixels take
+///
+pub fn preload(dir: &str, store: &dyn Store) -> (usize, usize) {
+    let mut count = 0;
+    let mut bytes = 0;
+
+    for slot in store.list(dir) {
+        let path = image_path(dir, &slot);
+        let stamp = stamp(&path);
+        let result = store
+            .dimensions(dir, &slot)
+            .and_then(|(width, height)| store.read_slot(dir, &slot, width, height));
+        match result {
+            Ok(img) => {
+                count += 1;
+                bytes += img.iter().map(|row| row.len()).sum::<usize>() * size_of::<u16>();
+                let img = Arc::new(img);
+                insert(
+                    Entry {
+                        path,
+                        stamp,
+                        img,
+                        pinned: true,
+                    },
+                    0,
+                );
+            }
+            Err(err) => eprintln!("Skipped preloading image_{}.bmp: {}", slot, err),
+        }
+    }
+    (count, bytes)
+}
+
+/// Removes the cached image of a slot (after its image was moved or removed)
+///
+/// # Arguments
+///
+/// * `dir` - Directory where images are stored
+/// * `name` - The slot of the image
+///
+pub fn invalidate(dir: &str, name: &Slot) {
+    let path = format!("{dir}/image_{name}.bmp");
+    ENTRIES
+        .lock()
+        .unwrap_or_else(|err| err.into_inner())
+        .retain(|entry| !is_image_of(&entry.path, &path));
+}
+
+/// Checks whether a file is the image at a path in any of the forms that it can be stored in
+fn is_image_of(file: &str, path: &str) -> bool {
+    STORED_SUFFIXES
+        .iter()
+        .any(|suffix| file.strip_suffix(suffix) == Some(path))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::image::save_bmp_image;
+    use crate::store::FileStore;
+    use crate::testing::temp_dir;
+
+    /// Gets whether the cache holds an image for the given slot
+    fn is_cached(dir: &str, name: &Slot) -> bool {
+        let path = image_path(dir, name);
+        ENTRIES
+            .lock()
+            .unwrap()
+            .iter()
+            .any(|entry| entry.path == path)
+    }
+
+    #[test]
+    fn changed_files_are_read_again() {
+        let dir = temp_dir("changed_files_are_read_again");
+        let name = Slot::Number(1);
+        save_bmp_image(&[vec![0xF800; 2]], &format!("{dir}/image_1")).unwrap();
+
+        assert_eq!(
+            *load_cached(&dir, &name, 2, 1, 8, &FileStore::default()).unwrap(),
+            [vec![0xF800; 2]]
+        );
+        assert!(is_cached(&dir, &name));
+
+        // replaced behind the back of the cache, with a file of another size
+        save_bmp_image(
+            &[vec![0x07E0; 2], vec![0x07E0; 2]],
+            &format!("{dir}/image_1"),
+        )
+        .unwrap();
+        assert!(matches!(
+            load_cached(&dir, &name, 2, 1, 8, &FileStore::default()),
+            Err(LoadError::DimensionMismatch { .. })
+        ));
+        assert_eq!(
+            *load_cached(&dir, &name, 2, 2, 8, &FileStore::default()).unwrap(),
+            vec![vec![0x07E0; 2]; 2]
+        );
+
+        std::fs::remove_file(format!("{dir}/image_1.bmp")).unwrap();
+        assert!(matches!(
+            load_cached(&dir, &name, 2, 2, 8, &FileStore::default()),
+            Err(LoadError::NotFound)
+        ));
+        assert!(!is_cached(&dir, &name));
+    }
+
+    #[test]
+    fn least_recently_used_images_are_evicted() {
+        let dir = temp_dir("least_recently_used_images_are_evicted");
+        for slot in 1..=3 {
+            save_bmp_image(&[vec![0x001F; 2]], &format!("{dir}/image_{slot}")).unwrap();
+        }
+
+        load_cached(&dir, &Slot::Number(1), 2, 1, 2, &FileStore::default()).unwrap();
+        load_cached(&dir, &Slot::Number(2), 2, 1, 2, &FileStore::default()).unwrap();
+        load_cached(&dir, &Slot::Number(1), 2, 1, 2, &FileStore::default()).unwrap();
+        load_cached(&dir, &Slot::Number(3), 2, 1, 2, &FileStore::default()).unwrap();
+
+        assert!(is_cached(&dir, &Slot::Number(1)));
+        assert!(!is_cached(&dir, &Slot::Number(2)));
+        assert!(is_cached(&dir, &Slot::Number(3)));
+
+        invalidate(&dir, &Slot::Number(3));
+        assert!(!is_cached(&dir, &Slot::Number(3)));
+    }
+
+    #[test]
+    fn preloaded_images_are_pinned() {
+        let dir = temp_dir("preloaded_images_are_pinned");
+        save_bmp_image(&[vec![0xF800; 2]], &format!("{dir}/image_1")).unwrap();
+        save_bmp_image(&vec![vec![0x07E0; 3]; 2], &format!("{dir}/image_2")).unwrap();
+        std::fs::write(format!("{dir}/image_3.bmp"), b"not an image").unwrap();
+
+        // the corrupt image is skipped
+        assert_eq!(preload(&dir, &FileStore::default()), (2, 16));
+        assert!(!is_cached(&dir, &Slot::Number(3)));
+
+        // loading other images does not evict pinned ones, even with the cache disabled
+        save_bmp_image(&[vec![0x001F; 2]], &format!("{dir}/image_4")).unwrap();
+        load_cached(&dir, &Slot::Number(4), 2, 1, 0, &FileStore::default()).unwrap();
+        assert!(!is_cached(&dir, &Slot::Number(4)));
+
+        std::fs::remove_file(format!("{dir}/image_1.bmp")).unwrap();
+        assert_eq!(
+            *load_cached(&dir, &Slot::Number(1), 2, 1, 0, &FileStore::default()).unwrap(),
+            [vec![0xF800; 2]]
+        );
+
+        rename(&dir, &Slot::Number(2), &Slot::Number(5));
+        assert!(!is_cached(&dir, &Slot::Number(2)));
+        assert!(is_cached(&dir, &Slot::Number(5)));
+    }
+}
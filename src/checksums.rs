@@ -0,0 +1,304 @@
+//! SHA-256 checksums of the images, stored next to them in the format of `sha256sum`, so that
+//! copies of the image directory can be checked with `sha256sum -c`
+//!
+//! The pixels of saved images are also hashed (independently of how they are stored), so that
+//! saves which would not change the image of their slot can be skipped.
+
+use std::collections::BTreeMap;
+use std::io::Read;
+use std::sync::Mutex;
+
+use crate::cache::{stamp, Stamp};
+use crate::image::TEMP_SUFFIX;
+use crate::slots::{image_path, Slot};
+use crate::store::Store;
+
+/// Suffix that is appended to the name of an image to get the name of its checksum file
+pub const CHECKSUM_SUFFIX: &str = ".sha256";
+
+/// Outcome of checking the image of a slot against its checksum file
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Verification {
+    /// The image matches its checksum
+    Match,
+    /// The image has no checksum file
+    Missing,
+    /// The checksum file is not in the format of `sha256sum`, or is for another file
+    Malformed,
+    /// The image does not match its checksum, and has been changed (or damaged) since it was saved
+    Mismatch { expected: String, actual: String },
+}
+
+/// SHA-256 digest of the dimensions and pixels of an image (as little-endian 5-6-5 colors row by
+/// row), which is the same for two images only if they are identical
+pub type PixelsDigest = [u8; 32];
+
+/// Digest of the pixels that the image of a slot was saved from, with the stamp of the image file
+/// that the save left
+type StoredDigest = (Stamp, PixelsDigest);
+
+/// Digests of the images that were last saved to (or compared with) each slot, by the directory
+/// and slot of the image
+static STORED_DIGESTS: Mutex<BTreeMap<(String, Slot), StoredDigest>> = Mutex::new(BTreeMap::new());
+
+/// Gets the path of the checksum file of a slot
+///
+/// The checksum file of a compressed image is still named after the uncompressed image, so that a
+/// slot always has a single checksum file. The checksum itself is that of the compressed file.
+///
+/// # Arguments
+///
+/// * `dir` - Directory where images are stored
+/// * `name` - The slot of the image
+///
+pub fn checksum_path(dir: &str, name: &Slot) -> String {
+    format!("{dir}/image_{name}.bmp{CHECKSUM_SUFFIX}")
+}
+
+/// Gets the name of a file from its path
+fn file_name(path: &str) -> &str {
+    path.rsplit('/').next().unwrap_or(path)
+}
+
+/// Computes the SHA-256 digest of a file, as lowercase hexadecimal digits
+///
+/// # Arguments
+///
+/// * `path` - Path of the file
+///
+fn sha256_hex(path: &str) -> std::io::Result<String> {
+    let mut file = std::fs::File::open(path)?;
+    let mut context = ring::digest::Context::new(&ring::digest::SHA256);
+
+    let mut buffer = [0; 8192];
+    loop {
+        match file.read(&mut buffer)? {
+            0 => break,
+            read => context.update(&buffer[..read]),
+        }
+    }
+    Ok(context
+        .finish()
+        .as_ref()
+        .iter()
+        .map(|byte| format!("{:02x}", byte))
+        .collect())
+}
+
+/// Writes the checksum file of a slot, describing its current image
+///
+/// The image must have been replaced (atomically) before, so that the checksum always describes a
+/// complete image. The checksum file is itself replaced atomically.
+///
+/// # Arguments
+///
+/// * `dir` - Directory where images are stored
+/// * `name` - The slot of the image
+///
+pub fn write_checksum(dir: &str, name: &Slot) -> std::io::Result<()> {
+    let image = image_path(dir, name);
+    let digest = sha256_hex(&image)?;
+    let path = checksum_path(dir, name);
+    let temp = format!("{path}{TEMP_SUFFIX}");
+
+    let result = std::fs::write(&temp, format!("{digest}  {}\n", file_name(&image)))
+        .and_then(|()| std::fs::rename(&temp, &path));
+    if result.is_err() {
+        let _ = std::fs::remove_file(&temp);
+    }
+    result
+}
+
+/// Checks the image of a slot against its checksum file
+///
+/// # Arguments
+///
+/// * `dir` - Directory where images are stored
+/// * `name` - The slot of the image
+///
+/// # Errors
+///
+/// * When the image, or its checksum file (if it exists), can not be read
+///
+pub fn verify_checksum(dir: &str, name: &Slot) -> std::io::Result<Verification> {
+    let contents = match std::fs::read_to_string(checksum_path(dir, name)) {
+        Ok(contents) => contents,
+        Err(err) if err.kind() == std::io::ErrorKind::NotFound => return Ok(Verification::Missing),
+        Err(err) => return Err(err),
+    };
+
+    let image = image_path(dir, name);
+    let expected_name = file_name(&image);
+    let Some(expected) = contents
+        .trim_end()
+        .split_once("  ")
+        .filter(|&(digest, file_name)| {
+            digest.len() == 64
+                && digest.bytes().all(|byte| byte.is_ascii_hexdigit())
+                && file_name == expected_name
+        })
+        .map(|(digest, _)| digest.to_ascii_lowercase())
+    else {
+        return Ok(Verification::Malformed);
+    };
+
+    let actual = sha256_hex(&image)?;
+    match actual == expected {
+        true => Ok(Verification::Match),
+        false => Ok(Verification::Mismatch { expected, actual }),
+    }
+}
+
+/// Computes the digest of the pixels of an image
+///
+/// # Arguments
+///
+/// * `img` - The image, as 5-6-5 colors
+///
+pub fn pixels_digest(img: &[Vec<u16>]) -> PixelsDigest {
+    let mut context = ring::digest::Context::new(&ring::digest::SHA256);
+    let width = img.first().map_or(0, |row| row.len());
+    context.update(&(img.len() as u64).to_le_bytes());
+    context.update(&(width as u64).to_le_bytes());
+    for row in img {
+        let bytes: Vec<u8> = row.iter().flat_map(|v| v.to_le_bytes()).collect();
+        context.update(&bytes);
+    }
+
+    let mut digest = [0; 32];
+    digest.copy_from_slice(context.finish().as_ref());
+    digest
+}
+
+/// Records the digest of the pixels that the image of a slot was just saved from
+///
+/// # Arguments
+///
+/// * `dir` - Directory where images are stored
+/// * `name` - The slot of the image
+/// * `digest` - Digest of the saved pixels
+///
+pub fn remember_pixels_digest(dir: &str, name: &Slot, digest: PixelsDigest) {
+    let key = (dir.to_string(), name.clone());
+    let mut digests = STORED_DIGESTS.lock().unwrap_or_else(|err| err.into_inner());
+    match stamp(&image_path(dir, name)) {
+        Some(stamp) => digests.insert(key, (stamp, digest)),
+        None => digests.remove(&key),
+    };
+}
+
+/// Gets the digest of the pixels of the image currently stored in a slot
+///
+/// The digest that was recorded when the image was saved is used while the image file has not
+/// changed since, and the image is loaded (and hashed) otherwise.
+///
+/// # Arguments
+///
+/// * `dir` - Directory where images are stored
+/// * `name` - The slot of the image
+/// * `width` - Number of columns of the image it is compared with
+/// * `height` - Number of rows of the image it is compared with
+/// * `store` - Store that the image is kept in
+///
+/// # Returns
+///
+/// The digest, unless the slot has no image (or it has to be loaded, and is not of the given
+/// dimensions)
+///
+pub fn stored_pixels_digest(
+    dir: &str,
+    name: &Slot,
+    width: usize,
+    height: usize,
+    store: &dyn Store,
+) -> Option<PixelsDigest> {
+    let key = (dir.to_string(), name.clone());
+    let current = stamp(&image_path(dir, name));
+    if let Some((stamp, digest)) = STORED_DIGESTS
+        .lock()
+        .unwrap_or_else(|err| err.into_inner())
+        .get(&key)
+    {
+        if Some(*stamp) == current {
+            return Some(*digest);
+        }
+    }
+
+    let digest = pixels_digest(&store.read_slot(dir, name, width, height).ok()?);
+    if let Some(stamp) = current {
+        STORED_DIGESTS
+            .lock()
+            .unwrap_or_else(|err| err.into_inner())
+            .insert(key, (stamp, digest));
+    }
+    Some(digest)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::temp_dir;
+
+    #[test]
+    fn checksums_use_the_format_of_sha256sum() {
+        let dir = temp_dir("checksums_use_the_format_of_sha256sum");
+        std::fs::write(format!("{dir}/image_1.bmp"), b"abc").unwrap();
+
+        write_checksum(&dir, &Slot::Number(1)).unwrap();
+        assert_eq!(
+            std::fs::read_to_string(checksum_path(&dir, &Slot::Number(1))).unwrap(),
+            "ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad  image_1.bmp\n"
+        );
+        assert_eq!(
+            verify_checksum(&dir, &Slot::Number(1)).unwrap(),
+            Verification::Match
+        );
+    }
+
+    #[test]
+    fn damaged_images_do_not_match() {
+        let dir = temp_dir("damaged_images_do_not_match");
+        let name = Slot::Number(2);
+        assert!(verify_checksum(&dir, &name).is_ok_and(|v| v == Verification::Missing));
+
+        std::fs::write(format!("{dir}/image_2.bmp"), b"abc").unwrap();
+        write_checksum(&dir, &name).unwrap();
+        std::fs::write(format!("{dir}/image_2.bmp"), b"abd").unwrap();
+        assert!(matches!(
+            verify_checksum(&dir, &name).unwrap(),
+            Verification::Mismatch { .. }
+        ));
+
+        std::fs::write(checksum_path(&dir, &name), "abc  image_2.bmp\n").unwrap();
+        assert_eq!(
+            verify_checksum(&dir, &name).unwrap(),
+            Verification::Malformed
+        );
+    }
+
+    #[test]
+    fn stored_digests_follow_the_image_file() {
+        let dir = temp_dir("stored_digests_follow_the_image_file");
+        let name = Slot::Number(3);
+        let store = crate::store::FileStore::default();
+        let img = vec![vec![0xF800, 0x07E0]];
+        assert_eq!(stored_pixels_digest(&dir, &name, 2, 1, &store), None);
+
+        // images that were not saved by the server are hashed from their file
+        crate::image::save_bmp_image(&img, &format!("{dir}/image_3")).unwrap();
+        assert_eq!(
+            stored_pixels_digest(&dir, &name, 2, 1, &store),
+            Some(pixels_digest(&img))
+        );
+        assert_ne!(
+            stored_pixels_digest(&dir, &name, 1, 2, &store),
+            Some(pixels_digest(&[vec![0xF800], vec![0x07E0]]))
+        );
+
+        crate::image::save_bmp_image(&[vec![0x001F; 3]], &format!("{dir}/image_3")).unwrap();
+        assert_ne!(
+            stored_pixels_digest(&dir, &name, 3, 1, &store),
+            Some(pixels_digest(&img))
+        );
+    }
+}
@@ -0,0 +1,137 @@
+//! Orderly close of a connection, so that the client always receives the last bytes that the
+//! server sent (such as the status byte of an error) instead of a reset
+//!
+//! Closing a socket whose receive buffer still holds unread bytes (such as the rows of a save that
+//! was refused after its header) makes the operating system reset the connection, which can discard
+//! the reply before the client has read it. Every connection is therefore closed as follows:
+//!
+//! 1. The reply (and, with TLS, the `close_notify` alert) is flushed.
+//! 2. The sending half of the connection is shut down, so the client reads the end of the stream
+//!    right after the reply.
+//! 3. After an error, whatever the client still sends is read and discarded, until it closes its
+//!    side of the connection, [`MAX_DRAIN_LEN`] bytes were discarded or [`DRAIN_TIMEOUT`] has
+//!    passed.
+//! 4. Both halves of the connection are shut down, and the socket is closed with a linger of
+//!    [`LINGER_TIMEOUT`], so that the close waits for the reply to be delivered.
+//!
+//! Clients should read until the end of the stream (or until they have read the reply they expect)
+//! before closing their side of the connection.
+
+use std::io::Read;
+use std::net::{Shutdown, TcpStream};
+use std::time::{Duration, Instant};
+
+/// Longest time for which closing a socket waits for the bytes that were sent to be delivered
+pub const LINGER_TIMEOUT: Duration = Duration::from_secs(2);
+/// Longest time for which the bytes that the client still sends after an error are discarded
+pub const DRAIN_TIMEOUT: Duration = Duration::from_secs(1);
+/// Largest number of bytes that the client may still send after an error before the connection is
+/// closed regardless
+pub const MAX_DRAIN_LEN: usize = 4 * 1024 * 1024;
+
+/// Sets how long closing a socket waits for the bytes that were sent to be delivered
+///
+/// # Arguments
+///
+/// * `stream` - The connection
+/// * `timeout` - How long to wait (`None` to close in the background, as by default)
+///
+/// # Errors
+///
+/// * When the option can not be set on the socket
+///
+#[cfg(unix)]
+pub fn set_linger(stream: &TcpStream, timeout: Option<Duration>) -> std::io::Result<()> {
+    use std::os::unix::io::AsRawFd;
+
+    let linger = libc::linger {
+        l_onoff: timeout.is_some() as libc::c_int,
+        l_linger: timeout.map_or(0, |timeout| timeout.as_secs() as libc::c_int),
+    };
+    // SAFETY: the descriptor belongs to the stream, and the option is read from a valid `linger`
+    let result = unsafe {
+        libc::setsockopt(
+            stream.as_raw_fd(),
+            libc::SOL_SOCKET,
+            libc::SO_LINGER,
+            &linger as *const libc::linger as *const libc::c_void,
+            std::mem::size_of::<libc::linger>() as libc::socklen_t,
+        )
+    };
+    match result {
+        0 => Ok(()),
+        _ => Err(std::io::Error::last_os_error()),
+    }
+}
+
+/// Sets how long closing a socket waits for the bytes that were sent to be delivered, which is not
+/// supported on this platform (so sockets are always closed in the background)
+#[cfg(not(unix))]
+pub fn set_linger(_stream: &TcpStream, _timeout: Option<Duration>) -> std::io::Result<()> {
+    Ok(())
+}
+
+/// Shuts a connection down in order, after its reply has been flushed
+///
+/// # Arguments
+///
+/// * `stream` - The connection
+/// * `drain` - Whether to discard what the client still sends (after an error, when the request
+///   may not have been read completely)
+///
+pub fn close_connection(mut stream: &TcpStream, drain: bool) {
+    let _ = stream.shutdown(Shutdown::Write);
+
+    if drain {
+        let deadline = Instant::now() + DRAIN_TIMEOUT;
+        let mut buffer = [0u8; 4096];
+        let mut drained = 0;
+        while drained < MAX_DRAIN_LEN {
+            let left = deadline.saturating_duration_since(Instant::now());
+            if left.is_zero() || stream.set_read_timeout(Some(left)).is_err() {
+                break;
+            }
+            match stream.read(&mut buffer) {
+                Ok(0) | Err(_) => break,
+                Ok(len) => drained += len,
+            }
+        }
+    }
+
+    let _ = stream.shutdown(Shutdown::Both);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Write;
+    use std::net::TcpListener;
+
+    #[test]
+    fn replies_are_delivered_before_unread_requests_are_discarded() {
+        let listener = TcpListener::bind(("127.0.0.1", 0)).unwrap();
+        let addr = listener.local_addr().unwrap();
+
+        let client = std::thread::spawn(move || {
+            let mut stream = TcpStream::connect(addr).unwrap();
+            // the server only reads the first byte, and replies to it while the rest is unread
+            stream.write_all(&vec![0xAB; 256 * 1024]).unwrap();
+            stream.shutdown(Shutdown::Write).unwrap();
+            let mut reply = Vec::new();
+            stream.read_to_end(&mut reply).unwrap();
+            reply
+        });
+
+        let (mut stream, _) = listener.accept().unwrap();
+        set_linger(&stream, Some(LINGER_TIMEOUT)).unwrap();
+        let mut byte = [0u8];
+        stream.read_exact(&mut byte).unwrap();
+        stream.write_all(&[0xF1]).unwrap();
+        stream.flush().unwrap();
+        close_connection(&stream, true);
+        drop(stream);
+
+        // the client reads the reply and then the end of the stream, instead of a reset
+        assert_eq!(client.join().unwrap(), [0xF1]);
+    }
+}
@@ -0,0 +1,1896 @@
+//! Subcommands that work on the image directory directly, without starting the server
+
+use clap::{Subcommand, ValueEnum};
+
+use crate::archive::{backup, restore_backup, RestoreOutcome, RestoreReport};
+use crate::checksums::*;
+use crate::image::{
+    import_image, load_whole_bmp, overlay_image, rgb565_2_rgb888, rgb565_bytes, rgb888_2_rgb565,
+    save_bmp_image, save_gif_animation, save_png_image, scale_nearest, upscale, LoadError,
+};
+use crate::metadata::*;
+use crate::palette::{Palette, MAX_PALETTE_LEN};
+use crate::slots::*;
+use crate::store::{migrate_slot, Backend, FileStore, SqliteStore, Store};
+use crate::trash::{list_trash, restore_trash};
+
+#[derive(Subcommand, Debug)]
+pub enum Command {
+    /// List every slot that has an image, along with how and when it was last saved
+    List {
+        /// Print the slots as a JSON array instead of a table, for scripts
+        #[arg(long)]
+        json: bool,
+    },
+
+    /// Swap the image stored in a slot with its backup (the image it last replaced), or restore
+    /// every slot of an archive written by the backup subcommand
+    Restore {
+        /// The slot of the image, either a number or a name
+        #[arg(long, required_unless_present = "from", conflicts_with = "from")]
+        slot: Option<Slot>,
+
+        /// Archive to restore every slot from, instead of swapping a slot with its backup
+        #[arg(long)]
+        from: Option<String>,
+
+        /// Replace the images of slots that already have one, when restoring from an archive
+        #[arg(long, requires = "from")]
+        overwrite: bool,
+    },
+
+    /// List every version in the history of a slot, from the oldest to the newest
+    History {
+        /// The slot of the image, either a number or a name
+        #[arg(long)]
+        slot: Slot,
+    },
+
+    /// Replace the image stored in a slot with a version from its history
+    Revert {
+        /// The slot of the image, either a number or a name
+        #[arg(long)]
+        slot: Slot,
+
+        /// The version to revert to, as listed by the history subcommand
+        #[arg(long)]
+        version: u128,
+    },
+
+    /// Write every version in the history of a slot as the frames of an animated GIF image
+    Timelapse {
+        /// The slot of the image, either a number or a name
+        #[arg(long)]
+        slot: Slot,
+
+        /// Path of the GIF image to write
+        #[arg(long)]
+        out: String,
+
+        /// Time for which each version is shown, in milliseconds
+        #[arg(long, default_value_t = 250)]
+        delay_ms: u16,
+    },
+
+    /// Write the image stored in a slot (or in every slot) in a format that can be viewed or
+    /// embedded in firmware
+    Export {
+        /// The slot of the image, either a number or a name
+        #[arg(long, required_unless_present = "all", conflicts_with = "all")]
+        slot: Option<Slot>,
+
+        /// Export every slot that has an image, into the directory given by `--out-dir`
+        #[arg(long, requires = "out_dir")]
+        all: bool,
+
+        /// Format to write the image in (by default, PNG when exporting every slot or when the
+        /// path ends with `.png`, and raw otherwise)
+        #[arg(long, value_enum)]
+        format: Option<ExportFormat>,
+
+        /// Path of the file to write
+        #[arg(long, required_unless_present = "all", conflicts_with = "all")]
+        out: Option<String>,
+
+        /// Directory to write the images into, when exporting every slot
+        #[arg(long, requires = "all")]
+        out_dir: Option<String>,
+
+        /// Factor by which the image is scaled up, by repeating every pixel
+        #[arg(long, default_value_t = 1, value_parser = clap::value_parser!(u16).range(1..=16))]
+        scale: u16,
+
+        /// Also write a C header with the dimensions of the image, next to the file
+        #[arg(long)]
+        header: bool,
+    },
+
+    /// Check every image against its checksum file, and report images that are damaged or have no
+    /// checksum
+    Verify,
+
+    /// Count how many pixels of the image stored in a slot are of each color of the palette
+    Histogram {
+        /// The slot of the image, either a number or a name
+        #[arg(long)]
+        slot: Slot,
+
+        /// Print the counts as JSON instead of a table, for scripts
+        #[arg(long)]
+        json: bool,
+    },
+
+    /// Store an image of any common format (PNG, JPEG or BMP) in a slot, scaled to the size of the
+    /// canvas and reduced to the colors of the palette
+    Import {
+        /// The slot to store the image in, either a number or a name
+        #[arg(long)]
+        slot: Slot,
+
+        /// Path of the image to import
+        #[arg(long)]
+        file: String,
+
+        /// Number of columns of the canvas
+        #[arg(long, default_value_t = 320, value_parser = clap::value_parser!(u16).range(1..))]
+        width: u16,
+
+        /// Number of rows of the canvas
+        #[arg(long, default_value_t = 240, value_parser = clap::value_parser!(u16).range(1..))]
+        height: u16,
+    },
+
+    /// Draw the image of a slot over the image of another slot, and store the result in a third
+    /// slot (which may be either of them)
+    Merge {
+        /// The slot of the image to draw over, either a number or a name
+        #[arg(long)]
+        base: Slot,
+
+        /// The slot of the image to draw, either a number or a name
+        #[arg(long)]
+        overlay: Slot,
+
+        /// The slot to store the merged image in, either a number or a name
+        #[arg(long)]
+        out: Slot,
+
+        /// Code of the palette whose pixels of the overlay are not drawn (the code of white by
+        /// default)
+        #[arg(long)]
+        transparent_code: Option<u8>,
+
+        /// Scale the overlay to the dimensions of the base when they differ, instead of refusing
+        /// to merge them
+        #[arg(long)]
+        scale: bool,
+    },
+
+    /// Move every image of the image directory into the given store, out of the other one (along
+    /// with its metadata)
+    MigrateStore {
+        /// Store to move the images into
+        #[arg(long, value_enum)]
+        to: Backend,
+
+        /// Path of the database to move the images into or out of
+        #[arg(long, default_value = "canvas.db")]
+        db: String,
+    },
+
+    /// Rewrite every image of the image directory for a new palette, so that every pixel keeps its
+    /// code (and gets the color of the code in the new palette)
+    MigratePalette {
+        /// Palette file (TOML or JSON) that the images were saved with
+        #[arg(long)]
+        from: String,
+
+        /// Palette file (TOML or JSON) to rewrite the images for
+        #[arg(long)]
+        to: String,
+
+        /// Print how many pixels of every image would change, without rewriting any of them
+        #[arg(long)]
+        dry_run: bool,
+    },
+
+    /// Compare the images of two slots (or of a slot and a BMP file), exiting with 0 when they are
+    /// identical, 1 when they differ and 2 when either of them can not be loaded
+    Diff {
+        /// The first image, either a slot (a number or a name) or the path of a BMP file
+        #[arg(long)]
+        a: String,
+
+        /// The second image, either a slot (a number or a name) or the path of a BMP file
+        #[arg(long)]
+        b: String,
+
+        /// Write a PNG image to this path, which shows the pixels that differ in magenta over a
+        /// dimmed copy of the first image
+        #[arg(long)]
+        out: Option<String>,
+    },
+
+    /// Write every file of the image directory (images, backups, metadata, thumbnails and history)
+    /// into a zip archive, along with a manifest of the files
+    Backup {
+        /// Path of the archive to write
+        #[arg(long, default_value = "canvas-backup.zip")]
+        out: String,
+    },
+
+    /// List or restore the images that were removed from their slots (such as by clearing them)
+    Trash {
+        #[command(subcommand)]
+        action: TrashCommand,
+    },
+}
+
+/// Subcommands of the trash subcommand
+#[derive(Subcommand, Debug)]
+pub enum TrashCommand {
+    /// List every image in the trash, from the oldest to the newest
+    List,
+
+    /// Move an image from the trash back into its slot, which must not have an image
+    Restore {
+        /// The entry of the image, as listed by `trash list`
+        entry: String,
+    },
+}
+
+/// Formats that images can be exported in
+#[derive(ValueEnum, Clone, Copy, Debug)]
+pub enum ExportFormat {
+    /// Little-endian 16-bit (5-6-5) colors in row-major order from the top row, without a header
+    Raw,
+    /// 24-bit color PNG image
+    Png,
+}
+
+impl ExportFormat {
+    /// Gets the extension of the files written in this format
+    fn extension(self) -> &'static str {
+        match self {
+            Self::Raw => "bin",
+            Self::Png => "png",
+        }
+    }
+}
+
+/// A slot as listed by the list subcommand
+#[derive(serde::Serialize)]
+struct ListedSlot {
+    #[serde(flatten)]
+    info: SlotInfo,
+    /// Whether every color of the image is in the active palette, if the image could be read
+    in_palette: Option<bool>,
+}
+
+/// Gets whether every color of the image stored in a slot is in a palette, if the image can be read
+///
+/// Images saved with another palette (or edited outside of the canvas) are still served, but their
+/// colors are sent as the nearest colors of the palette.
+///
+/// # Arguments
+///
+/// * `dir` - Directory where images are stored
+/// * `slot` - The slot of the image
+/// * `palette` - The palette that the colors are checked against
+///
+fn colors_in_palette(dir: &str, slot: &Slot, palette: &Palette) -> Option<bool> {
+    let img = load_whole_bmp(&format!("{dir}/image_{slot}"), palette).ok()?;
+    Some(
+        img.iter()
+            .flatten()
+            .all(|&color| palette.color_2_code(color).is_some()),
+    )
+}
+
+/// Gets every slot of a directory that has an image, as listed by the list subcommand
+///
+/// # Arguments
+///
+/// * `dir` - Directory where images are stored
+/// * `palette` - The palette that the colors of the images are checked against
+///
+fn list_inventory(dir: &str, palette: &Palette) -> Vec<ListedSlot> {
+    slot_inventory(dir)
+        .into_iter()
+        .map(|info| ListedSlot {
+            in_palette: colors_in_palette(dir, &info.slot, palette),
+            info,
+        })
+        .collect()
+}
+
+/// Number of pixels of an image that are sent as a code of the palette
+#[derive(serde::Serialize, Debug, PartialEq, Eq)]
+struct ColorUsage {
+    /// The code of the color
+    code: u8,
+    /// The 16-bit (5-6-5) color of the code
+    rgb565: u16,
+    /// Number of pixels that are exactly of this color
+    pixels: usize,
+    /// Number of pixels of colors outside of the palette, which are sent as this color because it
+    /// is the nearest color of the palette
+    approximated: usize,
+}
+
+/// Counts the pixels of an image that are sent as each code of a palette, in the order of the codes
+///
+/// # Arguments
+///
+/// * `img` - The 16-bit color bitmap to count the pixels of
+/// * `palette` - The palette that the colors of the image are converted with
+///
+fn color_histogram(img: &[Vec<u16>], palette: &Palette) -> Vec<ColorUsage> {
+    let mut usage: Vec<ColorUsage> = (0..MAX_PALETTE_LEN as u8)
+        .filter_map(|code| {
+            Some(ColorUsage {
+                code,
+                rgb565: palette.code_2_color(code)?,
+                pixels: 0,
+                approximated: 0,
+            })
+        })
+        .collect();
+
+    // the same colors are converted to codes when the image is loaded by the client
+    for &color in img.iter().flatten() {
+        let (code, exact) = match palette.color_2_code(color) {
+            Some(code) => (code, true),
+            None => (palette.nearest_code(color), false),
+        };
+        let entry = usage.iter_mut().find(|entry| entry.code == code).unwrap();
+        match exact {
+            true => entry.pixels += 1,
+            false => entry.approximated += 1,
+        }
+    }
+    usage
+}
+
+/// Color that the pixels which differ are drawn in, in the visualization of a diff (magenta)
+const DIFF_COLOR: u16 = 0xF81F;
+
+/// Pixels in which two images of the same dimensions differ
+#[derive(Debug, PartialEq, Eq)]
+struct ImageDiff {
+    /// Number of pixels that differ
+    pixels: usize,
+    /// Leftmost column, topmost row, rightmost column and bottommost row of the pixels that
+    /// differ, if any do
+    bounds: Option<(usize, usize, usize, usize)>,
+}
+
+/// Loads an image to compare, which is either the image of a slot (if it is a valid slot) or a
+/// BMP file, and gets a description of it
+///
+/// # Arguments
+///
+/// * `dir` - Directory where images are stored
+/// * `image` - A slot (a number or a name), or the path of a BMP file
+/// * `palette` - The active palette
+///
+/// # Errors
+///
+/// * The same errors as [`load_whole_bmp`]
+///
+fn load_compared(
+    dir: &str,
+    image: &str,
+    palette: &Palette,
+) -> Result<(String, Vec<Vec<u16>>), LoadError> {
+    match image.parse::<Slot>() {
+        Ok(slot) => load_whole_bmp(&format!("{dir}/image_{slot}"), palette)
+            .map(|img| (format!("image_{slot}.bmp"), img)),
+        Err(_) => load_whole_bmp(image.strip_suffix(".bmp").unwrap_or(image), palette)
+            .map(|img| (image.to_string(), img)),
+    }
+}
+
+/// Finds the pixels in which two images of the same dimensions differ
+///
+/// # Arguments
+///
+/// * `a` - The first 16-bit color bitmap
+/// * `b` - The second 16-bit color bitmap
+///
+fn diff_images(a: &[Vec<u16>], b: &[Vec<u16>]) -> ImageDiff {
+    let mut diff = ImageDiff {
+        pixels: 0,
+        bounds: None,
+    };
+    for (y, (row_a, row_b)) in a.iter().zip(b).enumerate() {
+        for (x, _) in row_a
+            .iter()
+            .zip(row_b)
+            .enumerate()
+            .filter(|(_, (a, b))| a != b)
+        {
+            diff.pixels += 1;
+            diff.bounds = Some(match diff.bounds {
+                None => (x, y, x, y),
+                Some((left, top, right, _)) => (left.min(x), top, right.max(x), y),
+            });
+        }
+    }
+    diff
+}
+
+/// Draws the pixels in which two images of the same dimensions differ in [`DIFF_COLOR`], over a
+/// dimmed grayscale copy of the first image
+///
+/// # Arguments
+///
+/// * `a` - The first 16-bit color bitmap
+/// * `b` - The second 16-bit color bitmap
+///
+fn diff_visualization(a: &[Vec<u16>], b: &[Vec<u16>]) -> Vec<Vec<u16>> {
+    a.iter()
+        .zip(b)
+        .map(|(row_a, row_b)| {
+            row_a
+                .iter()
+                .zip(row_b)
+                .map(|(&a, &b)| match a == b {
+                    true => {
+                        let [r, g, b] = rgb565_2_rgb888(a);
+                        let luma = (r as u32 * 299 + g as u32 * 587 + b as u32 * 114) / 1000;
+                        let dimmed = (64 + luma / 4) as u8;
+                        rgb888_2_rgb565(dimmed, dimmed, dimmed)
+                    }
+                    false => DIFF_COLOR,
+                })
+                .collect()
+        })
+        .collect()
+}
+
+/// Number of pixels of an image that are recolored by a palette migration
+#[derive(Debug, Default, PartialEq, Eq)]
+struct PaletteMigration {
+    /// Number of pixels whose color changes
+    recolored: usize,
+    /// Number of pixels whose colors are not in the old palette, which are given the code of the
+    /// nearest color of the old palette
+    unmatched: usize,
+    /// Number of pixels whose codes are not in the new palette, which are given the nearest color
+    /// of the new palette
+    unmapped: usize,
+}
+
+/// Recolors an image from one palette to another, keeping the code of every pixel
+///
+/// # Arguments
+///
+/// * `img` - The image, with the colors of the old palette
+/// * `from` - The palette that the image was saved with
+/// * `to` - The palette to recolor the image for
+///
+fn migrate_colors(
+    img: &[Vec<u16>],
+    from: &Palette,
+    to: &Palette,
+) -> (Vec<Vec<u16>>, PaletteMigration) {
+    let mut migration = PaletteMigration::default();
+    let migrated = img
+        .iter()
+        .map(|row| {
+            row.iter()
+                .map(|&color| {
+                    let code = from.color_2_code(color).unwrap_or_else(|| {
+                        migration.unmatched += 1;
+                        from.nearest_code(color)
+                    });
+                    let migrated = to.code_2_color(code).unwrap_or_else(|| {
+                        migration.unmapped += 1;
+                        to.code_2_color(to.nearest_code(color)).unwrap()
+                    });
+                    if migrated != color {
+                        migration.recolored += 1;
+                    }
+                    migrated
+                })
+                .collect()
+        })
+        .collect();
+    (migrated, migration)
+}
+
+/// Recolors the image stored in a slot from one palette to another, after backing it up (unless
+/// nothing would change, or it is only a dry run)
+///
+/// # Arguments
+///
+/// * `dir` - Directory where images are stored
+/// * `slot` - The slot of the image
+/// * `from` - The palette that the image was saved with
+/// * `to` - The palette to recolor the image for
+/// * `dry_run` - Whether to leave the image as it is
+///
+fn migrate_slot_palette(
+    dir: &str,
+    slot: &Slot,
+    from: &Palette,
+    to: &Palette,
+    dry_run: bool,
+) -> Result<PaletteMigration, String> {
+    // a running server may be saving to the same slot, so it is skipped until it is done
+    let _lock = match lock_slot(dir, slot) {
+        Ok(lock) => lock,
+        Err(err) if err.kind() == std::io::ErrorKind::AlreadyExists => {
+            return Err("it is being saved by the server".to_string())
+        }
+        Err(err) => return Err(format!("failed to lock it: {}", err)),
+    };
+
+    let img =
+        load_whole_bmp(&format!("{dir}/image_{slot}"), from).map_err(|err| err.to_string())?;
+    let (migrated, migration) = migrate_colors(&img, from, to);
+    if dry_run || migration.recolored == 0 {
+        return Ok(migration);
+    }
+
+    backup_slot(dir, slot).map_err(|err| format!("failed to back it up: {}", err))?;
+    let path = format!("{dir}/image_{slot}");
+    save_bmp_image(&migrated, &path).map_err(|err| err.to_string())?;
+    // the image may have been stored compressed, which would otherwise still be served
+    remove_stale_image(&format!("{path}.bmp")).map_err(|err| err.to_string())?;
+    if let Err(err) = write_checksum(dir, slot) {
+        eprintln!("Failed to write checksum of image_{}.bmp: {}", slot, err);
+    }
+    if let Err(err) = archive_slot(dir, slot) {
+        eprintln!("Failed to archive image_{}.bmp: {}", slot, err);
+    }
+    Ok(migration)
+}
+
+/// Calls a function that writes the image of a slot while holding the lock of the slot
+///
+/// # Arguments
+///
+/// * `dir` - Directory where images are stored
+/// * `slot` - The slot that is written
+/// * `write` - The function that writes the image of the slot
+///
+/// # Errors
+///
+/// * A message describing why the slot could not be locked, such as it being saved by the server
+/// * The error of `write`
+///
+fn with_slot_locked(
+    dir: &str,
+    slot: &Slot,
+    write: impl FnOnce() -> std::io::Result<()>,
+) -> Result<(), String> {
+    // a running server may be saving to the same slot, so never write it at the same time
+    let _lock = lock_slot(dir, slot).map_err(|err| match err.kind() {
+        std::io::ErrorKind::AlreadyExists => {
+            "it is being saved by the server, try again once it has finished".to_string()
+        }
+        _ => format!("failed to lock it: {}", err),
+    })?;
+    write().map_err(|err| err.to_string())
+}
+
+/// Stores an image in a slot in place of its current image, which is backed up first
+///
+/// # Arguments
+///
+/// * `dir` - Directory where images are stored
+/// * `slot` - The slot to store the image in
+/// * `img` - The image to store
+///
+/// # Errors
+///
+/// * A message describing why the image was not stored, such as the slot being saved by the server
+///
+fn store_image(dir: &str, slot: &Slot, img: &[Vec<u16>]) -> Result<(), String> {
+    std::fs::create_dir_all(dir)
+        .map_err(|err| format!("Failed to create image directory {}: {}", dir, err))?;
+
+    // a running server may be saving to the same slot, so never write it at the same time
+    let _lock = lock_slot(dir, slot).map_err(|err| match err.kind() {
+        std::io::ErrorKind::AlreadyExists => format!(
+            "image_{}.bmp is being saved by the server, try again once it has finished",
+            slot
+        ),
+        _ => format!("Failed to lock image_{}.bmp: {}", slot, err),
+    })?;
+
+    backup_slot(dir, slot)
+        .map_err(|err| format!("Failed to back up image_{}.bmp: {}", slot, err))?;
+    save_bmp_image(img, &format!("{dir}/image_{slot}"))
+        .map_err(|err| format!("Failed to save image_{}.bmp: {}", slot, err))?;
+    // the image may have been stored compressed, which would otherwise still be served
+    remove_stale_image(&format!("{dir}/image_{slot}.bmp"))
+        .map_err(|err| format!("Failed to remove the previous image_{}.bmp: {}", slot, err))?;
+    if let Err(err) = write_checksum(dir, slot) {
+        eprintln!("Failed to write checksum of image_{}.bmp: {}", slot, err);
+    }
+    if let Err(err) = archive_slot(dir, slot) {
+        eprintln!("Failed to archive image_{}.bmp: {}", slot, err);
+    }
+    Ok(())
+}
+
+/// Formats a duration in the largest unit that it has at least one of (such as `"3h"`)
+///
+/// # Arguments
+///
+/// * `ms` - The duration, in milliseconds
+///
+fn format_age(ms: u64) -> String {
+    let secs = ms / 1000;
+    match secs {
+        0..60 => format!("{}s", secs),
+        60..3600 => format!("{}m", secs / 60),
+        3600..86400 => format!("{}h", secs / 3600),
+        _ => format!("{}d", secs / 86400),
+    }
+}
+
+/// Builds a C header that describes the dimensions of an exported image
+///
+/// # Arguments
+///
+/// * `out` - Path of the exported image, whose file name (without extension) prefixes the names
+/// * `width` - Number of columns in the image
+/// * `height` - Number of rows in the image
+///
+fn export_header(out: &str, width: usize, height: usize) -> String {
+    let stem = std::path::Path::new(out)
+        .file_stem()
+        .map_or("image".into(), |stem| stem.to_string_lossy());
+    let prefix: String = stem
+        .chars()
+        .map(|c| match c.is_ascii_alphanumeric() {
+            true => c.to_ascii_uppercase(),
+            false => '_',
+        })
+        .collect();
+
+    format!("#pragma once\n\n#define {prefix}_WIDTH {width}\n#define {prefix}_HEIGHT {height}\n")
+}
+
+/// Exports the image stored in a slot, and reports the outcome
+///
+/// Gets whether the image (and its header, if requested) was written.
+///
+/// # Arguments
+///
+/// * `dir` - Directory where images are stored
+/// * `slot` - The slot of the image
+/// * `format` - Format to write the image in
+/// * `out` - Path of the file to write
+/// * `scale` - Factor by which the image is scaled up
+/// * `header` - Whether to also write a C header with the dimensions of the image
+/// * `palette` - The active palette
+///
+fn export_slot(
+    dir: &str,
+    slot: &Slot,
+    format: ExportFormat,
+    out: &str,
+    scale: usize,
+    header: bool,
+    palette: &Palette,
+) -> bool {
+    // a missing slot is reported instead of being exported as a blank image
+    let img = match load_whole_bmp(&format!("{dir}/image_{slot}"), palette) {
+        Ok(img) => upscale(&img, scale),
+        Err(err) => {
+            eprintln!("Failed to load image_{}.bmp: {}", slot, err);
+            return false;
+        }
+    };
+
+    let result = match format {
+        ExportFormat::Raw => std::fs::write(out, rgb565_bytes(&img)).map_err(|err| err.to_string()),
+        ExportFormat::Png => save_png_image(&img, out.strip_suffix(".png").unwrap_or(out))
+            .map_err(|err| err.to_string()),
+    };
+    if let Err(err) = result {
+        eprintln!("Failed to write {}: {}", out, err);
+        return false;
+    }
+    println!("Exported image_{}.bmp to {}", slot, out);
+
+    if header {
+        let width = img.first().map_or(0, |row| row.len());
+        let path = std::path::Path::new(out).with_extension("h");
+        if let Err(err) = std::fs::write(&path, export_header(out, width, img.len())) {
+            eprintln!("Failed to write {}: {}", path.display(), err);
+            return false;
+        }
+        println!("Wrote dimensions to {}", path.display());
+    }
+    true
+}
+
+/// Runs a subcommand, and gets the exit code of the process
+///
+/// # Arguments
+///
+/// * `command` - The subcommand to run
+/// * `dir` - Directory where images are stored
+/// * `palette` - The palette that codes are converted with
+///
+pub fn run(command: &Command, dir: &str, palette: &Palette) -> i32 {
+    match command {
+        Command::List { json: true } => {
+            match serde_json::to_string_pretty(&list_inventory(dir, palette)) {
+                Ok(json) => println!("{}", json),
+                Err(err) => {
+                    eprintln!("Failed to serialize the slots of {}: {}", dir, err);
+                    return 1;
+                }
+            }
+            0
+        }
+        Command::List { json: false } => {
+            let inventory = list_inventory(dir, palette);
+            if inventory.is_empty() {
+                println!("{} has no images", dir);
+                return 0;
+            }
+
+            let now_ms = std::time::SystemTime::now()
+                .duration_since(std::time::UNIX_EPOCH)
+                .map_or(0, |duration| duration.as_millis() as u64);
+
+            println!(
+                "{:<8} {:>11} {:>10} {:>9} {:>7}  LAST SAVE",
+                "SLOT", "ROWS x COLS", "BYTES", "MODIFIED", "PALETTE"
+            );
+            for ListedSlot { info, in_palette } in inventory {
+                let dimensions = match (info.height, info.width) {
+                    (Some(height), Some(width)) => format!("{} x {}", height, width),
+                    _ => "unreadable".to_string(),
+                };
+                let modified = format!(
+                    "{} ago",
+                    format_age(now_ms.saturating_sub(info.modified_ms))
+                );
+
+                // the metadata is only informational, so slots without it are still listed
+                let last_save = read_metadata(dir, &info.slot).map_or(String::new(), |metadata| {
+                    format!(
+                        "at {} by {} ({} compressed rows, {} ms)",
+                        metadata.timestamp_ms,
+                        metadata.peer,
+                        metadata.compressed_rows,
+                        metadata.duration_ms
+                    )
+                });
+                let in_palette = match in_palette {
+                    Some(true) => "yes",
+                    Some(false) => "no",
+                    None => "-",
+                };
+                let line = format!(
+                    "{:<8} {:>11} {:>10} {:>9} {:>7}  {}",
+                    info.slot, dimensions, info.bytes, modified, in_palette, last_save
+                );
+                println!("{}", line.trim_end());
+            }
+            0
+        }
+        Command::Restore {
+            slot: Some(slot), ..
+        } => match with_slot_locked(dir, slot, || restore_slot(dir, slot)) {
+            Ok(()) => {
+                println!("Restored the backup of image_{}.bmp", slot);
+                0
+            }
+            Err(err) => {
+                eprintln!(
+                    "Failed to restore the backup of image_{}.bmp: {}",
+                    slot, err
+                );
+                1
+            }
+        },
+        Command::Restore {
+            from: Some(from),
+            overwrite,
+            ..
+        } => {
+            let mut report = RestoreReport::default();
+            let result = restore_backup(dir, from, *overwrite, &mut report);
+
+            for (slot, outcome, files) in &report.slots {
+                match outcome {
+                    RestoreOutcome::Restored => println!(
+                        "Restored {} ({} file{})",
+                        slot,
+                        files,
+                        if *files == 1 { "" } else { "s" }
+                    ),
+                    RestoreOutcome::Skipped => println!(
+                        "Skipped {}, which already has an image (use --overwrite to replace it)",
+                        slot
+                    ),
+                }
+            }
+            if report.other_files > 0 {
+                println!("Restored {} other files", report.other_files);
+            }
+
+            match result {
+                Ok(()) => 0,
+                Err(err) => {
+                    eprintln!("Failed to restore from {}: {}", from, err);
+                    let restored = report
+                        .slots
+                        .iter()
+                        .filter(|(_, outcome, _)| *outcome == RestoreOutcome::Restored)
+                        .count();
+                    if restored > 0 {
+                        eprintln!(
+                            "Only the {} slots listed as restored above were restored",
+                            restored
+                        );
+                    } else {
+                        eprintln!("No slots were restored");
+                    }
+                    1
+                }
+            }
+        }
+        Command::Restore { .. } => unreachable!("clap requires either --slot or --from"),
+        Command::History { slot } => {
+            let versions = list_history(dir, slot);
+            if versions.is_empty() {
+                println!("image_{}.bmp has no history", slot);
+            }
+            for version in versions {
+                let path = format!("{}/{}.bmp", history_dir(dir, slot), version);
+                match std::fs::metadata(&path) {
+                    Ok(metadata) => println!("{:<16} {:>10} bytes", version, metadata.len()),
+                    Err(_) => println!("{:<16} {:>10}", version, "missing"),
+                }
+            }
+            0
+        }
+        Command::Revert { slot, version } => {
+            match with_slot_locked(dir, slot, || revert_slot(dir, slot, *version)) {
+                Ok(()) => {
+                    println!("Reverted image_{}.bmp to version {}", slot, version);
+                    0
+                }
+                Err(err) => {
+                    eprintln!(
+                        "Failed to revert image_{}.bmp to version {}: {}",
+                        slot, version, err
+                    );
+                    1
+                }
+            }
+        }
+        Command::Timelapse {
+            slot,
+            out,
+            delay_ms,
+        } => {
+            let frames = load_history(dir, slot, palette);
+            if frames.is_empty() {
+                eprintln!("image_{}.bmp has no history to animate", slot);
+                return 1;
+            }
+
+            let filename = out.strip_suffix(".gif").unwrap_or(out);
+            match save_gif_animation(&frames, *delay_ms, filename, palette) {
+                Ok(()) => {
+                    println!("Wrote {} versions to {}.gif", frames.len(), filename);
+                    0
+                }
+                Err(err) => {
+                    eprintln!("Failed to write timelapse of image_{}.bmp: {}", slot, err);
+                    1
+                }
+            }
+        }
+        Command::Export {
+            slot: Some(slot),
+            format,
+            out: Some(out),
+            scale,
+            header,
+            ..
+        } => {
+            let format = format.unwrap_or(match out.ends_with(".png") {
+                true => ExportFormat::Png,
+                false => ExportFormat::Raw,
+            });
+            match export_slot(dir, slot, format, out, *scale as usize, *header, palette) {
+                true => 0,
+                false => 1,
+            }
+        }
+        Command::Export {
+            format,
+            out_dir: Some(out_dir),
+            scale,
+            header,
+            ..
+        } => {
+            let slots = list_slots(dir);
+            if slots.is_empty() {
+                eprintln!("{} has no images to export", dir);
+                return 1;
+            }
+            if let Err(err) = std::fs::create_dir_all(out_dir) {
+                eprintln!("Failed to create {}: {}", out_dir, err);
+                return 1;
+            }
+
+            // keep exporting the other slots when one of them fails, and report it at the end
+            let format = format.unwrap_or(ExportFormat::Png);
+            let failed = slots
+                .iter()
+                .filter(|slot| {
+                    let out = format!("{out_dir}/image_{slot}.{}", format.extension());
+                    !export_slot(dir, slot, format, &out, *scale as usize, *header, palette)
+                })
+                .count();
+            match failed {
+                0 => 0,
+                _ => {
+                    eprintln!("Failed to export {} of {} slots", failed, slots.len());
+                    1
+                }
+            }
+        }
+        Command::Export { .. } => unreachable!("clap requires either --slot or --all"),
+        Command::Verify => {
+            let slots = list_slots(dir);
+            if slots.is_empty() {
+                println!("{} has no images", dir);
+                return 0;
+            }
+
+            let mut failures = 0;
+            for slot in &slots {
+                let problem = match verify_checksum(dir, slot) {
+                    Ok(Verification::Match) => {
+                        println!("image_{}.bmp: OK", slot);
+                        continue;
+                    }
+                    Ok(Verification::Missing) => "no checksum".to_string(),
+                    Ok(Verification::Malformed) => "malformed checksum file".to_string(),
+                    Ok(Verification::Mismatch { expected, actual }) => {
+                        format!("MISMATCH (expected {}, found {})", expected, actual)
+                    }
+                    Err(err) => format!("failed to verify: {}", err),
+                };
+                eprintln!("image_{}.bmp: {}", slot, problem);
+                failures += 1;
+            }
+
+            if failures > 0 {
+                eprintln!("{} of {} images failed verification", failures, slots.len());
+                return 1;
+            }
+            0
+        }
+        Command::Histogram { slot, json } => {
+            let img = match load_whole_bmp(&format!("{dir}/image_{slot}"), palette) {
+                Ok(img) => img,
+                Err(err) => {
+                    eprintln!("Failed to load image_{}.bmp: {}", slot, err);
+                    return 1;
+                }
+            };
+            let histogram = color_histogram(&img, palette);
+
+            if *json {
+                match serde_json::to_string_pretty(&histogram) {
+                    Ok(json) => println!("{}", json),
+                    Err(err) => {
+                        eprintln!("Failed to serialize the histogram: {}", err);
+                        return 1;
+                    }
+                }
+                return 0;
+            }
+
+            let total = img.iter().map(|row| row.len()).sum::<usize>().max(1);
+            println!(
+                "{:>4} {:>6} {:>10} {:>7} {:>12}",
+                "CODE", "COLOR", "PIXELS", "PERCENT", "APPROXIMATED"
+            );
+            for usage in &histogram {
+                println!(
+                    "{:>4} {:#06X} {:>10} {:>6.1}% {:>12}",
+                    usage.code,
+                    usage.rgb565,
+                    usage.pixels + usage.approximated,
+                    100.0 * (usage.pixels + usage.approximated) as f64 / total as f64,
+                    usage.approximated
+                );
+            }
+
+            let approximated: usize = histogram.iter().map(|usage| usage.approximated).sum();
+            if approximated > 0 {
+                println!(
+                    "{} pixels are not of a palette color, and are sent as the nearest color",
+                    approximated
+                );
+            }
+            0
+        }
+        Command::Import {
+            slot,
+            file,
+            width,
+            height,
+        } => {
+            let img = match import_image(file, *width as usize, *height as usize, palette) {
+                Ok(img) => img,
+                Err(err) => {
+                    eprintln!("Failed to read {}: {}", file, err);
+                    return 1;
+                }
+            };
+
+            if let Err(err) = store_image(dir, slot, &img) {
+                eprintln!("{}", err);
+                return 1;
+            }
+            println!("Imported {} into image_{}.bmp", file, slot);
+            0
+        }
+        Command::Merge {
+            base,
+            overlay,
+            out,
+            transparent_code,
+            scale,
+        } => {
+            let transparent = match transparent_code {
+                Some(code) => palette.code_2_color(*code),
+                None => palette.color_2_code(0xFFFF).and(Some(0xFFFF)),
+            };
+            let Some(transparent) = transparent else {
+                match transparent_code {
+                    Some(code) => eprintln!("Code {} is not in the palette", code),
+                    None => {
+                        eprintln!("The palette has no white, so --transparent-code is required")
+                    }
+                }
+                return 1;
+            };
+
+            let load = |slot: &Slot| {
+                load_whole_bmp(&format!("{dir}/image_{slot}"), palette).map_err(|err| {
+                    eprintln!("Failed to load image_{}.bmp: {}", slot, err);
+                })
+            };
+            let (Ok(base_img), Ok(overlay_img)) = (load(base), load(overlay)) else {
+                return 1;
+            };
+
+            let dimensions = |img: &[Vec<u16>]| (img.first().map_or(0, |row| row.len()), img.len());
+            let (width, height) = dimensions(&base_img);
+            let overlay_img = match dimensions(&overlay_img) {
+                dims if dims == (width, height) => overlay_img,
+                _ if *scale => scale_nearest(&overlay_img, width, height),
+                (overlay_width, overlay_height) => {
+                    eprintln!(
+                        "image_{}.bmp is {} x {} pixels, but image_{}.bmp is {} x {} pixels (merge them with --scale)",
+                        base, width, height, overlay, overlay_width, overlay_height
+                    );
+                    return 1;
+                }
+            };
+
+            if let Err(err) = store_image(
+                dir,
+                out,
+                &overlay_image(&base_img, &overlay_img, transparent),
+            ) {
+                eprintln!("{}", err);
+                return 1;
+            }
+            println!(
+                "Merged image_{}.bmp over image_{}.bmp into image_{}.bmp",
+                overlay, base, out
+            );
+            0
+        }
+        Command::MigrateStore { to, db } => {
+            let files = FileStore {
+                palette: *palette,
+                ..FileStore::default()
+            };
+            let database = match SqliteStore::open(db) {
+                Ok(database) => database,
+                Err(err) => {
+                    eprintln!("Failed to open database {}: {}", db, err);
+                    return 1;
+                }
+            };
+            let (from, to): (&dyn Store, &dyn Store) = match to {
+                Backend::Files => (&database, &files),
+                Backend::Sqlite => (&files, &database),
+            };
+
+            let slots = from.list(dir);
+            if slots.is_empty() {
+                println!("{} has no images to move", dir);
+                return 0;
+            }
+            if let Err(err) = std::fs::create_dir_all(dir) {
+                eprintln!("Failed to create image directory {}: {}", dir, err);
+                return 1;
+            }
+
+            let mut failures = 0;
+            for slot in &slots {
+                // a running server may be saving to the same slot, so it is skipped until it is done
+                let result = match lock_slot(dir, slot) {
+                    Ok(_lock) => migrate_slot(from, to, dir, slot).map_err(|err| err.to_string()),
+                    Err(err) if err.kind() == std::io::ErrorKind::AlreadyExists => {
+                        Err("it is being saved by the server".to_string())
+                    }
+                    Err(err) => Err(format!("failed to lock it: {}", err)),
+                };
+                match result {
+                    Ok(()) => println!("Moved image_{}.bmp", slot),
+                    Err(err) => {
+                        eprintln!("Failed to move image_{}.bmp: {}", slot, err);
+                        failures += 1;
+                    }
+                }
+            }
+
+            if failures > 0 {
+                eprintln!("{} of {} images were not moved", failures, slots.len());
+                return 1;
+            }
+            0
+        }
+        Command::MigratePalette { from, to, dry_run } => {
+            let (from_palette, to_palette) = match (Palette::load(from), Palette::load(to)) {
+                (Ok(from_palette), Ok(to_palette)) => (from_palette, to_palette),
+                (Err(err), _) => {
+                    eprintln!("Failed to load palette {}: {}", from, err);
+                    return 1;
+                }
+                (_, Err(err)) => {
+                    eprintln!("Failed to load palette {}: {}", to, err);
+                    return 1;
+                }
+            };
+
+            let slots = list_slots(dir);
+            if slots.is_empty() {
+                println!("{} has no images to migrate", dir);
+                return 0;
+            }
+
+            let mut failures = 0;
+            for slot in &slots {
+                match migrate_slot_palette(dir, slot, &from_palette, &to_palette, *dry_run) {
+                    Ok(migration) => {
+                        let verb = match (*dry_run, migration.recolored) {
+                            (_, 0) => "Kept",
+                            (true, _) => "Would recolor",
+                            (false, _) => "Recolored",
+                        };
+                        println!(
+                            "{} image_{}.bmp: {} pixels recolored, {} not in the old palette, {} with codes not in the new palette",
+                            verb, slot, migration.recolored, migration.unmatched, migration.unmapped
+                        );
+                    }
+                    Err(err) => {
+                        eprintln!("Failed to migrate image_{}.bmp: {}", slot, err);
+                        failures += 1;
+                    }
+                }
+            }
+
+            if failures > 0 {
+                eprintln!("{} of {} images were not migrated", failures, slots.len());
+                return 1;
+            }
+            0
+        }
+        Command::Diff { a, b, out } => {
+            let (a_name, a_img) = match load_compared(dir, a, palette) {
+                Ok(loaded) => loaded,
+                Err(err) => {
+                    eprintln!("Failed to load {}: {}", a, err);
+                    return 2;
+                }
+            };
+            let (b_name, b_img) = match load_compared(dir, b, palette) {
+                Ok(loaded) => loaded,
+                Err(err) => {
+                    eprintln!("Failed to load {}: {}", b, err);
+                    return 2;
+                }
+            };
+
+            let dimensions = |img: &[Vec<u16>]| (img.first().map_or(0, |row| row.len()), img.len());
+            let ((a_width, a_height), (b_width, b_height)) =
+                (dimensions(&a_img), dimensions(&b_img));
+            if (a_width, a_height) != (b_width, b_height) {
+                println!(
+                    "{} is {} x {} pixels, but {} is {} x {} pixels",
+                    a_name, a_width, a_height, b_name, b_width, b_height
+                );
+                return 1;
+            }
+
+            let diff = diff_images(&a_img, &b_img);
+            if let Some(out) = out {
+                let filename = out.strip_suffix(".png").unwrap_or(out);
+                if let Err(err) = save_png_image(&diff_visualization(&a_img, &b_img), filename) {
+                    eprintln!("Failed to write {}.png: {}", filename, err);
+                    return 2;
+                }
+                println!("Wrote the differences to {}.png", filename);
+            }
+
+            match diff.bounds {
+                None => {
+                    println!("{} and {} are identical", a_name, b_name);
+                    0
+                }
+                Some((left, top, right, bottom)) => {
+                    println!(
+                        "{} of the {} pixels differ, in columns {} to {} and rows {} to {}",
+                        diff.pixels,
+                        a_width * a_height,
+                        left,
+                        right,
+                        top,
+                        bottom
+                    );
+                    1
+                }
+            }
+        }
+        Command::Backup { out } => match backup(dir, out) {
+            Ok(manifest) => {
+                println!("Backed up {} files to {}", manifest.files.len(), out);
+                0
+            }
+            Err(err) => {
+                eprintln!("Failed to back up {}: {}", dir, err);
+                1
+            }
+        },
+        Command::Trash {
+            action: TrashCommand::List,
+        } => {
+            let entries = list_trash(dir);
+            if entries.is_empty() {
+                println!("The trash of {} is empty", dir);
+                return 0;
+            }
+
+            let now_ms = std::time::SystemTime::now()
+                .duration_since(std::time::UNIX_EPOCH)
+                .map_or(0, |duration| duration.as_millis() as u64);
+            println!("{:<32} {:<8} {:>9}", "ENTRY", "SLOT", "REMOVED");
+            for entry in entries {
+                println!(
+                    "{:<32} {:<8} {:>9}",
+                    entry.name,
+                    entry.slot,
+                    format!(
+                        "{} ago",
+                        format_age(now_ms.saturating_sub(entry.deleted_ms))
+                    )
+                );
+            }
+            0
+        }
+        Command::Trash {
+            action: TrashCommand::Restore { entry },
+        } => {
+            let Some(slot) = list_trash(dir)
+                .into_iter()
+                .find(|trashed| &trashed.name == entry)
+                .map(|trashed| trashed.slot)
+            else {
+                eprintln!("The trash of {} has no entry {}", dir, entry);
+                return 1;
+            };
+
+            // a running server may be saving to the same slot, so never write it at the same time
+            let result = match lock_slot(dir, &slot) {
+                Ok(_lock) => restore_trash(dir, entry),
+                Err(err) => Err(err),
+            };
+            match result {
+                Ok(slot) => {
+                    println!("Restored {} into image_{}.bmp", entry, slot);
+                    0
+                }
+                Err(err) if err.kind() == std::io::ErrorKind::AlreadyExists => {
+                    eprintln!(
+                        "image_{}.bmp already has an image (or is being saved), move it away first",
+                        slot
+                    );
+                    1
+                }
+                Err(err) => {
+                    eprintln!("Failed to restore {}: {}", entry, err);
+                    1
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::image::{load_png_image, save_zstd_bmp_image_as, ColorFormat};
+    use crate::testing::temp_dir;
+
+    #[test]
+    fn ages_use_the_largest_unit() {
+        assert_eq!(format_age(999), "0s");
+        assert_eq!(format_age(59_000), "59s");
+        assert_eq!(format_age(60_000), "1m");
+        assert_eq!(format_age(3 * 3_600_000 + 59_000), "3h");
+        assert_eq!(format_age(9 * 86_400_000), "9d");
+    }
+
+    #[test]
+    fn identical_images_have_no_diff() {
+        let dir = temp_dir("identical_images_have_no_diff");
+        let img = vec![vec![0xF800, 0x07E0, 0x001F], vec![0xFFFF, 0x0000, 0xF800]];
+        save_bmp_image(&img, &format!("{dir}/image_3")).unwrap();
+        save_bmp_image(&img, &format!("{dir}/image_4")).unwrap();
+
+        assert_eq!(
+            diff_images(&img, &img),
+            ImageDiff {
+                pixels: 0,
+                bounds: None
+            }
+        );
+        let diff = Command::Diff {
+            a: "3".to_string(),
+            b: "4".to_string(),
+            out: None,
+        };
+        assert_eq!(run(&diff, &dir, &Palette::BUILTIN), 0);
+    }
+
+    #[test]
+    fn single_changed_pixels_are_found() {
+        let dir = temp_dir("single_changed_pixels_are_found");
+        let a = vec![vec![0xFFFF; 5]; 4];
+        let mut b = a.clone();
+        b[2][3] = 0x0000;
+        save_bmp_image(&a, &format!("{dir}/image_3")).unwrap();
+        save_bmp_image(&b, &format!("{dir}/other")).unwrap();
+
+        assert_eq!(
+            diff_images(&a, &b),
+            ImageDiff {
+                pixels: 1,
+                bounds: Some((3, 2, 3, 2))
+            }
+        );
+
+        // The second image is a file rather than a slot, and the differences are drawn
+        let out = format!("{dir}/diff.png");
+        let diff = Command::Diff {
+            a: "3".to_string(),
+            b: format!("{dir}/other.bmp"),
+            out: Some(out.clone()),
+        };
+        assert_eq!(run(&diff, &dir, &Palette::BUILTIN), 1);
+
+        let drawn = load_png_image(&format!("{dir}/diff"), 5, 4, &Palette::BUILTIN).unwrap();
+        assert_eq!(drawn[2][3], DIFF_COLOR);
+        assert!(drawn
+            .iter()
+            .flatten()
+            .enumerate()
+            .all(|(i, &pixel)| (pixel == DIFF_COLOR) == (i == 2 * 5 + 3)));
+    }
+
+    #[test]
+    fn images_of_different_dimensions_differ() {
+        let dir = temp_dir("images_of_different_dimensions_differ");
+        save_bmp_image(&vec![vec![0xFFFF; 5]; 4], &format!("{dir}/image_3")).unwrap();
+        save_bmp_image(&vec![vec![0xFFFF; 4]; 5], &format!("{dir}/image_4")).unwrap();
+
+        let out = format!("{dir}/diff.png");
+        let diff = |b: &str| Command::Diff {
+            a: "3".to_string(),
+            b: b.to_string(),
+            out: Some(out.clone()),
+        };
+        assert_eq!(run(&diff("4"), &dir, &Palette::BUILTIN), 1);
+        assert!(!std::path::Path::new(&out).exists());
+
+        // Images that can not be loaded are errors rather than differences
+        assert_eq!(run(&diff("5"), &dir, &Palette::BUILTIN), 2);
+    }
+
+    #[test]
+    fn export_writes_scaled_png() {
+        let dir = temp_dir("export_writes_scaled_png");
+        let img = vec![vec![0xF800, 0x07E0, 0x001F], vec![0xFFFF, 0x0000, 0xF800]];
+        save_bmp_image(&img, &format!("{dir}/image_1")).unwrap();
+
+        let out = format!("{dir}/drawing.png");
+        let export = |slot: u16, scale| Command::Export {
+            slot: Some(Slot::Number(slot)),
+            all: false,
+            format: None,
+            out: Some(out.clone()),
+            out_dir: None,
+            scale,
+            header: false,
+        };
+
+        assert_eq!(run(&export(1, 1), &dir, &Palette::BUILTIN), 0);
+        assert_eq!(
+            load_png_image(&format!("{dir}/drawing"), 3, 2, &Palette::BUILTIN).unwrap(),
+            img
+        );
+
+        assert_eq!(run(&export(1, 4), &dir, &Palette::BUILTIN), 0);
+        let scaled = load_png_image(&format!("{dir}/drawing"), 12, 8, &Palette::BUILTIN).unwrap();
+        assert_eq!(scaled, upscale(&img, 4));
+        assert_eq!(scaled[7][11], img[1][2]);
+
+        // a missing slot fails without replacing the previous export
+        std::fs::remove_file(&out).unwrap();
+        assert_eq!(run(&export(2, 1), &dir, &Palette::BUILTIN), 1);
+        assert!(!std::path::Path::new(&out).exists());
+    }
+
+    #[test]
+    fn export_all_writes_every_slot() {
+        let dir = temp_dir("export_all_writes_every_slot");
+        save_bmp_image(&[vec![0xF800; 2]], &format!("{dir}/image_1")).unwrap();
+        save_bmp_image(&[vec![0x001F; 3]], &format!("{dir}/image_card")).unwrap();
+
+        let out_dir = format!("{dir}/exports");
+        let command = Command::Export {
+            slot: None,
+            all: true,
+            format: None,
+            out: None,
+            out_dir: Some(out_dir.clone()),
+            scale: 2,
+            header: false,
+        };
+        assert_eq!(run(&command, &dir, &Palette::BUILTIN), 0);
+        assert_eq!(
+            load_png_image(&format!("{out_dir}/image_1"), 4, 2, &Palette::BUILTIN).unwrap(),
+            vec![vec![0xF800; 4]; 2]
+        );
+        assert_eq!(
+            load_png_image(&format!("{out_dir}/image_card"), 6, 2, &Palette::BUILTIN).unwrap(),
+            vec![vec![0x001F; 6]; 2]
+        );
+    }
+
+    #[test]
+    fn verify_catches_damaged_images() {
+        let dir = temp_dir("verify_catches_damaged_images");
+        for slot in [Slot::Number(1), Slot::Number(2)] {
+            save_bmp_image(&vec![vec![0xF800; 4]; 2], &format!("{dir}/image_{slot}")).unwrap();
+            write_checksum(&dir, &slot).unwrap();
+        }
+        assert_eq!(run(&Command::Verify, &dir, &Palette::BUILTIN), 0);
+
+        // flip a single bit of a pixel
+        let path = format!("{dir}/image_2.bmp");
+        let mut bytes = std::fs::read(&path).unwrap();
+        *bytes.last_mut().unwrap() ^= 1;
+        std::fs::write(&path, bytes).unwrap();
+        assert_eq!(run(&Command::Verify, &dir, &Palette::BUILTIN), 1);
+
+        // images without checksums also fail
+        save_bmp_image(&vec![vec![0xF800; 4]; 2], &path.replace(".bmp", "")).unwrap();
+        write_checksum(&dir, &Slot::Number(2)).unwrap();
+        save_bmp_image(&[vec![0x07E0; 4]], &format!("{dir}/image_3")).unwrap();
+        assert_eq!(run(&Command::Verify, &dir, &Palette::BUILTIN), 1);
+        std::fs::remove_file(format!("{dir}/image_3.bmp")).unwrap();
+        assert_eq!(run(&Command::Verify, &dir, &Palette::BUILTIN), 0);
+    }
+
+    #[test]
+    fn compressed_images_are_exported_and_verified_like_plain_ones() {
+        let plain = temp_dir("compressed_images_are_exported_and_verified_like_plain_ones-plain");
+        let compressed =
+            temp_dir("compressed_images_are_exported_and_verified_like_plain_ones-zstd");
+        let img = vec![vec![0xF800; 40], vec![0x07E0; 40], vec![0x001F; 40]];
+        save_bmp_image(&img, &format!("{plain}/image_1")).unwrap();
+        save_zstd_bmp_image_as(
+            &img,
+            &format!("{compressed}/image_1"),
+            ColorFormat::Rgb565,
+            3,
+        )
+        .unwrap();
+        write_checksum(&compressed, &Slot::Number(1)).unwrap();
+
+        assert_eq!(slot_inventory(&compressed)[0].width, Some(40));
+        assert_eq!(slot_inventory(&compressed)[0].height, Some(3));
+        assert_eq!(run(&Command::Verify, &compressed, &Palette::BUILTIN), 0);
+
+        let export = |dir: &str| {
+            let command = Command::Export {
+                slot: None,
+                all: true,
+                format: None,
+                out: None,
+                out_dir: Some(format!("{dir}/exports")),
+                scale: 1,
+                header: false,
+            };
+            assert_eq!(run(&command, dir, &Palette::BUILTIN), 0);
+            std::fs::read(format!("{dir}/exports/image_1.png")).unwrap()
+        };
+        assert_eq!(export(&compressed), export(&plain));
+    }
+
+    #[test]
+    fn histograms_count_exact_and_approximated_pixels() {
+        // red, green twice, and a slightly darker red which is sent as red
+        let img = vec![vec![0xF800, 0x07E0], vec![0x07E0, 0xE800]];
+        let histogram = color_histogram(&img, &Palette::BUILTIN);
+
+        assert_eq!(histogram.len(), 16);
+        let counts: Vec<(usize, usize)> = histogram
+            .iter()
+            .map(|usage| (usage.pixels, usage.approximated))
+            .collect();
+        assert_eq!(counts[..3], [(1, 1), (2, 0), (0, 0)]);
+        assert!(counts[3..].iter().all(|&count| count == (0, 0)));
+
+        let histogram = color_histogram(&img, &Palette::GRAY4);
+        assert_eq!(histogram.len(), 4);
+        assert_eq!(
+            histogram
+                .iter()
+                .map(|usage| usage.pixels + usage.approximated)
+                .sum::<usize>(),
+            4
+        );
+        assert!(histogram.iter().all(|usage| usage.pixels == 0));
+
+        let dir = temp_dir("histograms_count_exact_and_approximated_pixels");
+        save_bmp_image(&img, &format!("{dir}/image_1")).unwrap();
+        let histogram = |slot| Command::Histogram {
+            slot: Slot::Number(slot),
+            json: false,
+        };
+        assert_eq!(run(&histogram(1), &dir, &Palette::BUILTIN), 0);
+        assert_eq!(run(&histogram(2), &dir, &Palette::BUILTIN), 1);
+    }
+
+    #[test]
+    fn list_checks_colors_against_the_palette() {
+        let dir = temp_dir("list_checks_colors_against_the_palette");
+        save_bmp_image(&[vec![0x0000, 0xFFFF]], &format!("{dir}/image_1")).unwrap();
+        save_bmp_image(&[vec![0xF800, 0xFFFF]], &format!("{dir}/image_2")).unwrap();
+        std::fs::write(format!("{dir}/image_3.bmp"), b"BM").unwrap();
+
+        let in_palette = |palette| -> Vec<Option<bool>> {
+            list_inventory(&dir, palette)
+                .iter()
+                .map(|listed| listed.in_palette)
+                .collect()
+        };
+        assert_eq!(
+            in_palette(&Palette::BUILTIN),
+            [Some(true), Some(true), None]
+        );
+        assert_eq!(in_palette(&Palette::GRAY4), [Some(true), Some(false), None]);
+    }
+
+    #[test]
+    fn import_reduces_to_the_active_palette() {
+        let dir = temp_dir("import_reduces_to_the_active_palette");
+        let command = Command::Import {
+            slot: Slot::Number(1),
+            file: format!("{}/tests/data/gradient.png", env!("CARGO_MANIFEST_DIR")),
+            width: 8,
+            height: 2,
+        };
+        // the slot already holds a compressed image, which must not outlive the imported one
+        save_zstd_bmp_image_as(
+            &vec![vec![0xF800; 8]; 2],
+            &format!("{dir}/image_1"),
+            ColorFormat::Rgb565,
+            3,
+        )
+        .unwrap();
+        assert_eq!(run(&command, &dir, &Palette::GRAY4), 0);
+
+        let img = load_whole_bmp(&format!("{dir}/image_1"), &Palette::BUILTIN).unwrap();
+        assert!(img
+            .iter()
+            .flatten()
+            .all(|&color| Palette::GRAY4.color_2_code(color).is_some()));
+        assert!(!std::path::Path::new(&format!("{dir}/image_1.bmp.zst")).exists());
+    }
+
+    #[test]
+    fn images_migrate_to_new_palettes() {
+        let dir = temp_dir("images_migrate_to_new_palettes");
+        let images = format!("{dir}/images");
+        std::fs::create_dir_all(&images).unwrap();
+        let palette_file = |name: &str, colors: &[(u8, u16)]| {
+            let path = format!("{dir}/{name}.toml");
+            let entries: String = colors
+                .iter()
+                .map(|(code, color)| format!("[[colors]]\ncode = {}\nrgb565 = {}\n", code, color))
+                .collect();
+            std::fs::write(&path, entries).unwrap();
+            path
+        };
+        // red and green are tweaked, and white is dropped
+        let from = palette_file("old", &[(0, 0x0000), (1, 0xF800), (2, 0x07E0), (3, 0xFFFF)]);
+        let to = palette_file("new", &[(0, 0x0000), (1, 0xFA00), (2, 0x07E4)]);
+
+        // 0xF000 was never in the old palette, and is nearest to its red
+        let img = vec![vec![0x0000, 0xF800, 0x07E0], vec![0xF000, 0xFFFF, 0xF800]];
+        save_bmp_image(&img, &format!("{images}/image_1")).unwrap();
+
+        let migrate = |from: &str, to: &str, dry_run| Command::MigratePalette {
+            from: from.to_string(),
+            to: to.to_string(),
+            dry_run,
+        };
+        assert_eq!(
+            run(&migrate(&from, &to, true), &images, &Palette::BUILTIN),
+            0
+        );
+        assert_eq!(
+            load_whole_bmp(&format!("{images}/image_1"), &Palette::BUILTIN).unwrap(),
+            img
+        );
+        assert!(!std::path::Path::new(&backup_path(&images, &Slot::Number(1))).exists());
+
+        assert_eq!(
+            run(&migrate(&from, &to, false), &images, &Palette::BUILTIN),
+            0
+        );
+        assert_eq!(
+            load_whole_bmp(&format!("{images}/image_1"), &Palette::BUILTIN).unwrap(),
+            [[0x0000, 0xFA00, 0x07E4], [0xFA00, 0xFA00, 0xFA00]]
+        );
+        assert_eq!(
+            load_whole_bmp(
+                backup_path(&images, &Slot::Number(1))
+                    .strip_suffix(".bmp")
+                    .unwrap(),
+                &Palette::BUILTIN
+            )
+            .unwrap(),
+            img
+        );
+
+        // migrated images are already in the new palette, so they are kept as they are
+        let (_, migration) = migrate_colors(
+            &[vec![0x0000, 0xFA00]],
+            &Palette::load(&to).unwrap(),
+            &Palette::load(&to).unwrap(),
+        );
+        assert_eq!(migration, PaletteMigration::default());
+        let (_, migration) = migrate_colors(
+            &img,
+            &Palette::load(&from).unwrap(),
+            &Palette::load(&to).unwrap(),
+        );
+        assert_eq!(
+            migration,
+            PaletteMigration {
+                recolored: 5,
+                unmatched: 1,
+                unmapped: 1
+            }
+        );
+
+        assert_eq!(
+            run(
+                &migrate(&from, &format!("{dir}/missing.toml"), false),
+                &images,
+                &Palette::BUILTIN
+            ),
+            1
+        );
+    }
+
+    #[test]
+    fn overlays_are_merged_over_their_base() {
+        let dir = temp_dir("overlays_are_merged_over_their_base");
+        let (red, green, blue, white) = (0xF800, 0x07E0, 0x001F, 0xFFFF);
+        let background = vec![vec![blue; 3]; 2];
+        let character = vec![vec![white, red, white], vec![green, green, white]];
+        save_bmp_image(&background, &format!("{dir}/image_1")).unwrap();
+        save_bmp_image(&character, &format!("{dir}/image_2")).unwrap();
+        save_bmp_image(&[vec![red, white]], &format!("{dir}/image_small")).unwrap();
+
+        let merge = |overlay: &str, transparent_code, scale| Command::Merge {
+            base: Slot::Number(1),
+            overlay: overlay.parse().unwrap(),
+            out: Slot::Number(3),
+            transparent_code,
+            scale,
+        };
+        let merged = || load_whole_bmp(&format!("{dir}/image_3"), &Palette::BUILTIN).unwrap();
+
+        // white is transparent by default
+        assert_eq!(run(&merge("2", None, false), &dir, &Palette::BUILTIN), 0);
+        assert_eq!(merged(), [[blue, red, blue], [green, green, blue]]);
+        assert_eq!(run(&merge("2", Some(1), false), &dir, &Palette::BUILTIN), 0);
+        assert_eq!(merged(), [[white, red, white], [blue, blue, white]]);
+        assert_eq!(
+            run(&merge("2", Some(200), false), &dir, &Palette::BUILTIN),
+            1
+        );
+
+        // overlays of other dimensions are only merged when scaled
+        assert_eq!(
+            run(&merge("small", None, false), &dir, &Palette::BUILTIN),
+            1
+        );
+        assert_eq!(run(&merge("small", None, true), &dir, &Palette::BUILTIN), 0);
+        assert_eq!(merged(), [[red, red, blue], [red, red, blue]]);
+
+        // the base and overlay are left as they were
+        assert_eq!(
+            load_whole_bmp(&format!("{dir}/image_1"), &Palette::BUILTIN).unwrap(),
+            background
+        );
+        assert_eq!(run(&merge("4", None, false), &dir, &Palette::BUILTIN), 1);
+    }
+
+    #[test]
+    fn trashed_images_are_listed_and_restored() {
+        let dir = temp_dir("trashed_images_are_listed_and_restored");
+        let img = vec![vec![0xF800, 0x07E0, 0x001F], vec![0xFFFF, 0x0000, 0x520A]];
+        let store = FileStore::default();
+        let metadata = SlotMetadata {
+            v: METADATA_VERSION,
+            timestamp_ms: 1000,
+            peer: "192.168.1.20:50123".to_string(),
+            width: 3,
+            height: 2,
+            compressed_rows: 0,
+            duration_ms: 12,
+        };
+        store
+            .write_slot(&dir, &Slot::Number(1), &img, &metadata)
+            .unwrap();
+        store.trash(&dir, &Slot::Number(1)).unwrap();
+        assert_eq!(list_slots(&dir), []);
+
+        let list = Command::Trash {
+            action: TrashCommand::List,
+        };
+        assert_eq!(run(&list, &dir, &Palette::BUILTIN), 0);
+        let restore = |entry: &str| Command::Trash {
+            action: TrashCommand::Restore {
+                entry: entry.to_string(),
+            },
+        };
+        assert_eq!(run(&restore("1_image_1"), &dir, &Palette::BUILTIN), 1);
+
+        let [entry] = &list_trash(&dir)[..] else {
+            panic!("not a single entry in the trash");
+        };
+        assert_eq!(run(&restore(&entry.name), &dir, &Palette::BUILTIN), 0);
+        assert_eq!(list_slots(&dir), [Slot::Number(1)]);
+        assert_eq!(
+            load_whole_bmp(&format!("{dir}/image_1"), &Palette::BUILTIN).unwrap(),
+            img
+        );
+        assert_eq!(read_metadata(&dir, &Slot::Number(1)), Some(metadata));
+        assert!(matches!(
+            verify_checksum(&dir, &Slot::Number(1)),
+            Ok(Verification::Match)
+        ));
+        assert_eq!(list_trash(&dir), []);
+    }
+
+    #[test]
+    fn locked_slots_are_not_restored_or_reverted() {
+        let dir = temp_dir("locked_slots_are_not_restored_or_reverted");
+        let first = vec![vec![0xF800, 0x07E0], vec![0x001F, 0xFFFF]];
+        let second = vec![vec![0x0000, 0x0000], vec![0x0000, 0x0000]];
+        save_bmp_image(&first, &format!("{dir}/image_1")).unwrap();
+        let version = archive_slot(&dir, &Slot::Number(1)).unwrap();
+        backup_slot(&dir, &Slot::Number(1)).unwrap();
+        save_bmp_image(&second, &format!("{dir}/image_1")).unwrap();
+
+        let restore = Command::Restore {
+            slot: Some(Slot::Number(1)),
+            from: None,
+            overwrite: false,
+        };
+        let revert = Command::Revert {
+            slot: Slot::Number(1),
+            version,
+        };
+
+        // the server is saving to the slot
+        let lock = lock_slot(&dir, &Slot::Number(1)).unwrap();
+        assert_eq!(run(&restore, &dir, &Palette::BUILTIN), 1);
+        assert_eq!(run(&revert, &dir, &Palette::BUILTIN), 1);
+        assert_eq!(
+            load_slot(&dir, &Slot::Number(1), 2, 2, &Palette::BUILTIN).unwrap(),
+            second
+        );
+
+        drop(lock);
+        assert_eq!(run(&restore, &dir, &Palette::BUILTIN), 0);
+        assert_eq!(
+            load_slot(&dir, &Slot::Number(1), 2, 2, &Palette::BUILTIN).unwrap(),
+            first
+        );
+        save_bmp_image(&second, &format!("{dir}/image_1")).unwrap();
+        assert_eq!(run(&revert, &dir, &Palette::BUILTIN), 0);
+        assert_eq!(
+            load_slot(&dir, &Slot::Number(1), 2, 2, &Palette::BUILTIN).unwrap(),
+            first
+        );
+    }
+
+    #[test]
+    fn images_migrate_into_the_database_and_back() {
+        let dir = temp_dir("images_migrate_into_the_database_and_back");
+        let db = format!("{dir}/canvas.db");
+        let img = vec![vec![0xF800, 0x07E0, 0x001F]; 2];
+        save_bmp_image(&img, &format!("{dir}/image_1")).unwrap();
+        save_bmp_image(&img, &format!("{dir}/image_sketch")).unwrap();
+        let metadata = SlotMetadata {
+            v: METADATA_VERSION,
+            timestamp_ms: 1000,
+            peer: "192.168.1.20:50123".to_string(),
+            width: 3,
+            height: 2,
+            compressed_rows: 1,
+            duration_ms: 12,
+        };
+        write_metadata(&dir, &Slot::Number(1), &metadata).unwrap();
+
+        let into = |to| Command::MigrateStore { to, db: db.clone() };
+        assert_eq!(run(&into(Backend::Sqlite), &dir, &Palette::BUILTIN), 0);
+        assert_eq!(list_slots(&dir), []);
+        let database = SqliteStore::open(&db).unwrap();
+        assert_eq!(
+            database.read_slot(&dir, &Slot::Number(1), 3, 2).unwrap(),
+            img
+        );
+        assert_eq!(
+            database.metadata(&dir, &Slot::Number(1)),
+            Some(metadata.clone())
+        );
+
+        assert_eq!(run(&into(Backend::Files), &dir, &Palette::BUILTIN), 0);
+        assert_eq!(database.list(&dir), []);
+        let slot = Slot::Name("sketch".to_string());
+        assert_eq!(list_slots(&dir), [Slot::Number(1), slot.clone()]);
+        assert_eq!(
+            load_whole_bmp(&format!("{dir}/image_sketch"), &Palette::BUILTIN).unwrap(),
+            img
+        );
+        assert_eq!(read_metadata(&dir, &Slot::Number(1)), Some(metadata));
+        assert!(matches!(
+            verify_checksum(&dir, &slot),
+            Ok(Verification::Match)
+        ));
+    }
+}
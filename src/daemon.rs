@@ -0,0 +1,53 @@
+//! Detaching the server from its terminal with `--daemon`, so that it can be managed as a service
+
+/// Forks the server into the background, leaving the foreground process to exit
+///
+/// The server keeps its working directory (so that relative paths keep referring to the same
+/// files), and everything that it prints is appended to the log file afterwards (or discarded when
+/// there is none). This must be called before any threads are started, since only the calling
+/// thread survives the fork.
+///
+/// # Arguments
+///
+/// * `pid_file` - Path of the file that the ID of the background process is written to, if any
+/// * `log_file` - Path of the file that the output of the server is appended to, if any
+///
+/// # Errors
+///
+/// * When the log file can not be opened, or the PID file can not be written (such as when it is
+///   locked by another running server)
+///
+#[cfg(unix)]
+pub fn daemonize(pid_file: Option<&str>, log_file: Option<&str>) -> Result<(), String> {
+    let working_dir = std::env::current_dir().map_err(|err| err.to_string())?;
+    let mut daemon = daemonize::Daemonize::new()
+        .working_directory(working_dir)
+        .umask(0o022);
+
+    if let Some(pid_file) = pid_file {
+        daemon = daemon.pid_file(pid_file);
+    }
+    if let Some(log_file) = log_file {
+        let open = |path: &str| {
+            std::fs::OpenOptions::new()
+                .create(true)
+                .append(true)
+                .open(path)
+                .map_err(|err| format!("failed to open {}: {}", path, err))
+        };
+        daemon = daemon.stdout(open(log_file)?).stderr(open(log_file)?);
+    }
+
+    daemon.start().map_err(|err| err.to_string())
+}
+
+/// Forks the server into the background, which is not supported on this platform
+///
+/// # Errors
+///
+/// * Always, since processes can not be forked on this platform
+///
+#[cfg(not(unix))]
+pub fn daemonize(_pid_file: Option<&str>, _log_file: Option<&str>) -> Result<(), String> {
+    Err("running as a daemon is only supported on Unix".to_string())
+}
@@ -0,0 +1,105 @@
+//! Overall deadline of a connection, so that a client which keeps sending (or receiving) a byte
+//! just before every read times out can not hold on to a worker thread indefinitely
+
+use std::io::{Read, Write};
+use std::time::{Duration, Instant};
+
+/// Connection which fails every read and write once it has been open for longer than allowed
+pub struct DeadlineStream<S> {
+    inner: S,
+    deadline: Option<Instant>,
+}
+
+impl<S> DeadlineStream<S> {
+    /// Wraps a connection that was just accepted
+    ///
+    /// # Arguments
+    ///
+    /// * `inner` - The connection
+    /// * `timeout` - Longest time for which the connection is served (without limit if `None`)
+    ///
+    pub fn new(inner: S, timeout: Option<Duration>) -> Self {
+        Self {
+            inner,
+            deadline: timeout.map(|timeout| Instant::now() + timeout),
+        }
+    }
+
+    /// Gets the wrapped connection
+    pub fn get_mut(&mut self) -> &mut S {
+        &mut self.inner
+    }
+
+    /// Fails once the deadline of the connection has passed
+    fn check(&self) -> std::io::Result<()> {
+        match self.deadline {
+            Some(deadline) if Instant::now() >= deadline => Err(std::io::Error::new(
+                std::io::ErrorKind::TimedOut,
+                "connection was open for longer than --connection-timeout",
+            )),
+            _ => Ok(()),
+        }
+    }
+}
+
+impl<S: Read> Read for DeadlineStream<S> {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        self.check()?;
+        self.inner.read(buf)
+    }
+}
+
+impl<S: Write> Write for DeadlineStream<S> {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        self.check()?;
+        self.inner.write(buf)
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Reader which takes a while to deliver every byte
+    struct Dribble;
+
+    impl Read for Dribble {
+        fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+            std::thread::sleep(Duration::from_millis(10));
+            buf[0] = 1;
+            Ok(1)
+        }
+    }
+
+    impl Write for Dribble {
+        fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn slow_connections_time_out() {
+        let mut stream = DeadlineStream::new(Dribble, Some(Duration::from_millis(50)));
+        let mut buf = [0u8; 3];
+        stream.read_exact(&mut buf).unwrap();
+
+        let mut buf = [0u8; 100];
+        let err = stream.read_exact(&mut buf).unwrap_err();
+        assert_eq!(err.kind(), std::io::ErrorKind::TimedOut);
+        assert_eq!(
+            stream.write(&[0]).unwrap_err().kind(),
+            std::io::ErrorKind::TimedOut
+        );
+
+        let mut stream = DeadlineStream::new(Dribble, None);
+        stream.read_exact(&mut buf[..10]).unwrap();
+    }
+}
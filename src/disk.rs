@@ -0,0 +1,82 @@
+//! Free space of the filesystem that images are stored on, so that saves which would not fit are
+//! refused before their rows are received
+
+/// Number of bytes in a mebibyte, the unit of `--min-free-mb`
+pub const MIB: u64 = 1024 * 1024;
+
+/// Function that gets the number of bytes available to the server on the filesystem of a path
+///
+/// The server always uses [`available_bytes`], but tests replace it to simulate a full disk.
+pub type FreeSpaceProbe = fn(&str) -> std::io::Result<u64>;
+
+/// Gets the number of bytes available to unprivileged users on the filesystem of a path
+///
+/// The image directory is only created by the first save, so the nearest ancestor of the path that
+/// exists is looked at instead if the path does not exist yet.
+///
+/// # Arguments
+///
+/// * `path` - Path of a file or directory on the filesystem
+///
+/// # Errors
+///
+/// * When the filesystem can not be queried (or on platforms where that is not supported)
+///
+#[cfg(unix)]
+pub fn available_bytes(path: &str) -> std::io::Result<u64> {
+    use std::os::unix::ffi::OsStrExt;
+
+    let mut path = std::path::Path::new(path);
+    while !path.exists() {
+        match path.parent() {
+            Some(parent) if !parent.as_os_str().is_empty() => path = parent,
+            _ => {
+                path = std::path::Path::new(".");
+                break;
+            }
+        }
+    }
+
+    let path = std::ffi::CString::new(path.as_os_str().as_bytes())?;
+    let mut stat = std::mem::MaybeUninit::<libc::statvfs>::uninit();
+    // SAFETY: the path is a valid C string, and `stat` is only read after statvfs has filled it in
+    let stat = unsafe {
+        if libc::statvfs(path.as_ptr(), stat.as_mut_ptr()) != 0 {
+            return Err(std::io::Error::last_os_error());
+        }
+        stat.assume_init()
+    };
+
+    #[allow(clippy::unnecessary_cast)]
+    Ok(stat.f_bavail as u64 * stat.f_frsize as u64)
+}
+
+/// Gets the number of bytes available to unprivileged users on the filesystem of a path
+///
+/// # Errors
+///
+/// * Always, since the filesystem can not be queried on this platform
+///
+#[cfg(not(unix))]
+pub fn available_bytes(_path: &str) -> std::io::Result<u64> {
+    Err(std::io::Error::new(
+        std::io::ErrorKind::Unsupported,
+        "free space can not be queried on this platform",
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn missing_paths_use_their_ancestors() {
+        let dir = std::env::temp_dir();
+        let available = available_bytes(&dir.to_string_lossy()).unwrap();
+        assert!(available > 0);
+
+        let missing = dir.join("canvas-server-missing").join("images-dir");
+        assert!(available_bytes(&missing.to_string_lossy()).is_ok());
+        assert!(available_bytes("images-dir-that-does-not-exist").is_ok());
+    }
+}
@@ -0,0 +1,271 @@
+//! Errors which end the serving of a request, and the status bytes that they are reported with
+
+use crate::image::{LoadError, SaveError};
+use crate::protocol::*;
+use crate::slots::SlotNameError;
+
+/// Reasons for which a request could not be served
+#[derive(Debug)]
+pub enum ServeError {
+    /// The connection failed (or timed out) while doing the described step, so the client can not
+    /// be told about it
+    Connection {
+        during: String,
+        source: std::io::Error,
+    },
+    /// The connection failed while the described step was receiving a buffer, of which only some
+    /// bytes arrived
+    IncompleteRead {
+        during: String,
+        received: usize,
+        expected: usize,
+        failure: ReadFailure,
+        source: std::io::Error,
+    },
+    /// The opcode of the request is not one that the server knows
+    UnknownOpcode(u8),
+    /// The request is for an image with no rows or no columns
+    BadDimensions { height: usize, width: usize },
+    /// The requested region of an image does not lie within the image
+    RegionOutOfBounds {
+        x: usize,
+        y: usize,
+        width: usize,
+        height: usize,
+        image_width: usize,
+        image_height: usize,
+    },
+    /// The image sent to be merged has other dimensions than the image of the slot
+    MergeDimensionMismatch {
+        width: usize,
+        height: usize,
+        image_width: usize,
+        image_height: usize,
+    },
+    /// The transparent code of a merge is not in the palette
+    UnknownTransparentCode(u8),
+    /// The segments of a compressed row cover a different number of pixels than the row has
+    MalformedRow {
+        row: usize,
+        pixels: usize,
+        width: usize,
+    },
+    /// The request named its slot with an invalid name
+    InvalidSlotName(SlotNameError),
+    /// The request requires authentication, and the client did not present the correct token
+    Unauthorized,
+    /// The slot that the request refers to has no image
+    NotFound,
+    /// The slot that the request would write to already has an image, and may not be overwritten
+    SlotOccupied,
+    /// The slot that the request would write to is locked by another writer (such as `import`)
+    SlotBusy,
+    /// Saving the image would make the images of the directory take more space than allowed
+    QuotaExceeded { total: u64, quota: u64 },
+    /// The disk does not have enough free space for the image (and the headroom kept free)
+    ServerFull { required: u64, available: u64 },
+    /// The requested image could not be loaded
+    Load(LoadError),
+    /// The received image could not be saved
+    Save(SaveError),
+    /// The image directory could not be changed while doing the described step
+    Storage {
+        during: String,
+        source: std::io::Error,
+    },
+}
+
+/// Ways in which the connection can fail while a buffer is received
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ReadFailure {
+    /// The client sent nothing for longer than the socket timeout (or the connection was open for
+    /// longer than `--connection-timeout`)
+    TimedOut,
+    /// The client closed (or reset) the connection
+    Disconnected,
+    /// The connection failed for any other reason
+    Other,
+}
+
+impl ReadFailure {
+    /// Gets the way in which a read of the connection failed
+    pub fn of(err: &std::io::Error) -> Self {
+        use std::io::ErrorKind;
+
+        match err.kind() {
+            // sockets report read timeouts as either, depending on the platform
+            ErrorKind::TimedOut | ErrorKind::WouldBlock => Self::TimedOut,
+            ErrorKind::UnexpectedEof
+            | ErrorKind::ConnectionReset
+            | ErrorKind::ConnectionAborted
+            | ErrorKind::BrokenPipe => Self::Disconnected,
+            _ => Self::Other,
+        }
+    }
+}
+
+impl ServeError {
+    /// Gets the status byte that the client is sent for this error, if the client can be sent one
+    pub fn status(&self) -> Option<u8> {
+        match self {
+            Self::Connection { .. } | Self::IncompleteRead { .. } => None,
+            Self::UnknownOpcode(_)
+            | Self::UnknownTransparentCode(_)
+            | Self::MalformedRow { .. }
+            | Self::InvalidSlotName(_) => Some(STATUS_BAD_REQUEST),
+            Self::BadDimensions { .. }
+            | Self::RegionOutOfBounds { .. }
+            | Self::MergeDimensionMismatch { .. } => Some(STATUS_BAD_DIMENSIONS),
+            Self::Unauthorized => Some(STATUS_UNAUTHORIZED),
+            Self::NotFound => Some(STATUS_NOT_FOUND),
+            Self::SlotOccupied => Some(STATUS_SLOT_OCCUPIED),
+            Self::SlotBusy => Some(STATUS_SLOT_BUSY),
+            Self::QuotaExceeded { .. } => Some(STATUS_QUOTA_EXCEEDED),
+            Self::ServerFull { .. } => Some(STATUS_SERVER_FULL),
+            Self::Load(LoadError::Unsupported { .. }) => Some(STATUS_UNSUPPORTED_IMAGE),
+            Self::Load(_) => Some(STATUS_CORRUPT_IMAGE),
+            Self::Save(_) | Self::Storage { .. } => Some(STATUS_SERVER_ERROR),
+        }
+    }
+}
+
+impl std::fmt::Display for ServeError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Connection { during, source } => {
+                write!(f, "connection failed while {}: {}", during, source)
+            }
+            Self::IncompleteRead {
+                during,
+                received,
+                expected,
+                failure,
+                source,
+            } => {
+                match failure {
+                    ReadFailure::TimedOut => write!(f, "connection timed out while {}", during)?,
+                    ReadFailure::Disconnected => write!(f, "client disconnected while {}", during)?,
+                    ReadFailure::Other => write!(f, "connection failed while {}", during)?,
+                }
+                write!(
+                    f,
+                    ", after {} of {} bytes arrived: {}",
+                    received, expected, source
+                )
+            }
+            Self::UnknownOpcode(opcode) => write!(f, "unknown opcode {}", opcode),
+            Self::BadDimensions { height, width } => {
+                write!(f, "image can not be {} x {}", height, width)
+            }
+            Self::RegionOutOfBounds {
+                x,
+                y,
+                width,
+                height,
+                image_width,
+                image_height,
+            } => write!(
+                f,
+                "region of {} x {} at ({}, {}) is not within the {} x {} image",
+                height, width, x, y, image_height, image_width
+            ),
+            Self::MergeDimensionMismatch {
+                width,
+                height,
+                image_width,
+                image_height,
+            } => write!(
+                f,
+                "can not merge a {} x {} image into a {} x {} image",
+                height, width, image_height, image_width
+            ),
+            Self::UnknownTransparentCode(code) => {
+                write!(f, "transparent code {} is not in the palette", code)
+            }
+            Self::MalformedRow { row, pixels, width } => write!(
+                f,
+                "compressed row {} covers {} pixels instead of {}",
+                row, pixels, width
+            ),
+            Self::InvalidSlotName(err) => write!(f, "invalid slot name: {}", err),
+            Self::Unauthorized => write!(f, "client is not authorized"),
+            Self::NotFound => write!(f, "slot has no image"),
+            Self::SlotOccupied => write!(f, "slot already has an image"),
+            Self::SlotBusy => write!(f, "slot is locked by another writer"),
+            Self::QuotaExceeded { total, quota } => write!(
+                f,
+                "images already take {} of the {} bytes allowed",
+                total, quota
+            ),
+            Self::ServerFull {
+                required,
+                available,
+            } => write!(
+                f,
+                "disk is full, {} bytes are free but {} are needed (including --min-free-mb)",
+                available, required
+            ),
+            Self::Load(err) => write!(f, "failed to load image: {}", err),
+            Self::Save(err) => write!(f, "failed to save image: {}", err),
+            Self::Storage { during, source } => write!(f, "failed while {}: {}", during, source),
+        }
+    }
+}
+
+impl std::error::Error for ServeError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Self::Connection { source, .. }
+            | Self::IncompleteRead { source, .. }
+            | Self::Storage { source, .. } => Some(source),
+            Self::InvalidSlotName(err) => Some(err),
+            Self::Load(err) => Some(err),
+            Self::Save(err) => Some(err),
+            _ => None,
+        }
+    }
+}
+
+impl From<LoadError> for ServeError {
+    fn from(err: LoadError) -> Self {
+        Self::Load(err)
+    }
+}
+
+impl From<SaveError> for ServeError {
+    fn from(err: SaveError) -> Self {
+        Self::Save(err)
+    }
+}
+
+impl From<SlotNameError> for ServeError {
+    fn from(err: SlotNameError) -> Self {
+        Self::InvalidSlotName(err)
+    }
+}
+
+/// Gets a function which wraps an I/O error of the connection as a [`ServeError::Connection`]
+///
+/// # Arguments
+///
+/// * `during` - Description of the step that failed (such as `"reading row 3"`)
+///
+pub fn connection(during: impl Into<String>) -> impl FnOnce(std::io::Error) -> ServeError {
+    move |source| ServeError::Connection {
+        during: during.into(),
+        source,
+    }
+}
+
+/// Gets a function which wraps an I/O error of the image directory as a [`ServeError::Storage`]
+///
+/// # Arguments
+///
+/// * `during` - Description of the step that failed (such as `"backing up image_3.bmp"`)
+///
+pub fn storage(during: impl Into<String>) -> impl FnOnce(std::io::Error) -> ServeError {
+    move |source| ServeError::Storage {
+        during: during.into(),
+        source,
+    }
+}
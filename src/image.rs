@@ -6,207 +6,2552 @@ use std::io::SeekFrom;
 
 use byteorder::*;
 
-/// Saves a 16-bit color (5-6-5) BMP Image to the filesystem
+use crate::palette::{Palette, MAX_PALETTE_LEN};
+
+/// Value of `biCompression` for uncompressed pixel data
+const BI_RGB: u32 = 0;
+/// Value of `biCompression` for uncompressed pixel data whose channels are described by bit masks
+const BI_BITFIELDS: u32 = 3;
+/// Bit masks of the red, green and blue channels of a 16-bit color (5-6-5)
+const RGB565_MASKS: [u32; 3] = [0xF800, 0x07E0, 0x001F];
+/// Bit masks of the red, green and blue channels of a 16-bit color (5-5-5)
+const RGB555_MASKS: [u32; 3] = [0x7C00, 0x03E0, 0x001F];
+/// Colors of the 0 and 1 bits of monochrome images (the black and white of the palette)
+pub const MONO_COLORS: [u16; 2] = [0x0000, 0xFFFF];
+/// Offset of the pixel data in monochrome images (file header, `BITMAPINFOHEADER` and the 2 colors
+/// of the color table)
+const MONO_PIXEL_DATA_OFFSET: u32 = 14 + 40 + 8;
+
+/// Layouts of the 16-bit colors in saved BMP images
+///
+/// Images are always 5-6-5 in memory (which is what the palette is defined in), and are only
+/// converted to other layouts when they are written to a file.
+#[derive(clap::ValueEnum, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ColorFormat {
+    /// 5 bits of red, 6 bits of green and 5 bits of blue
+    #[value(name = "565")]
+    Rgb565,
+    /// 5 bits of each channel, with the highest bit unused
+    #[value(name = "555")]
+    Rgb555,
+}
+
+impl ColorFormat {
+    /// Gets the bit masks of the red, green and blue channels of a color in this format
+    fn masks(self) -> [u32; 3] {
+        match self {
+            Self::Rgb565 => RGB565_MASKS,
+            Self::Rgb555 => RGB555_MASKS,
+        }
+    }
+
+    /// Gets the size of the DIB header of images in this format
+    ///
+    /// 5-6-5 images use a `BITMAPINFOHEADER` followed by the channel masks (as always written by
+    /// this server), and 5-5-5 images use a `BITMAPV3INFOHEADER`, which includes the masks.
+    fn dib_header_size(self) -> u32 {
+        match self {
+            Self::Rgb565 => 40,
+            Self::Rgb555 => 56,
+        }
+    }
+
+    /// Gets the offset of the pixel data in images in this format (file header, DIB header and
+    /// channel masks)
+    fn pixel_data_offset(self) -> u32 {
+        match self {
+            Self::Rgb565 => 14 + 40 + 12,
+            Self::Rgb555 => 14 + 56,
+        }
+    }
+
+    /// Converts a 16-bit color (5-6-5) to this format
+    ///
+    /// # Arguments
+    ///
+    /// * `color` - The 16-bit color (5-6-5) to convert
+    ///
+    pub fn encode(self, color: u16) -> u16 {
+        match self {
+            Self::Rgb565 => color,
+            // drop the lowest bit of green, and move red down into its place
+            Self::Rgb555 => ((color >> 1) & 0x7FE0) | (color & 0x001F),
+        }
+    }
+
+    /// Converts a color in this format to a 16-bit color (5-6-5)
+    ///
+    /// The colors of the palette are converted back to exactly the colors they were converted
+    /// from, so that images saved in this format can still be converted to codes.
+    ///
+    /// # Arguments
+    ///
+    /// * `color` - The color in this format to convert
+    /// * `palette` - The palette whose colors are converted back exactly
+    ///
+    pub fn decode(self, color: u16, palette: &Palette) -> u16 {
+        match self {
+            Self::Rgb565 => color,
+            Self::Rgb555 => (0..MAX_PALETTE_LEN as u8)
+                .filter_map(|code| palette.code_2_color(code))
+                .find(|&palette_color| self.encode(palette_color) == color)
+                .unwrap_or_else(|| {
+                    // replicate the highest bit of green into the bit that was dropped
+                    let green = (color >> 5) & 0x1F;
+                    ((color << 1) & 0xF800) | (green << 6) | ((green >> 4) << 5) | (color & 0x001F)
+                }),
+        }
+    }
+}
+
+/// Suffix of the temporary files that images are written to before they replace the actual files
+pub const TEMP_SUFFIX: &str = ".tmp";
+
+/// Suffix of images that are stored compressed with gzip (after the `.bmp` extension)
+pub const GZIP_SUFFIX: &str = ".gz";
+
+/// Suffix of images that are stored compressed with zstd (after the `.bmp` extension)
+pub const ZSTD_SUFFIX: &str = ".zst";
+
+/// Suffixes of every form that an image file can be stored in (after the `.bmp` extension), in
+/// the order in which they are preferred when more than one of them is equally new
+pub const STORED_SUFFIXES: [&str; 3] = ["", GZIP_SUFFIX, ZSTD_SUFFIX];
+
+/// First bytes of every gzip stream
+const GZIP_MAGIC: [u8; 2] = [0x1F, 0x8B];
+
+/// First bytes of every zstd frame
+const ZSTD_MAGIC: [u8; 4] = [0x28, 0xB5, 0x2F, 0xFD];
+
+/// Level that images are compressed with zstd at, unless another one is given, which is fast while
+/// still shrinking drawings of flat colors to a small fraction of their size
+pub const DEFAULT_ZSTD_LEVEL: i32 = 3;
+
+/// Compression of the files that received images are stored in
+#[derive(clap::ValueEnum, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum StoreCompression {
+    /// Plain BMP files (`image_{slot}.bmp`)
+    None,
+    /// BMP files compressed with gzip (`image_{slot}.bmp.gz`)
+    Gzip,
+    /// BMP files compressed with zstd (`image_{slot}.bmp.zst`)
+    Zstd,
+}
+
+impl StoreCompression {
+    /// Gets the suffix of the files stored with this compression (after the `.bmp` extension)
+    pub fn suffix(self) -> &'static str {
+        match self {
+            Self::None => "",
+            Self::Gzip => GZIP_SUFFIX,
+            Self::Zstd => ZSTD_SUFFIX,
+        }
+    }
+}
+
+/// Gets the suffix of the form that a BMP file is stored in (after the `.bmp` extension), which is
+/// empty if it is not compressed
+///
+/// # Arguments
+///
+/// * `path` - Path of the file, including its extension
+///
+pub fn stored_suffix(path: &str) -> &'static str {
+    STORED_SUFFIXES
+        .into_iter()
+        .find(|suffix| path.ends_with(&format!(".bmp{suffix}")))
+        .unwrap_or("")
+}
+
+/// A BMP file that is being read, either directly or after it was decompressed
+trait BmpReader: Read + Seek {}
+
+impl<T: Read + Seek> BmpReader for T {}
+
+/// Gets the path of the file that a BMP image is stored in, out of `{filename}.bmp`,
+/// `{filename}.bmp.gz` and `{filename}.bmp.zst`
+///
+/// The form that was modified last is picked when more than one of them exists (such as when a
+/// plain copy of a compressed image was placed next to it), since it is the one that was meant to
+/// replace the others.
+///
+/// # Arguments
+///
+/// * `filename` - The name of the file (extensionless)
+///
+pub fn stored_bmp_path(filename: &str) -> Option<String> {
+    let mut newest: Option<(std::time::SystemTime, String)> = None;
+    for suffix in STORED_SUFFIXES {
+        let path = format!("{filename}.bmp{suffix}");
+        let Ok(metadata) = std::fs::metadata(&path) else {
+            continue;
+        };
+        let modified = metadata.modified().unwrap_or(std::time::UNIX_EPOCH);
+        if newest.as_ref().is_none_or(|(newest, _)| modified > *newest) {
+            newest = Some((modified, path));
+        }
+    }
+    newest.map(|(_, path)| path)
+}
+
+/// Opens a BMP file for reading, decompressing it first if it is compressed with gzip or zstd
+///
+/// The file is picked by [`stored_bmp_path`]. It is decompressed if its contents start with the
+/// gzip or zstd magic bytes, so that copies of compressed images (such as backups) can be read
+/// regardless of their names.
+///
+/// # Arguments
+///
+/// * `filename` - The name of the file (extensionless)
+///
+/// # Errors
+///
+/// * [`LoadError::NotFound`] when no form of the file exists
+/// * [`LoadError::BadHeader`] when the file is compressed but is not a valid gzip or zstd stream
+/// * [`LoadError::Truncated`] when the file is compressed but ends before its stream
+/// * [`LoadError::Io`] when the file could not be opened or read for any other reason
+///
+fn open_bmp(filename: &str) -> Result<Box<dyn BmpReader>, LoadError> {
+    let path = stored_bmp_path(filename).ok_or(LoadError::NotFound)?;
+    let mut bmp_file = match File::open(path) {
+        Ok(file) => file,
+        // the file was removed since it was picked
+        Err(err) if err.kind() == std::io::ErrorKind::NotFound => return Err(LoadError::NotFound),
+        Err(err) => return Err(LoadError::Io(err)),
+    };
+
+    let mut magic = Vec::with_capacity(ZSTD_MAGIC.len());
+    (&mut bmp_file)
+        .take(ZSTD_MAGIC.len() as u64)
+        .read_to_end(&mut magic)
+        .map_err(LoadError::Io)?;
+    bmp_file.rewind().map_err(LoadError::Io)?;
+
+    let mut contents = Vec::new();
+    let result = if magic.starts_with(&GZIP_MAGIC) {
+        flate2::read::GzDecoder::new(bmp_file).read_to_end(&mut contents)
+    } else if magic.starts_with(&ZSTD_MAGIC) {
+        zstd::stream::read::Decoder::new(bmp_file)
+            .and_then(|mut decoder| decoder.read_to_end(&mut contents))
+    } else {
+        return Ok(Box::new(bmp_file));
+    };
+    result.map_err(|err| match err.kind() {
+        std::io::ErrorKind::UnexpectedEof => LoadError::Truncated,
+        std::io::ErrorKind::InvalidInput
+        | std::io::ErrorKind::InvalidData
+        | std::io::ErrorKind::Other => LoadError::BadHeader,
+        _ => LoadError::Io(err),
+    })?;
+    Ok(Box::new(std::io::Cursor::new(contents)))
+}
+
+/// Reasons for which a BMP image could not be saved to the filesystem
+#[derive(Debug)]
+pub enum SaveError {
+    /// The image has no rows or no columns
+    Empty,
+    /// A row of the image does not have the same number of pixels as the first row
+    RaggedRows {
+        row: usize,
+        width: usize,
+        expected_width: usize,
+    },
+    /// A pixel of an image that is saved as monochrome is neither black nor white
+    NotMonochrome {
+        row: usize,
+        column: usize,
+        color: u16,
+    },
+    /// Any error raised by the filesystem while writing the file
+    Io(std::io::Error),
+}
+
+impl std::fmt::Display for SaveError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            SaveError::Empty => write!(f, "image has no pixels"),
+            SaveError::RaggedRows {
+                row,
+                width,
+                expected_width,
+            } => write!(
+                f,
+                "row {} of image has {} pixels instead of {}",
+                row, width, expected_width
+            ),
+            SaveError::NotMonochrome { row, column, color } => write!(
+                f,
+                "pixel {} of row {} has color {:#06X}, which is neither black nor white",
+                column, row, color
+            ),
+            SaveError::Io(err) => write!(f, "failed to write image: {}", err),
+        }
+    }
+}
+
+impl std::error::Error for SaveError {}
+
+impl From<std::io::Error> for SaveError {
+    fn from(err: std::io::Error) -> Self {
+        SaveError::Io(err)
+    }
+}
+
+/// Saves a 16-bit color (5-6-5) BMP Image to the filesystem, in the 5-6-5 layout
+///
+/// The image is written with `biCompression = BI_BITFIELDS` and explicit 5-6-5 channel masks
+/// (instead of `BI_RGB`, which viewers interpret as 5-5-5), so the colors are displayed correctly
+/// by standard image viewers and not just by this server.
+///
+/// # Arguments
+///
+/// * `data` - A 16-bit color bitmap that must be saved
+/// * `filename` - The name of the file (extensionless)
+///
+/// # Errors
+///
+/// * [`SaveError::Empty`] when the given image has 0 rows or 0 columns
+/// * [`SaveError::RaggedRows`] when the rows of the given image have different lengths
+/// * [`SaveError::Io`] when the file could not be created or written to
+///
+/// The image is validated before the file is created, so an invalid image never leaves a file behind.
+/// The file is replaced atomically, so an existing image is never left partially overwritten.
+///
+pub fn save_bmp_image(data: &[Vec<u16>], filename: &str) -> Result<(), SaveError> {
+    save_bmp_image_as(data, filename, ColorFormat::Rgb565)
+}
+
+/// Saves a 16-bit color (5-6-5) BMP Image to the filesystem, with its colors converted to the given
+/// layout
+///
+/// # Arguments
+///
+/// * `data` - A 16-bit color bitmap that must be saved
+/// * `filename` - The name of the file (extensionless)
+/// * `format` - Layout of the colors in the file
+///
+/// # Errors
+///
+/// * The same errors as [`save_bmp_image`]
+///
+pub fn save_bmp_image_as(
+    data: &[Vec<u16>],
+    filename: &str,
+    format: ColorFormat,
+) -> Result<(), SaveError> {
+    write_bmp_image(data, &format!("{}.bmp", filename), format, Encoding::Plain)
+}
+
+/// Saves a 16-bit color (5-6-5) BMP Image to the filesystem compressed with gzip (as
+/// `{filename}.bmp.gz`), with its colors converted to the given layout
+///
+/// Images of flat colors take a small fraction of their uncompressed size, and are decompressed
+/// transparently by [`load_bmp_image`].
+///
+/// # Arguments
+///
+/// * `data` - A 16-bit color bitmap that must be saved
+/// * `filename` - The name of the file (extensionless)
+/// * `format` - Layout of the colors in the file
+///
+/// # Errors
+///
+/// * The same errors as [`save_bmp_image`]
+///
+pub fn save_compressed_bmp_image_as(
+    data: &[Vec<u16>],
+    filename: &str,
+    format: ColorFormat,
+) -> Result<(), SaveError> {
+    write_bmp_image(
+        data,
+        &format!("{}.bmp{}", filename, GZIP_SUFFIX),
+        format,
+        Encoding::Gzip,
+    )
+}
+
+/// Saves a 16-bit color (5-6-5) BMP Image to the filesystem compressed with zstd (as
+/// `{filename}.bmp.zst`), with its colors converted to the given layout
+///
+/// The whole BMP file is compressed, and is decompressed transparently by [`load_bmp_image`].
 ///
 /// # Arguments
 ///
 /// * `data` - A 16-bit color bitmap that must be saved
 /// * `filename` - The name of the file (extensionless)
+/// * `format` - Layout of the colors in the file
+/// * `level` - Level to compress the file at (higher levels are smaller, but slower to write)
+///
+/// # Errors
+///
+/// * The same errors as [`save_bmp_image`]
+///
+pub fn save_zstd_bmp_image_as(
+    data: &[Vec<u16>],
+    filename: &str,
+    format: ColorFormat,
+    level: i32,
+) -> Result<(), SaveError> {
+    write_bmp_image(
+        data,
+        &format!("{}.bmp{}", filename, ZSTD_SUFFIX),
+        format,
+        Encoding::Zstd(level),
+    )
+}
+
+/// Saves a black and white image to the filesystem as a monochrome (1-bit) BMP Image (which takes
+/// a sixteenth of the space of a 16-bit image), optionally compressed (as `{filename}.bmp.gz` or
+/// `{filename}.bmp.zst`)
+///
+/// The file has a color table of 2 entries (black for 0 bits and white for 1 bits), so it is
+/// displayed correctly by standard image viewers, and is loaded by [`load_bmp_image`] like any other
+/// image.
+///
+/// # Arguments
+///
+/// * `data` - A 16-bit color bitmap whose pixels are all black or white (see [`MONO_COLORS`])
+/// * `filename` - The name of the file (extensionless)
+/// * `compression` - How to compress the file, if at all
+/// * `level` - Level to compress the file at with zstd
 ///
-/// # Panics
+/// # Errors
+///
+/// * [`SaveError::NotMonochrome`] when a pixel of the image is neither black nor white
+/// * The same errors as [`save_bmp_image`]
+///
+pub fn save_bmp_image_mono(
+    data: &[Vec<u16>],
+    filename: &str,
+    compression: StoreCompression,
+    level: i32,
+) -> Result<(), SaveError> {
+    let width = validate_image(data)?;
+    let height = data.len();
+    for (row, pixels) in data.iter().enumerate() {
+        if let Some(column) = pixels.iter().position(|color| !MONO_COLORS.contains(color)) {
+            return Err(SaveError::NotMonochrome {
+                row,
+                column,
+                color: pixels[column],
+            });
+        }
+    }
+
+    let row_size = padded_row_size(width, 1);
+    let image_size = row_size * height;
+
+    let mut headers = Vec::with_capacity(MONO_PIXEL_DATA_OFFSET as usize);
+    headers.write_all(b"BM").unwrap(); // Write the 2-byte string "BM"
+    headers
+        .write_u32::<LE>(MONO_PIXEL_DATA_OFFSET + (image_size as u32))
+        .unwrap(); // Write a 32-bit unsigned integer (image size + pixel data offset)
+    headers.write_u32::<LE>(0).unwrap(); // Write a 32-bit unsigned integer (0)
+    headers.write_u32::<LE>(MONO_PIXEL_DATA_OFFSET).unwrap(); // Write a 32-bit unsigned integer (pixel data offset)
+
+    headers.write_u32::<LE>(40).unwrap(); // Write a 32-bit unsigned integer (DIB header size)
+    headers.write_i32::<LE>(width as i32).unwrap(); // Write a 32-bit signed integer (width)
+    headers.write_i32::<LE>(height as i32).unwrap(); // Write a 32-bit signed integer (height)
+    headers.write_u16::<LE>(1).unwrap(); // Write a 16-bit unsigned integer (1)
+    headers.write_u16::<LE>(1).unwrap(); // Write a 16-bit unsigned integer (1 bit per pixel)
+    headers.write_u32::<LE>(BI_RGB).unwrap(); // Write a 32-bit unsigned integer (0)
+    headers.write_u32::<LE>(image_size as u32).unwrap(); // Write a 32-bit unsigned integer (image size)
+    headers.write_u32::<LE>(0).unwrap(); // Write a 32-bit unsigned integer (0)
+    headers.write_u32::<LE>(0).unwrap(); // Write a 32-bit unsigned integer (0)
+    headers.write_u32::<LE>(2).unwrap(); // Write a 32-bit unsigned integer (colors in the table)
+    headers.write_u32::<LE>(0).unwrap(); // Write a 32-bit unsigned integer (0)
+    for color in MONO_COLORS {
+        let [r, g, b] = rgb565_2_rgb888(color);
+        headers.write_all(&[b, g, r, 0]).unwrap(); // Write a color of the color table
+    }
+
+    let mut row_data = vec![0u8; row_size];
+    let write_contents = |bmp_file: &mut dyn Write| -> Result<(), SaveError> {
+        bmp_file.write_all(&headers)?;
+
+        // Write pixel data, with the leftmost pixel of every byte in its highest bit
+        for row in data.iter().rev() {
+            row_data.fill(0);
+            for (column, &color) in row.iter().enumerate() {
+                if color == MONO_COLORS[1] {
+                    row_data[column / 8] |= 0x80 >> (column % 8);
+                }
+            }
+            bmp_file.write_all(&row_data)?;
+        }
+
+        Ok(())
+    };
+
+    let encoding = match compression {
+        StoreCompression::None => Encoding::Plain,
+        StoreCompression::Gzip => Encoding::Gzip,
+        StoreCompression::Zstd => Encoding::Zstd(level),
+    };
+    let path = format!("{}.bmp{}", filename, compression.suffix());
+    save_encoded(&path, encoding, write_contents)
+}
+
+/// Checks whether every pixel of an image is black or white, so that it can be saved with
+/// [`save_bmp_image_mono`]
+pub fn is_monochrome(data: &[Vec<u16>]) -> bool {
+    data.iter()
+        .flatten()
+        .all(|color| MONO_COLORS.contains(color))
+}
+
+/// Encodings that the contents of a BMP file can be written with
+#[derive(Clone, Copy)]
+enum Encoding {
+    /// The contents are written as they are
+    Plain,
+    /// The contents are compressed with gzip
+    Gzip,
+    /// The contents are compressed with zstd, at the given level
+    Zstd(i32),
+}
+
+/// Writes a 16-bit color (5-6-5) BMP Image to a file, optionally compressed
 ///
-/// * When the given image has 0 rows
-/// * When the program does not have sufficient priviledges to create/modify the file at the given location
+/// # Arguments
+///
+/// * `data` - A 16-bit color bitmap that must be saved
+/// * `path` - Path of the file, including its extension
+/// * `format` - Layout of the colors in the file
+/// * `encoding` - How to compress the file, if at all
 ///
-pub fn save_bmp_image(data: &[Vec<u16>], filename: &str) {
+fn write_bmp_image(
+    data: &[Vec<u16>],
+    path: &str,
+    format: ColorFormat,
+    encoding: Encoding,
+) -> Result<(), SaveError> {
+    let width = validate_image(data)?;
     let height = data.len();
-    let width = data.first().unwrap().len();
 
     let row_size = width * 2;
-    let padding_size = (4 - (row_size % 4)) % 4;
+    let padding_size = bmp_row_padding(width, 2);
     let image_size = (row_size + padding_size) * height;
 
     let padding = vec![0; padding_size];
 
     let mut bmp_header = Vec::with_capacity(14);
-    let mut dib_header = Vec::with_capacity(40);
+    let pixel_data_offset = format.pixel_data_offset();
+    let mut dib_header = Vec::with_capacity((pixel_data_offset - 14) as usize);
 
     bmp_header.write_all(b"BM").unwrap(); // Write the 2-byte string "BM"
     bmp_header
-        .write_u32::<LE>(54 + (image_size as u32))
-        .unwrap(); // Write a 32-bit unsigned integer (image size + 54)
+        .write_u32::<LE>(pixel_data_offset + (image_size as u32))
+        .unwrap(); // Write a 32-bit unsigned integer (image size + pixel data offset)
     bmp_header.write_u16::<LE>(0).unwrap(); // Write a 16-bit unsigned integer (0)
     bmp_header.write_u16::<LE>(0).unwrap(); // Write a 16-bit unsigned integer (0)
-    bmp_header.write_u32::<LE>(54).unwrap(); // Write a 32-bit unsigned integer (54)
+    bmp_header.write_u32::<LE>(pixel_data_offset).unwrap(); // Write a 32-bit unsigned integer (pixel data offset)
 
-    dib_header.write_u32::<LE>(40).unwrap(); // Write a 32-bit unsigned integer (40)
+    dib_header
+        .write_u32::<LE>(format.dib_header_size())
+        .unwrap(); // Write a 32-bit unsigned integer (DIB header size)
     dib_header.write_i32::<LE>(width as i32).unwrap(); // Write a 32-bit signed integer (width)
     dib_header.write_i32::<LE>(height as i32).unwrap(); // Write a 32-bit signed integer (height)
     dib_header.write_u16::<LE>(1).unwrap(); // Write a 16-bit unsigned integer (1)
     dib_header.write_u16::<LE>(16).unwrap(); // Write a 16-bit unsigned integer (16)
-    dib_header.write_u32::<LE>(0).unwrap(); // Write a 32-bit unsigned integer (0)
+    dib_header.write_u32::<LE>(BI_BITFIELDS).unwrap(); // Write a 32-bit unsigned integer (3)
     dib_header.write_u32::<LE>(image_size as u32).unwrap(); // Write a 32-bit unsigned integer (image size)
     dib_header.write_u32::<LE>(0).unwrap(); // Write a 32-bit unsigned integer (0)
     dib_header.write_u32::<LE>(0).unwrap(); // Write a 32-bit unsigned integer (0)
     dib_header.write_u32::<LE>(0).unwrap(); // Write a 32-bit unsigned integer (0)
     dib_header.write_u32::<LE>(0).unwrap(); // Write a 32-bit unsigned integer (0)
+    for mask in format.masks() {
+        dib_header.write_u32::<LE>(mask).unwrap(); // Write a 32-bit unsigned integer (channel mask)
+    }
+    if format == ColorFormat::Rgb555 {
+        dib_header.write_u32::<LE>(0).unwrap(); // Write a 32-bit unsigned integer (alpha mask)
+    }
 
-    // Write to BMP file
-    let mut bmp_file =
-        File::create(format!("{}.bmp", filename)).expect("Failed to create BMP file");
-    bmp_file
-        .write_all(&bmp_header)
-        .expect("Failed to write BMP header");
-    bmp_file
-        .write_all(&dib_header)
-        .expect("Failed to write DIB header");
+    let write_contents = |bmp_file: &mut dyn Write| -> Result<(), SaveError> {
+        bmp_file.write_all(&bmp_header)?;
+        bmp_file.write_all(&dib_header)?;
 
-    // Write pixel data
-    for row in data.iter().rev() {
-        for &v in row.iter() {
-            bmp_file
-                .write_all(&v.to_le_bytes())
-                .expect("Failed to write pixel data");
+        // Write pixel data
+        for row in data.iter().rev() {
+            for &v in row.iter() {
+                bmp_file.write_all(&format.encode(v).to_le_bytes())?;
+            }
+
+            // Write padding bytes
+            bmp_file.write_all(&padding)?;
         }
 
-        // Write padding bytes
-        bmp_file
-            .write_all(&padding)
-            .expect("Failed to write padding");
-    }
+        Ok(())
+    };
+
+    save_encoded(path, encoding, write_contents)
 }
 
-/// Loads a 16-bit color (5-6-5) BMP Image from the filesystem
+/// Checks that an image has pixels and that all of its rows are as long, and gets its width
 ///
-/// If the image dimensions do not match the expected dimensions or the image does not exist, a blank image is returned
+/// # Errors
 ///
-/// # Arguments
+/// * [`SaveError::Empty`] when the given image has 0 rows or 0 columns
+/// * [`SaveError::RaggedRows`] when the rows of the given image have different lengths
 ///
-/// * `filename` - The name of the file (extensionless)
-/// * `expected_width` - The expected width of the image
-/// * `expected_height` - The expected height of the image
+fn validate_image(data: &[Vec<u16>]) -> Result<usize, SaveError> {
+    let width = data.first().map_or(0, |row| row.len());
+
+    if width == 0 {
+        return Err(SaveError::Empty);
+    }
+    if let Some((row, ragged)) = data.iter().enumerate().find(|(_, row)| row.len() != width) {
+        return Err(SaveError::RaggedRows {
+            row,
+            width: ragged.len(),
+            expected_width: width,
+        });
+    }
+    Ok(width)
+}
+
+/// Writes the contents of a BMP file atomically, compressed with the given encoding
 ///
-/// # Panics
+/// # Arguments
 ///
-/// * When the program does not have sufficient priviledges to open/read the file at the given location
+/// * `path` - Path of the file, including its extension
+/// * `encoding` - How to compress the file, if at all
+/// * `write_contents` - Function that writes the uncompressed contents of the file
 ///
-pub fn load_bmp_image(
-    filename: &str,
-    expected_width: usize,
-    expected_height: usize,
-) -> Vec<Vec<u16>> {
-    // Open the BMP file
-    let Ok(mut bmp_file) = File::open(format!("{}.bmp", filename)) else {
-        let result = vec![vec![0u16; expected_width]; expected_height];
-        return result;
-    };
+fn save_encoded<F>(path: &str, encoding: Encoding, mut write_contents: F) -> Result<(), SaveError>
+where
+    F: FnMut(&mut dyn Write) -> Result<(), SaveError>,
+{
+    // Write to a temporary BMP file, which replaces the actual file once it is complete
+    save_atomically(path, |file| match encoding {
+        Encoding::Plain => write_contents(file),
+        Encoding::Gzip => {
+            let mut encoder = flate2::write::GzEncoder::new(file, flate2::Compression::default());
+            write_contents(&mut encoder)?;
+            encoder.finish()?;
+            Ok(())
+        }
+        Encoding::Zstd(level) => {
+            let mut encoder = zstd::stream::write::Encoder::new(file, level)?;
+            write_contents(&mut encoder)?;
+            encoder.finish()?;
+            Ok(())
+        }
+    })
+}
 
-    // Read the BMP Header
-    let mut bmp_header = [0; 54];
-    bmp_file
-        .read_exact(&mut bmp_header)
-        .expect("Failed to read BMP header");
-    bmp_file
-        .seek(SeekFrom::Start(54))
-        .expect("Failed to seek to pixel data");
+/// Gets the number of bytes that pad each row of a BMP image, since rows are stored in multiples of
+/// 4 bytes
+///
+/// # Arguments
+///
+/// * `width` - Number of columns in the image
+/// * `bytes_per_pixel` - Size of each pixel in the file (2 for the 16-bit images that are saved)
+///
+fn bmp_row_padding(width: usize, bytes_per_pixel: usize) -> usize {
+    (4 - (width * bytes_per_pixel) % 4) % 4
+}
 
-    // Extract image dimensions from the header
-    let width = u32::from_le_bytes([
-        bmp_header[18],
-        bmp_header[19],
-        bmp_header[20],
-        bmp_header[21],
-    ]) as usize;
-    let height = u32::from_le_bytes([
-        bmp_header[22],
-        bmp_header[23],
-        bmp_header[24],
-        bmp_header[25],
-    ]) as usize;
+/// Gets the number of bytes of each row of a BMP image, including the padding that makes it a
+/// multiple of 4 bytes (which, with 1 bit per pixel, is up to 31 pixels of padding)
+///
+/// # Arguments
+///
+/// * `width` - Number of columns in the image
+/// * `bit_count` - Number of bits of each pixel in the file
+///
+fn padded_row_size(width: usize, bit_count: usize) -> usize {
+    (width * bit_count).div_ceil(32) * 4
+}
 
-    // if the actual dimensions do not match the expected dimensions, return a blank image with the expected dimensions
-    if width != expected_width || height != expected_height {
-        let result = vec![vec![0u16; expected_width]; expected_height];
-        return result;
-    }
+/// Gets the size of the file that [`save_bmp_image_mono`] writes for an image, in bytes
+///
+/// # Arguments
+///
+/// * `width` - Number of columns in the image
+/// * `height` - Number of rows in the image
+///
+pub fn mono_bmp_file_size(width: usize, height: usize) -> u64 {
+    MONO_PIXEL_DATA_OFFSET as u64 + (padded_row_size(width, 1) * height) as u64
+}
 
-    // Calculate the size of each row, including padding if necessary
-    let row_size = width * 2; // Each pixel is 16 bits (2 bytes)
-    let padding_size = (4 - (row_size % 4)) % 4; // Calculate padding needed per row
+/// Gets the size of the file that [`save_bmp_image_as`] writes for an image, in bytes
+///
+/// # Arguments
+///
+/// * `width` - Number of columns in the image
+/// * `height` - Number of rows in the image
+/// * `format` - Layout of the colors in the file
+///
+pub fn bmp_file_size(width: usize, height: usize, format: ColorFormat) -> u64 {
+    let row_size = width * 2;
+    let padding_size = bmp_row_padding(width, 2);
+    format.pixel_data_offset() as u64 + ((row_size + padding_size) * height) as u64
+}
 
-    let mut padding = vec![0; padding_size];
+/// Writes a file by first writing to a temporary file (with [`TEMP_SUFFIX`] appended to its path)
+/// and then renaming the temporary file over the actual file
+///
+/// The temporary file is synced to the disk before it is renamed and is deleted if anything fails,
+/// so the actual file is either left untouched or replaced with the complete contents
+///
+/// # Arguments
+///
+/// * `path` - Path of the actual file
+/// * `write` - Function that writes the contents of the file
+///
+fn save_atomically<F>(path: &str, write: F) -> Result<(), SaveError>
+where
+    F: FnOnce(&mut dyn Write) -> Result<(), SaveError>,
+{
+    let temp_path = format!("{}{}", path, TEMP_SUFFIX);
 
-    // Read the pixel data
-    let mut pixels = vec![vec![0; width]; height];
-    let mut color_data = [0, 0];
+    let result = (|| {
+        let mut temp_file = File::create(&temp_path)?;
 
-    for row in pixels.iter_mut().rev() {
-        for element in row.iter_mut() {
-            bmp_file
-                .read_exact(&mut color_data)
-                .expect("Failed to read color data");
+        let mut writer = std::io::BufWriter::new(&mut temp_file);
+        write(&mut writer)?;
+        writer.flush()?;
+        drop(writer);
 
-            *element = u16::from_le_bytes(color_data);
-        }
+        temp_file.sync_all()?;
+        std::fs::rename(&temp_path, path)?;
+        Ok(())
+    })();
 
-        bmp_file
-            .read_exact(&mut padding)
-            .expect("Failed to read padding data");
+    if result.is_err() {
+        let _ = std::fs::remove_file(&temp_path);
     }
-
-    pixels
+    result
 }
 
-/// Converts a 16-bit color to a 4-bit code
-///
-/// The code is placed in the lower nibble of the returned byte
+/// Saves a 16-bit color (5-6-5) image to the filesystem as a 24-bit color (8-8-8) PNG Image
 ///
 /// # Arguments
 ///
-/// * `color` - The 16-bit color to convert to its code
+/// * `data` - A 16-bit color bitmap that must be saved
+/// * `filename` - The name of the file (extensionless)
 ///
 /// # Errors
 ///
-/// * When the supplied color does not map to any code
-///
-pub fn color_2_code(color: u16) -> Option<u8> {
-    match color {
-        0xF800u16 => Some(0),
-        0x07E0u16 => Some(1),
-        0x001Fu16 => Some(2),
-        0x07FFu16 => Some(3),
-        0xF81Fu16 => Some(4),
-        0xFFE0u16 => Some(5),
-        0xFFFFu16 => Some(6),
-        0x520Au16 => Some(7),
-        0x0000u16 => Some(8),
-        _ => None,
-    }
+/// * When the file could not be created or written to
+/// * When the given image has 0 rows or columns
+///
+pub fn save_png_image(data: &[Vec<u16>], filename: &str) -> Result<(), png::EncodingError> {
+    let height = data.len();
+    let width = data.first().map_or(0, |row| row.len());
+
+    let png_file = File::create(format!("{}.png", filename))?;
+
+    let mut encoder = png::Encoder::new(
+        std::io::BufWriter::new(png_file),
+        width as u32,
+        height as u32,
+    );
+    encoder.set_color(png::ColorType::Rgb);
+    encoder.set_depth(png::BitDepth::Eight);
+
+    let pixels: Vec<u8> = data
+        .iter()
+        .flatten()
+        .flat_map(|&color| rgb565_2_rgb888(color))
+        .collect();
+
+    let mut writer = encoder.write_header()?;
+    writer.write_image_data(&pixels)?;
+    writer.finish()
 }
 
-/// Converts a 4-bit code to a 16-bit color
+/// Saves a sequence of 16-bit color (5-6-5) images of the same dimensions as an animated GIF image
 ///
-/// The code must be placed in the lower nibble of the passed byte
+/// Every frame is indexed into a single global palette made of the colors of the given palette,
+/// and pixels of colors outside of the palette are mapped to the nearest color of the palette.
 ///
 /// # Arguments
 ///
-/// * `code` - The 4-bit color to convert to its code
+/// * `frames` - The 16-bit color bitmaps to save, in the order in which they are shown
+/// * `delay_ms` - Time for which each frame is shown, in milliseconds (in steps of 10 ms)
+/// * `filename` - The name of the file (extensionless)
+/// * `palette` - The palette that the colors of the frames are reduced to
 ///
 /// # Errors
 ///
-/// * When the supplied code does not map to any color
-///
-pub fn code_2_color(code: u8) -> Option<u16> {
-    match code {
-        0 => Some(0xF800u16),
-        1 => Some(0x07E0u16),
-        2 => Some(0x001Fu16),
-        3 => Some(0x07FFu16),
-        4 => Some(0xF81Fu16),
-        5 => Some(0xFFE0u16),
-        6 => Some(0xFFFFu16),
-        7 => Some(0x520Au16),
-        8 => Some(0x0000u16),
-        _ => None,
+/// * When the file could not be created or written to
+/// * When there are no frames, or the frames are larger than 65535 pixels in either dimension
+///
+pub fn save_gif_animation(
+    frames: &[Vec<Vec<u16>>],
+    delay_ms: u16,
+    filename: &str,
+    palette: &Palette,
+) -> Result<(), gif::EncodingError> {
+    let invalid = |reason| std::io::Error::new(std::io::ErrorKind::InvalidInput, reason);
+
+    let first = frames
+        .first()
+        .ok_or_else(|| invalid("animation has no frames"))?;
+    let (Ok(width), Ok(height)) = (
+        u16::try_from(first.first().map_or(0, |row| row.len())),
+        u16::try_from(first.len()),
+    ) else {
+        return Err(invalid("frames are too large for a GIF image").into());
+    };
+
+    // the index of every color in the palette is its code, and codes without a color are black
+    let colors: Vec<u8> = (0..MAX_PALETTE_LEN as u8)
+        .flat_map(|code| rgb565_2_rgb888(palette.code_2_color(code).unwrap_or(0)))
+        .collect();
+
+    let gif_file = File::create(format!("{}.gif", filename))?;
+    let mut encoder = gif::Encoder::new(std::io::BufWriter::new(gif_file), width, height, &colors)?;
+    encoder.set_repeat(gif::Repeat::Infinite)?;
+
+    for frame in frames {
+        let indices: Vec<u8> = frame
+            .iter()
+            .flatten()
+            .map(|&color| palette.nearest_code(color))
+            .collect();
+
+        encoder.write_frame(&gif::Frame {
+            width,
+            height,
+            delay: delay_ms / 10,
+            buffer: std::borrow::Cow::Owned(indices),
+            ..gif::Frame::default()
+        })?;
+    }
+    Ok(())
+}
+
+/// Gets the pixels of a 16-bit color (5-6-5) image as a raw stream of little-endian colors, in
+/// row-major order from the top row to the bottom row, without any header
+///
+/// # Arguments
+///
+/// * `data` - A 16-bit color bitmap
+///
+pub fn rgb565_bytes(data: &[Vec<u16>]) -> Vec<u8> {
+    data.iter()
+        .flatten()
+        .flat_map(|color| color.to_le_bytes())
+        .collect()
+}
+
+/// Creates an image of a single color, such as the image that an empty slot is loaded as
+///
+/// # Arguments
+///
+/// * `width` - Number of columns of the image
+/// * `height` - Number of rows of the image
+/// * `color` - The 16-bit color of every pixel
+///
+pub fn blank_image(width: usize, height: usize, color: u16) -> Vec<Vec<u16>> {
+    vec![vec![color; width]; height]
+}
+
+/// Scales an image up by an integer factor, by repeating every pixel in a square of that size
+///
+/// # Arguments
+///
+/// * `data` - A 16-bit color bitmap
+/// * `factor` - Number of times that every pixel is repeated along each edge
+///
+pub fn upscale(data: &[Vec<u16>], factor: usize) -> Vec<Vec<u16>> {
+    data.iter()
+        .flat_map(|row| {
+            let row: Vec<u16> = row
+                .iter()
+                .flat_map(|&color| std::iter::repeat_n(color, factor))
+                .collect();
+            std::iter::repeat_n(row, factor)
+        })
+        .collect()
+}
+
+/// Scales an image to the given dimensions (up or down, and independently along each edge), by
+/// picking the nearest pixel of the image for every pixel of the scaled image
+///
+/// Pixels are only ever copied and never blended, so the scaled image has no colors (and so no
+/// codes) that the image does not have.
+///
+/// # Arguments
+///
+/// * `data` - A 16-bit color bitmap, with at least one pixel
+/// * `width` - Number of columns of the scaled image
+/// * `height` - Number of rows of the scaled image
+///
+pub fn scale_nearest(data: &[Vec<u16>], width: usize, height: usize) -> Vec<Vec<u16>> {
+    let src_height = data.len();
+    let src_width = data.first().map_or(0, |row| row.len());
+
+    (0..height)
+        .map(|y| {
+            let row = &data[y * src_height / height];
+            (0..width).map(|x| row[x * src_width / width]).collect()
+        })
+        .collect()
+}
+
+/// Mirrors an image horizontally, swapping its left and right edges
+///
+/// # Arguments
+///
+/// * `data` - A 16-bit color bitmap
+///
+pub fn flip_horizontal(data: &[Vec<u16>]) -> Vec<Vec<u16>> {
+    data.iter()
+        .map(|row| row.iter().rev().copied().collect())
+        .collect()
+}
+
+/// Mirrors an image vertically, swapping its top and bottom edges
+///
+/// # Arguments
+///
+/// * `data` - A 16-bit color bitmap
+///
+pub fn flip_vertical(data: &[Vec<u16>]) -> Vec<Vec<u16>> {
+    data.iter().rev().cloned().collect()
+}
+
+/// Draws an overlay over a base image, copying every pixel of the overlay except those of its
+/// transparent color (where the base shows through)
+///
+/// # Arguments
+///
+/// * `base` - A 16-bit color bitmap
+/// * `overlay` - A 16-bit color bitmap of the same dimensions as the base
+/// * `transparent` - Color of the pixels of the overlay that are not copied
+///
+pub fn overlay_image(base: &[Vec<u16>], overlay: &[Vec<u16>], transparent: u16) -> Vec<Vec<u16>> {
+    base.iter()
+        .zip(overlay)
+        .map(|(base_row, overlay_row)| {
+            base_row
+                .iter()
+                .zip(overlay_row)
+                .map(|(&base, &overlay)| match overlay == transparent {
+                    true => base,
+                    false => overlay,
+                })
+                .collect()
+        })
+        .collect()
+}
+
+/// Reflections that images can be mirrored with, when they are saved (with `--mirror`) or loaded
+/// (as asked for by the client)
+#[derive(clap::ValueEnum, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Mirror {
+    /// Swap the left and right edges
+    Horizontal,
+    /// Swap the top and bottom edges
+    Vertical,
+    /// Swap both, which is the same as rotating by 180 degrees
+    Both,
+}
+
+impl Mirror {
+    /// Gets the reflection that the flags of a load ask for, if any
+    ///
+    /// # Arguments
+    ///
+    /// * `horizontal` - Whether to swap the left and right edges
+    /// * `vertical` - Whether to swap the top and bottom edges
+    ///
+    pub fn from_flips(horizontal: bool, vertical: bool) -> Option<Self> {
+        match (horizontal, vertical) {
+            (false, false) => None,
+            (true, false) => Some(Self::Horizontal),
+            (false, true) => Some(Self::Vertical),
+            (true, true) => Some(Self::Both),
+        }
+    }
+
+    /// Applies the reflection to an image
+    ///
+    /// # Arguments
+    ///
+    /// * `data` - A 16-bit color bitmap
+    ///
+    pub fn apply(self, data: &[Vec<u16>]) -> Vec<Vec<u16>> {
+        match self {
+            Self::Horizontal => flip_horizontal(data),
+            Self::Vertical => flip_vertical(data),
+            Self::Both => flip_vertical(&flip_horizontal(data)),
+        }
+    }
+}
+
+/// Rotations and reflections that can be applied to an image
+#[derive(clap::ValueEnum, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Transform {
+    /// Rotate by 90 degrees clockwise
+    Rot90,
+    /// Rotate by 180 degrees
+    Rot180,
+    /// Rotate by 270 degrees clockwise (90 degrees counter-clockwise)
+    Rot270,
+    /// Mirror the columns, swapping the left and right edges
+    FlipH,
+    /// Mirror the rows, swapping the top and bottom edges
+    FlipV,
+}
+
+impl Transform {
+    /// Checks whether the transform swaps the width and the height of an image
+    pub fn swaps_dimensions(self) -> bool {
+        matches!(self, Self::Rot90 | Self::Rot270)
+    }
+
+    /// Applies the transform to an image
+    ///
+    /// # Arguments
+    ///
+    /// * `data` - A 16-bit color bitmap
+    ///
+    pub fn apply(self, data: &[Vec<u16>]) -> Vec<Vec<u16>> {
+        let height = data.len();
+        let width = data.first().map_or(0, |row| row.len());
+        let (new_height, new_width) = match self.swaps_dimensions() {
+            true => (width, height),
+            false => (height, width),
+        };
+
+        (0..new_height)
+            .map(|y| {
+                (0..new_width)
+                    .map(|x| match self {
+                        Self::Rot90 => data[height - 1 - x][y],
+                        Self::Rot180 => data[height - 1 - y][width - 1 - x],
+                        Self::Rot270 => data[x][width - 1 - y],
+                        Self::FlipH => data[y][width - 1 - x],
+                        Self::FlipV => data[height - 1 - y][x],
+                    })
+                    .collect()
+            })
+            .collect()
+    }
+}
+
+/// Decodes an image in any common format (PNG, JPEG or BMP), scales it to the given dimensions and
+/// maps every pixel to the nearest color of the palette, so that it can be drawn on the canvas
+///
+/// # Arguments
+///
+/// * `filename` - Path of the image, including its extension
+/// * `width` - Number of columns of the canvas
+/// * `height` - Number of rows of the canvas
+/// * `palette` - The palette that the colors of the image are reduced to
+///
+/// # Errors
+///
+/// * When the file can not be read, or is not an image in a supported format
+///
+pub fn import_image(
+    filename: &str,
+    width: usize,
+    height: usize,
+    palette: &Palette,
+) -> Result<Vec<Vec<u16>>, ::image::ImageError> {
+    let img = ::image::open(filename)?
+        .resize_exact(
+            width as u32,
+            height as u32,
+            ::image::imageops::FilterType::Triangle,
+        )
+        .to_rgb8();
+
+    Ok(img
+        .rows()
+        .map(|row| {
+            row.map(|pixel| {
+                let [r, g, b] = pixel.0;
+                let code = palette.nearest_code(rgb888_2_rgb565(r, g, b));
+                palette.code_2_color(code).unwrap()
+            })
+            .collect()
+        })
+        .collect())
+}
+
+/// Reasons for which a BMP image could not be loaded from the filesystem
+#[derive(Debug)]
+pub enum LoadError {
+    /// The file does not exist
+    NotFound,
+    /// The file is too short to contain a header, or the header is not that of a BMP file
+    BadHeader,
+    /// The file ended before all of the pixel data could be read
+    Truncated,
+    /// The image uses a bit depth or compression method that cannot be read
+    Unsupported { bit_count: u16, compression: u32 },
+    /// The dimensions of the stored image do not match the expected dimensions
+    DimensionMismatch { width: usize, height: usize },
+    /// Any other error raised by the filesystem while reading the file
+    Io(std::io::Error),
+}
+
+impl std::fmt::Display for LoadError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            LoadError::NotFound => write!(f, "image does not exist"),
+            LoadError::BadHeader => write!(f, "image has a missing or malformed BMP header"),
+            LoadError::Truncated => write!(f, "image pixel data is truncated"),
+            LoadError::Unsupported {
+                bit_count,
+                compression,
+            } => write!(
+                f,
+                "image has unsupported bit depth {} or compression {}",
+                bit_count, compression
+            ),
+            LoadError::DimensionMismatch { width, height } => {
+                write!(f, "image has unexpected dimensions {} x {}", height, width)
+            }
+            LoadError::Io(err) => write!(f, "failed to read image: {}", err),
+        }
+    }
+}
+
+impl std::error::Error for LoadError {}
+
+/// Maps an error raised while reading pixel data, treating an early end-of-file as truncation
+fn pixel_read_error(err: std::io::Error) -> LoadError {
+    match err.kind() {
+        std::io::ErrorKind::UnexpectedEof => LoadError::Truncated,
+        _ => LoadError::Io(err),
+    }
+}
+
+/// Loads a 16-bit color (5-6-5) BMP Image from the filesystem
+///
+/// Both images written by [`save_bmp_image_as`] (`BI_BITFIELDS` with 5-6-5 or 5-5-5 masks, the latter
+/// of which are converted to 5-6-5) and uncompressed
+/// (`BI_RGB`) 16-bit images are accepted, the latter of which were written by older versions of
+/// this server.
+/// 24-bit BMP images are also accepted, and each of their pixels is converted to a 16-bit color, as
+/// are indexed (1-bit, 4-bit and 8-bit) images such as the monochrome ones written by
+/// [`save_bmp_image_mono`], whose pixels take the colors of their color table (or black, for an
+/// index past the end of the table).
+/// Both bottom-up images (positive height) and top-down images (negative height) can be loaded.
+///
+/// # Arguments
+///
+/// * `filename` - The name of the file (extensionless)
+/// * `expected_width` - The expected width of the image
+/// * `expected_height` - The expected height of the image
+/// * `palette` - The palette whose colors are kept exactly by images in the 5-5-5 layout
+///
+/// # Errors
+///
+/// * [`LoadError::NotFound`] when the file does not exist
+/// * [`LoadError::BadHeader`] when the file does not start with a valid BMP header
+/// * [`LoadError::Unsupported`] when the image is not an uncompressed 1-bit, 4-bit, 8-bit, 16-bit
+///   (5-6-5 or 5-5-5) or 24-bit BMP
+/// * [`LoadError::DimensionMismatch`] when the image dimensions do not match the expected dimensions
+/// * [`LoadError::Truncated`] when the file ends before all of the pixel data has been read
+/// * [`LoadError::Io`] when the file could not be opened or read for any other reason
+///
+pub fn load_bmp_image(
+    filename: &str,
+    expected_width: usize,
+    expected_height: usize,
+    palette: &Palette,
+) -> Result<Vec<Vec<u16>>, LoadError> {
+    // Open the BMP file (which may be compressed)
+    let mut bmp_file = open_bmp(filename)?;
+
+    // Read the BMP Header
+    let mut bmp_header = [0; 54];
+    bmp_file
+        .read_exact(&mut bmp_header)
+        .map_err(|err| match err.kind() {
+            std::io::ErrorKind::UnexpectedEof => LoadError::BadHeader,
+            _ => LoadError::Io(err),
+        })?;
+
+    if &bmp_header[0..2] != b"BM" {
+        return Err(LoadError::BadHeader);
+    }
+
+    // Extract the pixel data offset and the pixel format from the header
+    let data_offset = u32::from_le_bytes([
+        bmp_header[10],
+        bmp_header[11],
+        bmp_header[12],
+        bmp_header[13],
+    ]);
+    let bit_count = u16::from_le_bytes([bmp_header[28], bmp_header[29]]);
+    let compression = u32::from_le_bytes([
+        bmp_header[30],
+        bmp_header[31],
+        bmp_header[32],
+        bmp_header[33],
+    ]);
+
+    // the pixel data can not overlap the headers
+    if data_offset < 54 {
+        return Err(LoadError::BadHeader);
+    }
+    let format = match (bit_count, compression) {
+        (1 | 4 | 8 | 16 | 24, BI_RGB) => Some(ColorFormat::Rgb565),
+        (16, BI_BITFIELDS) => {
+            // the channel masks follow the 40 byte DIB header (or are its continuation in later versions)
+            let mut masks = [0; 12];
+            bmp_file
+                .read_exact(&mut masks)
+                .map_err(|err| match err.kind() {
+                    std::io::ErrorKind::UnexpectedEof => LoadError::BadHeader,
+                    _ => LoadError::Io(err),
+                })?;
+
+            let masks: Vec<u32> = masks
+                .chunks_exact(4)
+                .map(|mask| u32::from_le_bytes([mask[0], mask[1], mask[2], mask[3]]))
+                .collect();
+            [ColorFormat::Rgb565, ColorFormat::Rgb555]
+                .into_iter()
+                .find(|format| masks == format.masks())
+        }
+        _ => None,
+    };
+    let Some(format) = format else {
+        return Err(LoadError::Unsupported {
+            bit_count,
+            compression,
+        });
+    };
+
+    // Extract image dimensions from the header
+    let width = i32::from_le_bytes([
+        bmp_header[18],
+        bmp_header[19],
+        bmp_header[20],
+        bmp_header[21],
+    ]);
+    let height = i32::from_le_bytes([
+        bmp_header[22],
+        bmp_header[23],
+        bmp_header[24],
+        bmp_header[25],
+    ]);
+
+    // a negative height means the rows are stored top-down instead of bottom-up
+    let top_down = height < 0;
+    let Ok(width) = usize::try_from(width) else {
+        return Err(LoadError::BadHeader);
+    };
+    let height = height.unsigned_abs() as usize;
+
+    if width != expected_width || height != expected_height {
+        return Err(LoadError::DimensionMismatch { width, height });
+    }
+
+    // the color table of an indexed image follows its DIB header, and holds the color of every
+    // index (of every index that is used, when the header has the number of colors)
+    let colors = match bit_count {
+        1 | 4 | 8 => {
+            let dib_header_size = u32::from_le_bytes([
+                bmp_header[14],
+                bmp_header[15],
+                bmp_header[16],
+                bmp_header[17],
+            ]);
+            let colors_used = u32::from_le_bytes([
+                bmp_header[46],
+                bmp_header[47],
+                bmp_header[48],
+                bmp_header[49],
+            ]);
+            let num_colors = match colors_used {
+                0 => 1 << bit_count,
+                colors_used if colors_used <= 1 << bit_count => colors_used,
+                _ => return Err(LoadError::BadHeader),
+            };
+            let table_offset = 14 + dib_header_size as u64;
+            if table_offset + 4 * num_colors as u64 > data_offset as u64 {
+                return Err(LoadError::BadHeader);
+            }
+            let mut table = vec![0; 4 * num_colors as usize];
+            bmp_file
+                .seek(SeekFrom::Start(table_offset))
+                .and_then(|_| bmp_file.read_exact(&mut table))
+                .map_err(|err| match err.kind() {
+                    std::io::ErrorKind::UnexpectedEof => LoadError::BadHeader,
+                    _ => LoadError::Io(err),
+                })?;
+            let colors: Vec<u16> = table
+                .chunks_exact(4)
+                .map(|entry| rgb888_2_rgb565(entry[2], entry[1], entry[0]))
+                .collect();
+            Some(colors)
+        }
+        _ => None,
+    };
+
+    // Calculate the size of each row, including padding if necessary
+    let bytes_per_pixel = (bit_count / 8) as usize;
+    let row_size = padded_row_size(width, bit_count as usize);
+
+    // the pixels are allocated from the dimensions in the header, which a damaged file could make
+    // far larger than the file itself
+    let file_len = bmp_file.seek(SeekFrom::End(0)).map_err(LoadError::Io)?;
+    let data_end = row_size
+        .checked_mul(height)
+        .and_then(|size| (size as u64).checked_add(data_offset as u64));
+    if data_end.is_none_or(|end| end > file_len) {
+        return Err(LoadError::Truncated);
+    }
+    bmp_file
+        .seek(SeekFrom::Start(data_offset as u64))
+        .map_err(LoadError::Io)?;
+
+    // Read the pixel data
+    let mut pixels = vec![vec![0; width]; height];
+    let mut row_data = vec![0; row_size];
+
+    for row in pixels.iter_mut() {
+        bmp_file
+            .read_exact(&mut row_data)
+            .map_err(pixel_read_error)?;
+
+        // the leftmost pixel of every byte of an indexed image is in its highest bits
+        if let Some(colors) = &colors {
+            let bits = bit_count as usize;
+            let pixels_per_byte = 8 / bits;
+            for (column, element) in row.iter_mut().enumerate() {
+                let shift = 8 - bits * (column % pixels_per_byte + 1);
+                let index =
+                    (row_data[column / pixels_per_byte] as usize >> shift) & ((1 << bits) - 1);
+                *element = colors.get(index).copied().unwrap_or(0x0000);
+            }
+            continue;
+        }
+
+        for (element, color_data) in row.iter_mut().zip(row_data.chunks_exact(bytes_per_pixel)) {
+            *element = match *color_data {
+                [lo, hi] => format.decode(u16::from_le_bytes([lo, hi]), palette),
+                [b, g, r] => rgb888_2_rgb565(r, g, b),
+                _ => unreachable!(),
+            };
+        }
+    }
+
+    // rows were read in the order they are stored
+    if !top_down {
+        pixels.reverse();
+    }
+
+    Ok(pixels)
+}
+
+/// Reads the dimensions of a BMP image from its header, without loading the image
+///
+/// # Arguments
+///
+/// * `filename` - The name of the file (extensionless)
+///
+/// # Returns
+///
+/// The width and height of the image
+///
+/// # Errors
+///
+/// * [`LoadError::NotFound`] when the file does not exist
+/// * [`LoadError::BadHeader`] when the file does not start with a valid BMP header
+/// * [`LoadError::Io`] when the file could not be opened or read for any other reason
+///
+pub fn read_bmp_dimensions(filename: &str) -> Result<(usize, usize), LoadError> {
+    let mut bmp_file = open_bmp(filename)?;
+
+    let mut bmp_header = [0; 26];
+    bmp_file
+        .read_exact(&mut bmp_header)
+        .map_err(|err| match err.kind() {
+            std::io::ErrorKind::UnexpectedEof => LoadError::BadHeader,
+            _ => LoadError::Io(err),
+        })?;
+
+    if &bmp_header[0..2] != b"BM" {
+        return Err(LoadError::BadHeader);
+    }
+
+    let width = i32::from_le_bytes([
+        bmp_header[18],
+        bmp_header[19],
+        bmp_header[20],
+        bmp_header[21],
+    ]);
+    let height = i32::from_le_bytes([
+        bmp_header[22],
+        bmp_header[23],
+        bmp_header[24],
+        bmp_header[25],
+    ]);
+
+    let Ok(width) = usize::try_from(width) else {
+        return Err(LoadError::BadHeader);
+    };
+    Ok((width, height.unsigned_abs() as usize))
+}
+
+/// Loads a BMP image from the filesystem with whichever dimensions it has
+///
+/// # Arguments
+///
+/// * `filename` - The name of the file (extensionless)
+/// * `palette` - The palette whose colors are kept exactly by images in the 5-5-5 layout
+///
+/// # Errors
+///
+/// * The same errors as [`load_bmp_image`], except for [`LoadError::DimensionMismatch`]
+///
+pub fn load_whole_bmp(filename: &str, palette: &Palette) -> Result<Vec<Vec<u16>>, LoadError> {
+    let (width, height) = read_bmp_dimensions(filename)?;
+    load_bmp_image(filename, width, height, palette)
+}
+
+/// Loads a PNG Image from the filesystem as a 16-bit color (5-6-5) image
+///
+/// Every pixel is mapped to the nearest color of the palette (ignoring its transparency), so that
+/// the loaded image can always be converted to codes
+///
+/// # Arguments
+///
+/// * `filename` - The name of the file (extensionless)
+/// * `expected_width` - The expected width of the image
+/// * `expected_height` - The expected height of the image
+/// * `palette` - The palette that the colors of the image are mapped to
+///
+/// # Errors
+///
+/// * [`LoadError::NotFound`] when the file does not exist
+/// * [`LoadError::BadHeader`] when the file is not a valid PNG file
+/// * [`LoadError::DimensionMismatch`] when the image dimensions do not match the expected dimensions
+/// * [`LoadError::Truncated`] when the file ends before all of the pixel data has been read
+/// * [`LoadError::Io`] when the file could not be opened or read for any other reason
+///
+pub fn load_png_image(
+    filename: &str,
+    expected_width: usize,
+    expected_height: usize,
+    palette: &Palette,
+) -> Result<Vec<Vec<u16>>, LoadError> {
+    let png_file = match File::open(format!("{}.png", filename)) {
+        Ok(png_file) => png_file,
+        Err(err) if err.kind() == std::io::ErrorKind::NotFound => return Err(LoadError::NotFound),
+        Err(err) => return Err(LoadError::Io(err)),
+    };
+
+    // expand palettes and low bit depths, and strip 16-bit channels so every channel is a byte
+    let mut decoder = png::Decoder::new(std::io::BufReader::new(png_file));
+    decoder.set_transformations(png::Transformations::EXPAND | png::Transformations::STRIP_16);
+
+    let mut reader = decoder.read_info().map_err(png_read_error)?;
+
+    let (width, height) = (reader.info().width as usize, reader.info().height as usize);
+    if width != expected_width || height != expected_height {
+        return Err(LoadError::DimensionMismatch { width, height });
+    }
+
+    let mut pixel_data = vec![0; reader.output_buffer_size()];
+    let info = reader.next_frame(&mut pixel_data).map_err(png_read_error)?;
+
+    let channels = info.color_type.samples();
+
+    let pixels = pixel_data
+        .chunks_exact(info.line_size)
+        .take(height)
+        .map(|row| {
+            row.chunks_exact(channels)
+                .take(width)
+                .map(|pixel| {
+                    let color = match info.color_type {
+                        png::ColorType::Grayscale | png::ColorType::GrayscaleAlpha => {
+                            rgb888_2_rgb565(pixel[0], pixel[0], pixel[0])
+                        }
+                        _ => rgb888_2_rgb565(pixel[0], pixel[1], pixel[2]),
+                    };
+                    palette.code_2_color(palette.nearest_code(color)).unwrap()
+                })
+                .collect()
+        })
+        .collect();
+
+    Ok(pixels)
+}
+
+/// Maps an error raised while decoding a PNG image
+fn png_read_error(err: png::DecodingError) -> LoadError {
+    match err {
+        png::DecodingError::IoError(err) => pixel_read_error(err),
+        _ => LoadError::BadHeader,
+    }
+}
+
+/// Converts a 24-bit color to a 16-bit color (5-6-5) by dropping the low bits of each channel
+///
+/// # Arguments
+///
+/// * `r` - The 8-bit red channel
+/// * `g` - The 8-bit green channel
+/// * `b` - The 8-bit blue channel
+///
+pub fn rgb888_2_rgb565(r: u8, g: u8, b: u8) -> u16 {
+    ((r as u16 >> 3) << 11) | ((g as u16 >> 2) << 5) | (b as u16 >> 3)
+}
+
+/// Converts a 16-bit color (5-6-5) to a 24-bit color, as its red, green and blue channels
+///
+/// The low bits of each channel are filled by repeating its high bits, so that the full range of
+/// every channel is used (i.e. white stays white)
+///
+/// # Arguments
+///
+/// * `color` - The 16-bit color to convert
+///
+pub fn rgb565_2_rgb888(color: u16) -> [u8; 3] {
+    let r = ((color >> 11) & 0x1F) as u8;
+    let g = ((color >> 5) & 0x3F) as u8;
+    let b = (color & 0x1F) as u8;
+
+    [
+        (r << 3) | (r >> 2),
+        (g << 2) | (g >> 4),
+        (b << 3) | (b >> 2),
+    ]
+}
+
+/// The palette shared with the canvas app, as pairs of 4-bit codes and their 16-bit colors
+pub const PALETTE: [(u8, u16); 16] = [
+    (0, 0xF800u16),
+    (1, 0x07E0u16),
+    (2, 0x001Fu16),
+    (3, 0x07FFu16),
+    (4, 0xF81Fu16),
+    (5, 0xFFE0u16),
+    (6, 0xFFFFu16),
+    (7, 0x520Au16),
+    (8, 0x0000u16),
+    (9, 0xFD20u16),
+    (10, 0xA145u16),
+    (11, 0xFE19u16),
+    (12, 0x0320u16),
+    (13, 0x0010u16),
+    (14, 0xD69Au16),
+    (15, 0x4208u16),
+];
+
+/// Computes a hash of the pixels of an image, to cheaply tell apart images with different contents
+///
+/// # Arguments
+///
+/// * `data` - A 16-bit color bitmap
+///
+pub fn content_hash(data: &[Vec<u16>]) -> u64 {
+    use std::hash::{Hash, Hasher};
+
+    let mut hasher = std::collections::hash_map::DefaultHasher::new();
+    data.hash(&mut hasher);
+    hasher.finish()
+}
+
+/// Builds an image with one horizontal band for each color of a palette, in the order of the codes
+///
+/// # Arguments
+///
+/// * `palette` - The palette to preview
+/// * `width` - Width of the image
+/// * `band_height` - Height of each band
+///
+pub fn palette_preview(palette: &Palette, width: usize, band_height: usize) -> Vec<Vec<u16>> {
+    (0..MAX_PALETTE_LEN as u8)
+        .filter_map(|code| palette.code_2_color(code))
+        .flat_map(|color| std::iter::repeat_n(vec![color; width], band_height))
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::temp_dir;
+
+    /// Path (extensionless) of a fixture under `tests/data/`
+    fn fixture(name: &str) -> String {
+        format!("{}/tests/data/{}", env!("CARGO_MANIFEST_DIR"), name)
+    }
+
+    #[test]
+    fn save_writes_bitfields_header() {
+        let dir = temp_dir("save_writes_bitfields_header");
+        let img = vec![vec![0xF800, 0x07E0, 0x001F], vec![0xFFFF, 0x0000, 0x520A]];
+        save_bmp_image(&img, &format!("{dir}/image")).unwrap();
+
+        let bytes = std::fs::read(format!("{dir}/image.bmp")).unwrap();
+        #[rustfmt::skip]
+        let expected_header: [u8; 66] = [
+            // file header
+            b'B', b'M', 82, 0, 0, 0, 0, 0, 0, 0, 66, 0, 0, 0,
+            // DIB header
+            40, 0, 0, 0, 3, 0, 0, 0, 2, 0, 0, 0, 1, 0, 16, 0, 3, 0, 0, 0, 16, 0, 0, 0,
+            0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0,
+            // channel masks
+            0x00, 0xF8, 0, 0, 0xE0, 0x07, 0, 0, 0x1F, 0x00, 0, 0,
+        ];
+        assert_eq!(bytes.len(), 82);
+        assert_eq!(&bytes[..66], &expected_header);
+
+        assert_eq!(
+            load_bmp_image(&format!("{dir}/image"), 3, 2, &Palette::BUILTIN).unwrap(),
+            img
+        );
+    }
+
+    #[test]
+    fn odd_widths_are_padded_to_whole_words() {
+        let dir = temp_dir("odd_widths_are_padded_to_whole_words");
+        assert_eq!(bmp_row_padding(3, 3), 3);
+
+        for (width, padding) in [(1, 2), (2, 0), (3, 2), (240, 0), (241, 2)] {
+            assert_eq!(bmp_row_padding(width, 2), padding);
+
+            // every pixel differs from its neighbours, so a misaligned row would not match
+            let img: Vec<Vec<u16>> = (0..3)
+                .map(|row| (0..width).map(|col| (row * 1000 + col) as u16).collect())
+                .collect();
+            let filename = format!("{dir}/image_{width}");
+            save_bmp_image(&img, &filename).unwrap();
+            assert_eq!(
+                load_bmp_image(&filename, width, 3, &Palette::BUILTIN).unwrap(),
+                img
+            );
+
+            let bytes = std::fs::read(format!("{filename}.bmp")).unwrap();
+            assert_eq!(
+                bytes.len() as u64,
+                bmp_file_size(width, 3, ColorFormat::Rgb565)
+            );
+            let pixel_data = &bytes[ColorFormat::Rgb565.pixel_data_offset() as usize..];
+            for stored in pixel_data.chunks_exact(width * 2 + padding) {
+                assert!(stored[width * 2..].iter().all(|&byte| byte == 0));
+            }
+        }
+    }
+
+    #[test]
+    fn monochrome_images_survive_a_round_trip() {
+        let dir = temp_dir("monochrome_images_survive_a_round_trip");
+        let [black, white] = MONO_COLORS;
+
+        // rows of 1 bit pixels are padded to whole words, which takes up to 31 pixels
+        for (width, row_size) in [(1, 4), (7, 4), (8, 4), (9, 4), (32, 4), (33, 8), (240, 32)] {
+            assert_eq!(padded_row_size(width, 1), row_size);
+
+            // a diagonal pattern, so that misplaced bits or misaligned rows would not match
+            let img: Vec<Vec<u16>> = (0..5)
+                .map(|row| {
+                    (0..width)
+                        .map(|col| if (row + col) % 3 == 0 { white } else { black })
+                        .collect()
+                })
+                .collect();
+            let filename = format!("{dir}/image_{width}");
+            save_bmp_image_mono(&img, &filename, StoreCompression::None, DEFAULT_ZSTD_LEVEL)
+                .unwrap();
+            assert_eq!(
+                load_bmp_image(&filename, width, 5, &Palette::BUILTIN).unwrap(),
+                img
+            );
+
+            let bytes = std::fs::read(format!("{filename}.bmp")).unwrap();
+            assert_eq!(bytes.len() as u64, mono_bmp_file_size(width, 5));
+            assert_eq!(u16::from_le_bytes([bytes[28], bytes[29]]), 1);
+            assert_eq!(&bytes[54..62], &[0, 0, 0, 0, 0xFF, 0xFF, 0xFF, 0]);
+        }
+
+        // the bits of a byte are its pixels from left to right, with white as 1
+        let filename = format!("{dir}/image_bits");
+        let img = vec![vec![
+            white, black, black, white, white, white, black, white, white,
+        ]];
+        save_bmp_image_mono(&img, &filename, StoreCompression::None, DEFAULT_ZSTD_LEVEL).unwrap();
+        let bytes = std::fs::read(format!("{filename}.bmp")).unwrap();
+        assert_eq!(&bytes[62..], &[0b1001_1101, 0b1000_0000, 0, 0]);
+
+        // compressed monochrome images are loaded like any other
+        let filename = format!("{dir}/image_zstd");
+        save_bmp_image_mono(&img, &filename, StoreCompression::Zstd, DEFAULT_ZSTD_LEVEL).unwrap();
+        assert_eq!(
+            load_bmp_image(&filename, 9, 1, &Palette::BUILTIN).unwrap(),
+            img
+        );
+    }
+
+    /// Writes a bottom-up indexed BMP image, whose pixels are indices into `table`
+    ///
+    /// The number of colors in the header is that of the table, unless `full_table` is set, in
+    /// which case it is 0 and the table is padded to every index that the bits can hold.
+    fn write_indexed_bmp(
+        path: &str,
+        indices: &[Vec<u8>],
+        table: &[u16],
+        bit_count: u16,
+        full_table: bool,
+    ) {
+        let (width, height) = (indices[0].len(), indices.len());
+        let num_colors = match full_table {
+            true => 1 << bit_count,
+            false => table.len(),
+        };
+        let row_size = padded_row_size(width, bit_count as usize);
+        let data_offset = 14 + 40 + 4 * num_colors;
+        let file_size = data_offset + row_size * height;
+
+        let mut bytes = Vec::with_capacity(file_size);
+        bytes.extend_from_slice(b"BM");
+        bytes.extend_from_slice(&(file_size as u32).to_le_bytes());
+        bytes.extend_from_slice(&0u32.to_le_bytes());
+        bytes.extend_from_slice(&(data_offset as u32).to_le_bytes());
+        bytes.extend_from_slice(&40u32.to_le_bytes());
+        bytes.extend_from_slice(&(width as i32).to_le_bytes());
+        bytes.extend_from_slice(&(height as i32).to_le_bytes());
+        bytes.extend_from_slice(&1u16.to_le_bytes());
+        bytes.extend_from_slice(&bit_count.to_le_bytes());
+        bytes.extend_from_slice(&BI_RGB.to_le_bytes());
+        bytes.extend_from_slice(&((row_size * height) as u32).to_le_bytes());
+        bytes.extend_from_slice(&[0; 8]);
+        let colors_used = if full_table { 0 } else { table.len() as u32 };
+        bytes.extend_from_slice(&colors_used.to_le_bytes());
+        bytes.extend_from_slice(&0u32.to_le_bytes());
+        for index in 0..num_colors {
+            let [r, g, b] = rgb565_2_rgb888(table.get(index).copied().unwrap_or(0));
+            bytes.extend_from_slice(&[b, g, r, 0]);
+        }
+        for row in indices.iter().rev() {
+            let mut packed = vec![0u8; row_size];
+            let pixels_per_byte = 8 / bit_count as usize;
+            for (column, &index) in row.iter().enumerate() {
+                let shift = 8 - bit_count as usize * (column % pixels_per_byte + 1);
+                packed[column / pixels_per_byte] |= index << shift;
+            }
+            bytes.extend_from_slice(&packed);
+        }
+        std::fs::write(format!("{path}.bmp"), bytes).unwrap();
+    }
+
+    #[test]
+    fn indexed_images_are_loaded_through_their_color_table() {
+        let dir = temp_dir("indexed_images_are_loaded_through_their_color_table");
+        // the colors of the canvas palette, each of which is an index of the table
+        let table: Vec<u16> = (0..9)
+            .filter_map(|code| Palette::BUILTIN.code_2_color(code))
+            .collect();
+        let indices: Vec<Vec<u8>> = (0..4)
+            .map(|row| {
+                (0..11)
+                    .map(|col| ((row + col) % table.len()) as u8)
+                    .collect()
+            })
+            .collect();
+        let img: Vec<Vec<u16>> = indices
+            .iter()
+            .map(|row| row.iter().map(|&index| table[index as usize]).collect())
+            .collect();
+
+        for (bit_count, full_table) in [(4, false), (4, true), (8, false), (8, true)] {
+            let filename = format!("{dir}/image_{bit_count}_{full_table}");
+            write_indexed_bmp(&filename, &indices, &table, bit_count, full_table);
+            assert_eq!(
+                load_bmp_image(&filename, 11, 4, &Palette::BUILTIN).unwrap(),
+                img
+            );
+
+            // the loaded image is saved like any other, and survives the round trip
+            let saved = format!("{dir}/image_{bit_count}_{full_table}_saved");
+            save_bmp_image(
+                &load_whole_bmp(&filename, &Palette::BUILTIN).unwrap(),
+                &saved,
+            )
+            .unwrap();
+            assert_eq!(
+                load_bmp_image(&saved, 11, 4, &Palette::BUILTIN).unwrap(),
+                img
+            );
+        }
+
+        // indices past the end of the table are black
+        let filename = format!("{dir}/image_past_the_table");
+        write_indexed_bmp(&filename, &[vec![15, 1]], &table, 4, false);
+        assert_eq!(
+            load_bmp_image(&filename, 2, 1, &Palette::BUILTIN).unwrap(),
+            [[0x0000, table[1]]]
+        );
+
+        // a table with more colors than the bits can hold is not that of a valid image
+        let filename = format!("{dir}/image_large_table");
+        write_indexed_bmp(&filename, &[vec![0, 1]], &[0xFFFF; 17], 4, false);
+        assert!(matches!(
+            load_bmp_image(&filename, 2, 1, &Palette::BUILTIN),
+            Err(LoadError::BadHeader)
+        ));
+    }
+
+    #[test]
+    fn colored_images_are_not_saved_as_monochrome() {
+        let dir = temp_dir("colored_images_are_not_saved_as_monochrome");
+        let filename = format!("{dir}/image");
+        let img = vec![vec![0x0000, 0xFFFF], vec![0xFFFF, 0xF800]];
+        assert!(!is_monochrome(&img));
+
+        assert!(matches!(
+            save_bmp_image_mono(&img, &filename, StoreCompression::None, DEFAULT_ZSTD_LEVEL),
+            Err(SaveError::NotMonochrome {
+                row: 1,
+                column: 1,
+                color: 0xF800
+            })
+        ));
+        assert!(!std::path::Path::new(&format!("{filename}.bmp")).exists());
+    }
+
+    #[test]
+    fn save_writes_v3_header_for_555() {
+        let dir = temp_dir("save_writes_v3_header_for_555");
+        let img = vec![vec![0xF800, 0x07E0, 0x001F], vec![0xFFFF, 0x0000, 0x520A]];
+        save_bmp_image_as(&img, &format!("{dir}/image"), ColorFormat::Rgb555).unwrap();
+
+        let bytes = std::fs::read(format!("{dir}/image.bmp")).unwrap();
+        #[rustfmt::skip]
+        let expected_header: [u8; 70] = [
+            // file header
+            b'B', b'M', 86, 0, 0, 0, 0, 0, 0, 0, 70, 0, 0, 0,
+            // DIB header
+            56, 0, 0, 0, 3, 0, 0, 0, 2, 0, 0, 0, 1, 0, 16, 0, 3, 0, 0, 0, 16, 0, 0, 0,
+            0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0,
+            // channel masks (red, green, blue and alpha)
+            0x00, 0x7C, 0, 0, 0xE0, 0x03, 0, 0, 0x1F, 0x00, 0, 0, 0, 0, 0, 0,
+        ];
+        assert_eq!(bytes.len(), 86);
+        assert_eq!(&bytes[..70], &expected_header);
+        // the bottom row is stored first, starting with white
+        assert_eq!(&bytes[70..72], &0x7FFFu16.to_le_bytes());
+
+        assert_eq!(
+            load_bmp_image(&format!("{dir}/image"), 3, 2, &Palette::BUILTIN).unwrap(),
+            img
+        );
+
+        // viewers that honour the masks see the same colors
+        let decoded = ::image::open(format!("{dir}/image.bmp")).unwrap().to_rgb8();
+        assert_eq!(decoded.get_pixel(0, 0).0, [255, 0, 0]);
+        assert_eq!(decoded.get_pixel(1, 0).0, [0, 255, 0]);
+        assert_eq!(decoded.get_pixel(0, 1).0, [255, 255, 255]);
+    }
+
+    #[test]
+    fn color_formats_keep_palette_colors() {
+        for &(_, color) in PALETTE.iter() {
+            for format in [ColorFormat::Rgb565, ColorFormat::Rgb555] {
+                assert_eq!(
+                    format.decode(format.encode(color), &Palette::BUILTIN),
+                    color
+                );
+            }
+        }
+
+        assert_eq!(ColorFormat::Rgb555.encode(0xFFFF), 0x7FFF);
+        assert_eq!(ColorFormat::Rgb555.encode(0x07E0), 0x03E0);
+        // colors outside of the palette lose the lowest bit of green
+        assert_eq!(
+            ColorFormat::Rgb555.decode(0x0020, &Palette::BUILTIN),
+            0x0040
+        );
+        assert_eq!(
+            ColorFormat::Rgb555.decode(0x3DEF, &Palette::BUILTIN),
+            0x7BCF
+        );
+    }
+
+    #[test]
+    fn images_are_loaded_with_the_active_palette() {
+        let dir = temp_dir("images_are_loaded_with_the_active_palette");
+        // both grays are nearest to black in the built-in palette, and the first one loses the
+        // lowest bit of green in the 5-5-5 layout
+        let palette = Palette::from_entries(&[(0, 0x0821), (1, 0x1082), (2, 0xFFFF)]).unwrap();
+        let img = vec![vec![0x0821, 0x1082, 0xFFFF], vec![0x1082, 0x0821, 0x0821]];
+
+        save_png_image(&img, &format!("{dir}/image_1")).unwrap();
+        assert_eq!(
+            load_png_image(&format!("{dir}/image_1"), 3, 2, &palette).unwrap(),
+            img
+        );
+        assert_eq!(
+            load_png_image(&format!("{dir}/image_1"), 3, 2, &Palette::BUILTIN).unwrap(),
+            [[0x0000, 0x0000, 0xFFFF], [0x0000, 0x0000, 0x0000]]
+        );
+
+        save_bmp_image_as(&img, &format!("{dir}/image_2"), ColorFormat::Rgb555).unwrap();
+        assert_eq!(
+            load_bmp_image(&format!("{dir}/image_2"), 3, 2, &palette).unwrap(),
+            img
+        );
+    }
+
+    #[test]
+    fn every_palette_color_survives_a_round_trip() {
+        let dir = temp_dir("every_palette_color_survives_a_round_trip");
+        let img = vec![PALETTE
+            .iter()
+            .map(|&(_, color)| color)
+            .collect::<Vec<u16>>()];
+
+        for format in [ColorFormat::Rgb565, ColorFormat::Rgb555] {
+            save_bmp_image_as(&img, &format!("{dir}/image"), format).unwrap();
+            assert_eq!(
+                load_bmp_image(&format!("{dir}/image"), 16, 1, &Palette::BUILTIN).unwrap(),
+                img
+            );
+        }
+    }
+
+    /// Generates images of up to 20 x 20 pixels whose pixels are colors of the palette
+    fn palette_image() -> impl proptest::strategy::Strategy<Value = Vec<Vec<u16>>> {
+        use proptest::prelude::*;
+
+        let color = proptest::sample::select(PALETTE.map(|(_, color)| color).to_vec());
+        (1..=20usize, 1..=20usize).prop_flat_map(move |(width, height)| {
+            proptest::collection::vec(proptest::collection::vec(color.clone(), width), height)
+        })
+    }
+
+    proptest::proptest! {
+        // every width is covered, so that each amount of row padding is written and skipped
+        #[test]
+        fn palette_images_survive_a_round_trip(img in palette_image()) {
+            let dir = tempfile::tempdir().unwrap();
+            let filename = dir.path().join("image").to_string_lossy().into_owned();
+            let (width, height) = (img[0].len(), img.len());
+
+            for format in [ColorFormat::Rgb565, ColorFormat::Rgb555] {
+                save_bmp_image_as(&img, &filename, format).unwrap();
+                proptest::prop_assert_eq!(
+                    std::fs::metadata(format!("{filename}.bmp")).unwrap().len(),
+                    bmp_file_size(width, height, format)
+                );
+                proptest::prop_assert_eq!(read_bmp_dimensions(&filename).unwrap(), (width, height));
+                proptest::prop_assert_eq!(&load_bmp_image(&filename, width, height, &Palette::BUILTIN).unwrap(), &img);
+            }
+        }
+    }
+
+    #[test]
+    fn dimensions_beyond_the_file_are_refused() {
+        let dir = temp_dir("dimensions_beyond_the_file_are_refused");
+        let filename = format!("{dir}/image");
+        save_bmp_image(&vec![vec![0xF800; 2]; 2], &filename).unwrap();
+
+        // the pixels would take 8 GiB, but there is no need to allocate them to tell
+        let mut bytes = std::fs::read(format!("{filename}.bmp")).unwrap();
+        bytes[18..22].copy_from_slice(&0x10000i32.to_le_bytes());
+        bytes[22..26].copy_from_slice(&0x10000i32.to_le_bytes());
+        std::fs::write(format!("{filename}.bmp"), &bytes).unwrap();
+
+        assert_eq!(read_bmp_dimensions(&filename).unwrap(), (0x10000, 0x10000));
+        assert!(matches!(
+            load_whole_bmp(&filename, &Palette::BUILTIN),
+            Err(LoadError::Truncated)
+        ));
+    }
+
+    proptest::proptest! {
+        // damaged files (such as ones cut short on an SD card) must be refused with an error,
+        // never with a panic, so the bytes of a valid image are overwritten at random
+        #[test]
+        fn damaged_bmp_files_never_panic(
+            img in palette_image(),
+            damage in proptest::collection::vec(
+                (proptest::arbitrary::any::<proptest::sample::Index>(), proptest::arbitrary::any::<u8>()),
+                1..16,
+            ),
+            cut in proptest::option::of(proptest::arbitrary::any::<proptest::sample::Index>()),
+        ) {
+            let dir = tempfile::tempdir().unwrap();
+            let filename = dir.path().join("image").to_string_lossy().into_owned();
+            let (width, height) = (img[0].len(), img.len());
+            save_bmp_image(&img, &filename).unwrap();
+
+            let mut bytes = std::fs::read(format!("{filename}.bmp")).unwrap();
+            for (index, byte) in damage {
+                let index = index.index(bytes.len());
+                bytes[index] = byte;
+            }
+            if let Some(cut) = cut {
+                bytes.truncate(cut.index(bytes.len()));
+            }
+            std::fs::write(format!("{filename}.bmp"), &bytes).unwrap();
+
+            let _ = read_bmp_dimensions(&filename);
+            let _ = load_bmp_image(&filename, width, height, &Palette::BUILTIN);
+            let _ = load_whole_bmp(&filename, &Palette::BUILTIN);
+        }
+
+        #[test]
+        fn arbitrary_files_never_panic(
+            bytes in proptest::collection::vec(proptest::arbitrary::any::<u8>(), 0..256),
+        ) {
+            let dir = tempfile::tempdir().unwrap();
+            let filename = dir.path().join("image").to_string_lossy().into_owned();
+            std::fs::write(format!("{filename}.bmp"), &bytes).unwrap();
+
+            let _ = read_bmp_dimensions(&filename);
+            let _ = load_bmp_image(&filename, 4, 4, &Palette::BUILTIN);
+            let _ = load_whole_bmp(&filename, &Palette::BUILTIN);
+        }
+    }
+
+    #[test]
+    fn save_rejects_ragged_rows() {
+        let dir = temp_dir("save_rejects_ragged_rows");
+        let img = vec![vec![0xF800, 0x07E0, 0x001F], vec![0xFFFF, 0x0000]];
+
+        let err = save_bmp_image(&img, &format!("{dir}/image")).unwrap_err();
+        assert!(matches!(
+            err,
+            SaveError::RaggedRows {
+                row: 1,
+                width: 2,
+                expected_width: 3
+            }
+        ));
+        assert!(!std::path::Path::new(&format!("{dir}/image.bmp")).exists());
+
+        let err = save_bmp_image(&[], &format!("{dir}/image")).unwrap_err();
+        assert!(matches!(err, SaveError::Empty));
+    }
+
+    /// Writer which fails after a number of bytes have been written to it
+    struct FailAfter<'a> {
+        inner: &'a mut dyn Write,
+        remaining: usize,
+    }
+
+    impl Write for FailAfter<'_> {
+        fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+            if self.remaining == 0 {
+                return Err(std::io::Error::other("simulated failure"));
+            }
+            let len = buf.len().min(self.remaining);
+            self.remaining -= len;
+            self.inner.write(&buf[..len])
+        }
+
+        fn flush(&mut self) -> std::io::Result<()> {
+            self.inner.flush()
+        }
+    }
+
+    #[test]
+    fn failed_save_leaves_original_untouched() {
+        let dir = temp_dir("failed_save_leaves_original_untouched");
+        let path = format!("{dir}/image.bmp");
+        std::fs::write(&path, b"original").unwrap();
+
+        let err = save_atomically(&path, |writer| {
+            let mut writer = FailAfter {
+                inner: writer,
+                remaining: 4,
+            };
+            writer.write_all(b"replacement")?;
+            Ok(())
+        })
+        .unwrap_err();
+
+        assert!(matches!(err, SaveError::Io(_)));
+        assert_eq!(std::fs::read(&path).unwrap(), b"original");
+        assert!(!std::path::Path::new(&format!("{path}{TEMP_SUFFIX}")).exists());
+
+        save_atomically(&path, |writer| {
+            writer.write_all(b"replacement")?;
+            Ok(())
+        })
+        .unwrap();
+        assert_eq!(std::fs::read(&path).unwrap(), b"replacement");
+        assert!(!std::path::Path::new(&format!("{path}{TEMP_SUFFIX}")).exists());
+    }
+
+    #[test]
+    fn compressed_images_are_decompressed_on_load() {
+        let dir = temp_dir("compressed_images_are_decompressed_on_load");
+        let img: Vec<Vec<u16>> = (0..3).map(|row| vec![PALETTE[row].1; 5]).collect();
+        save_compressed_bmp_image_as(&img, &format!("{dir}/image"), ColorFormat::Rgb555).unwrap();
+
+        let compressed = std::fs::read(format!("{dir}/image.bmp{GZIP_SUFFIX}")).unwrap();
+        assert_eq!(compressed[..2], GZIP_MAGIC);
+        assert_eq!(
+            read_bmp_dimensions(&format!("{dir}/image")).unwrap(),
+            (5, 3)
+        );
+        assert_eq!(
+            load_bmp_image(&format!("{dir}/image"), 5, 3, &Palette::BUILTIN).unwrap(),
+            img
+        );
+
+        // copies are recognized by their contents, whatever they are named
+        std::fs::write(format!("{dir}/copy.bmp"), &compressed).unwrap();
+        assert_eq!(
+            load_whole_bmp(&format!("{dir}/copy"), &Palette::BUILTIN).unwrap(),
+            img
+        );
+
+        std::fs::write(
+            format!("{dir}/copy.bmp"),
+            &compressed[..compressed.len() / 2],
+        )
+        .unwrap();
+        assert!(matches!(
+            load_whole_bmp(&format!("{dir}/copy"), &Palette::BUILTIN),
+            Err(LoadError::Truncated)
+        ));
+    }
+
+    #[test]
+    fn zstd_images_are_decompressed_on_load() {
+        let dir = temp_dir("zstd_images_are_decompressed_on_load");
+        let img: Vec<Vec<u16>> = (0..3).map(|row| vec![PALETTE[row].1; 50]).collect();
+        save_zstd_bmp_image_as(&img, &format!("{dir}/image"), ColorFormat::Rgb565, 19).unwrap();
+
+        let compressed = std::fs::read(format!("{dir}/image.bmp{ZSTD_SUFFIX}")).unwrap();
+        assert_eq!(compressed[..4], ZSTD_MAGIC);
+        assert!((compressed.len() as u64) < bmp_file_size(50, 3, ColorFormat::Rgb565) / 4);
+        assert_eq!(
+            read_bmp_dimensions(&format!("{dir}/image")).unwrap(),
+            (50, 3)
+        );
+        assert_eq!(
+            load_bmp_image(&format!("{dir}/image"), 50, 3, &Palette::BUILTIN).unwrap(),
+            img
+        );
+
+        // the decompressed file is exactly the file that would have been stored uncompressed
+        save_bmp_image(&img, &format!("{dir}/plain")).unwrap();
+        assert_eq!(
+            zstd::stream::decode_all(&compressed[..]).unwrap(),
+            std::fs::read(format!("{dir}/plain.bmp")).unwrap()
+        );
+
+        // copies are recognized by their contents, whatever they are named
+        std::fs::write(format!("{dir}/copy.bmp"), &compressed).unwrap();
+        assert_eq!(
+            load_whole_bmp(&format!("{dir}/copy"), &Palette::BUILTIN).unwrap(),
+            img
+        );
+
+        std::fs::write(
+            format!("{dir}/copy.bmp"),
+            &compressed[..compressed.len() / 2],
+        )
+        .unwrap();
+        assert!(matches!(
+            load_whole_bmp(&format!("{dir}/copy"), &Palette::BUILTIN),
+            Err(LoadError::Truncated)
+        ));
+
+        let mut garbage = ZSTD_MAGIC.to_vec();
+        garbage.extend_from_slice(&[0xFF; 16]);
+        std::fs::write(format!("{dir}/copy.bmp"), &garbage).unwrap();
+        assert!(matches!(
+            load_whole_bmp(&format!("{dir}/copy"), &Palette::BUILTIN),
+            Err(LoadError::BadHeader)
+        ));
+    }
+
+    #[test]
+    fn the_newest_form_of_an_image_is_loaded() {
+        let dir = temp_dir("the_newest_form_of_an_image_is_loaded");
+        let filename = format!("{dir}/image");
+        let set_modified = |suffix: &str, secs: u64| {
+            File::options()
+                .write(true)
+                .open(format!("{filename}.bmp{suffix}"))
+                .unwrap()
+                .set_modified(std::time::UNIX_EPOCH + std::time::Duration::from_secs(secs))
+                .unwrap();
+        };
+        assert_eq!(stored_bmp_path(&filename), None);
+
+        save_bmp_image(&[vec![PALETTE[1].1; 2]], &filename).unwrap();
+        save_zstd_bmp_image_as(&[vec![PALETTE[2].1; 2]], &filename, ColorFormat::Rgb565, 1)
+            .unwrap();
+        set_modified("", 2_000_000_000);
+        set_modified(ZSTD_SUFFIX, 1_000_000_000);
+        assert_eq!(
+            stored_bmp_path(&filename).unwrap(),
+            format!("{filename}.bmp")
+        );
+        assert_eq!(
+            load_bmp_image(&filename, 2, 1, &Palette::BUILTIN).unwrap(),
+            [[PALETTE[1].1; 2]]
+        );
+
+        set_modified(ZSTD_SUFFIX, 3_000_000_000);
+        assert_eq!(
+            stored_bmp_path(&filename).unwrap(),
+            format!("{filename}.bmp{ZSTD_SUFFIX}")
+        );
+        assert_eq!(
+            load_bmp_image(&filename, 2, 1, &Palette::BUILTIN).unwrap(),
+            [[PALETTE[2].1; 2]]
+        );
+
+        // equally new forms are picked in a fixed order, starting with the uncompressed file
+        set_modified("", 3_000_000_000);
+        assert_eq!(
+            stored_bmp_path(&filename).unwrap(),
+            format!("{filename}.bmp")
+        );
+    }
+
+    #[test]
+    fn save_png_matches_source() {
+        let dir = temp_dir("save_png_matches_source");
+        let img: Vec<Vec<u16>> = (0..4)
+            .map(|row| {
+                (0..5)
+                    .map(|col| PALETTE[(row + col) % PALETTE.len()].1)
+                    .collect()
+            })
+            .collect();
+        save_png_image(&img, &format!("{dir}/image")).unwrap();
+
+        let decoder = png::Decoder::new(File::open(format!("{dir}/image.png")).unwrap());
+        let mut reader = decoder.read_info().unwrap();
+        let mut pixels = vec![0; reader.output_buffer_size()];
+        let info = reader.next_frame(&mut pixels).unwrap();
+
+        assert_eq!((info.width, info.height), (5, 4));
+        assert_eq!(info.color_type, png::ColorType::Rgb);
+        for (row, png_row) in img.iter().zip(pixels.chunks_exact(info.line_size)) {
+            for (&color, rgb) in row.iter().zip(png_row.chunks_exact(3)) {
+                assert_eq!(rgb, rgb565_2_rgb888(color));
+                assert_eq!(rgb888_2_rgb565(rgb[0], rgb[1], rgb[2]), color);
+            }
+        }
+    }
+
+    #[test]
+    fn load_png_maps_to_nearest_color() {
+        let dir = temp_dir("load_png_maps_to_nearest_color");
+
+        // slightly off-palette red, green and white, and a very dark gray
+        let img = vec![vec![0xE800, 0x07C0], vec![0xF7DE, 0x1082]];
+        save_png_image(&img, &format!("{dir}/image")).unwrap();
+
+        assert_eq!(
+            load_png_image(&format!("{dir}/image"), 2, 2, &Palette::BUILTIN).unwrap(),
+            vec![vec![0xF800, 0x07E0], vec![0xFFFF, 0x0000]]
+        );
+        assert!(matches!(
+            load_png_image(&format!("{dir}/image"), 3, 2, &Palette::BUILTIN).unwrap_err(),
+            LoadError::DimensionMismatch {
+                width: 2,
+                height: 2
+            }
+        ));
+    }
+
+    #[test]
+    fn load_legacy_image() {
+        let img = load_bmp_image(&fixture("valid"), 3, 2, &Palette::BUILTIN).unwrap();
+        assert_eq!(
+            img,
+            vec![vec![0xF800, 0x07E0, 0x001F], vec![0xFFFF, 0x0000, 0x520A]]
+        );
+    }
+
+    #[test]
+    fn load_missing_image() {
+        let err = load_bmp_image(&fixture("missing"), 3, 2, &Palette::BUILTIN).unwrap_err();
+        assert!(matches!(err, LoadError::NotFound));
+    }
+
+    #[test]
+    fn load_bad_header() {
+        let err = load_bmp_image(&fixture("bad_header"), 3, 2, &Palette::BUILTIN).unwrap_err();
+        assert!(matches!(err, LoadError::BadHeader));
+
+        let err = load_bmp_image(&fixture("short_header"), 3, 2, &Palette::BUILTIN).unwrap_err();
+        assert!(matches!(err, LoadError::BadHeader));
+    }
+
+    #[test]
+    fn load_truncated_image() {
+        let err = load_bmp_image(&fixture("truncated"), 3, 2, &Palette::BUILTIN).unwrap_err();
+        assert!(matches!(err, LoadError::Truncated));
+    }
+
+    #[test]
+    fn load_unsupported_format() {
+        let err = load_bmp_image(&fixture("rle8"), 3, 2, &Palette::BUILTIN).unwrap_err();
+        assert!(matches!(
+            err,
+            LoadError::Unsupported {
+                bit_count: 8,
+                compression: 1
+            }
+        ));
+    }
+
+    #[test]
+    fn load_24_bit_image() {
+        let img = load_bmp_image(&fixture("rgb24"), 3, 2, &Palette::BUILTIN).unwrap();
+        assert_eq!(
+            img,
+            vec![vec![0xF800, 0x07E0, 0x001F], vec![0xFFFF, 0x0000, 0x520A]]
+        );
+
+        let img = load_bmp_image(&fixture("rgb24_wide"), 5, 1, &Palette::BUILTIN).unwrap();
+        assert_eq!(img, vec![vec![0x0000, 0x0821, 0x8410, 0xFFFF, 0xFFFF]]);
+    }
+
+    #[test]
+    fn load_top_down_image() {
+        let img = load_bmp_image(&fixture("top_down"), 3, 2, &Palette::BUILTIN).unwrap();
+        assert_eq!(
+            img,
+            load_bmp_image(&fixture("valid"), 3, 2, &Palette::BUILTIN).unwrap()
+        );
+    }
+
+    #[test]
+    fn load_data_after_color_table() {
+        let img = load_bmp_image(&fixture("color_table"), 3, 2, &Palette::BUILTIN).unwrap();
+        assert_eq!(
+            img,
+            load_bmp_image(&fixture("valid"), 3, 2, &Palette::BUILTIN).unwrap()
+        );
+    }
+
+    #[test]
+    fn load_data_with_gap_and_trailing_slack() {
+        let img = load_bmp_image(&fixture("slack"), 3, 2, &Palette::BUILTIN).unwrap();
+        assert_eq!(
+            img,
+            load_bmp_image(&fixture("valid"), 3, 2, &Palette::BUILTIN).unwrap()
+        );
+    }
+
+    #[test]
+    fn read_dimensions() {
+        assert_eq!(read_bmp_dimensions(&fixture("valid")).unwrap(), (3, 2));
+        assert_eq!(read_bmp_dimensions(&fixture("top_down")).unwrap(), (3, 2));
+        assert!(matches!(
+            read_bmp_dimensions(&fixture("short_header")).unwrap_err(),
+            LoadError::BadHeader
+        ));
+    }
+
+    #[test]
+    fn raw_export_is_top_down() {
+        let img = load_whole_bmp(&fixture("valid"), &Palette::BUILTIN).unwrap();
+        let bytes = rgb565_bytes(&img);
+
+        assert_eq!(bytes.len(), 3 * 2 * 2);
+        assert_eq!(bytes[0..2], [0x00, 0xF8]);
+        assert_eq!(bytes[4..6], [0x1F, 0x00]);
+        assert_eq!(bytes[6..8], [0xFF, 0xFF]);
+        assert_eq!(bytes[10..12], [0x0A, 0x52]);
+    }
+
+    #[test]
+    fn blank_images_have_a_single_color() {
+        let img = blank_image(3, 2, 0xFFFF);
+        assert_eq!(img, vec![vec![0xFFFF; 3]; 2]);
+        assert_eq!(blank_image(1, 1, 0x0000), [[0x0000]]);
+        assert!(blank_image(0, 4, 0x0000).iter().all(|row| row.is_empty()));
+    }
+
+    #[test]
+    fn checkerboards_keep_their_pattern_when_scaled() {
+        // a 4 x 4 checkerboard of 2 x 2 squares
+        let board = |square: usize, size: usize| -> Vec<Vec<u16>> {
+            (0..size)
+                .map(|y| {
+                    (0..size)
+                        .map(|x| match (x / square + y / square) % 2 {
+                            0 => 0xFFFF,
+                            _ => 0x0000,
+                        })
+                        .collect()
+                })
+                .collect()
+        };
+        let img = board(2, 4);
+
+        assert_eq!(scale_nearest(&img, 4, 4), img);
+        assert_eq!(scale_nearest(&img, 8, 8), board(4, 8));
+        assert_eq!(scale_nearest(&img, 2, 2), board(1, 2));
+        assert_eq!(scale_nearest(&board(4, 8), 4, 4), img);
+
+        // the edges are scaled independently, and only the colors of the image are used
+        let stretched = scale_nearest(&img, 8, 2);
+        assert_eq!(
+            stretched,
+            vec![
+                vec![0xFFFF, 0xFFFF, 0xFFFF, 0xFFFF, 0x0000, 0x0000, 0x0000, 0x0000],
+                vec![0x0000, 0x0000, 0x0000, 0x0000, 0xFFFF, 0xFFFF, 0xFFFF, 0xFFFF],
+            ]
+        );
+        assert!(scale_nearest(&img, 7, 5)
+            .iter()
+            .flatten()
+            .all(|&pixel| pixel == 0xFFFF || pixel == 0x0000));
+    }
+
+    #[test]
+    fn overlays_are_drawn_except_where_transparent() {
+        let base = vec![vec![1, 2, 3], vec![4, 5, 6]];
+        let overlay = vec![vec![0xFFFF, 9, 0xFFFF], vec![7, 0xFFFF, 0xFFFF]];
+
+        assert_eq!(
+            overlay_image(&base, &overlay, 0xFFFF),
+            [[1, 9, 3], [7, 5, 6]]
+        );
+        // an overlay without its transparent color hides the base entirely
+        assert_eq!(overlay_image(&base, &overlay, 0x0000), overlay);
+        assert_eq!(overlay_image(&base, &base, 5), base);
+    }
+
+    #[test]
+    fn flips_mirror_asymmetric_images() {
+        // no two pixels are the same, so every pixel has to end up in the right place
+        let img = vec![vec![1, 2, 3], vec![4, 5, 6]];
+
+        assert_eq!(flip_horizontal(&img), [[3, 2, 1], [6, 5, 4]]);
+        assert_eq!(flip_vertical(&img), [[4, 5, 6], [1, 2, 3]]);
+        assert_eq!(Mirror::Horizontal.apply(&img), flip_horizontal(&img));
+        assert_eq!(Mirror::Vertical.apply(&img), flip_vertical(&img));
+        assert_eq!(Mirror::Both.apply(&img), Transform::Rot180.apply(&img));
+
+        // flips do not commute with rotations
+        let flipped_then_rotated = Transform::Rot90.apply(&flip_horizontal(&img));
+        let rotated_then_flipped = flip_horizontal(&Transform::Rot90.apply(&img));
+        assert_eq!(flipped_then_rotated, [[6, 3], [5, 2], [4, 1]]);
+        assert_eq!(rotated_then_flipped, [[1, 4], [2, 5], [3, 6]]);
+    }
+
+    #[test]
+    fn transforms_move_the_corners() {
+        // the corners are 1 (top left), 3 (top right), 4 (bottom left) and 6 (bottom right)
+        let img = vec![vec![1, 2, 3], vec![4, 5, 6]];
+        let corners = |img: &[Vec<u16>]| {
+            let (last_row, last_col) = (img.len() - 1, img[0].len() - 1);
+            [
+                img[0][0],
+                img[0][last_col],
+                img[last_row][0],
+                img[last_row][last_col],
+            ]
+        };
+
+        let cases = [
+            (Transform::Rot90, (3, 2), [4, 1, 6, 3]),
+            (Transform::Rot180, (2, 3), [6, 4, 3, 1]),
+            (Transform::Rot270, (3, 2), [3, 6, 1, 4]),
+            (Transform::FlipH, (2, 3), [3, 1, 6, 4]),
+            (Transform::FlipV, (2, 3), [4, 6, 1, 3]),
+        ];
+        for (transform, (height, width), expected) in cases {
+            let transformed = transform.apply(&img);
+            assert_eq!(
+                (transformed.len(), transformed[0].len()),
+                (height, width),
+                "{:?}",
+                transform
+            );
+            assert_eq!(corners(&transformed), expected, "{:?}", transform);
+        }
+
+        // rotations undo each other, and reflections undo themselves
+        assert_eq!(Transform::Rot270.apply(&Transform::Rot90.apply(&img)), img);
+        assert_eq!(Transform::FlipV.apply(&Transform::FlipV.apply(&img)), img);
+    }
+
+    #[test]
+    fn load_dimension_mismatch() {
+        let err = load_bmp_image(&fixture("valid"), 2, 3, &Palette::BUILTIN).unwrap_err();
+        assert!(matches!(
+            err,
+            LoadError::DimensionMismatch {
+                width: 3,
+                height: 2
+            }
+        ));
+    }
+
+    #[test]
+    fn palette_preview_has_a_band_per_code() {
+        let img = palette_preview(&Palette::BUILTIN, 4, 2);
+        assert_eq!(img.len(), 2 * PALETTE.len());
+        for (band, &(_, color)) in img.chunks(2).zip(PALETTE.iter()) {
+            assert!(band.iter().flatten().all(|&pixel| pixel == color));
+            assert!(band.iter().all(|row| row.len() == 4));
+        }
+    }
+
+    #[test]
+    fn palette_conversions_are_consistent() {
+        // every code of the nibble has a color, and no two codes share one
+        for code in 0..=0xFu8 {
+            let color = Palette::BUILTIN.code_2_color(code).unwrap();
+            assert_eq!(Palette::BUILTIN.color_2_code(color), Some(code));
+        }
+        for &(code, color) in PALETTE.iter() {
+            assert_eq!(Palette::BUILTIN.code_2_color(code), Some(color));
+            assert_eq!(Palette::BUILTIN.color_2_code(color), Some(code));
+        }
+        for code in 0x10..=0xFFu8 {
+            assert_eq!(Palette::BUILTIN.code_2_color(code), None);
+        }
+        assert_eq!(Palette::BUILTIN.color_2_code(0x1234), None);
+    }
+
+    #[test]
+    fn off_palette_colors_use_nearest_codes() {
+        for &(code, color) in PALETTE.iter() {
+            assert_eq!(Palette::BUILTIN.nearest_code(color), code);
+        }
+
+        // a slightly darker red, a dim green, a greenish blue, a dark grey and an off-white
+        assert_eq!(Palette::BUILTIN.nearest_code(0xE800), 0);
+        assert_eq!(Palette::BUILTIN.nearest_code(0x0600), 1);
+        assert_eq!(Palette::BUILTIN.nearest_code(0x011F), 2);
+        assert_eq!(Palette::BUILTIN.nearest_code(0x2104), 8);
+        assert_eq!(Palette::BUILTIN.nearest_code(0xEF7D), 6);
+    }
+
+    #[test]
+    fn imported_images_use_nearest_colors() {
+        let img = import_image(&fixture("gradient.png"), 8, 2, &Palette::BUILTIN).unwrap();
+        let codes = img
+            .iter()
+            .map(|row| {
+                row.iter()
+                    .map(|&color| Palette::BUILTIN.color_2_code(color).unwrap())
+                    .collect()
+            })
+            .collect::<Vec<Vec<u8>>>();
+        // the top row fades from black to white through the greys, the bottom row from red to blue
+        // through brown, grey and navy
+        assert_eq!(
+            codes,
+            vec![
+                vec![8, 15, 7, 7, 14, 14, 14, 6],
+                vec![0, 0, 10, 10, 7, 13, 2, 2]
+            ]
+        );
     }
 }
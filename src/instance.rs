@@ -0,0 +1,134 @@
+//! Lock on the image directory, so that a second server started on the same directory (such as one
+//! started by hand while another runs as a service) refuses to start instead of writing over the
+//! images of the first one
+//!
+//! The lock is an advisory lock (`flock`) on a file in the image directory, which the operating
+//! system releases when the server exits, however it exits. The file also holds the process ID of
+//! the server, so that the server which holds the lock can be named. The subcommands do not take
+//! the lock, so that they can be used while the server runs. Instead, every subcommand that writes
+//! a slot (such as `import`, `restore` and `revert`) holds the lock of that slot while writing it,
+//! and every image is replaced by renaming a complete file over it, so no reader ever sees a
+//! partially written image.
+
+use std::fs::File;
+
+/// Name of the file in the image directory that is locked by the server using it
+pub const INSTANCE_LOCK_NAME: &str = ".canvas-server.lock";
+
+/// Lock on an image directory, which is released when dropped (or when the server exits)
+#[derive(Debug)]
+pub struct InstanceLock {
+    _file: File,
+}
+
+/// Reasons for which an image directory could not be locked
+#[derive(Debug)]
+pub enum InstanceLockError {
+    /// Another server is using the image directory, with the given process ID (if it is known)
+    Held { pid: Option<u32> },
+    /// The lock file could not be opened, read or written
+    Io(std::io::Error),
+}
+
+impl std::fmt::Display for InstanceLockError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Held { pid: Some(pid) } => {
+                write!(f, "another server (process {}) is using it", pid)
+            }
+            Self::Held { pid: None } => write!(f, "another server is using it"),
+            Self::Io(err) => write!(f, "failed to lock {}: {}", INSTANCE_LOCK_NAME, err),
+        }
+    }
+}
+
+impl std::error::Error for InstanceLockError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Self::Io(err) => Some(err),
+            Self::Held { .. } => None,
+        }
+    }
+}
+
+/// Locks an image directory for the server, and records the process ID of the server in the lock
+/// file
+///
+/// # Arguments
+///
+/// * `dir` - Directory where images are stored, which must exist
+///
+/// # Errors
+///
+/// * [`InstanceLockError::Held`] when another server holds the lock
+/// * [`InstanceLockError::Io`] when the lock file can not be opened or written
+///
+#[cfg(unix)]
+pub fn lock_instance(dir: &str) -> Result<InstanceLock, InstanceLockError> {
+    use std::io::{Read, Seek, Write};
+    use std::os::unix::io::AsRawFd;
+
+    let mut file = std::fs::OpenOptions::new()
+        .read(true)
+        .write(true)
+        .create(true)
+        .truncate(false)
+        .open(format!("{dir}/{INSTANCE_LOCK_NAME}"))
+        .map_err(InstanceLockError::Io)?;
+
+    // SAFETY: the descriptor belongs to the file, which stays open while it is locked
+    if unsafe { libc::flock(file.as_raw_fd(), libc::LOCK_EX | libc::LOCK_NB) } != 0 {
+        let err = std::io::Error::last_os_error();
+        if err.kind() != std::io::ErrorKind::WouldBlock {
+            return Err(InstanceLockError::Io(err));
+        }
+        let mut contents = String::new();
+        let pid = file
+            .read_to_string(&mut contents)
+            .ok()
+            .and_then(|_| contents.trim().parse().ok());
+        return Err(InstanceLockError::Held { pid });
+    }
+
+    file.set_len(0)
+        .and_then(|()| file.rewind())
+        .and_then(|()| writeln!(file, "{}", std::process::id()))
+        .map_err(InstanceLockError::Io)?;
+    Ok(InstanceLock { _file: file })
+}
+
+/// Locks an image directory for the server, which is not supported on this platform (so the lock
+/// is always taken)
+///
+/// # Errors
+///
+/// * [`InstanceLockError::Io`] when the lock file can not be created
+///
+#[cfg(not(unix))]
+pub fn lock_instance(dir: &str) -> Result<InstanceLock, InstanceLockError> {
+    let file =
+        File::create(format!("{dir}/{INSTANCE_LOCK_NAME}")).map_err(InstanceLockError::Io)?;
+    Ok(InstanceLock { _file: file })
+}
+
+#[cfg(all(test, unix))]
+mod tests {
+    use super::*;
+    use crate::testing::temp_dir;
+
+    #[test]
+    fn second_servers_are_refused() {
+        let dir = temp_dir("second_servers_are_refused");
+
+        let lock = lock_instance(&dir).unwrap();
+        match lock_instance(&dir) {
+            Err(InstanceLockError::Held { pid }) => assert_eq!(pid, Some(std::process::id())),
+            result => panic!("locked twice: {:?}", result),
+        }
+
+        // the lock is released with the server, even if its file is left behind
+        drop(lock);
+        let _lock = lock_instance(&dir).unwrap();
+        assert!(std::path::Path::new(&format!("{dir}/{INSTANCE_LOCK_NAME}")).exists());
+    }
+}
@@ -1,5 +1,3 @@
-#![feature(iter_array_chunks)]
-
 #![doc(html_favicon_url = "https://i0.wp.com/dumblebots.com/wp-content/uploads/2023/12/dumblebots-logo-round.png")]
 #![doc(html_logo_url = "https://i0.wp.com/dumblebots.com/wp-content/uploads/2023/12/dumblebots-logo-round.png")]
 
@@ -15,16 +13,55 @@
 //! # Arduino WiFI TFT LCD Canvas Server
 //! Server for the [Arduino WiFi TFT LCD Canvas App](https://github.com/Aditya-A-garwal/Arduino-WiFi-TFT-LCD-Canvas-App).
 
+mod archive;
+mod cache;
+mod checksums;
+mod close;
+mod commands;
+mod daemon;
+mod deadline;
+mod disk;
+mod error;
 mod image;
+mod instance;
+mod metadata;
+mod palette;
+mod protocol;
+mod slots;
+mod store;
+#[cfg(test)]
+mod testing;
+mod thumbnails;
+mod tls;
+mod transfers;
+mod trash;
+mod usage;
+mod watch;
 
 use std::io::{Read, Write};
-use std::net::{TcpListener, TcpStream};
+use std::net::{SocketAddr, TcpListener, TcpStream};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
 use std::thread::{self};
 
 use clap::Parser;
 use pbr::ProgressBar;
+use rayon::prelude::*;
+use rustls::{ServerConfig, ServerConnection, StreamOwned};
 
+use checksums::{pixels_digest, remember_pixels_digest, stored_pixels_digest};
+use commands::Command;
+use deadline::DeadlineStream;
+use disk::{FreeSpaceProbe, MIB};
+use error::*;
 use image::*;
+use metadata::*;
+use palette::{Palette, PalettePreset, MAX_PALETTE_LEN};
+use protocol::*;
+use slots::*;
+use store::{Backend, FileStore, SqliteStore, Store};
+use thumbnails::*;
+use transfers::{append_transfer, Transfer};
 
 /// Width of the progress bar in characters
 const PROGRESS_BAR_WIDTH: usize = 96;
@@ -32,32 +69,534 @@ const PROGRESS_BAR_WIDTH: usize = 96;
 const SOCKET_TIMEOUT: Option<std::time::Duration> = Some(std::time::Duration::from_secs(8));
 /// Whether to display the progress bar or not
 const SHOW_PROGRESS_BAR: bool = true;
+/// Width of the palette preview image in pixels
+const PREVIEW_WIDTH: usize = 64;
+/// Height of each band of the palette preview image in pixels
+const PREVIEW_BAND_HEIGHT: usize = 16;
+
+/// Set once the server has been asked to shut down, after which no new connections are accepted
+static SHUTDOWN: AtomicBool = AtomicBool::new(false);
+
+/// How much is printed about the requests that are served
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+enum Verbosity {
+    /// Only errors
+    Quiet,
+    /// A summary of every request, and the progress of every transfer
+    Normal,
+    /// Also every row that is transferred
+    Debug,
+}
+
+/// Color that empty slots (and images of other dimensions) are loaded as
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum BlankColor {
+    /// The color of a code of the palette
+    Code(u8),
+    /// A 16-bit (5-6-5) color
+    Color(u16),
+}
 
 #[derive(Parser, Debug)]
 #[command(version, about, long_about = None)]
 struct Args {
+    #[command(subcommand)]
+    command: Option<Command>,
+
     /// Port on which to list for incoming requests
-    #[arg(short, long, default_value_t = 5005)]
+    #[arg(short, long, default_value_t = 5005, env = "CANVAS_PORT")]
     port: u16,
 
+    /// Print more about the requests that are served (`-v` also prints every row that is
+    /// transferred)
+    #[arg(short, long, action = clap::ArgAction::Count, env = "CANVAS_VERBOSE")]
+    verbose: u8,
+
+    /// Only print errors while serving requests
+    #[arg(short, long, conflicts_with = "verbose", env = "CANVAS_QUIET")]
+    quiet: bool,
+
     /// Path to directory where images are stored
-    #[arg(short, long, default_value_t = String::from("images-dir"))]
+    #[arg(short, long, global = true, default_value_t = String::from("images-dir"), env = "CANVAS_IMAGE_DIR")]
     image_dir: String,
+
+    /// Path to a PEM encoded certificate chain, enables TLS (the client must also speak TLS)
+    #[arg(long, requires = "tls_key", env = "CANVAS_TLS_CERT")]
+    tls_cert: Option<String>,
+
+    /// Path to the PEM encoded private key of the TLS certificate
+    #[arg(long, requires = "tls_cert", env = "CANVAS_TLS_KEY")]
+    tls_key: Option<String>,
+
+    /// Also save every received image as a PNG file, alongside the BMP file
+    #[arg(long, env = "CANVAS_SAVE_PNG")]
+    save_png: bool,
+
+    /// Expect a device ID byte after every request header, and store the images of each device in
+    /// its own subdirectory of the image directory
+    #[arg(long, env = "CANVAS_MULTI_DEVICE")]
+    multi_device: bool,
+
+    /// Token that clients must present for administrative requests (which are refused without it)
+    #[arg(long, env = "CANVAS_AUTH_TOKEN")]
+    auth_token: Option<String>,
+
+    /// Remove images from the trash at startup once they have been in it for this many days (they
+    /// are kept until they are restored by default)
+    #[arg(long, env = "CANVAS_TRASH_KEEP_DAYS")]
+    trash_keep_days: Option<u64>,
+
+    /// Maximum number of versions to keep in the history of each slot (all are kept by default)
+    #[arg(long, env = "CANVAS_HISTORY_KEEP")]
+    history_keep: Option<usize>,
+
+    /// Store images that are identical to the image in another slot as hard links to that image
+    #[arg(long, env = "CANVAS_DEDUPE")]
+    dedupe: bool,
+
+    /// Longest time that a save waits for its slot while another save (or a subcommand) is writing
+    /// it, in milliseconds, before the client is told that the slot is busy
+    #[arg(long, default_value_t = 500, env = "CANVAS_LOCK_WAIT_MS")]
+    lock_wait_ms: u64,
+
+    /// Refuse to move an image into a slot that already has one (instead of replacing it)
+    #[arg(long, env = "CANVAS_NO_OVERWRITE")]
+    no_overwrite: bool,
+
+    /// Layout of the 16-bit colors in saved BMP images (images in either layout can be loaded)
+    #[arg(long, value_enum, default_value_t = ColorFormat::Rgb565, env = "CANVAS_COLOR_DEPTH")]
+    color_depth: ColorFormat,
+
+    /// Save images that only have black and white pixels as monochrome (1-bit) BMP images, which
+    /// take a sixteenth of the space (other images are saved with `--color-depth`)
+    #[arg(long, env = "CANVAS_MONO")]
+    mono: bool,
+
+    /// Largest number of rows of an image that is accepted, so that a corrupted (or malicious)
+    /// header can not make the server allocate more memory than it has
+    #[arg(long, default_value_t = 1024, value_parser = clap::value_parser!(u16).range(1..), env = "CANVAS_MAX_HEIGHT")]
+    max_height: u16,
+
+    /// Largest number of columns of an image that is accepted
+    #[arg(long, default_value_t = 1024, value_parser = clap::value_parser!(u16).range(1..), env = "CANVAS_MAX_WIDTH")]
+    max_width: u16,
+
+    /// Number of bits of the code in each segment of a compressed row, which clients learn from
+    /// the capabilities of the server (older clients only send 4-bit codes)
+    #[arg(long, default_value_t = SegmentFormat::DEFAULT.code_bits, value_parser = clap::value_parser!(u32).range(1..=8), env = "CANVAS_SEGMENT_CODE_BITS")]
+    segment_code_bits: u32,
+
+    /// Number of bits of the count in each segment of a compressed row, which must fit in the
+    /// 16 bits of the segment with the code (older clients only send 9-bit counts)
+    #[arg(long, default_value_t = SegmentFormat::DEFAULT.count_bits, value_parser = clap::value_parser!(u32).range(1..=15), env = "CANVAS_SEGMENT_COUNT_BITS")]
+    segment_count_bits: u32,
+
+    /// Refuse saves that would leave less than this many mebibytes free on the disk of the image
+    /// directory
+    #[arg(long, default_value_t = 1, env = "CANVAS_MIN_FREE_MB")]
+    min_free_mb: u64,
+
+    /// Gets the free space of the disk of the image directory (replaced in tests)
+    #[arg(skip = disk::available_bytes as FreeSpaceProbe)]
+    free_space: FreeSpaceProbe,
+
+    /// Store received images compressed with gzip (as `image_{slot}.bmp.gz`), which both compressed
+    /// and uncompressed images can be loaded from (the same as `--store-compression gzip`)
+    #[arg(
+        long,
+        conflicts_with = "store_compression",
+        env = "CANVAS_COMPRESS_STORAGE"
+    )]
+    compress_storage: bool,
+
+    /// Compression of the files that received images are stored in, which images stored in any
+    /// form can be loaded from
+    #[arg(long, value_enum, default_value_t = StoreCompression::None, env = "CANVAS_STORE_COMPRESSION")]
+    store_compression: StoreCompression,
+
+    /// Level that images are compressed at with `--store-compression zstd`, from 1 (fastest) to 22
+    /// (smallest)
+    #[arg(long, default_value_t = DEFAULT_ZSTD_LEVEL, value_parser = clap::value_parser!(i32).range(1..=22), env = "CANVAS_STORE_COMPRESSION_LEVEL")]
+    store_compression_level: i32,
+
+    /// Color that empty slots (and images of other dimensions than requested) are loaded as, either
+    /// a code of the palette (such as 3) or a 16-bit color in hex (such as 0xFFFF), black by default
+    #[arg(long, value_parser = parse_blank_color, env = "CANVAS_BLANK_COLOR")]
+    blank_color: Option<BlankColor>,
+
+    /// Scale stored images to the dimensions that the client asks for when they differ (instead of
+    /// sending a blank image), such as after the firmware moved to a larger display
+    #[arg(long, env = "CANVAS_SCALE_ON_MISMATCH")]
+    scale_on_mismatch: bool,
+
+    /// Convert the codes of received images to colors on every core once all of their rows have
+    /// been received, instead of row by row as they arrive (which can help with large images on
+    /// hosts with many cores)
+    #[arg(long, env = "CANVAS_PARALLEL")]
+    parallel: bool,
+
+    /// Save the rows of an image that were received when others could not be, filling the rows
+    /// that were lost with the background (the color of empty slots) instead of refusing the whole
+    /// image, for clients on unreliable links. Saves that receive no row at all are still refused
+    #[arg(long, env = "CANVAS_LENIENT_SAVE")]
+    lenient_save: bool,
+
+    /// Append a row to this CSV file for every completed save and load, with when it was
+    /// completed, the client, the opcode, the slot, the dimensions, the bytes of the rows and how
+    /// long it took
+    #[arg(long, env = "CANVAS_TRANSFER_LOG")]
+    transfer_log: Option<String>,
+
+    /// Number of recently loaded (or saved) images to keep in memory, so that they can be loaded
+    /// again without reading them from the disk (0 disables the cache)
+    #[arg(long, default_value_t = 8, env = "CANVAS_CACHE_SLOTS")]
+    cache_slots: usize,
+
+    /// Load every image into memory at startup, and keep saved images in memory too, so that loads
+    /// never wait for the disk (even when an image file goes missing)
+    #[arg(long, env = "CANVAS_PRELOAD")]
+    preload: bool,
+
+    /// Rotate or flip every image as it is loaded, for displays that are mounted the other way
+    /// around (the dimensions of a load are those of the transformed image, and saved images are
+    /// stored as they are received)
+    #[arg(long, value_enum, env = "CANVAS_LOAD_TRANSFORM")]
+    load_transform: Option<Transform>,
+
+    /// Mirror every image as it is received, before it is stored (so loads send the mirrored
+    /// image), for clients whose displays are mirrored
+    #[arg(long, value_enum, env = "CANVAS_MIRROR")]
+    mirror: Option<Mirror>,
+
+    /// Send the rows of loaded images at no more than this many bytes per second (as fast as
+    /// possible by default), to simulate slow links or to leave room on the link for others
+    #[arg(long, value_parser = clap::value_parser!(u64).range(1..), env = "CANVAS_LOAD_RATE_BYTES_PER_SEC")]
+    load_rate_bytes_per_sec: Option<u64>,
+
+    /// Longest time for which a single connection is served, in seconds, however often the client
+    /// sends something (0 for no limit)
+    #[arg(long, default_value_t = 300, env = "CANVAS_CONNECTION_TIMEOUT")]
+    connection_timeout: u64,
+
+    /// Watch the image directory for images that are copied into it or changed by the subcommands,
+    /// even when the cache is disabled (by default, it is only watched while the cache is enabled)
+    #[arg(long, env = "CANVAS_WATCH", conflicts_with = "no_watch")]
+    watch: bool,
+
+    /// Do not watch the image directory, even when the cache is enabled (changed images are then
+    /// only noticed by the cache when they are loaded, and their thumbnails when the server starts)
+    #[arg(long, env = "CANVAS_NO_WATCH")]
+    no_watch: bool,
+
+    /// Write every saved image, even when it is identical to the image already stored in its slot
+    /// (which is skipped by default, leaving its file and history untouched)
+    #[arg(long, env = "CANVAS_ALWAYS_WRITE")]
+    always_write: bool,
+
+    /// Fork into the background once the arguments have been checked, for init scripts and
+    /// `systemctl` (only on Unix)
+    #[arg(long, env = "CANVAS_DAEMON")]
+    daemon: bool,
+
+    /// Stay in the foreground, which is the default (for overriding `--daemon` in scripts)
+    #[arg(long, overrides_with = "daemon", env = "CANVAS_FOREGROUND")]
+    foreground: bool,
+
+    /// Write the process ID of the daemon to this file, which is kept locked while it runs
+    #[arg(long, requires = "daemon", env = "CANVAS_PID_FILE")]
+    pid_file: Option<String>,
+
+    /// Append everything that the daemon prints to this file (which is discarded otherwise)
+    #[arg(long, requires = "daemon", env = "CANVAS_LOG_FILE")]
+    log_file: Option<String>,
+
+    /// Refuse saves that would make the images of a directory (of each device, with
+    /// `--multi-device`) take more than this many bytes
+    #[arg(long, env = "CANVAS_MAX_DIR_SIZE")]
+    max_dir_size: Option<u64>,
+
+    /// Code that is stored in place of codes which are not in the palette, in received images (the
+    /// code of the color nearest to black by default, which is 8 in the palette of the canvas app)
+    #[arg(long, value_parser = clap::value_parser!(u8).range(0..=15), env = "CANVAS_FALLBACK_CODE")]
+    fallback_code: Option<u8>,
+
+    /// Read the colors that the codes stand for from a TOML or JSON file (such as
+    /// `palettes/builtin.toml`), instead of using the palette of the canvas app
+    #[arg(long, global = true, value_parser = parse_palette, env = "CANVAS_PALETTE")]
+    palette: Option<Palette>,
+
+    /// Built-in palette that the codes stand for, unless a palette file is given with `--palette`
+    #[arg(
+        long,
+        global = true,
+        value_enum,
+        default_value_t = PalettePreset::Color,
+        conflicts_with = "palette",
+        env = "CANVAS_PALETTE_PRESET"
+    )]
+    palette_preset: PalettePreset,
+
+    /// Backend that images are stored in (the `migrate-store` subcommand moves existing images
+    /// between them)
+    #[arg(long, value_enum, default_value_t = Backend::Files, env = "CANVAS_STORE")]
+    store: Backend,
+
+    /// Path of the database that images are stored in, with `--store sqlite`
+    #[arg(long, default_value = "canvas.db", env = "CANVAS_DB")]
+    db: String,
+
+    /// Size of the stack of each thread that serves a client, in bytes (the default of the platform
+    /// is used otherwise)
+    #[arg(long, env = "CANVAS_WORKER_STACK_SIZE")]
+    worker_stack_size: Option<usize>,
+
+    /// List every slot that has an image, with its dimensions and size, and exit (the same as the
+    /// list subcommand)
+    #[arg(long, env = "CANVAS_LIST")]
+    list: bool,
+
+    /// Write a BMP image with one band for each color of the palette to the given path, and exit
+    #[arg(long, env = "CANVAS_WRITE_PALETTE_PREVIEW")]
+    write_palette_preview: Option<String>,
+
+    /// Write a grid of the thumbnails of every slot to the given path (PNG if it ends with ".png",
+    /// BMP otherwise), and exit
+    #[arg(long, env = "CANVAS_CONTACT_SHEET")]
+    contact_sheet: Option<String>,
+}
+
+impl Args {
+    /// Gets the palette that codes are converted with
+    fn palette(&self) -> &Palette {
+        self.palette
+            .as_ref()
+            .unwrap_or(self.palette_preset.palette())
+    }
+
+    /// Gets the format of the segments of compressed rows, which is only valid if the code and the
+    /// count fit in a segment (which is checked at startup)
+    fn segment_format(&self) -> SegmentFormat {
+        SegmentFormat {
+            code_bits: self.segment_code_bits,
+            count_bits: self.segment_count_bits,
+        }
+    }
+
+    /// Gets how much is printed about the requests that are served
+    fn verbosity(&self) -> Verbosity {
+        match (self.quiet, self.verbose) {
+            (true, _) => Verbosity::Quiet,
+            (false, 0) => Verbosity::Normal,
+            (false, _) => Verbosity::Debug,
+        }
+    }
+
+    /// Checks whether the image directory is watched for changes made outside of the server
+    fn watches(&self) -> bool {
+        let cached = self.cache_slots > 0 || self.preload;
+        self.watch || (cached && !self.no_watch)
+    }
+
+    /// Checks whether messages of the given level are printed
+    fn logs(&self, level: Verbosity) -> bool {
+        self.verbosity() >= level
+    }
+
+    /// Gets the color that empty slots are loaded as, which is only valid if its code is in the
+    /// palette (which is checked at startup)
+    fn blank_color(&self) -> u16 {
+        match self.blank_color {
+            None => 0x0000,
+            Some(BlankColor::Code(code)) => self.palette().code_2_color(code).unwrap_or(0x0000),
+            Some(BlankColor::Color(color)) => color,
+        }
+    }
+
+    /// Gets the code that is stored in place of codes which are not in the palette
+    fn fallback_code(&self) -> u8 {
+        self.fallback_code
+            .unwrap_or_else(|| self.palette().nearest_code(0x0000))
+    }
+
+    /// Gets the store that images are kept in
+    ///
+    /// # Errors
+    ///
+    /// * When the database can not be opened (with `--store sqlite`)
+    ///
+    fn store(&self) -> Result<Box<dyn Store>, ServeError> {
+        match self.store {
+            Backend::Files => Ok(Box::new(FileStore {
+                color_depth: self.color_depth,
+                mono: self.mono,
+                compression: match self.compress_storage {
+                    true => StoreCompression::Gzip,
+                    false => self.store_compression,
+                },
+                compression_level: self.store_compression_level,
+                dedupe: self.dedupe,
+                max_dir_size: self.max_dir_size,
+                history_keep: self.history_keep,
+                save_png: self.save_png,
+                palette: *self.palette(),
+            })),
+            Backend::Sqlite => SqliteStore::open(&self.db)
+                .map(|store| Box::new(store) as Box<dyn Store>)
+                .map_err(|err| {
+                    storage(format!("opening database {}", self.db))(std::io::Error::other(err))
+                }),
+        }
+    }
+}
+
+/// Loads the palette given on the command line, so that invalid palettes are refused at startup
+fn parse_palette(path: &str) -> Result<Palette, String> {
+    Palette::load(path).map_err(|err| err.to_string())
+}
+
+/// Reads the color given to `--blank-color`, which is a 16-bit color if it is in hex (starting with
+/// `0x` or `#`) and a code of the palette otherwise
+fn parse_blank_color(value: &str) -> Result<BlankColor, String> {
+    match value
+        .strip_prefix("0x")
+        .or_else(|| value.strip_prefix("0X"))
+        .or_else(|| value.strip_prefix('#'))
+    {
+        Some(hex) => u16::from_str_radix(hex, 16)
+            .map(BlankColor::Color)
+            .map_err(|_| format!("{} is not a 16-bit color", value)),
+        None => value
+            .parse()
+            .map(BlankColor::Code)
+            .map_err(|_| format!("{} is neither a code nor a 16-bit color", value)),
+    }
 }
 
 fn main() {
-    let args = Args::parse();
+    let args = Arc::new(Args::parse());
+
+    // subcommands work on the image directory directly, without starting the server
+    if let Some(command) = &args.command {
+        std::process::exit(commands::run(command, &args.image_dir, args.palette()));
+    }
+    if args.list {
+        std::process::exit(commands::run(
+            &Command::List { json: false },
+            &args.image_dir,
+            args.palette(),
+        ));
+    }
+
+    let segment_format = args.segment_format();
+    if SegmentFormat::new(segment_format.code_bits, segment_format.count_bits).is_none() {
+        eprintln!(
+            "Segments can not have {} bits of code and {} bits of count, which must fit in 16 bits",
+            segment_format.code_bits, segment_format.count_bits
+        );
+        std::process::exit(1);
+    }
+    if let Some(code) = (0..MAX_PALETTE_LEN as u8).find(|&code| {
+        args.palette().code_2_color(code).is_some() && !segment_format.fits_code(code)
+    }) {
+        eprintln!(
+            "The palette has code {}, which does not fit in {} bits, pick more with --segment-code-bits",
+            code, segment_format.code_bits
+        );
+        std::process::exit(1);
+    }
+
+    if args.palette().code_2_color(args.fallback_code()).is_none() {
+        eprintln!(
+            "The fallback code {} is not in the palette, pick another one with --fallback-code",
+            args.fallback_code()
+        );
+        std::process::exit(1);
+    }
+
+    if let Some(BlankColor::Code(code)) = args.blank_color {
+        if args.palette().code_2_color(code).is_none() {
+            eprintln!(
+                "The blank code {} is not in the palette, pick another one with --blank-color",
+                code
+            );
+            std::process::exit(1);
+        }
+    }
+
+    if let Some(path) = &args.write_palette_preview {
+        let filename = path.strip_suffix(".bmp").unwrap_or(path);
+        match save_bmp_image(
+            &palette_preview(args.palette(), PREVIEW_WIDTH, PREVIEW_BAND_HEIGHT),
+            filename,
+        ) {
+            Ok(()) => println!("Wrote palette preview to {}.bmp", filename),
+            Err(err) => {
+                eprintln!("Failed to write palette preview: {}", err);
+                std::process::exit(1);
+            }
+        }
+        return;
+    }
+
+    if let Some(path) = &args.contact_sheet {
+        let Some(sheet) = contact_sheet(&args.image_dir, args.palette()) else {
+            eprintln!("{} has no images", args.image_dir);
+            std::process::exit(1);
+        };
+
+        let result = match path.strip_suffix(".png") {
+            Some(filename) => save_png_image(&sheet, filename)
+                .map(|()| format!("{}.png", filename))
+                .map_err(|err| err.to_string()),
+            None => {
+                let filename = path.strip_suffix(".bmp").unwrap_or(path);
+                save_bmp_image(&sheet, filename)
+                    .map(|()| format!("{}.bmp", filename))
+                    .map_err(|err| err.to_string())
+            }
+        };
+        match result {
+            Ok(written) => println!("Wrote contact sheet to {}", written),
+            Err(err) => {
+                eprintln!("Failed to write contact sheet: {}", err);
+                std::process::exit(1);
+            }
+        }
+        return;
+    }
+
+    // forked before anything else is started, since only this thread is kept by the fork
+    if args.daemon && !args.foreground {
+        if let Err(err) = daemon::daemonize(args.pid_file.as_deref(), args.log_file.as_deref()) {
+            eprintln!("Failed to start the daemon: {}", err);
+            std::process::exit(1);
+        }
+    }
 
     let host = "0.0.0.0";
     let port = args.port;
 
-    let image_dir = args.image_dir;
+    let image_dir = &args.image_dir;
 
     println!();
     println!("Starting Dumblebots Arduino Canvas Server...");
     println!();
 
-    match std::fs::create_dir(&image_dir) {
+    let tls_config = match (&args.tls_cert, &args.tls_key) {
+        (Some(cert_path), Some(key_path)) => match tls::load_server_config(cert_path, key_path) {
+            Ok(config) => {
+                println!("TLS is enabled");
+                Some(config)
+            }
+            Err(err) => {
+                eprintln!("Failed to load TLS certificate and key: {}", err);
+                return;
+            }
+        },
+        _ => None,
+    };
+
+    match std::fs::create_dir(image_dir) {
         Ok(()) => println!("Successfully created images directory"),
         Err(err) => {
             if err.kind() == std::io::ErrorKind::AlreadyExists {
@@ -69,6 +608,81 @@ fn main() {
         }
     };
 
+    // held until the server exits, so that a second server on the same directory refuses to start
+    let _instance_lock = match instance::lock_instance(image_dir) {
+        Ok(lock) => lock,
+        Err(err) => {
+            eprintln!("Can not use image directory {}: {}", image_dir, err);
+            std::process::exit(1);
+        }
+    };
+
+    remove_temp_files(image_dir);
+    if args.multi_device {
+        for dir in device_dirs(image_dir) {
+            remove_temp_files(&dir);
+        }
+    }
+
+    if let Some(days) = args.trash_keep_days {
+        let keep = std::time::Duration::from_secs(days * 24 * 60 * 60);
+        let mut dirs = vec![image_dir.to_string()];
+        if args.multi_device {
+            dirs.extend(device_dirs(image_dir));
+        }
+        for dir in dirs {
+            match trash::prune_trash(&dir, keep) {
+                Ok(0) => {}
+                Ok(pruned) => println!("Removed {} old images from the trash of {}", pruned, dir),
+                Err(err) => eprintln!("Failed to prune the trash of {}: {}", dir, err),
+            }
+        }
+    }
+
+    // a database that can not be opened would fail every request, so the server is not started
+    let store = match args.store() {
+        Ok(store) => store,
+        Err(err) => {
+            eprintln!("Failed to open the image store: {}", err);
+            return;
+        }
+    };
+
+    if args.preload {
+        let (mut count, mut bytes) = cache::preload(image_dir, store.as_ref());
+        if args.multi_device {
+            for dir in device_dirs(image_dir) {
+                let (device_count, device_bytes) = cache::preload(&dir, store.as_ref());
+                count += device_count;
+                bytes += device_bytes;
+            }
+        }
+        println!(
+            "Preloaded {} images, taking {:.1} MiB of memory",
+            count,
+            bytes as f64 / MIB as f64
+        );
+    }
+
+    // kept until the server stops, since dropping it stops the watching
+    let _watcher = match (args.store, args.watches()) {
+        (Backend::Files, true) => watch::watch(image_dir, args.multi_device, args.palette()),
+        _ => None,
+    };
+
+    // thumbnails are only a convenience, so they are caught up with without delaying the server
+    let thumbnail_dir = image_dir.clone();
+    let multi_device = args.multi_device;
+    let palette = *args.palette();
+    thread::spawn(move || {
+        refresh_thumbnails(&thumbnail_dir, &palette);
+        if multi_device {
+            for dir in device_dirs(&thumbnail_dir) {
+                refresh_thumbnails(&dir, &palette);
+            }
+        }
+    });
+
     let listener = match TcpListener::bind((host, port)) {
         Ok(listener) => listener,
         Err(err) => {
@@ -82,25 +696,52 @@ fn main() {
         }
     };
 
+    if let Ok(netifas) = local_ip_address::list_afinet_netifas() {
+        println!("Reachable on the following addresses:");
+        for (ifname, ip_addr) in netifas.iter().filter(|(_, ip_addr)| !ip_addr.is_loopback()) {
+            println!("    {:<16} {}:{}", ifname, ip_addr, port);
+        }
+    }
+
     if let Ok(local_ip_addr) = local_ip_address::local_ip() {
         println!("Waiting for request on \"{:?}:{}\"", local_ip_addr, port)
     } else {
         println!("Waiting for requests on port \"{}\"", port);
     }
 
+    let mut workers = Vec::new();
+
     for stream in listener.incoming() {
+        if SHUTDOWN.load(Ordering::SeqCst) {
+            break;
+        }
+
         match stream {
             Ok(stream) => {
-                let dir = image_dir.clone();
-                thread::spawn(move || {
-                    serve_client(stream, &dir);
-                });
+                let args = args.clone();
+                let tls_config = tls_config.clone();
+                workers.retain(|worker: &thread::JoinHandle<()>| !worker.is_finished());
+
+                let mut worker = thread::Builder::new();
+                if let Some(size) = args.worker_stack_size {
+                    worker = worker.stack_size(size);
+                }
+                match worker.spawn(move || serve_client(stream, &args, tls_config)) {
+                    Ok(worker) => workers.push(worker),
+                    Err(err) => eprintln!("Failed to start a thread for the connection: {}", err),
+                }
             }
             Err(e) => {
                 eprintln!("Failed to accept connection: {}", e);
             }
         }
     }
+
+    // let the transfers that are still in progress finish
+    println!("Shutting down, waiting for {} connections", workers.len());
+    for worker in workers {
+        let _ = worker.join();
+    }
 }
 
 /// Serves a single request from a single client
@@ -108,10 +749,10 @@ fn main() {
 /// # Arguments
 ///
 /// * `stream` - TCP connection with the client
+/// * `args` - Command line arguments of the server
+/// * `tls_config` - TLS configuration to wrap the connection with, if TLS is enabled
 ///
-fn serve_client(mut stream: TcpStream, dir: &str) {
-    let mut buffer = [0; 6];
-
+fn serve_client(stream: TcpStream, args: &Args, tls_config: Option<Arc<ServerConfig>>) {
     // try to set the timeout for this connection
     let Ok(()) = stream.set_read_timeout(SOCKET_TIMEOUT) else {
         eprintln!("Failed to set timeout for socket");
@@ -124,235 +765,3816 @@ fn serve_client(mut stream: TcpStream, dir: &str) {
         return;
     };
 
-    let Ok(()) = stream.read_exact(&mut buffer) else {
-        eprintln!("Failed Request");
+    // closing the socket waits for the last reply to be delivered (see the close module)
+    if let Err(err) = close::set_linger(&stream, Some(close::LINGER_TIMEOUT)) {
+        eprintln!("Failed to set linger for socket: {}", err);
+    }
+
+    // every read is bounded by the socket timeout, and the connection as a whole by its deadline
+    let timeout = match args.connection_timeout {
+        0 => None,
+        secs => Some(std::time::Duration::from_secs(secs)),
+    };
+
+    let Some(tls_config) = tls_config else {
+        let mut stream = DeadlineStream::new(stream, timeout);
+        let result = serve_request(&mut stream, peer, args);
+        if let Err(err) = &result {
+            report_error(&mut stream, peer, err);
+        }
+        close::close_connection(stream.get_mut(), result.is_err());
         return;
     };
 
-    let rw = buffer[0];
-    let name = buffer[1];
-    let height = u16::from_le_bytes([buffer[2], buffer[3]]) as usize;
-    let width = u16::from_le_bytes([buffer[4], buffer[5]]) as usize;
+    let Ok(conn) = ServerConnection::new(tls_config) else {
+        eprintln!("Failed to start TLS session with \"{}\"", peer);
+        return;
+    };
+    let mut stream = DeadlineStream::new(StreamOwned::new(conn, stream), timeout);
 
-    if rw == 1 {
-        println!(
-            r#"
-            Saving new image from "{}" with
-            Dimensions: {} x {}
-            name: image_{}.bmp
-            "#,
-            peer, height, width, name
-        );
-        save_image(height, width, name, stream, dir);
-    } else if rw == 2 {
-        println!(
-            r#"
-            Loading new image to "{}" with
-            Dimensions: {} x {}
-            name: image_{}.bmp
-            "#,
-            peer, height, width, name
-        );
-        load_image(height, width, name, stream, dir);
+    let result = serve_request(&mut stream, peer, args);
+    if let Err(err) = &result {
+        report_error(&mut stream, peer, err);
     }
+
+    // let the client know that the session ended on purpose
+    stream.get_mut().conn.send_close_notify();
+    let _ = stream.flush();
+    close::close_connection(&stream.get_mut().sock, result.is_err());
 }
 
-/// Saves an image sent from the client to the filesystem
+/// Logs the error that ended a request, and sends the client the status byte of the error (unless
+/// the connection itself failed)
 ///
 /// # Arguments
 ///
-/// * `height` - Number of rows in the image
-/// * `width` - Number of columns in the image
-/// * `stream` - TCP connection with the client
-/// * `name` - The slot number of the image
-/// * `dir` - Directory to save image to
+/// * `stream` - Connection with the client, either plain TCP or TLS
+/// * `peer` - Address of the client
+/// * `err` - The error that ended the request
 ///
-fn save_image(height: usize, width: usize, name: u8, mut stream: TcpStream, dir: &str) {
-    let mut img = Vec::with_capacity(height);
-
-    let mut pb = match SHOW_PROGRESS_BAR {
-        false => None,
-        true => {
-            let mut pb = ProgressBar::new(height as u64);
-            pb.set_width(Some(PROGRESS_BAR_WIDTH));
-            Some(pb)
-        }
-    };
-
-    for row in 0..height {
-        let mut mode = [0u8];
-        let mut codes = vec![0; width];
-
-        let Ok(_) = stream.read_exact(&mut mode) else {
-            eprintln!("Error reading mode");
-            return;
-        };
-
-        if mode[0] == 0 {
-            let Ok(_) = stream.read_exact(&mut codes) else {
-                eprintln!("Error reading row {}", row);
-                return;
-            };
-        } else {
-            let mut segments_bytes = vec![0u8; 2 * (mode[0] as usize)];
-            let mut segments = vec![0u16; mode[0] as usize];
-
-            let Ok(_) = stream.read_exact(&mut segments_bytes) else {
-                eprintln!("Error reading compressed row {}", row);
-                return;
-            };
+fn report_error<S: Write>(mut stream: S, peer: SocketAddr, err: &ServeError) {
+    eprintln!("Failed to serve request from \"{}\": {}", peer, err);
 
-            segments
-                .iter_mut()
-                .zip(segments_bytes.into_iter().array_chunks::<2>())
-                .for_each(|(seg, pair)| *seg = u16::from_le_bytes(pair));
-
-            uncompress(&segments, &mut codes);
-        }
-        img.push(codes.iter().map(|&v| code_2_color(v).unwrap()).collect());
-
-        match &mut pb {
-            Some(pb) => pb.inc(),
-            None => 0,
-        };
+    if let Some(status) = err.status() {
+        let _ = stream.write_all(&[status]);
+        let _ = stream.flush();
     }
-    match &mut pb {
-        Some(pb) => pb.finish_println(""),
-        None => (),
-    };
-
-    save_bmp_image(&img, &format!("{dir}/image_{name}"));
 }
 
-/// Loads an image from the filesystem to the client
+/// Reads the header of a request and dispatches it to the appropriate handler
 ///
 /// # Arguments
 ///
-/// * `expected_height` - Number of rows in the image as expected by the client
-/// * `expected_width` - Number of columns in the image as expected by the client
-/// * `stream` - TCP connection with the client
-/// * `name` - The slot number of the image
-/// * `dir` - Directory to retrieve the image from
+/// * `stream` - Connection with the client, either plain TCP or TLS
+/// * `peer` - Address of the client
+/// * `args` - Command line arguments of the server
 ///
-fn load_image(
-    expected_height: usize,
-    expected_width: usize,
-    name: u8,
-    mut stream: TcpStream,
-    dir: &str,
-) {
-    let img = load_bmp_image(
-        &format!("{dir}/image_{name}"),
-        expected_width,
-        expected_height,
-    );
+fn serve_request<S: Read + Write>(
+    mut stream: S,
+    peer: SocketAddr,
+    args: &Args,
+) -> Result<(), ServeError> {
+    let mut buffer = [0; 6];
 
-    let mut pb = match SHOW_PROGRESS_BAR {
-        false => None,
-        true => {
-            let mut pb = ProgressBar::new(expected_height as u64);
-            pb.set_width(Some(PROGRESS_BAR_WIDTH));
-            Some(pb)
+    stream
+        .read_exact(&mut buffer)
+        .map_err(connection("reading the request header"))?;
+
+    // the connection may start by negotiating its features, and then sends its request as usual
+    let features = match buffer[0] {
+        OP_NEGOTIATE => {
+            let requested = u32::from_le_bytes([buffer[1], buffer[2], buffer[3], buffer[4]]);
+            let features = Features::SUPPORTED.intersection(Features::from_bits(requested));
+            if args.logs(Verbosity::Normal) {
+                println!(
+                    "Negotiated features {:#x} (of {:#x}) with \"{}\"",
+                    features.bits(),
+                    requested,
+                    peer
+                );
+            }
+            let mut reply = vec![STATUS_OK];
+            reply.extend_from_slice(&features.bits().to_le_bytes());
+            stream
+                .write_all(&reply)
+                .and_then(|()| stream.flush())
+                .map_err(connection("replying to the negotiation"))?;
+            stream
+                .read_exact(&mut buffer)
+                .map_err(connection("reading the request header"))?;
+            features
         }
+        _ => Features::NONE,
     };
 
-    for (i, row) in img.iter().enumerate() {
-        let codes: Vec<u8> = (*row).iter().map(|&v| color_2_code(v).unwrap()).collect();
-
-        let Ok(()) = stream.write_all(&codes) else {
-            eprintln!("Error while sending row {}", i);
-            return;
-        };
-        let Ok(()) = stream.flush() else {
-            eprintln!("Error while flushing row {}", i);
-            return;
-        };
+    // loads and saves may have flags in the opcode byte, which are kept for other requests so that
+    // they are refused as unknown opcodes
+    let (rw, flags) = match (buffer[0] & !LOAD_FLAGS, buffer[0] & !SAVE_FLAGS) {
+        (OP_LOAD | OP_CROP, _) => (buffer[0] & !LOAD_FLAGS, buffer[0] & LOAD_FLAGS),
+        (_, OP_SAVE | OP_MERGE) => (buffer[0] & !SAVE_FLAGS, buffer[0] & SAVE_FLAGS),
+        _ => (buffer[0], 0),
+    };
+    let is_load = matches!(rw, OP_LOAD | OP_CROP);
+    let flip = Mirror::from_flips(
+        is_load && flags & LOAD_FLIP_HORIZONTAL != 0,
+        is_load && flags & LOAD_FLIP_VERTICAL != 0,
+    );
+    let layout = match features.contains(Features::WIDE_SEGMENTS) {
+        true => RowLayout::Wide,
+        false => RowLayout::from_flags(if is_load { 0 } else { flags }),
+    };
+    let name = buffer[1];
+    let height = u16::from_le_bytes([buffer[2], buffer[3]]) as usize;
+    let width = u16::from_le_bytes([buffer[4], buffer[5]]) as usize;
 
-        if (i % 10) == 0 {
-            let Ok(()) = stream.read_exact(&mut [0u8]) else {
-                eprintln!("Not received confirmation after row {}", i);
-                return;
-            };
-        }
-        match &mut pb {
-            Some(pb) => pb.inc(),
-            None => 0,
-        };
+    // images without pixels can neither be stored as a BMP file nor drawn on the canvas, and the
+    // image (and its buffers) are allocated from the dimensions, so they are bounded before that
+    if matches!(rw, OP_SAVE | OP_LOAD | OP_CROP | OP_MERGE)
+        && (height == 0
+            || width == 0
+            || height > args.max_height as usize
+            || width > args.max_width as usize)
+    {
+        return Err(ServeError::BadDimensions { height, width });
     }
 
-    let Ok(()) = stream.read_exact(&mut [0u8]) else {
-        println!("Not recieved final confirmation");
-        return;
+    // with multiple devices, the images of each device are stored in a separate subdirectory
+    let dir = if args.multi_device {
+        let mut device_id = [0u8];
+        stream
+            .read_exact(&mut device_id)
+            .map_err(connection("reading the device ID"))?;
+        if args.logs(Verbosity::Normal) {
+            println!("Request from device {}", device_id[0]);
+        }
+        format!("{}/{}", args.image_dir, device_id[0])
+    } else {
+        args.image_dir.clone()
     };
-    match &mut pb {
-        Some(pb) => pb.finish_println(""),
-        None => (),
+
+    // only requests that refer to a slot can name it, so that other requests keep their format
+    let slot = match rw {
+        OP_SAVE | OP_LOAD | OP_RENAME | OP_COPY | OP_CHECKSUM | OP_CROP | OP_MERGE => {
+            read_slot(name, features, &mut stream)?
+        }
+        _ => Slot::Number(name.into()),
     };
+    // only the slot byte of the header is reserved, so slot 255 can still be requested as a u16
+    let most_recent = name == MOST_RECENT_SLOT;
+
+    match rw {
+        OP_SAVE => {
+            if args.logs(Verbosity::Normal) {
+                println!(
+                    r#"
+            Saving new image from "{}" with
+            Dimensions: {} x {}
+            name: image_{}.bmp
+            "#,
+                    peer, height, width, slot
+                );
+            }
+            save_image(
+                height, width, &slot, None, layout, features, stream, peer, &dir, args,
+            )
+        }
+        OP_MERGE => {
+            let mut code = [0u8];
+            stream
+                .read_exact(&mut code)
+                .map_err(connection("reading the transparent code"))?;
+            let transparent = args
+                .palette()
+                .code_2_color(code[0])
+                .ok_or(ServeError::UnknownTransparentCode(code[0]))?;
+            if args.logs(Verbosity::Normal) {
+                println!(
+                    "Merging a {} x {} image from \"{}\" into image_{}.bmp",
+                    height, width, peer, slot
+                );
+            }
+            save_image(
+                height,
+                width,
+                &slot,
+                Some(transparent),
+                layout,
+                features,
+                stream,
+                peer,
+                &dir,
+                args,
+            )
+        }
+        OP_LOAD => {
+            if args.logs(Verbosity::Normal) {
+                println!(
+                    r#"
+            Loading new image to "{}" with
+            Dimensions: {} x {}
+            name: image_{}.bmp
+            "#,
+                    peer, height, width, slot
+                );
+            }
+            load_image(
+                height,
+                width,
+                &slot,
+                most_recent,
+                flip,
+                stream,
+                peer,
+                &dir,
+                args,
+            )
+        }
+        OP_CROP => {
+            if args.logs(Verbosity::Normal) {
+                println!(
+                    "Loading a {} x {} region of image_{}.bmp to \"{}\"",
+                    height, width, slot, peer
+                );
+            }
+            crop_image(
+                height,
+                width,
+                &slot,
+                most_recent,
+                flip,
+                stream,
+                peer,
+                &dir,
+                args,
+            )
+        }
+        OP_SHUTDOWN => {
+            if args.logs(Verbosity::Normal) {
+                println!("Shutdown requested by \"{}\"", peer);
+            }
+            shutdown_server(stream, args)
+        }
+        OP_RENAME => rename_image(&slot, features, stream, &dir, args),
+        OP_COPY => copy_image(&slot, features, stream, &dir, args),
+        OP_CLEAR => clear_images(stream, peer, &dir, args),
+        OP_CHECKSUM => {
+            if args.logs(Verbosity::Normal) {
+                println!("Checksum of image_{}.bmp requested by \"{}\"", slot, peer);
+            }
+            send_checksum(&slot, most_recent, stream, &dir, args)
+        }
+        OP_CAPABILITIES => {
+            if args.logs(Verbosity::Normal) {
+                println!("Capabilities requested by \"{}\"", peer);
+            }
+            let mut reply = vec![STATUS_OK];
+            reply.extend_from_slice(&capabilities(args));
+            stream
+                .write_all(&reply)
+                .and_then(|()| stream.flush())
+                .map_err(connection("sending the capabilities"))
+        }
+        OP_PING => {
+            if args.logs(Verbosity::Normal) {
+                println!("Ping from \"{}\"", peer);
+            }
+            stream
+                .write_all(&[STATUS_PONG])
+                .and_then(|()| stream.flush())
+                .map_err(connection("replying to the ping"))
+        }
+        _ => Err(ServeError::UnknownOpcode(rw)),
+    }
 }
 
-/// Uncompress a row from segment-representation into its pixel-representation and get the number of pixels
+/// Describes the version and capabilities of the server, in the format of the reply to
+/// [`OP_CAPABILITIES`] (after its status byte)
 ///
 /// # Arguments
 ///
-/// * `segments` - Slice of 16-bit integers, each representing a valid segment with a code and size
-/// * `codes` - Mutable slice of 8-bit integers, where the uncompressed data must be stored
+/// * `args` - Command line arguments of the server
 ///
-pub fn uncompress(segments: &[u16], codes: &mut [u8]) -> usize {
-    let mut idx = 0;
+fn capabilities(args: &Args) -> [u8; CAPABILITIES_LEN] {
+    let version = |part: &str| part.parse::<u8>().unwrap_or(u8::MAX);
+    let opcodes = SUPPORTED_OPCODES
+        .iter()
+        .fold(0u32, |mask, &opcode| mask | (1 << opcode));
 
-    for &segment in segments.iter() {
-        let code = (segment & 0xF) as u8;
-        let count = ((segment >> 4) & 0x1FF) as usize;
+    let mut reply = [0; CAPABILITIES_LEN];
+    reply[0] = version(env!("CARGO_PKG_VERSION_MAJOR"));
+    reply[1] = version(env!("CARGO_PKG_VERSION_MINOR"));
+    reply[2] = version(env!("CARGO_PKG_VERSION_PATCH"));
+    reply[3..7].copy_from_slice(&opcodes.to_le_bytes());
+    reply[7] = args.palette().color_count() as u8;
+    reply[8..10].copy_from_slice(&args.max_height.to_le_bytes());
+    reply[10..12].copy_from_slice(&args.max_width.to_le_bytes());
+    reply[12] = args.segment_code_bits as u8;
+    reply[13] = args.segment_count_bits as u8;
+    reply[14] = SAVE_FLAGS;
+    reply
+}
 
-        if codes.len() < (idx + count) {
-            break;
+/// Shuts the server down gracefully, if the client presents the correct authentication token
+///
+/// # Arguments
+///
+/// * `stream` - Connection with the client
+/// * `args` - Command line arguments of the server
+///
+fn shutdown_server<S: Read + Write>(mut stream: S, args: &Args) -> Result<(), ServeError> {
+    if !authenticate(&mut stream, args) {
+        return Err(ServeError::Unauthorized);
+    }
+
+    SHUTDOWN.store(true, Ordering::SeqCst);
+    let result = stream
+        .write_all(&[STATUS_OK])
+        .and_then(|()| stream.flush())
+        .map_err(connection("confirming the shutdown"));
+
+    // wake up the listener, which only notices the shutdown when it accepts the next connection
+    let _ = TcpStream::connect(("127.0.0.1", args.port));
+    result
+}
+
+/// Removes the image of every slot, if the client presents the correct authentication token, and
+/// replies with the number of images that were removed
+///
+/// Slots that are being saved are left alone. The images are moved into the trash (and the backups
+/// and history of the slots are kept), so that images which were removed by mistake can still be
+/// restored.
+///
+/// # Arguments
+///
+/// * `stream` - Connection with the client
+/// * `peer` - Address of the client
+/// * `dir` - Directory where images are stored
+/// * `args` - Command line arguments of the server
+///
+fn clear_images<S: Read + Write>(
+    mut stream: S,
+    peer: SocketAddr,
+    dir: &str,
+    args: &Args,
+) -> Result<(), ServeError> {
+    if !authenticate(&mut stream, args) {
+        println!("Refused to clear every image of {} for \"{}\"", dir, peer);
+        return Err(ServeError::Unauthorized);
+    }
+    println!("!!! Clearing every image of {} for \"{}\"", dir, peer);
+
+    let store = args.store()?;
+    let mut removed: u16 = 0;
+    for slot in store.list(dir) {
+        let result = match lock_slot(dir, &slot) {
+            Ok(_lock) => store.trash(dir, &slot),
+            Err(err) => Err(err),
+        };
+        match result {
+            Ok(()) => {
+                cache::invalidate(dir, &slot);
+                removed = removed.saturating_add(1);
+            }
+            Err(err) if err.kind() == std::io::ErrorKind::AlreadyExists => {
+                eprintln!("Kept image_{}.bmp, which is being saved", slot);
+            }
+            Err(err) => eprintln!("Failed to remove image_{}.bmp: {}", slot, err),
         }
+    }
+    usage::invalidate(dir);
+    println!("!!! Cleared {} images of {} for \"{}\"", removed, dir, peer);
 
-        codes
-            .iter_mut()
-            .skip(idx)
-            .take(count)
-            .for_each(|v| *v = code);
-        idx += count;
+    let mut reply = vec![STATUS_OK];
+    reply.extend_from_slice(&removed.to_le_bytes());
+    stream
+        .write_all(&reply)
+        .and_then(|()| stream.flush())
+        .map_err(connection("confirming the clear"))
+}
+
+/// Reads the slot that a request refers to, which is either the slot number of the header, or what
+/// follows the header for [`NAMED_SLOT`] (a name) and [`WIDE_SLOT`] (a 16-bit slot number), when
+/// the connection negotiated [`Features::NAMED_SLOTS`] and [`Features::WIDE_SLOTS`] respectively
+///
+/// Invalid names are refused (with [`ServeError::InvalidSlotName`]) before they are used to access
+/// any file.
+///
+/// # Arguments
+///
+/// * `name` - The slot number of the header
+/// * `features` - Features that the connection negotiated
+/// * `stream` - Connection with the client
+///
+fn read_slot<S: Read>(name: u8, features: Features, mut stream: S) -> Result<Slot, ServeError> {
+    match name {
+        NAMED_SLOT if features.contains(Features::NAMED_SLOTS) => {}
+        WIDE_SLOT if features.contains(Features::WIDE_SLOTS) => {
+            let mut number = [0u8; 2];
+            stream
+                .read_exact(&mut number)
+                .map_err(connection("reading the slot number"))?;
+            return Ok(Slot::Number(u16::from_le_bytes(number)));
+        }
+        _ => return Ok(Slot::Number(name.into())),
     }
 
-    idx
+    let mut len = [0u8];
+    stream
+        .read_exact(&mut len)
+        .map_err(connection("reading the length of the slot name"))?;
+    let mut bytes = vec![0u8; len[0] as usize];
+    stream
+        .read_exact(&mut bytes)
+        .map_err(connection("reading the slot name"))?;
+
+    Ok(Slot::named(&bytes)?)
 }
 
-/// Compresse a row from pixel-representation into its segment-representation and get the number of segments, pixels
+/// Moves the image in a slot to the slot requested by the client, and replies with a status byte
 ///
 /// # Arguments
 ///
-/// * `segments` - Mutable slice of 16-bit integers, where the compressed data must be stored
-/// * `codes` - Slice of 8-bit integers, each representing a valid code
+/// * `name` - The slot of the image to move
+/// * `features` - Features that the connection negotiated, which tell how the destination is sent
+/// * `stream` - Connection with the client
+/// * `dir` - Directory where images are stored
+/// * `args` - Command line arguments of the server
 ///
-pub fn compress(segments: &mut [u16], codes: &[u8]) -> (usize, usize) {
-    let mut num_segments = 0usize;
-    let mut num_pixels = 0usize;
+fn rename_image<S: Read + Write>(
+    name: &Slot,
+    features: Features,
+    mut stream: S,
+    dir: &str,
+    args: &Args,
+) -> Result<(), ServeError> {
+    let mut destination = [0u8];
+    stream
+        .read_exact(&mut destination)
+        .map_err(connection("reading the destination slot"))?;
+    let destination = read_slot(destination[0], features, &mut stream)?;
 
-    let mut code_it = codes.iter().enumerate();
-    let mut segment_it = segments.iter_mut();
+    // both slots are held until the image has moved, and are locked in the same order by every
+    // move so that moves in opposite directions never wait for each other
+    let (first, second) = match name <= &destination {
+        true => (name, &destination),
+        false => (&destination, name),
+    };
+    let _first = lock_for_writing(dir, first, args)?;
+    let _second = match first == second {
+        true => None,
+        false => Some(lock_for_writing(dir, second, args)?),
+    };
 
-    while let Some((l, &lo)) = code_it.next() {
-        let r = codes
-            .iter()
-            .skip(l + 1)
-            .position(|&hi| hi != lo)
-            .unwrap_or(codes.len());
+    match args
+        .store()?
+        .rename(dir, name, &destination, !args.no_overwrite)
+    {
+        Ok(()) => {
+            // an image that was replaced by the move no longer takes any space
+            usage::invalidate(dir);
+            cache::rename(dir, name, &destination);
+            if args.logs(Verbosity::Normal) {
+                println!("Moved image_{}.bmp to image_{}.bmp", name, destination);
+            }
+        }
+        Err(err) => {
+            return Err(match err.kind() {
+                std::io::ErrorKind::NotFound => ServeError::NotFound,
+                std::io::ErrorKind::AlreadyExists => ServeError::SlotOccupied,
+                _ => storage(format!(
+                    "moving image_{}.bmp to image_{}.bmp",
+                    name, destination
+                ))(err),
+            })
+        }
+    }
 
-        let code = (lo & 0xF) as u16;
-        let count = ((r - l) & 0x1FF) as u16;
+    stream
+        .write_all(&[STATUS_OK])
+        .and_then(|()| stream.flush())
+        .map_err(connection("confirming the move"))
+}
 
-        let Some(segment) = segment_it.next() else {
-            break;
+/// Locks a slot that a request writes to, waiting for as long as the server is configured to for
+/// other writers of the slot to finish
+///
+/// # Arguments
+///
+/// * `dir` - Directory where images are stored
+/// * `name` - The slot to lock
+/// * `args` - Command line arguments of the server
+///
+/// # Errors
+///
+/// * [`ServeError::SlotBusy`] when the slot is still locked after the wait
+/// * [`ServeError::Storage`] when the lock file can not be created
+///
+fn lock_for_writing(dir: &str, name: &Slot, args: &Args) -> Result<SlotLock, ServeError> {
+    let lock_wait = std::time::Duration::from_millis(args.lock_wait_ms);
+    lock_slot_waiting(dir, name, lock_wait).map_err(|err| match err.kind() {
+        std::io::ErrorKind::AlreadyExists => ServeError::SlotBusy,
+        _ => storage(format!("locking image_{}.bmp", name))(err),
+    })
+}
+
+/// Copies the image in a slot to the slot requested by the client, and replies with a status byte
+///
+/// # Arguments
+///
+/// * `name` - The slot of the image to copy
+/// * `features` - Features that the connection negotiated, which tell how the destination is sent
+/// * `stream` - Connection with the client
+/// * `dir` - Directory where images are stored
+/// * `args` - Command line arguments of the server
+///
+fn copy_image<S: Read + Write>(
+    name: &Slot,
+    features: Features,
+    mut stream: S,
+    dir: &str,
+    args: &Args,
+) -> Result<(), ServeError> {
+    let mut destination = [0u8];
+    stream
+        .read_exact(&mut destination)
+        .map_err(connection("reading the destination slot"))?;
+    let destination = read_slot(destination[0], features, &mut stream)?;
+
+    // held until the image and the files derived from it have all been copied, so that a save of
+    // the destination never writes between them
+    let _lock = lock_for_writing(dir, &destination, args)?;
+
+    match args
+        .store()?
+        .copy(dir, name, &destination, !args.no_overwrite)
+    {
+        Ok(()) => {
+            // the copy takes as much space as the image it was copied from
+            usage::invalidate(dir);
+            cache::invalidate(dir, &destination);
+            if args.logs(Verbosity::Normal) {
+                println!("Copied image_{}.bmp to image_{}.bmp", name, destination);
+            }
+        }
+        Err(err) => {
+            return Err(match err.kind() {
+                std::io::ErrorKind::NotFound => ServeError::NotFound,
+                std::io::ErrorKind::AlreadyExists => ServeError::SlotOccupied,
+                _ => storage(format!(
+                    "copying image_{}.bmp to image_{}.bmp",
+                    name, destination
+                ))(err),
+            })
+        }
+    }
+
+    stream
+        .write_all(&[STATUS_OK])
+        .and_then(|()| stream.flush())
+        .map_err(connection("confirming the copy"))
+}
+
+/// Reads the authentication token presented by the client, and checks it against the server's token
+///
+/// The token is sent as a single length byte followed by the bytes of the token. Clients are never
+/// authenticated when the server has no token.
+///
+/// # Arguments
+///
+/// * `stream` - Connection with the client
+/// * `args` - Command line arguments of the server
+///
+fn authenticate<S: Read>(mut stream: S, args: &Args) -> bool {
+    let mut len = [0u8];
+    let Ok(()) = stream.read_exact(&mut len) else {
+        return false;
+    };
+    let mut token = vec![0u8; len[0] as usize];
+    let Ok(()) = stream.read_exact(&mut token) else {
+        return false;
+    };
+
+    let Some(expected) = &args.auth_token else {
+        return false;
+    };
+
+    // compare every byte, so the time taken does not reveal how much of the token was correct
+    expected.len() == token.len()
+        && expected
+            .bytes()
+            .zip(token.iter())
+            .fold(0, |diff, (a, &b)| diff | (a ^ b))
+            == 0
+}
+
+/// Saves an image sent from the client to the filesystem, or merges it into the image of the slot
+///
+/// # Arguments
+///
+/// * `height` - Number of rows in the image
+/// * `width` - Number of columns in the image
+/// * `name` - The slot of the image
+/// * `merge` - Color of the pixels of the received image that are left as they were in the image
+///   of the slot, for a merge (instead of a save)
+/// * `layout` - How the rows of the image are sent
+/// * `features` - Features that the connection negotiated
+/// * `stream` - Connection with the client
+/// * `peer` - Address of the client
+/// * `dir` - Directory to save image to
+/// * `args` - Command line arguments of the server
+///
+#[allow(clippy::too_many_arguments)]
+fn save_image<S: Read + Write>(
+    height: usize,
+    width: usize,
+    name: &Slot,
+    merge: Option<u16>,
+    layout: RowLayout,
+    features: Features,
+    mut stream: S,
+    peer: SocketAddr,
+    dir: &str,
+    args: &Args,
+) -> Result<(), ServeError> {
+    let store = args.store()?;
+    // merges into slots that can not be merged into are refused before the image is received (and
+    // checked again once the slot is locked, in case it was replaced in the meantime)
+    let merged_image = |store: &dyn Store| {
+        let image = cache::load_cached(dir, name, width, height, args.cache_slots, store);
+        image.map_err(|err| match err {
+            LoadError::NotFound => ServeError::NotFound,
+            LoadError::DimensionMismatch {
+                width: image_width,
+                height: image_height,
+            } => ServeError::MergeDimensionMismatch {
+                width,
+                height,
+                image_width,
+                image_height,
+            },
+            err => err.into(),
+        })
+    };
+    if merge.is_some() {
+        merged_image(store.as_ref())?;
+    }
+
+    // refuse images that can not be stored before receiving them, instead of failing halfway
+    let required = bmp_file_size(width, height, args.color_depth) + args.min_free_mb * MIB;
+    match (args.free_space)(dir) {
+        Ok(available) if available < required => {
+            return Err(ServeError::ServerFull {
+                required,
+                available,
+            })
+        }
+        Ok(_) => {}
+        Err(err) => eprintln!("Failed to check the free space of {}: {}", dir, err),
+    }
+
+    let mut img = Vec::with_capacity(height);
+
+    let started = std::time::Instant::now();
+    // number of rows that were sent compressed, and that would have been smaller in the other mode
+    let mut compressed_rows = 0usize;
+    let mut received = 0u64;
+    let mut suboptimal_rows = 0usize;
+
+    // rows are traced instead at the debug level, which would break up the bar
+    let mut pb = match SHOW_PROGRESS_BAR && args.verbosity() == Verbosity::Normal {
+        false => None,
+        true => {
+            let mut pb = ProgressBar::new(height as u64);
+            pb.set_width(Some(PROGRESS_BAR_WIDTH));
+            Some(pb)
+        }
+    };
+
+    // wide rows are always sent in the wide format, whatever the format of the server
+    let format = match layout {
+        RowLayout::Narrow => args.segment_format(),
+        RowLayout::Wide => SegmentFormat::WIDE,
+    };
+
+    // the buffers of a row are reused for every row, only the rows of the image itself are allocated
+    let mut codes = vec![0; width];
+    let mut buffers = RowBuffers {
+        mode: vec![0u8; layout.mode_len()],
+        segments_bytes: vec![0u8; segments_bytes_len(layout.max_segments(), layout)],
+        segments: vec![0u32; layout.max_segments()],
+    };
+
+    // clients with bugs (or a corrupted connection) may send codes that are not in the palette
+    let palette = args.palette();
+    let fallback_color = palette.code_2_color(args.fallback_code()).unwrap();
+    let mut substituted = 0usize;
+    // with --parallel, the received codes are kept until every row has been received
+    let mut code_rows = Vec::with_capacity(if args.parallel { height } else { 0 });
+
+    // with --lenient-save, rows that can not be received are filled with the background instead
+    let background_code = palette.nearest_code(args.blank_color());
+    let mut filled_rows = 0usize;
+    let mut connection_lost = false;
+    // the error of the first row that was lost, which refuses the save if no row was received
+    let mut first_loss = None;
+
+    for row in 0..height {
+        // nothing more can be understood from the client after a failed read, so every later row
+        // is filled as well (only malformed rows leave the connection where the next row starts)
+        let received_row = match connection_lost {
+            true => None,
+            false => {
+                match receive_row(&mut stream, row, &mut codes, &mut buffers, layout, format) {
+                    Ok(num_segments) => Some(num_segments),
+                    Err(err) if args.lenient_save => {
+                        connection_lost = !matches!(err, ServeError::MalformedRow { .. });
+                        eprintln!(
+                            "Filling row {} of image_{}.bmp from \"{}\" with the background: {}",
+                            row, name, peer, err
+                        );
+                        first_loss.get_or_insert(err);
+                        None
+                    }
+                    Err(err) => return Err(err),
+                }
+            }
+        };
+
+        match received_row {
+            Some(0) => {
+                received += (layout.mode_len() + width) as u64;
+                if compressed_row_size(&codes, format, layout).is_some_and(|size| size < width) {
+                    suboptimal_rows += 1;
+                }
+            }
+            Some(num_segments) => {
+                let segments_len = segments_bytes_len(num_segments, layout);
+                received += (layout.mode_len() + segments_len) as u64;
+                compressed_rows += 1;
+                if width < segments_len {
+                    suboptimal_rows += 1;
+                }
+            }
+            None => {
+                codes.fill(background_code);
+                filled_rows += 1;
+            }
+        }
+        if args.logs(Verbosity::Debug) {
+            match received_row {
+                Some(0) => println!("Received row {} raw", row),
+                Some(segments) => println!("Received row {} as {} segments", row, segments),
+                None => println!("Filled row {} with the background", row),
+            }
+        }
+        match args.parallel {
+            true => code_rows.push(codes.clone()),
+            false => {
+                let (colors, count) = codes_2_colors(&codes, palette, fallback_color);
+                substituted += count;
+                img.push(colors);
+            }
+        }
+
+        match &mut pb {
+            Some(pb) => pb.inc(),
+            None => 0,
         };
+    }
+    if let Some(pb) = &mut pb {
+        pb.finish_println("");
+    }
+    // an image without a single received row would only replace the slot with the background
+    if filled_rows == height {
+        if let Some(err) = first_loss {
+            return Err(err);
+        }
+    }
+    // rows are independent once received, so they can be converted in any order
+    if args.parallel {
+        let converted: Vec<_> = code_rows
+            .par_iter()
+            .map(|codes| codes_2_colors(codes, palette, fallback_color))
+            .collect();
+        for (colors, count) in converted {
+            substituted += count;
+            img.push(colors);
+        }
+    }
+    if filled_rows > 0 {
+        eprintln!(
+            "Filled {} of {} rows of image_{}.bmp with the background",
+            filled_rows, height, name
+        );
+    }
+    if substituted > 0 {
+        eprintln!(
+            "Stored code {} for {} pixels of image_{}.bmp whose codes are not in the palette",
+            args.fallback_code(),
+            substituted,
+            name
+        );
+    }
+    if let Some(mirror) = args.mirror {
+        img = mirror.apply(&img);
+    }
+
+    // the directory of a device is only created once it saves its first image
+    std::fs::create_dir_all(dir).map_err(storage(format!("creating image directory {}", dir)))?;
 
-        *segment = (count << 4) | code;
-        num_segments += 1;
-        num_pixels += r - l;
+    // held until the image and the files derived from it have all been written
+    let _lock = lock_for_writing(dir, name, args)?;
 
-        code_it.nth(r - 1);
+    if let Some(transparent) = merge {
+        img = overlay_image(&merged_image(store.as_ref())?, &img, transparent);
     }
 
-    (num_segments, num_pixels)
+    let duration = started.elapsed();
+    if args.logs(Verbosity::Normal) {
+        println!(
+        "Received {} rows ({} compressed) in {:.2?}, {} rows would have been smaller in the other mode",
+        height, compressed_rows, duration, suboptimal_rows
+    );
+    }
+
+    let metadata = SlotMetadata {
+        v: METADATA_VERSION,
+        timestamp_ms: std::time::SystemTime::now()
+            .duration_since(std::time::UNIX_EPOCH)
+            .map_or(0, |since| since.as_millis() as u64),
+        peer: peer.to_string(),
+        width,
+        height,
+        compressed_rows,
+        duration_ms: duration.as_millis() as u64,
+    };
+
+    // clients may save again without changing anything, which would only wear the disk and fill
+    // the backups and history with copies
+    let digest = pixels_digest(&img);
+    if !args.always_write
+        && stored_pixels_digest(dir, name, width, height, store.as_ref()) == Some(digest)
+    {
+        if args.logs(Verbosity::Normal) {
+            println!("image_{}.bmp unchanged, skipped", name);
+        }
+    } else {
+        store.write_slot(dir, name, &img, &metadata)?;
+        remember_pixels_digest(dir, name, digest);
+
+        // the next load of the slot is served from memory, unless the stored colors differ from
+        // the received ones (which 5-5-5 images do)
+        match args.color_depth {
+            ColorFormat::Rgb565 => cache::store(dir, name, img, args.cache_slots, args.preload),
+            ColorFormat::Rgb555 => cache::invalidate(dir, name),
+        }
+    }
+
+    // let the client know how well its choice of modes worked (older clients can ignore this), and
+    // when it asked for it, whether any of its rows were lost
+    let mut reply = Vec::with_capacity(3);
+    if features.contains(Features::SAVE_STATUS) {
+        reply.push(match filled_rows {
+            0 => STATUS_OK,
+            _ => STATUS_PARTIAL_SAVE,
+        });
+    }
+    reply.extend_from_slice(&(suboptimal_rows.min(u16::MAX as usize) as u16).to_le_bytes());
+    stream
+        .write_all(&reply)
+        .and_then(|()| stream.flush())
+        .map_err(connection("sending the mode feedback"))?;
+
+    let opcode = match merge {
+        Some(_) => OP_MERGE,
+        None => OP_SAVE,
+    };
+    log_transfer(
+        args,
+        peer,
+        opcode,
+        name,
+        (width, height),
+        received,
+        duration,
+    );
+    Ok(())
+}
+
+/// Buffers that the rows of a save are received into, which are reused for every row
+struct RowBuffers {
+    /// The mode of the row
+    mode: Vec<u8>,
+    /// The segments of a compressed row, as they are sent
+    segments_bytes: Vec<u8>,
+    /// The segments of a compressed row
+    segments: Vec<u32>,
+}
+
+/// Receives a row of a save, and gets the number of its segments (0 for a raw row)
+///
+/// # Arguments
+///
+/// * `stream` - Connection with the client
+/// * `row` - Index of the row in the image
+/// * `codes` - Buffer as wide as the image, where the codes of the row are stored
+/// * `buffers` - Buffers that the mode and the segments of the row are read into
+/// * `layout` - How the row is sent
+/// * `format` - How the code and the count are packed into each segment
+///
+/// # Errors
+///
+/// * [`ServeError::IncompleteRead`] when the connection fails before the whole row is read
+/// * [`ServeError::MalformedRow`] when the segments of the row do not cover it exactly (after
+///   which the next row can still be read)
+///
+fn receive_row<S: Read>(
+    mut stream: S,
+    row: usize,
+    codes: &mut [u8],
+    buffers: &mut RowBuffers,
+    layout: RowLayout,
+    format: SegmentFormat,
+) -> Result<usize, ServeError> {
+    let mode = &mut buffers.mode;
+    read_counted(
+        &mut stream,
+        mode,
+        format!("reading the mode of row {}", row),
+    )?;
+
+    let num_segments = match layout {
+        RowLayout::Narrow => mode[0] as usize,
+        RowLayout::Wide => u16::from_le_bytes([mode[0], mode[1]]) as usize,
+    };
+    if num_segments == 0 {
+        read_counted(&mut stream, codes, format!("reading row {}", row))?;
+        return Ok(0);
+    }
+
+    let segments_bytes = &mut buffers.segments_bytes[..segments_bytes_len(num_segments, layout)];
+    let segments = &mut buffers.segments[..num_segments];
+    read_counted(
+        &mut stream,
+        segments_bytes,
+        format!("reading compressed row {}", row),
+    )?;
+
+    segments
+        .iter_mut()
+        .zip(segments_bytes.chunks_exact(layout.segment_len()))
+        .for_each(|(seg, bytes)| {
+            *seg = match layout {
+                RowLayout::Narrow => u16::from_le_bytes([bytes[0], bytes[1]]).into(),
+                RowLayout::Wide => u32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]),
+            }
+        });
+
+    // the segments must cover the row exactly, so no pixels are left over from the previous row
+    match uncompress(segments, codes, format) {
+        Ok(_) => Ok(num_segments),
+        Err(err) => Err(ServeError::MalformedRow {
+            row,
+            pixels: err.pixels(),
+            width: codes.len(),
+        }),
+    }
+}
+
+/// Converts a row of codes to colors
+///
+/// # Arguments
+///
+/// * `codes` - The row, as a slice of codes
+/// * `palette` - The palette that the codes belong to
+/// * `fallback_color` - Color that is stored in place of codes which are not in the palette
+///
+/// # Returns
+///
+/// The colors of the row, and the number of codes that were not in the palette
+///
+fn codes_2_colors(codes: &[u8], palette: &Palette, fallback_color: u16) -> (Vec<u16>, usize) {
+    let mut substituted = 0;
+    let colors = codes
+        .iter()
+        .map(|&v| {
+            palette.code_2_color(v).unwrap_or_else(|| {
+                substituted += 1;
+                fallback_color
+            })
+        })
+        .collect();
+    (colors, substituted)
+}
+
+/// Gets the number of bytes that a compressed row with the given number of segments occupies
+///
+/// # Arguments
+///
+/// * `num_segments` - Number of segments in the row (the mode of the row)
+/// * `layout` - How the row is sent
+///
+fn segments_bytes_len(num_segments: usize, layout: RowLayout) -> usize {
+    layout.segment_len() * num_segments
+}
+
+/// Gets the number of bytes that a row would occupy if it was sent compressed, if it can be compressed
+///
+/// A row can only be compressed if it can be represented by at most
+/// [`RowLayout::max_segments`] segments (255 unless the row is wide)
+///
+/// # Arguments
+///
+/// * `codes` - The row, as a slice of codes
+/// * `format` - How the code and the count are packed into each segment
+/// * `layout` - How the row would be sent
+///
+fn compressed_row_size(codes: &[u8], format: SegmentFormat, layout: RowLayout) -> Option<usize> {
+    // a row never needs more segments than it has pixels
+    let mut segments = vec![0u32; codes.len().min(layout.max_segments())];
+    let (num_segments, num_pixels) = compress(&mut segments, codes, format);
+
+    match num_pixels == codes.len() {
+        true => Some(segments_bytes_len(num_segments, layout)),
+        false => None,
+    }
+}
+
+/// Loads an image from the filesystem to the client
+///
+/// # Arguments
+///
+/// * `expected_height` - Number of rows in the image as expected by the client
+/// * `expected_width` - Number of columns in the image as expected by the client
+/// * `stream` - Connection with the client
+/// * `name` - The slot of the image
+/// * `most_recent` - Whether the header asked for the most recently saved image instead (with
+///   [`MOST_RECENT_SLOT`])
+/// * `flip` - Reflection that the client asked for with the flags of the request, if any
+/// * `peer` - Address of the client
+/// * `dir` - Directory to retrieve the image from
+/// * `args` - Command line arguments of the server
+///
+#[allow(clippy::too_many_arguments)]
+fn load_image<S: Read + Write>(
+    expected_height: usize,
+    expected_width: usize,
+    name: &Slot,
+    most_recent: bool,
+    flip: Option<Mirror>,
+    mut stream: S,
+    peer: SocketAddr,
+    dir: &str,
+    args: &Args,
+) -> Result<(), ServeError> {
+    let ack_interval = read_ack_interval(&mut stream)?;
+    let store = args.store()?;
+
+    // the reserved slot refers to whichever image was saved most recently
+    let slot = match most_recent {
+        true => store.most_recent(dir),
+        false => Some(name.clone()),
+    };
+
+    // the client asks for the dimensions of the transformed image, and rotations swap them
+    let (stored_width, stored_height) = match args.load_transform {
+        Some(transform) if transform.swaps_dimensions() => (expected_height, expected_width),
+        _ => (expected_width, expected_height),
+    };
+
+    let img = match slot.map_or(Err(LoadError::NotFound), |slot| {
+        let load = |width, height| {
+            cache::load_cached(dir, &slot, width, height, args.cache_slots, store.as_ref())
+        };
+        match load(stored_width, stored_height) {
+            Err(LoadError::DimensionMismatch { width, height }) if args.scale_on_mismatch => {
+                if args.logs(Verbosity::Normal) {
+                    println!(
+                        "Scaling image_{}.bmp from {} x {} to {} x {}",
+                        slot, height, width, stored_height, stored_width
+                    );
+                }
+                load(width, height)
+                    .map(|img| Arc::new(scale_nearest(&img, stored_width, stored_height)))
+            }
+            result => result,
+        }
+    }) {
+        Ok(img) => transform_loaded(img, flip, args),
+        Err(LoadError::NotFound) | Err(LoadError::DimensionMismatch { .. }) => Arc::new(
+            blank_image(expected_width, expected_height, args.blank_color()),
+        ),
+        Err(err) => return Err(err.into()),
+    };
+
+    let (sent, duration) = send_rows(&img, name, stream, ack_interval, args)?;
+    log_transfer(
+        args,
+        peer,
+        OP_LOAD,
+        name,
+        (expected_width, expected_height),
+        sent,
+        duration,
+    );
+    Ok(())
+}
+
+/// Sends a region of the image stored in a slot to the client, which is the image as a load of
+/// the slot (with the same flags) would send it, so after `--load-transform` and any reflection
+///
+/// The offset of the region follows the slot of the request, as the column and the row of its top
+/// left corner (both little-endian `u16`s), and the dimensions in the header are those of the
+/// region. The rows of the region are then sent like those of a load.
+///
+/// # Arguments
+///
+/// * `height` - Number of rows of the region
+/// * `width` - Number of columns of the region
+/// * `name` - The slot of the image
+/// * `most_recent` - Whether the header asked for the most recently saved image instead (with
+///   [`MOST_RECENT_SLOT`])
+/// * `flip` - Reflection that the client asked for with the flags of the request, if any
+/// * `stream` - Connection with the client
+/// * `peer` - Address of the client
+/// * `dir` - Directory to retrieve the image from
+/// * `args` - Command line arguments of the server
+///
+#[allow(clippy::too_many_arguments)]
+fn crop_image<S: Read + Write>(
+    height: usize,
+    width: usize,
+    name: &Slot,
+    most_recent: bool,
+    flip: Option<Mirror>,
+    mut stream: S,
+    peer: SocketAddr,
+    dir: &str,
+    args: &Args,
+) -> Result<(), ServeError> {
+    let mut offset = [0u8; 4];
+    stream
+        .read_exact(&mut offset)
+        .map_err(connection("reading the offset of the region"))?;
+    let x = u16::from_le_bytes([offset[0], offset[1]]) as usize;
+    let y = u16::from_le_bytes([offset[2], offset[3]]) as usize;
+    let ack_interval = read_ack_interval(&mut stream)?;
+
+    let store = args.store()?;
+    let slot = match most_recent {
+        true => store.most_recent(dir),
+        false => Some(name.clone()),
+    }
+    .ok_or(ServeError::NotFound)?;
+
+    let (stored_width, stored_height) = store.dimensions(dir, &slot).map_err(|err| match err {
+        LoadError::NotFound => ServeError::NotFound,
+        err => err.into(),
+    })?;
+    let img = cache::load_cached(
+        dir,
+        &slot,
+        stored_width,
+        stored_height,
+        args.cache_slots,
+        store.as_ref(),
+    )
+    .map_err(|err| match err {
+        LoadError::NotFound => ServeError::NotFound,
+        err => err.into(),
+    })?;
+    let img = transform_loaded(img, flip, args);
+
+    let (image_width, image_height) = (img.first().map_or(0, |row| row.len()), img.len());
+    if x + width > image_width || y + height > image_height {
+        return Err(ServeError::RegionOutOfBounds {
+            x,
+            y,
+            width,
+            height,
+            image_width,
+            image_height,
+        });
+    }
+    let region: Vec<Vec<u16>> = img[y..y + height]
+        .iter()
+        .map(|row| row[x..x + width].to_vec())
+        .collect();
+
+    let (sent, duration) = send_rows(&region, name, stream, ack_interval, args)?;
+    log_transfer(args, peer, OP_CROP, name, (width, height), sent, duration);
+    Ok(())
+}
+
+/// Transforms a loaded image into the image that the client is sent, which is rotated or flipped
+/// by `--load-transform` first, and then mirrored as the client asked for (so that the flags of a
+/// request mirror the image as the client sees it, whichever way the display is mounted)
+///
+/// # Arguments
+///
+/// * `img` - The image as it is stored
+/// * `flip` - Reflection that the client asked for, if any
+/// * `args` - Command line arguments of the server
+///
+fn transform_loaded(
+    img: Arc<Vec<Vec<u16>>>,
+    flip: Option<Mirror>,
+    args: &Args,
+) -> Arc<Vec<Vec<u16>>> {
+    let img = match args.load_transform {
+        Some(transform) => Arc::new(transform.apply(&img)),
+        None => img,
+    };
+    match flip {
+        Some(flip) => Arc::new(flip.apply(&img)),
+        None => img,
+    }
+}
+
+/// Fills a buffer from the connection like [`Read::read_exact`], but reports how many of its bytes
+/// arrived (and whether the connection timed out or was closed) when it fails, so that flaky links
+/// can be told apart from clients that stop in the middle of a row
+///
+/// # Arguments
+///
+/// * `stream` - Connection with the client
+/// * `buffer` - The buffer to fill
+/// * `during` - Description of the step that reads the buffer (such as `"reading row 3"`)
+///
+/// # Errors
+///
+/// * [`ServeError::IncompleteRead`] when the connection fails before the buffer is filled
+///
+fn read_counted<S: Read>(
+    mut stream: S,
+    buffer: &mut [u8],
+    during: impl Into<String>,
+) -> Result<(), ServeError> {
+    let mut received = 0;
+    while received < buffer.len() {
+        let source = match stream.read(&mut buffer[received..]) {
+            Ok(0) => std::io::Error::from(std::io::ErrorKind::UnexpectedEof),
+            Ok(len) => {
+                received += len;
+                continue;
+            }
+            Err(err) if err.kind() == std::io::ErrorKind::Interrupted => continue,
+            Err(err) => err,
+        };
+        return Err(ServeError::IncompleteRead {
+            during: during.into(),
+            received,
+            expected: buffer.len(),
+            failure: ReadFailure::of(&source),
+            source,
+        });
+    }
+    Ok(())
+}
+
+/// Reads the number of rows after which the client acknowledges the rows that it is sent
+///
+/// # Arguments
+///
+/// * `stream` - Connection with the client
+///
+fn read_ack_interval<S: Read>(mut stream: S) -> Result<usize, ServeError> {
+    // the client picks how many rows it can buffer before it has to acknowledge them
+    let mut ack_interval = [0u8];
+    stream
+        .read_exact(&mut ack_interval)
+        .map_err(connection("reading the acknowledgement interval"))?;
+    Ok(match ack_interval[0] {
+        0 => DEFAULT_ACK_INTERVAL,
+        interval => (interval as usize).min(MAX_ACK_INTERVAL),
+    })
+}
+
+/// Sends the rows of an image to the client as codes, waiting for the client to acknowledge them
+/// after every `ack_interval` rows (and after the last row)
+///
+/// # Arguments
+///
+/// * `img` - The image to send
+/// * `name` - The slot of the image
+/// * `stream` - Connection with the client
+/// * `ack_interval` - Number of rows after which the client acknowledges the rows it has received
+/// * `args` - Command line arguments of the server
+///
+/// # Returns
+///
+/// The number of bytes of the rows that were sent, and how long sending them took
+///
+fn send_rows<S: Read + Write>(
+    img: &[Vec<u16>],
+    name: &Slot,
+    mut stream: S,
+    ack_interval: usize,
+    args: &Args,
+) -> Result<(u64, std::time::Duration), ServeError> {
+    let palette = args.palette();
+
+    // rows are traced instead at the debug level, which would break up the bar
+    let mut pb = match SHOW_PROGRESS_BAR && args.verbosity() == Verbosity::Normal {
+        false => None,
+        true => {
+            let mut pb = ProgressBar::new(img.len() as u64);
+            pb.set_width(Some(PROGRESS_BAR_WIDTH));
+            Some(pb)
+        }
+    };
+
+    let mut codes = Vec::with_capacity(img.first().map_or(0, |row| row.len()));
+    let mut approximated = 0;
+    let started = std::time::Instant::now();
+    let mut sent = 0;
+
+    for (i, row) in img.iter().enumerate() {
+        // images edited outside of the canvas may contain colors that are not in the palette
+        codes.clear();
+        codes.extend(row.iter().map(|&v| {
+            palette.color_2_code(v).unwrap_or_else(|| {
+                approximated += 1;
+                palette.nearest_code(v)
+            })
+        }));
+
+        stream
+            .write_all(&codes)
+            .and_then(|()| stream.flush())
+            .map_err(connection(format!("sending row {}", i)))?;
+        sent += codes.len() as u64;
+        if args.logs(Verbosity::Debug) {
+            println!("Sent row {} ({} bytes so far)", i, sent);
+        }
+        if let Some(rate) = args.load_rate_bytes_per_sec {
+            pace(started, sent, rate);
+        }
+
+        if (i % ack_interval) == 0 {
+            stream
+                .read_exact(&mut [0u8])
+                .map_err(connection(format!("waiting for confirmation of row {}", i)))?;
+        }
+        match &mut pb {
+            Some(pb) => pb.inc(),
+            None => 0,
+        };
+    }
+
+    stream
+        .read_exact(&mut [0u8])
+        .map_err(connection("waiting for the final confirmation"))?;
+    if let Some(pb) = &mut pb {
+        pb.finish_println("");
+    }
+    if approximated > 0 && args.logs(Verbosity::Normal) {
+        println!(
+            "Sent the nearest palette colors for {} pixels of image_{}.bmp",
+            approximated, name
+        );
+    }
+    Ok((sent, started.elapsed()))
+}
+
+/// Appends a completed save or load to the transfer log, if there is one
+///
+/// # Arguments
+///
+/// * `args` - Command line arguments of the server
+/// * `peer` - Address of the client
+/// * `opcode` - Opcode of the request
+/// * `name` - The slot of the image
+/// * `(width, height)` - Dimensions of the image
+/// * `bytes` - Number of bytes of the rows that were transferred
+/// * `duration` - Time taken by the transfer of the rows
+///
+fn log_transfer(
+    args: &Args,
+    peer: SocketAddr,
+    opcode: u8,
+    name: &Slot,
+    (width, height): (usize, usize),
+    bytes: u64,
+    duration: std::time::Duration,
+) {
+    let Some(path) = &args.transfer_log else {
+        return;
+    };
+    let transfer = Transfer {
+        timestamp_ms: std::time::SystemTime::now()
+            .duration_since(std::time::UNIX_EPOCH)
+            .map_or(0, |since| since.as_millis() as u64),
+        peer: peer.ip(),
+        opcode,
+        slot: name.clone(),
+        width,
+        height,
+        bytes,
+        duration_ms: duration.as_millis() as u64,
+    };
+    if let Err(err) = append_transfer(path, &transfer) {
+        eprintln!("Failed to append to the transfer log {}: {}", path, err);
+    }
+}
+
+/// Computes the checksum of an image, as described by [`OP_CHECKSUM`]
+///
+/// # Arguments
+///
+/// * `img` - The image, as 16-bit colors
+/// * `palette` - The palette that the colors of the image are sent as codes of
+///
+fn image_checksum(img: &[Vec<u16>], palette: &Palette) -> u32 {
+    let width = img.first().map_or(0, |row| row.len());
+    let mut hasher = crc32fast::Hasher::new();
+    hasher.update(&(img.len() as u16).to_le_bytes());
+    hasher.update(&(width as u16).to_le_bytes());
+    for row in img {
+        let codes: Vec<u8> = row
+            .iter()
+            .map(|&v| {
+                palette
+                    .color_2_code(v)
+                    .unwrap_or_else(|| palette.nearest_code(v))
+            })
+            .collect();
+        hasher.update(&codes);
+    }
+
+    match hasher.finalize() {
+        EMPTY_CHECKSUM => EMPTY_CHECKSUM + 1,
+        checksum => checksum,
+    }
+}
+
+/// Sends the checksum of the image stored in a slot to the client, without sending its pixels
+///
+/// The image is loaded at the dimensions that it is stored at, and transformed like a load would
+/// (the dimensions are part of the checksum), so the checksum changes whenever a load of the slot
+/// would send something else.
+///
+/// # Arguments
+///
+/// * `name` - The slot of the image
+/// * `most_recent` - Whether the header asked for the most recently saved image instead (with
+///   [`MOST_RECENT_SLOT`])
+/// * `stream` - Connection with the client
+/// * `dir` - Directory where images are stored
+/// * `args` - Command line arguments of the server
+///
+fn send_checksum<S: Read + Write>(
+    name: &Slot,
+    most_recent: bool,
+    mut stream: S,
+    dir: &str,
+    args: &Args,
+) -> Result<(), ServeError> {
+    let store = args.store()?;
+    let slot = match most_recent {
+        true => store.most_recent(dir),
+        false => Some(name.clone()),
+    };
+
+    let checksum = match slot.map_or(Err(LoadError::NotFound), |slot| {
+        let (width, height) = store.dimensions(dir, &slot)?;
+        cache::load_cached(dir, &slot, width, height, args.cache_slots, store.as_ref())
+    }) {
+        Ok(img) => match args.load_transform {
+            Some(transform) => image_checksum(&transform.apply(&img), args.palette()),
+            None => image_checksum(&img, args.palette()),
+        },
+        Err(LoadError::NotFound) => EMPTY_CHECKSUM,
+        Err(err) => return Err(err.into()),
+    };
+
+    let mut reply = vec![STATUS_OK];
+    reply.extend_from_slice(&checksum.to_le_bytes());
+    stream
+        .write_all(&reply)
+        .and_then(|()| stream.flush())
+        .map_err(connection("sending the checksum"))
+}
+
+/// Sleeps until sending the given number of bytes since the transfer started has taken long enough
+/// to stay under the given rate
+///
+/// The time spent waiting for acknowledgements is part of the elapsed time, so the round trips
+/// slow the transfer down only when they alone would keep it under the rate.
+///
+/// # Arguments
+///
+/// * `started` - When the first byte of the transfer was sent
+/// * `sent` - Number of bytes sent so far
+/// * `rate` - Largest number of bytes to send per second
+///
+fn pace(started: std::time::Instant, sent: u64, rate: u64) {
+    let due = std::time::Duration::from_secs_f64(sent as f64 / rate as f64);
+    if let Some(ahead) = due.checked_sub(started.elapsed()) {
+        thread::sleep(ahead);
+    }
+}
+
+/// Ways in which the segments of a compressed row fail to cover the row exactly
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum UncompressError {
+    /// The segments cover more pixels than the row has, of which only those within the row were
+    /// stored
+    Overflow { pixels: usize },
+    /// The segments cover fewer pixels than the row has, so the rest of the row was left as it was
+    Underflow { pixels: usize },
+}
+
+impl UncompressError {
+    /// Gets the number of pixels that the segments cover
+    pub fn pixels(self) -> usize {
+        match self {
+            Self::Overflow { pixels } | Self::Underflow { pixels } => pixels,
+        }
+    }
+}
+
+/// Uncompress a row from segment-representation into its pixel-representation and get the number of pixels
+///
+/// Consecutive segments of the same code are a single longer run, which is how runs longer than a
+/// segment can hold (511 pixels in the default format) are sent.
+///
+/// # Arguments
+///
+/// * `segments` - Slice of integers, each representing a valid segment with a code and size
+/// * `codes` - Mutable slice of 8-bit integers, where the uncompressed data must be stored
+/// * `format` - How the code and the count are packed into each segment (which must fit in a
+///   segment)
+///
+/// # Errors
+///
+/// * [`UncompressError::Overflow`] when the segments over-run the row, which are only stored up to
+///   its end (but all of their pixels are counted)
+/// * [`UncompressError::Underflow`] when the segments end before the row does
+///
+pub fn uncompress<T: Segment>(
+    segments: &[T],
+    codes: &mut [u8],
+    format: SegmentFormat,
+) -> Result<usize, UncompressError> {
+    let mut idx = 0;
+
+    for &segment in segments.iter() {
+        let (code, count) = format.unpack(segment.bits());
+
+        codes
+            .iter_mut()
+            .skip(idx)
+            .take(count)
+            .for_each(|v| *v = code);
+        idx += count;
+    }
+
+    match idx.cmp(&codes.len()) {
+        std::cmp::Ordering::Equal => Ok(idx),
+        std::cmp::Ordering::Greater => Err(UncompressError::Overflow { pixels: idx }),
+        std::cmp::Ordering::Less => Err(UncompressError::Underflow { pixels: idx }),
+    }
+}
+
+/// Compresse a row from pixel-representation into its segment-representation and get the number of segments, pixels
+///
+/// The segments are in the order of the row, and each covers the longest run of the same code that
+/// starts at the first pixel not covered by the segments before it. A segment holds at most
+/// [`SegmentFormat::max_count`] pixels (511 in the default format), which is a limit of the wire
+/// format, so longer runs are split into consecutive segments of the same code (and consecutive
+/// segments only have the same code when the first of them is full). When `segments` is too short
+/// for the row, only the pixels of the segments that fit are counted.
+///
+/// # Arguments
+///
+/// * `segments` - Mutable slice of integers, where the compressed data must be stored
+/// * `codes` - Slice of 8-bit integers, each representing a valid code
+/// * `format` - How the code and the count are packed into each segment (which must fit in a
+///   segment)
+///
+pub fn compress<T: Segment>(
+    segments: &mut [T],
+    codes: &[u8],
+    format: SegmentFormat,
+) -> (usize, usize) {
+    let mut num_segments = 0usize;
+    let mut num_pixels = 0usize;
+
+    let max_count = format.max_count();
+    let mut segment_it = segments.iter_mut();
+
+    while let Some(&code) = codes.get(num_pixels) {
+        let run = codes[num_pixels..]
+            .iter()
+            .take_while(|&&other| other == code)
+            .count();
+
+        for count in (0..run)
+            .step_by(max_count)
+            .map(|start| (run - start).min(max_count))
+        {
+            let Some(segment) = segment_it.next() else {
+                return (num_segments, num_pixels);
+            };
+            *segment = T::from_bits(format.pack(code, count));
+            num_segments += 1;
+            num_pixels += count;
+        }
+    }
+
+    (num_segments, num_pixels)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::temp_dir;
+
+    /// Connection which replays the bytes sent by a client, and collects the replies of the server
+    struct MockStream {
+        input: std::io::Cursor<Vec<u8>>,
+        output: Vec<u8>,
+    }
+
+    impl Read for MockStream {
+        fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+            self.input.read(buf)
+        }
+    }
+
+    impl Write for MockStream {
+        fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+            self.output.write(buf)
+        }
+
+        fn flush(&mut self) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
+
+    /// Serves a single request, and gets the bytes that the server replied with
+    fn serve(args: &Args, input: Vec<u8>) -> Vec<u8> {
+        let mut stream = MockStream {
+            input: std::io::Cursor::new(input),
+            output: Vec::new(),
+        };
+        let peer = "192.168.1.20:50123".parse().unwrap();
+        if let Err(err) = serve_request(&mut stream, peer, args) {
+            report_error(&mut stream, peer, &err);
+        }
+        stream.output
+    }
+
+    /// Serves a request on a connection that first negotiated the given features (all of which
+    /// the server must support), and gets what was sent after the reply to the negotiation
+    fn serve_negotiated(args: &Args, features: Features, input: Vec<u8>) -> Vec<u8> {
+        let mut negotiated = vec![OP_NEGOTIATE];
+        negotiated.extend_from_slice(&features.bits().to_le_bytes());
+        negotiated.push(0);
+        negotiated.extend_from_slice(&input);
+
+        let output = serve(args, negotiated);
+        let mut reply = vec![STATUS_OK];
+        reply.extend_from_slice(&features.bits().to_le_bytes());
+        assert_eq!(output[..5], reply);
+        output[5..].to_vec()
+    }
+
+    proptest::proptest! {
+        // clients with bugs (or anyone else who connects) may send anything, which must be refused
+        // with a status byte or a closed connection, never with a panic. Requests start with a
+        // header of small dimensions, so that they get past the header to the rows of images.
+        #[test]
+        fn malformed_requests_never_panic(
+            opcode in proptest::prop_oneof![
+                proptest::sample::select(SUPPORTED_OPCODES.to_vec()),
+                proptest::arbitrary::any::<u8>(),
+            ],
+            slot in proptest::arbitrary::any::<u8>(),
+            height in 1..=4u8,
+            width in 1..=4u8,
+            body in proptest::collection::vec(proptest::arbitrary::any::<u8>(), 0..96),
+        ) {
+            let dir = tempfile::tempdir().unwrap();
+            let args = Args::parse_from([
+                "canvas-server",
+                "--image-dir",
+                &dir.path().to_string_lossy(),
+                "--lock-wait-ms",
+                "0",
+                "--quiet",
+            ]);
+
+            let mut input = vec![opcode, slot, height, 0, width, 0];
+            input.extend_from_slice(&body);
+            serve(&args, input.clone());
+            // and once more, now that the first request may have saved an image to load
+            serve(&args, input);
+        }
+    }
+
+    #[test]
+    fn save_writes_metadata() {
+        let dir = temp_dir("save_writes_metadata");
+        let args = Args::parse_from(["canvas-server", "--image-dir", &dir]);
+        let peer: SocketAddr = "192.168.1.20:50123".parse().unwrap();
+
+        // one raw row, and one compressed row made of a single segment of 3 pixels of code 6
+        let mut input = vec![0, 0, 0, 0];
+        input.push(1);
+        input.extend_from_slice(&(6u16 | (3 << 4)).to_le_bytes());
+        let mut stream = MockStream {
+            input: std::io::Cursor::new(input),
+            output: Vec::new(),
+        };
+
+        let before = std::time::SystemTime::now()
+            .duration_since(std::time::UNIX_EPOCH)
+            .unwrap()
+            .as_millis() as u64;
+        save_image(
+            2,
+            3,
+            &Slot::Number(4),
+            None,
+            RowLayout::Narrow,
+            Features::NONE,
+            &mut stream,
+            peer,
+            &dir,
+            &args,
+        )
+        .unwrap();
+
+        assert_eq!(
+            load_slot(&dir, &Slot::Number(4), 3, 2, &Palette::BUILTIN).unwrap(),
+            vec![vec![0xF800; 3], vec![0xFFFF; 3]]
+        );
+
+        let metadata = read_metadata(&dir, &Slot::Number(4)).unwrap();
+        assert_eq!(metadata.v, METADATA_VERSION);
+        assert_eq!(metadata.peer, "192.168.1.20:50123");
+        assert_eq!((metadata.width, metadata.height), (3, 2));
+        assert_eq!(metadata.compressed_rows, 1);
+        assert!(metadata.timestamp_ms >= before);
+        assert!(metadata.duration_ms < 60_000);
+    }
+
+    #[test]
+    fn traversing_slot_names_are_refused() {
+        let dir = temp_dir("traversing_slot_names_are_refused");
+        let images = format!("{dir}/images");
+        let args = Args::parse_from(["canvas-server", "--image-dir", &images]);
+
+        // a save of a single pixel of code 0, named with the given name
+        for name in ["../../etc/passwd", "../image_0", ".."] {
+            let mut input = vec![OP_SAVE, NAMED_SLOT, 1, 0, 1, 0, name.len() as u8];
+            input.extend_from_slice(name.as_bytes());
+            input.extend_from_slice(&[0, 0]);
+            assert_eq!(
+                serve_negotiated(&args, Features::NAMED_SLOTS, input),
+                [STATUS_BAD_REQUEST]
+            );
+        }
+
+        // nothing was written, not even the image directory
+        assert_eq!(std::fs::read_dir(&dir).unwrap().count(), 0);
+    }
+
+    #[test]
+    fn wide_and_legacy_slots_interoperate() {
+        let dir = temp_dir("wide_and_legacy_slots_interoperate");
+        let args = Args::parse_from(["canvas-server", "--image-dir", &dir]);
+
+        // saves a single raw pixel with the given code, to the slot given after the opcode
+        let save = |slot: &[u8], code: u8| {
+            let mut input = vec![OP_SAVE];
+            input.extend_from_slice(&slot[..1]);
+            input.extend_from_slice(&[1, 0, 1, 0]);
+            input.extend_from_slice(&slot[1..]);
+            input.extend_from_slice(&[0, code]);
+            assert_eq!(serve_negotiated(&args, Features::WIDE_SLOTS, input), [0, 0]);
+        };
+        // loads a single pixel from the slot given after the opcode, and gets its code
+        let load = |slot: &[u8]| {
+            let mut input = vec![OP_LOAD];
+            input.extend_from_slice(&slot[..1]);
+            input.extend_from_slice(&[1, 0, 1, 0]);
+            input.extend_from_slice(&slot[1..]);
+            input.extend_from_slice(&[0, 1, 1]);
+            serve_negotiated(&args, Features::WIDE_SLOTS, input)
+        };
+
+        save(&[WIDE_SLOT, 0xFF, 0x00], 1);
+        save(&[WIDE_SLOT, 0x00, 0x01], 2);
+        save(&[WIDE_SLOT, 0xFF, 0xFF], 3);
+        assert_eq!(
+            list_slots(&dir),
+            [255, 256, 65535].map(Slot::Number).to_vec()
+        );
+        assert_eq!(load(&[WIDE_SLOT, 0x00, 0x01]), [2]);
+        assert_eq!(load(&[WIDE_SLOT, 0xFF, 0xFF]), [3]);
+
+        // both forms refer to the same low slots
+        save(&[3], 4);
+        assert_eq!(load(&[WIDE_SLOT, 3, 0]), [4]);
+        save(&[WIDE_SLOT, 3, 0], 5);
+        assert_eq!(load(&[3]), [5]);
+
+        // the slot numbers reserved in the header are still ordinary slots in the wide form
+        assert_eq!(load(&[WIDE_SLOT, 0xFF, 0x00]), [1]);
+        assert_eq!(load(&[MOST_RECENT_SLOT]), [5]);
+        assert_eq!(load(&[WIDE_SLOT, 0xFE, 0x00]), [8]);
+        save(&[WIDE_SLOT, 0xFE, 0x00], 6);
+        assert_eq!(load(&[WIDE_SLOT, 0xFE, 0x00]), [6]);
+
+        // without the features, the extended slot numbers are the ordinary slots of older firmware
+        for (slot, code) in [(WIDE_SLOT, 1), (NAMED_SLOT, 2)] {
+            let input = vec![OP_SAVE, slot, 1, 0, 1, 0, 0, code];
+            assert_eq!(serve(&args, input), [0, 0]);
+            let input = vec![OP_LOAD, slot, 1, 0, 1, 0, 0, 1];
+            assert_eq!(serve(&args, input), [code]);
+        }
+        assert_eq!(load(&[WIDE_SLOT, 0xFE, 0x00]), [2]);
+        assert_eq!(load(&[WIDE_SLOT, 0xFD, 0x00]), [1]);
+    }
+
+    #[test]
+    fn load_acknowledges_at_requested_interval() {
+        let dir = temp_dir("load_acknowledges_at_requested_interval");
+        let args = Args::parse_from(["canvas-server", "--image-dir", &dir]);
+
+        // counts the acknowledgements that the server waited for, while loading a blank 25 x 1 image
+        let acks = |interval: u8| {
+            let mut input = vec![OP_LOAD, 0, 25, 0, 1, 0, interval];
+            input.extend_from_slice(&[1; 32]);
+            let mut stream = MockStream {
+                input: std::io::Cursor::new(input),
+                output: Vec::new(),
+            };
+            serve_request(&mut stream, "192.168.1.20:50123".parse().unwrap(), &args).unwrap();
+            assert_eq!(stream.output, [8; 25]);
+            stream.input.position() - 7
+        };
+
+        // rows 0, 10 and 20 (the default), and the final acknowledgement
+        assert_eq!(acks(0), 4);
+        assert_eq!(acks(1), 26);
+        assert_eq!(acks(12), 4);
+        assert_eq!(acks(24), 3);
+        assert_eq!(acks(255), 2);
+    }
+
+    #[test]
+    fn off_palette_colors_are_loaded_as_nearest_codes() {
+        let dir = temp_dir("off_palette_colors_are_loaded_as_nearest_codes");
+        let args = Args::parse_from(["canvas-server", "--image-dir", &dir]);
+
+        save_bmp_image(&[vec![0xE800, 0x07E0, 0x2104]], &format!("{dir}/image_1")).unwrap();
+        assert_eq!(
+            serve(&args, vec![OP_LOAD, 1, 1, 0, 3, 0, 0, 1, 1]),
+            [0, 1, 8]
+        );
+
+        // an image edited outside of the canvas, with a dark red, a dim green and a dark grey
+        std::fs::copy(
+            format!("{}/tests/data/stray.bmp", env!("CARGO_MANIFEST_DIR")),
+            format!("{dir}/image_2.bmp"),
+        )
+        .unwrap();
+        assert_eq!(
+            serve(&args, vec![OP_LOAD, 2, 1, 0, 3, 0, 0, 1, 1]),
+            [0, 1, 15]
+        );
+    }
+
+    #[test]
+    fn saves_are_refused_over_the_quota() {
+        let dir = temp_dir("saves_are_refused_over_the_quota");
+        let args = Args::parse_from([
+            "canvas-server",
+            "--image-dir",
+            &dir,
+            "--max-dir-size",
+            "150",
+        ]);
+
+        // every 1 x 1 image takes 70 bytes, so the directory has room for two of them
+        assert_eq!(serve(&args, vec![OP_SAVE, 1, 1, 0, 1, 0, 0, 1]), [0, 0]);
+        assert_eq!(serve(&args, vec![OP_SAVE, 2, 1, 0, 1, 0, 0, 1]), [0, 0]);
+        assert_eq!(
+            serve(&args, vec![OP_SAVE, 3, 1, 0, 1, 0, 0, 1]),
+            [STATUS_QUOTA_EXCEEDED]
+        );
+        assert!(!std::path::Path::new(&format!("{dir}/image_3.bmp")).exists());
+
+        // replacing an image only takes the difference in size
+        assert_eq!(serve(&args, vec![OP_SAVE, 2, 1, 0, 1, 0, 0, 2]), [0, 0]);
+
+        // a refused save leaves the backup of the slot as it was
+        let backup = std::fs::read(format!("{dir}/image_2.bak.bmp")).unwrap();
+        let image = std::fs::read(format!("{dir}/image_2.bmp")).unwrap();
+        let mut tall = vec![OP_SAVE, 2, 8, 0, 1, 0];
+        tall.extend([0, 1].repeat(8));
+        assert_eq!(serve(&args, tall), [STATUS_QUOTA_EXCEEDED]);
+        assert_eq!(
+            std::fs::read(format!("{dir}/image_2.bak.bmp")).unwrap(),
+            backup
+        );
+        assert_eq!(std::fs::read(format!("{dir}/image_2.bmp")).unwrap(), image);
+    }
+
+    #[test]
+    fn every_code_survives_compression() {
+        let codes: Vec<u8> = (0..=0xFu8).flat_map(|code| [code, code]).collect();
+        let mut segments = [0u16; 16];
+        assert_eq!(
+            compress(&mut segments, &codes, SegmentFormat::DEFAULT),
+            (16, 32)
+        );
+
+        let mut uncompressed = vec![0u8; 32];
+        assert_eq!(
+            uncompress(&segments, &mut uncompressed, SegmentFormat::DEFAULT),
+            Ok(32)
+        );
+        assert_eq!(uncompressed, codes);
+    }
+
+    #[test]
+    fn compress_splits_mixed_rows_into_runs() {
+        let mut segments = [0u16; 8];
+        assert_eq!(
+            compress(&mut segments, &[1, 1, 2, 3, 3, 3], SegmentFormat::DEFAULT),
+            (3, 6)
+        );
+        assert_eq!(segments[..3], [(2 << 4) | 1, (1 << 4) | 2, (3 << 4) | 3]);
+
+        let mut codes = [0u8; 6];
+        assert_eq!(
+            uncompress(&segments[..3], &mut codes, SegmentFormat::DEFAULT),
+            Ok(6)
+        );
+        assert_eq!(codes, [1, 1, 2, 3, 3, 3]);
+    }
+
+    #[test]
+    fn runs_that_start_mid_row_keep_their_boundaries() {
+        for (codes, expected) in [
+            (
+                vec![1, 1, 2, 2, 2, 3],
+                vec![(2 << 4) | 1, (3 << 4) | 2, (1 << 4) | 3],
+            ),
+            (
+                vec![4, 9, 4, 9, 4],
+                vec![
+                    (1 << 4) | 4,
+                    (1 << 4) | 9,
+                    (1 << 4) | 4,
+                    (1 << 4) | 9,
+                    (1 << 4) | 4,
+                ],
+            ),
+            (
+                vec![7, 5, 5, 7, 7, 7, 5, 5, 5, 5],
+                vec![(1 << 4) | 7, (2 << 4) | 5, (3 << 4) | 7, (4 << 4) | 5],
+            ),
+        ] {
+            let mut segments = [0u16; 16];
+            let (num_segments, num_pixels) =
+                compress(&mut segments, &codes, SegmentFormat::DEFAULT);
+            assert_eq!((num_segments, num_pixels), (expected.len(), codes.len()));
+            assert_eq!(segments[..num_segments], expected);
+
+            let mut uncompressed = vec![0xAAu8; codes.len()];
+            assert_eq!(
+                uncompress(&expected, &mut uncompressed, SegmentFormat::DEFAULT),
+                Ok(codes.len())
+            );
+            assert_eq!(uncompressed, codes);
+        }
+    }
+
+    #[test]
+    fn long_runs_are_split_into_full_segments() {
+        let max_count = SegmentFormat::DEFAULT.max_count();
+        assert_eq!(max_count, 511);
+
+        for (run, expected) in [
+            (511, vec![(511 << 4) | 6]),
+            (512, vec![(511 << 4) | 6, (1 << 4) | 6]),
+            (1100, vec![(511 << 4) | 6, (511 << 4) | 6, (78 << 4) | 6]),
+        ] {
+            // the run is followed by a pixel of another code, which must still start a segment
+            let codes: Vec<u8> = std::iter::repeat_n(6, run).chain([2]).collect();
+            let mut segments = [0u16; 8];
+            let (num_segments, num_pixels) =
+                compress(&mut segments, &codes, SegmentFormat::DEFAULT);
+            assert_eq!((num_segments, num_pixels), (expected.len() + 1, run + 1));
+            assert_eq!(segments[..expected.len()], expected);
+            assert_eq!(segments[expected.len()], (1 << 4) | 2);
+
+            let mut uncompressed = vec![0u8; codes.len()];
+            assert_eq!(
+                uncompress(
+                    &segments[..num_segments],
+                    &mut uncompressed,
+                    SegmentFormat::DEFAULT
+                ),
+                Ok(codes.len())
+            );
+            assert_eq!(uncompressed, codes);
+        }
+
+        // a solid row thousands of pixels wide survives a round trip
+        let codes = vec![9u8; 4800];
+        let mut segments = [0u16; 16];
+        let (num_segments, num_pixels) = compress(&mut segments, &codes, SegmentFormat::DEFAULT);
+        assert_eq!((num_segments, num_pixels), (10, 4800));
+        let mut uncompressed = vec![0u8; codes.len()];
+        assert_eq!(
+            uncompress(
+                &segments[..num_segments],
+                &mut uncompressed,
+                SegmentFormat::DEFAULT
+            ),
+            Ok(4800)
+        );
+        assert_eq!(uncompressed, codes);
+
+        // only the pixels of the segments that fit are counted
+        let mut segments = [0u16; 2];
+        assert_eq!(
+            compress(&mut segments, &codes, SegmentFormat::DEFAULT),
+            (2, 1022)
+        );
+    }
+
+    #[test]
+    fn uncompress_reports_rows_that_are_not_covered_exactly() {
+        let mut codes = [0u8; 3];
+        assert_eq!(
+            uncompress(
+                &[(2u16 << 4) | 1, (1 << 4) | 2],
+                &mut codes,
+                SegmentFormat::DEFAULT
+            ),
+            Ok(3)
+        );
+        assert_eq!(codes, [1, 1, 2]);
+
+        // pixels past the row are counted, but not stored
+        let mut codes = [0u8; 3];
+        assert_eq!(
+            uncompress(
+                &[(2u16 << 4) | 1, (2 << 4) | 2],
+                &mut codes,
+                SegmentFormat::DEFAULT
+            ),
+            Err(UncompressError::Overflow { pixels: 4 })
+        );
+        assert_eq!(codes, [1, 1, 2]);
+
+        let mut codes = [0u8; 3];
+        assert_eq!(
+            uncompress(&[(1u16 << 4) | 5], &mut codes, SegmentFormat::DEFAULT),
+            Err(UncompressError::Underflow { pixels: 1 })
+        );
+        assert_eq!(codes, [5, 0, 0]);
+    }
+
+    #[test]
+    fn segments_can_have_wider_codes_and_counts() {
+        let format = SegmentFormat::new(6, 10).unwrap();
+        assert_eq!(format.max_count(), 1023);
+        assert_eq!(format.pack(5, 600), (600 << 6) | 5);
+        assert_eq!(format.unpack((600 << 6) | 5), (5, 600));
+        assert_eq!(SegmentFormat::new(8, 9), None);
+        assert_eq!(SegmentFormat::new(0, 9), None);
+
+        let codes: Vec<u8> = (0..64u8)
+            .flat_map(|code| std::iter::repeat_n(code, code as usize + 1))
+            .chain(std::iter::repeat_n(7, 1000))
+            .collect();
+        let mut segments = [0u16; 65];
+        assert_eq!(compress(&mut segments, &codes, format), (65, codes.len()));
+        let mut uncompressed = vec![0u8; codes.len()];
+        assert_eq!(
+            uncompress(&segments, &mut uncompressed, format),
+            Ok(codes.len())
+        );
+        assert_eq!(uncompressed, codes);
+
+        // the same segments mean something else in the default format
+        let mut uncompressed = vec![0u8; codes.len()];
+        let _ = uncompress(&segments, &mut uncompressed, SegmentFormat::DEFAULT);
+        assert_ne!(uncompressed, codes);
+    }
+
+    /// Generates rows of up to 1024 codes below `1 << code_bits`, either of random codes or of runs
+    /// of the same code (some of which are longer than a segment of the default format can hold)
+    fn code_rows(code_bits: u32) -> impl proptest::strategy::Strategy<Value = Vec<u8>> {
+        use proptest::prelude::*;
+
+        let code = 0..=((1u16 << code_bits) - 1) as u8;
+        proptest::prop_oneof![
+            proptest::collection::vec(code.clone(), 0..=1024),
+            proptest::collection::vec((code, 1..=1024usize), 0..=6).prop_map(|runs| {
+                let mut codes: Vec<u8> = runs
+                    .into_iter()
+                    .flat_map(|(code, len)| std::iter::repeat_n(code, len))
+                    .collect();
+                codes.truncate(1024);
+                codes
+            }),
+        ]
+    }
+
+    /// Generates every format of the segments, along with rows of codes that fit in it
+    fn formats_and_rows() -> impl proptest::strategy::Strategy<Value = (SegmentFormat, Vec<u8>)> {
+        use proptest::prelude::*;
+
+        (1..=8u32)
+            .prop_flat_map(|code_bits| (Just(code_bits), 1..=16 - code_bits))
+            .prop_flat_map(|(code_bits, count_bits)| {
+                let format = SegmentFormat::new(code_bits, count_bits).unwrap();
+                (Just(format), code_rows(code_bits))
+            })
+    }
+
+    proptest::proptest! {
+        #[test]
+        fn rows_survive_compression((format, codes) in formats_and_rows()) {
+            // no row takes more segments than it has pixels
+            let mut segments = vec![0u16; codes.len()];
+            let (num_segments, num_pixels) = compress(&mut segments, &codes, format);
+            proptest::prop_assert_eq!(num_pixels, codes.len());
+
+            // the counts returned are those of the segments that were written
+            let written = &segments[..num_segments];
+            let counts: Vec<usize> = written.iter().map(|&segment| format.unpack(segment.into()).1).collect();
+            proptest::prop_assert!(counts.iter().all(|&count| (1..=format.max_count()).contains(&count)));
+            proptest::prop_assert_eq!(counts.iter().sum::<usize>(), num_pixels);
+
+            // runs are only split when they fill a segment
+            for pair in written.windows(2) {
+                let (code, count) = format.unpack(pair[0].into());
+                proptest::prop_assert!(code != format.unpack(pair[1].into()).0 || count == format.max_count());
+            }
+
+            let mut uncompressed = vec![0xAAu8; codes.len()];
+            proptest::prop_assert_eq!(uncompress(written, &mut uncompressed, format), Ok(codes.len()));
+            proptest::prop_assert_eq!(uncompressed, codes);
+        }
+
+        #[test]
+        fn partially_compressed_rows_are_reconstructed(
+            codes in code_rows(4),
+            len in 0..=64usize,
+        ) {
+            let format = SegmentFormat::DEFAULT;
+            let mut segments = vec![0u16; len];
+            let (num_segments, num_pixels) = compress(&mut segments, &codes, format);
+            proptest::prop_assert!(num_segments <= len && num_pixels <= codes.len());
+            // a row is only cut short when every segment was used
+            proptest::prop_assert!(num_pixels == codes.len() || num_segments == len);
+
+            // the pixels that were reported are exactly those that can be reconstructed
+            let mut uncompressed = vec![0xAAu8; codes.len()];
+            let expected = match num_pixels == codes.len() {
+                true => Ok(num_pixels),
+                false => Err(UncompressError::Underflow { pixels: num_pixels }),
+            };
+            proptest::prop_assert_eq!(
+                uncompress(&segments[..num_segments], &mut uncompressed, format),
+                expected
+            );
+            proptest::prop_assert_eq!(&uncompressed[..num_pixels], &codes[..num_pixels]);
+            proptest::prop_assert!(uncompressed[num_pixels..].iter().all(|&code| code == 0xAA));
+        }
+    }
+
+    #[test]
+    fn saves_count_the_rows_that_would_have_been_smaller_in_the_other_mode() {
+        let dir = temp_dir("saves_count_the_rows_that_would_have_been_smaller_in_the_other_mode");
+        let args = Args::parse_from(["canvas-server", "--image-dir", &dir]);
+        let segment = |code: u8, count: usize| SegmentFormat::DEFAULT.pack(code, count) as u16;
+
+        let mut input = vec![OP_SAVE, 1, 4, 0, 4, 0];
+        // a raw row of a single run, which compresses to one segment
+        input.extend_from_slice(&[0, 5, 5, 5, 5]);
+        // a raw row of different codes, which would take a segment per code
+        input.extend_from_slice(&[0, 1, 2, 3, 4]);
+        // a compressed row of a segment per code, which is twice the size of the raw row
+        input.push(4);
+        for code in 1..=4 {
+            input.extend_from_slice(&segment(code, 1).to_le_bytes());
+        }
+        // a compressed row of a single run
+        input.push(1);
+        input.extend_from_slice(&segment(6, 4).to_le_bytes());
+
+        assert_eq!(serve(&args, input), 2u16.to_le_bytes());
+    }
+
+    #[test]
+    fn saves_use_the_configured_segment_format() {
+        let dir = temp_dir("saves_use_the_configured_segment_format");
+        let args = Args::parse_from([
+            "canvas-server",
+            "--image-dir",
+            &dir,
+            "--max-width",
+            "1000",
+            "--segment-code-bits",
+            "6",
+            "--segment-count-bits",
+            "10",
+        ]);
+        assert_eq!(
+            serve(&args, vec![OP_CAPABILITIES, 0, 0, 0, 0, 0])[13..15],
+            [6, 10]
+        );
+
+        // a run longer than 9 bits can count
+        let mut input = vec![OP_SAVE, 1, 1, 0, 0xE8, 0x03, 2];
+        input.extend_from_slice(&(2u16 | (600 << 6)).to_le_bytes());
+        input.extend_from_slice(&(6u16 | (400 << 6)).to_le_bytes());
+        assert_eq!(serve(&args, input), [0, 0]);
+
+        let mut expected = vec![PALETTE[2].1; 600];
+        expected.extend_from_slice(&[PALETTE[6].1; 400]);
+        assert_eq!(
+            load_slot(&dir, &Slot::Number(1), 1000, 1, &Palette::BUILTIN).unwrap(),
+            vec![expected]
+        );
+    }
+
+    #[test]
+    fn codes_outside_of_the_palette_are_substituted() {
+        let dir = temp_dir("codes_outside_of_the_palette_are_substituted");
+        let args = Args::parse_from(["canvas-server", "--image-dir", &dir, "--fallback-code", "6"]);
+
+        // a raw row with stray bytes, and a compressed row whose segment has the code 0xF (which
+        // every code nibble is valid for, now that the palette has 16 colors)
+        let mut input = vec![OP_SAVE, 1, 2, 0, 3, 0, 0, 1, 0xFF, 0x10, 1];
+        input.extend_from_slice(&(0xFu16 | (3 << 4)).to_le_bytes());
+        let output = serve(&args, input);
+        assert_eq!(output.len(), 2);
+
+        assert_eq!(
+            load_bmp_image(&format!("{dir}/image_1"), 3, 2, &Palette::BUILTIN).unwrap(),
+            [[0x07E0, 0xFFFF, 0xFFFF], [0x4208, 0x4208, 0x4208]]
+        );
+    }
+
+    #[test]
+    fn custom_palettes_are_used_for_transfers() {
+        let dir = temp_dir("custom_palettes_are_used_for_transfers");
+        let path = format!("{dir}/palette.toml");
+        std::fs::write(
+            &path,
+            "[[colors]]\ncode = 8\nrgb565 = 0x1234\n\n[[colors]]\ncode = 12\nrgb565 = 0xFFFF\n",
+        )
+        .unwrap();
+        let args = Args::parse_from(["canvas-server", "--image-dir", &dir, "--palette", &path]);
+
+        assert_eq!(serve(&args, vec![OP_SAVE, 1, 1, 0, 2, 0, 0, 12, 8]), [0, 0]);
+        assert_eq!(
+            load_bmp_image(&format!("{dir}/image_1"), 2, 1, &Palette::BUILTIN).unwrap(),
+            [[0xFFFF, 0x1234]]
+        );
+        assert_eq!(serve(&args, vec![OP_LOAD, 1, 1, 0, 2, 0, 0, 1, 1]), [12, 8]);
+
+        std::fs::write(&path, "[[colors]]\ncode = 16\nrgb565 = 0\n").unwrap();
+        let err = Args::try_parse_from(["canvas-server", "--palette", &path]).unwrap_err();
+        assert!(err.to_string().contains("color 1 has code 16"));
+    }
+
+    #[test]
+    fn custom_palettes_are_used_for_png_and_5_5_5_slots() {
+        let dir = temp_dir("custom_palettes_are_used_for_png_and_5_5_5_slots");
+        let path = format!("{dir}/palette.toml");
+        // both grays are nearest to black in the built-in palette, and the first one loses the
+        // lowest bit of green in the 5-5-5 layout
+        std::fs::write(
+            &path,
+            "[[colors]]\ncode = 8\nrgb565 = 0x0821\n\n[[colors]]\ncode = 9\nrgb565 = 0x1082\n\n\
+             [[colors]]\ncode = 12\nrgb565 = 0xFFFF\n",
+        )
+        .unwrap();
+        let args = Args::parse_from([
+            "canvas-server",
+            "--image-dir",
+            &dir,
+            "--palette",
+            &path,
+            "--color-depth",
+            "555",
+        ]);
+
+        save_png_image(&[vec![0x0821, 0x1082, 0xFFFF]], &format!("{dir}/image_1")).unwrap();
+        assert_eq!(
+            serve(&args, vec![OP_LOAD, 1, 1, 0, 3, 0, 0, 1, 1]),
+            [8, 9, 12]
+        );
+
+        assert_eq!(
+            serve(&args, vec![OP_SAVE, 2, 1, 0, 3, 0, 0, 8, 9, 12]),
+            [0, 0]
+        );
+        assert_eq!(
+            serve(&args, vec![OP_LOAD, 2, 1, 0, 3, 0, 0, 1, 1]),
+            [8, 9, 12]
+        );
+    }
+
+    #[test]
+    fn gray_images_only_contain_gray_colors() {
+        let dir = temp_dir("gray_images_only_contain_gray_colors");
+        let args = Args::parse_from([
+            "canvas-server",
+            "--image-dir",
+            &dir,
+            "--palette-preset",
+            "gray4",
+        ]);
+
+        // codes 4 and up are colors of the canvas app, which are not in the gray palette
+        let input = vec![OP_SAVE, 1, 1, 0, 6, 0, 0, 0, 1, 2, 3, 4, 8];
+        assert_eq!(serve(&args, input), [0, 0]);
+        assert_eq!(
+            load_slot(&dir, &Slot::Number(1), 6, 1, &Palette::BUILTIN).unwrap(),
+            vec![vec![0x0000, 0x528A, 0xAD75, 0xFFFF, 0x0000, 0x0000]]
+        );
+
+        let output = serve(&args, vec![OP_LOAD, 1, 1, 0, 6, 0, 0, 1, 1]);
+        assert_eq!(output, [0, 1, 2, 3, 0, 0]);
+
+        let err = Args::try_parse_from([
+            "canvas-server",
+            "--palette",
+            "palettes/builtin.toml",
+            "--palette-preset",
+            "gray4",
+        ])
+        .unwrap_err();
+        assert_eq!(err.kind(), clap::error::ErrorKind::ArgumentConflict);
+    }
+
+    #[test]
+    fn gray_png_and_5_5_5_slots_are_loaded_as_gray_codes() {
+        let dir = temp_dir("gray_png_and_5_5_5_slots_are_loaded_as_gray_codes");
+        let args = Args::parse_from([
+            "canvas-server",
+            "--image-dir",
+            &dir,
+            "--palette-preset",
+            "gray4",
+            "--color-depth",
+            "555",
+        ]);
+
+        // a mid gray which is nearer to the light level, but nearer to a dark color of the canvas
+        // app than to any of its lighter ones
+        save_png_image(
+            &[vec![0x0000, 0x528A, 0x8430, 0xFFFF]],
+            &format!("{dir}/image_1"),
+        )
+        .unwrap();
+        assert_eq!(
+            serve(&args, vec![OP_LOAD, 1, 1, 0, 4, 0, 0, 1, 1]),
+            [0, 1, 2, 3]
+        );
+
+        assert_eq!(
+            serve(&args, vec![OP_SAVE, 2, 1, 0, 4, 0, 0, 3, 2, 1, 0]),
+            [0, 0]
+        );
+        assert_eq!(
+            load_slot(&dir, &Slot::Number(2), 4, 1, &Palette::GRAY4).unwrap(),
+            vec![vec![0xFFFF, 0xAD75, 0x528A, 0x0000]]
+        );
+        assert_eq!(
+            serve(&args, vec![OP_LOAD, 2, 1, 0, 4, 0, 0, 1, 1]),
+            [3, 2, 1, 0]
+        );
+    }
+
+    #[test]
+    fn checksums_follow_saves_and_renames() {
+        let dir = temp_dir("checksums_follow_saves_and_renames");
+        let args = Args::parse_from(["canvas-server", "--image-dir", &dir]);
+
+        assert_eq!(serve(&args, vec![OP_SAVE, 1, 1, 0, 2, 0, 0, 0, 1]), [0, 0]);
+        assert_eq!(
+            checksums::verify_checksum(&dir, &Slot::Number(1)).unwrap(),
+            checksums::Verification::Match
+        );
+
+        assert_eq!(serve(&args, vec![OP_RENAME, 1, 0, 0, 0, 0, 2]), [STATUS_OK]);
+        assert!(!std::path::Path::new(&checksums::checksum_path(&dir, &Slot::Number(1))).exists());
+        assert_eq!(
+            checksums::verify_checksum(&dir, &Slot::Number(2)).unwrap(),
+            checksums::Verification::Match
+        );
+    }
+
+    #[test]
+    fn renames_wait_for_both_slots() {
+        let dir = temp_dir("renames_wait_for_both_slots");
+        let args = Args::parse_from(["canvas-server", "--image-dir", &dir, "--lock-wait-ms", "0"]);
+        assert_eq!(serve(&args, vec![OP_SAVE, 1, 1, 0, 2, 0, 0, 0, 1]), [0, 0]);
+
+        // a save of either slot holds the rename back
+        for locked in [1, 2] {
+            let lock = lock_slot(&dir, &Slot::Number(locked)).unwrap();
+            assert_eq!(
+                serve(&args, vec![OP_RENAME, 1, 0, 0, 0, 0, 2]),
+                [STATUS_SLOT_BUSY]
+            );
+            assert!(std::path::Path::new(&format!("{}/image_1.bmp", dir)).exists());
+            drop(lock);
+        }
+        assert_eq!(serve(&args, vec![OP_RENAME, 1, 0, 0, 0, 0, 2]), [STATUS_OK]);
+
+        // moves in opposite directions lock the slots in the same order
+        let args = Args::parse_from([
+            "canvas-server",
+            "--image-dir",
+            &dir,
+            "--lock-wait-ms",
+            "10000",
+        ]);
+        thread::scope(|scope| {
+            for (from, to) in [(2, 3), (3, 2)] {
+                let args = &args;
+                scope.spawn(move || {
+                    for _ in 0..20 {
+                        let reply = serve(args, vec![OP_RENAME, from, 0, 0, 0, 0, to]);
+                        assert!(reply == [STATUS_OK] || reply == [STATUS_NOT_FOUND]);
+                    }
+                });
+            }
+        });
+        assert_eq!(list_slots(&dir).len(), 1);
+    }
+
+    #[test]
+    fn loads_during_saves_get_whole_images() {
+        let dir = temp_dir("loads_during_saves_get_whole_images");
+        let args = Args::parse_from(["canvas-server", "--image-dir", &dir, "--cache-slots", "2"]);
+        let (height, width) = (16u8, 32u8);
+
+        // raw rows of a single code
+        let save = |code: u8| {
+            let mut input = vec![OP_SAVE, 3, height, 0, width, 0];
+            for _ in 0..height {
+                input.push(0);
+                input.extend_from_slice(&[code; 32]);
+            }
+            input
+        };
+        let mut load = vec![OP_LOAD, 3, height, 0, width, 0, 0];
+        load.extend_from_slice(&[1; 16]);
+
+        assert_eq!(serve(&args, save(2)), [16, 0]);
+        thread::scope(|scope| {
+            scope.spawn(|| {
+                for i in 0..49 {
+                    assert_eq!(serve(&args, save([5, 2][i % 2])), [16, 0]);
+                }
+            });
+            scope.spawn(|| {
+                for _ in 0..50 {
+                    let output = serve(&args, load.clone());
+                    assert_eq!(output.len(), height as usize * width as usize);
+                    assert!(
+                        output.iter().all(|&code| code == 2)
+                            || output.iter().all(|&code| code == 5),
+                        "load mixed two images"
+                    );
+                }
+            });
+        });
+
+        // the last save is loaded, whether from the cache or from the disk
+        assert!(serve(&args, load.clone()).iter().all(|&code| code == 5));
+        let uncached =
+            Args::parse_from(["canvas-server", "--image-dir", &dir, "--cache-slots", "0"]);
+        assert!(serve(&uncached, load).iter().all(|&code| code == 5));
+    }
+
+    #[test]
+    fn preloaded_images_are_loaded_without_their_files() {
+        let dir = temp_dir("preloaded_images_are_loaded_without_their_files");
+        let args = Args::parse_from(["canvas-server", "--image-dir", &dir, "--preload"]);
+        save_bmp_image(
+            &[vec![Palette::BUILTIN.code_2_color(4).unwrap(); 3]],
+            &format!("{dir}/image_1"),
+        )
+        .unwrap();
+        assert_eq!(cache::preload(&dir, &FileStore::default()), (1, 6));
+        std::fs::remove_file(format!("{dir}/image_1.bmp")).unwrap();
+
+        assert_eq!(
+            serve(&args, vec![OP_LOAD, 1, 1, 0, 3, 0, 0, 1, 1]),
+            [4, 4, 4]
+        );
+
+        // saves replace the image in memory as well
+        assert_eq!(
+            serve(&args, vec![OP_SAVE, 1, 1, 0, 3, 0, 0, 7, 7, 7]),
+            [1, 0]
+        );
+        std::fs::remove_file(format!("{dir}/image_1.bmp")).unwrap();
+        assert_eq!(
+            serve(&args, vec![OP_LOAD, 1, 1, 0, 3, 0, 0, 1, 1]),
+            [7, 7, 7]
+        );
+    }
+
+    #[test]
+    fn loads_are_paced_to_the_rate() {
+        let dir = temp_dir("loads_are_paced_to_the_rate");
+        let mut load = vec![OP_LOAD, 1, 4, 0, 50, 0, 0];
+        load.extend_from_slice(&[1; 8]);
+
+        // 200 bytes at 1000 bytes per second
+        let args = Args::parse_from([
+            "canvas-server",
+            "--image-dir",
+            &dir,
+            "--load-rate-bytes-per-sec",
+            "1000",
+        ]);
+        let started = std::time::Instant::now();
+        assert_eq!(serve(&args, load.clone()).len(), 200);
+        let elapsed = started.elapsed();
+        assert!(
+            elapsed >= std::time::Duration::from_millis(200),
+            "{:?}",
+            elapsed
+        );
+        assert!(elapsed < std::time::Duration::from_secs(2), "{:?}", elapsed);
+    }
+
+    #[test]
+    fn slots_can_be_copied() {
+        let dir = temp_dir("slots_can_be_copied");
+        let db = format!("{dir}/canvas.db");
+        for store in ["files", "sqlite"] {
+            let dir = format!("{dir}/{store}");
+            std::fs::create_dir_all(&dir).unwrap();
+            let parse = |extra: &[&str]| {
+                let mut argv = vec!["canvas-server", "--image-dir", &dir, "--store", store];
+                argv.extend_from_slice(&["--db", &db]);
+                argv.extend_from_slice(extra);
+                Args::parse_from(argv)
+            };
+            let args = parse(&[]);
+            let protected = parse(&["--no-overwrite"]);
+
+            assert_eq!(
+                serve(&args, vec![OP_COPY, 3, 0, 0, 0, 0, 7]),
+                [STATUS_NOT_FOUND]
+            );
+            assert_eq!(
+                serve(&args, vec![OP_SAVE, 3, 1, 0, 3, 0, 0, 1, 2, 3]),
+                [0, 0]
+            );
+            assert_eq!(serve(&args, vec![OP_COPY, 3, 0, 0, 0, 0, 7]), [STATUS_OK]);
+
+            // the copy can be changed without changing the image it was copied from
+            assert_eq!(
+                serve(&args, vec![OP_SAVE, 7, 1, 0, 3, 0, 0, 4, 4, 4]),
+                [1, 0]
+            );
+            assert_eq!(serve(&args, vec![OP_LOAD, 3, 1, 0, 3, 0, 0, 1]), [1, 2, 3]);
+
+            // an occupied destination is only replaced when the server allows overwriting
+            assert_eq!(
+                serve(&protected, vec![OP_COPY, 3, 0, 0, 0, 0, 7]),
+                [STATUS_SLOT_OCCUPIED]
+            );
+            assert_eq!(serve(&args, vec![OP_LOAD, 7, 1, 0, 3, 0, 0, 1]), [4, 4, 4]);
+            assert_eq!(serve(&args, vec![OP_COPY, 3, 0, 0, 0, 0, 7]), [STATUS_OK]);
+            assert_eq!(serve(&args, vec![OP_LOAD, 7, 1, 0, 3, 0, 0, 1]), [1, 2, 3]);
+
+            // a destination that is being saved is not copied over
+            let impatient = parse(&["--lock-wait-ms", "0"]);
+            let lock = lock_slot(&dir, &Slot::Number(7)).unwrap();
+            assert_eq!(
+                serve(&impatient, vec![OP_COPY, 1, 0, 0, 0, 0, 7]),
+                [STATUS_SLOT_BUSY]
+            );
+            drop(lock);
+
+            // the destination may be named instead of numbered
+            let mut input = vec![OP_COPY, 3, 0, 0, 0, 0, NAMED_SLOT, 6];
+            input.extend_from_slice(b"branch");
+            assert_eq!(
+                serve_negotiated(&args, Features::NAMED_SLOTS, input),
+                [STATUS_OK]
+            );
+            let mut input = vec![OP_LOAD, NAMED_SLOT, 1, 0, 3, 0, 6];
+            input.extend_from_slice(b"branch");
+            input.extend_from_slice(&[0, 1]);
+            assert_eq!(
+                serve_negotiated(&args, Features::NAMED_SLOTS, input),
+                [1, 2, 3]
+            );
+        }
+    }
+
+    #[test]
+    fn images_can_be_stored_in_a_database() {
+        let dir = temp_dir("images_can_be_stored_in_a_database");
+        let db = format!("{dir}/canvas.db");
+        let args = Args::parse_from([
+            "canvas-server",
+            "--image-dir",
+            &dir,
+            "--store",
+            "sqlite",
+            "--db",
+            &db,
+        ]);
+
+        assert_eq!(
+            serve(&args, vec![OP_SAVE, 1, 1, 0, 3, 0, 0, 7, 7, 7]),
+            [1, 0]
+        );
+        assert_eq!(serve(&args, vec![OP_SAVE, 2, 1, 0, 1, 0, 0, 3]), [0, 0]);
+        assert_eq!(list_slots(&dir), []);
+
+        assert_eq!(
+            serve(&args, vec![OP_LOAD, 1, 1, 0, 3, 0, 0, 1, 1]),
+            [7, 7, 7]
+        );
+        assert_eq!(serve(&args, vec![OP_RENAME, 2, 0, 0, 0, 0, 4]), [STATUS_OK]);
+        // the most recently saved image moved along with its slot
+        assert_eq!(serve(&args, vec![OP_LOAD, 255, 1, 0, 1, 0, 0, 1, 1]), [3]);
+        assert_eq!(
+            serve(&args, vec![OP_LOAD, 2, 1, 0, 1, 0, 0, 1, 1]),
+            [8],
+            "a slot without an image is loaded black"
+        );
+    }
+
+    #[test]
+    fn unchanged_saves_are_not_written() {
+        let dir = temp_dir("unchanged_saves_are_not_written");
+        let args = Args::parse_from(["canvas-server", "--image-dir", &dir]);
+        let slot = Slot::Number(1);
+        let save = |codes: [u8; 3]| {
+            let mut input = vec![OP_SAVE, 1, 1, 0, 3, 0, 0];
+            input.extend_from_slice(&codes);
+            input
+        };
+
+        assert_eq!(serve(&args, save([7, 7, 7])), [1, 0]);
+        let modified = || {
+            std::fs::metadata(format!("{dir}/image_1.bmp"))
+                .and_then(|metadata| metadata.modified())
+                .unwrap()
+        };
+        let first = modified();
+        assert_eq!(serve(&args, save([7, 7, 7])), [1, 0]);
+        assert_eq!(list_history(&dir, &slot).len(), 1);
+        assert!(!std::path::Path::new(&backup_path(&dir, &slot)).exists());
+        assert_eq!(modified(), first);
+
+        // a single different pixel is written
+        std::thread::sleep(std::time::Duration::from_millis(2));
+        assert_eq!(serve(&args, save([7, 6, 7])), [0, 0]);
+        assert_eq!(list_history(&dir, &slot).len(), 2);
+
+        let always = Args::parse_from(["canvas-server", "--image-dir", &dir, "--always-write"]);
+        std::thread::sleep(std::time::Duration::from_millis(2));
+        assert_eq!(serve(&always, save([7, 6, 7])), [0, 0]);
+        assert_eq!(list_history(&dir, &slot).len(), 3);
+    }
+
+    #[test]
+    fn compressed_storage_is_loaded_transparently() {
+        let dir = temp_dir("compressed_storage_is_loaded_transparently");
+        let plain = Args::parse_from(["canvas-server", "--image-dir", &dir]);
+        let compressed =
+            Args::parse_from(["canvas-server", "--image-dir", &dir, "--compress-storage"]);
+        let bmp = format!("{dir}/image_1.bmp");
+        let gz = format!("{bmp}{GZIP_SUFFIX}");
+
+        let mut input = vec![OP_SAVE, 1, 4, 0, 64, 0];
+        for _ in 0..4 {
+            input.push(1);
+            input.extend_from_slice(&(6u16 | (64 << 4)).to_le_bytes());
+        }
+        assert_eq!(serve(&compressed, input), [0, 0]);
+        assert!(!std::path::Path::new(&bmp).exists());
+        assert!(
+            std::fs::metadata(&gz).unwrap().len() < bmp_file_size(64, 4, ColorFormat::Rgb565) / 4
+        );
+        assert_eq!(list_slots(&dir), [Slot::Number(1)]);
+
+        let mut input = vec![OP_LOAD, 1, 4, 0, 64, 0, 0];
+        input.extend_from_slice(&[1; 8]);
+        let output = serve(&plain, input);
+        assert_eq!(output.len(), 4 * 64);
+        assert!(output.iter().all(|&code| code == 6));
+
+        // saving uncompressed replaces the compressed image, instead of sitting next to it
+        assert_eq!(serve(&plain, vec![OP_SAVE, 1, 1, 0, 1, 0, 0, 2]), [0, 0]);
+        assert!(std::path::Path::new(&bmp).exists());
+        assert!(!std::path::Path::new(&gz).exists());
+    }
+
+    #[test]
+    fn black_and_white_images_are_stored_as_monochrome() {
+        let dir = temp_dir("black_and_white_images_are_stored_as_monochrome");
+        let mono = Args::parse_from(["canvas-server", "--image-dir", &dir, "--mono"]);
+        let bmp = format!("{dir}/image_1.bmp");
+
+        // a raw row of black (8) and white (6) pixels is stored with 1 bit per pixel
+        let codes = [8, 6, 6, 8, 6, 8, 8, 8, 6, 6];
+        let mut input = vec![OP_SAVE, 1, 1, 0, codes.len() as u8, 0, 0];
+        input.extend_from_slice(&codes);
+        assert_eq!(serve(&mono, input), [0, 0]);
+        assert_eq!(
+            std::fs::metadata(&bmp).unwrap().len(),
+            mono_bmp_file_size(codes.len(), 1)
+        );
+
+        let mut input = vec![OP_LOAD, 1, 1, 0, codes.len() as u8, 0, 0];
+        input.push(1);
+        assert_eq!(serve(&mono, input), codes);
+
+        // images with any other color are stored as usual
+        assert_eq!(serve(&mono, vec![OP_SAVE, 1, 1, 0, 2, 0, 0, 8, 2]), [0, 0]);
+        assert_eq!(
+            std::fs::metadata(&bmp).unwrap().len(),
+            bmp_file_size(2, 1, ColorFormat::Rgb565)
+        );
+    }
+
+    #[test]
+    fn zstd_storage_round_trips() {
+        let dir = temp_dir("zstd_storage_round_trips");
+        let plain = Args::parse_from(["canvas-server", "--image-dir", &dir]);
+        let compressed = Args::parse_from([
+            "canvas-server",
+            "--image-dir",
+            &dir,
+            "--store-compression",
+            "zstd",
+            "--store-compression-level",
+            "1",
+        ]);
+        let bmp = format!("{dir}/image_1.bmp");
+        let zst = format!("{bmp}{ZSTD_SUFFIX}");
+
+        // a save over a plain image replaces it with a compressed one
+        assert_eq!(serve(&plain, vec![OP_SAVE, 1, 1, 0, 1, 0, 0, 2]), [0, 0]);
+        let mut input = vec![OP_SAVE, 1, 4, 0, 64, 0];
+        for _ in 0..4 {
+            input.push(1);
+            input.extend_from_slice(&(6u16 | (64 << 4)).to_le_bytes());
+        }
+        assert_eq!(serve(&compressed, input), [0, 0]);
+        assert!(!std::path::Path::new(&bmp).exists());
+        assert!(
+            std::fs::metadata(&zst).unwrap().len() < bmp_file_size(64, 4, ColorFormat::Rgb565) / 4
+        );
+        assert_eq!(list_slots(&dir), [Slot::Number(1)]);
+
+        let mut input = vec![OP_LOAD, 1, 4, 0, 64, 0, 0];
+        input.extend_from_slice(&[1; 8]);
+        let output = serve(&plain, input);
+        assert_eq!(output.len(), 4 * 64);
+        assert!(output.iter().all(|&code| code == 6));
+
+        assert!(Args::try_parse_from([
+            "canvas-server",
+            "--compress-storage",
+            "--store-compression",
+            "zstd"
+        ])
+        .is_err());
+    }
+
+    #[test]
+    fn saves_are_refused_when_the_disk_is_full() {
+        let dir = temp_dir("saves_are_refused_when_the_disk_is_full");
+        let mut args =
+            Args::parse_from(["canvas-server", "--image-dir", &dir, "--min-free-mb", "2"]);
+
+        // a 1 x 2 image takes 70 bytes
+        args.free_space = |_| Ok(2 * MIB + 69);
+        let mut stream = MockStream {
+            input: std::io::Cursor::new(vec![OP_SAVE, 1, 1, 0, 2, 0]),
+            output: Vec::new(),
+        };
+        let err =
+            serve_request(&mut stream, "192.168.1.20:50123".parse().unwrap(), &args).unwrap_err();
+        assert!(matches!(
+            err,
+            ServeError::ServerFull {
+                required: 2097222,
+                available: 2097221
+            }
+        ));
+        assert_eq!(err.status(), Some(STATUS_SERVER_FULL));
+        assert!(!std::path::Path::new(&format!("{dir}/image_1.bmp")).exists());
+
+        args.free_space = |_| Ok(2 * MIB + 70);
+        assert_eq!(serve(&args, vec![OP_SAVE, 1, 1, 0, 2, 0, 0, 0, 1]), [0, 0]);
+
+        // saves go ahead when the free space can not be checked
+        args.free_space = |_| Err(std::io::Error::other("no statvfs"));
+        assert_eq!(serve(&args, vec![OP_SAVE, 2, 1, 0, 2, 0, 0, 0, 1]), [0, 0]);
+    }
+
+    #[test]
+    fn oversized_images_are_refused_before_allocating() {
+        let dir = temp_dir("oversized_images_are_refused_before_allocating");
+        let args = Args::parse_from(["canvas-server", "--image-dir", &dir, "--max-width", "320"]);
+
+        /// Gets the resident set size of the process, in bytes
+        fn resident_bytes() -> Option<u64> {
+            let statm = std::fs::read_to_string("/proc/self/statm").ok()?;
+            let pages: u64 = statm.split_whitespace().nth(1)?.parse().ok()?;
+            Some(pages * 4096)
+        }
+
+        // the rows that a client would send after the header never arrive, so nothing is waited for
+        let before = resident_bytes();
+        let started = std::time::Instant::now();
+        for opcode in [OP_SAVE, OP_LOAD] {
+            assert_eq!(
+                serve(&args, vec![opcode, 1, 0xFF, 0xFF, 0xFF, 0xFF]),
+                [STATUS_BAD_DIMENSIONS]
+            );
+        }
+        assert!(started.elapsed() < std::time::Duration::from_secs(1));
+        if let (Some(before), Some(after)) = (before, resident_bytes()) {
+            assert!(after.saturating_sub(before) < 64 * 1024 * 1024);
+        }
+
+        assert_eq!(
+            serve(&args, vec![OP_SAVE, 1, 1, 4, 1, 0]),
+            [STATUS_BAD_DIMENSIONS]
+        );
+        assert_eq!(
+            serve(&args, vec![OP_SAVE, 1, 1, 0, 0x41, 1]),
+            [STATUS_BAD_DIMENSIONS]
+        );
+        assert!(!std::path::Path::new(&format!("{dir}/image_1.bmp")).exists());
+
+        let mut input = vec![OP_SAVE, 1, 1, 0, 0x40, 1, 1];
+        input.extend_from_slice(&(6u16 | (320 << 4)).to_le_bytes());
+        assert_eq!(serve(&args, input), [0, 0]);
+    }
+
+    #[test]
+    fn pings_do_not_touch_the_image_directory() {
+        let dir = temp_dir("pings_do_not_touch_the_image_directory");
+        std::fs::remove_dir(&dir).unwrap();
+        let args = Args::parse_from(["canvas-server", "--image-dir", &dir, "--multi-device"]);
+
+        assert_eq!(serve(&args, vec![OP_PING, 0, 0, 0, 0, 0, 3]), [STATUS_PONG]);
+        assert!(!std::path::Path::new(&dir).exists());
+    }
+
+    #[test]
+    fn devices_are_served_from_their_own_directories() {
+        let dir = temp_dir("devices_are_served_from_their_own_directories");
+        let args = Args::parse_from(["canvas-server", "--image-dir", &dir, "--multi-device"]);
+
+        assert_eq!(
+            serve(&args, vec![OP_SAVE, 1, 1, 0, 2, 0, 3, 0, 1, 2]),
+            [0, 0]
+        );
+        assert!(std::path::Path::new(&format!("{dir}/3/image_1.bmp")).exists());
+        assert!(!std::path::Path::new(&format!("{dir}/image_1.bmp")).exists());
+
+        // the slots of a device are only those in its directory
+        assert_eq!(list_slots(&format!("{dir}/3")), [Slot::Number(1)]);
+        assert!(list_slots(&dir).is_empty());
+        assert_eq!(
+            serve(&args, vec![OP_LOAD, 1, 1, 0, 2, 0, 3, 0, 1, 1]),
+            [1, 2]
+        );
+        assert_eq!(
+            serve(&args, vec![OP_LOAD, 1, 1, 0, 2, 0, 4, 0, 1, 1]),
+            [8, 8]
+        );
+        assert_eq!(device_dirs(&dir), [format!("{dir}/3")]);
+    }
+
+    #[test]
+    fn capabilities_describe_the_server() {
+        let dir = temp_dir("capabilities_describe_the_server");
+        std::fs::remove_dir(&dir).unwrap();
+        let args = Args::parse_from(["canvas-server", "--image-dir", &dir]);
+
+        let output = serve(&args, vec![OP_CAPABILITIES, 0, 0, 0, 0, 0]);
+        assert_eq!(output.len(), 1 + CAPABILITIES_LEN);
+        assert_eq!(output[0], STATUS_OK);
+        assert_eq!(output[1..4], [1, 0, 0]);
+        assert_eq!(
+            u32::from_le_bytes(output[4..8].try_into().unwrap()),
+            0b1_1111_1111_0000_0111
+        );
+        assert_eq!(output[8], 16);
+        assert_eq!(output[9..13], [0x00, 0x04, 0x00, 0x04]);
+        assert_eq!(output[13..15], [4, 9]);
+        assert_eq!(output[15], SAVE_WIDE_SEGMENTS);
+        assert!(!std::path::Path::new(&dir).exists());
+
+        // the palette size is that of the palette in use
+        let mut args = args;
+        args.palette = Some(Palette::from_entries(&[(0, 0xFFFF), (1, 0x0000)]).unwrap());
+        assert_eq!(serve(&args, vec![OP_CAPABILITIES, 0, 0, 0, 0, 0])[8], 2);
+    }
+
+    #[test]
+    fn checksums_change_only_with_the_image() {
+        let dir = temp_dir("checksums_change_only_with_the_image");
+        let args = Args::parse_from(["canvas-server", "--image-dir", &dir]);
+        let checksum = |slot: u8| serve(&args, vec![OP_CHECKSUM, slot, 0, 0, 0, 0]);
+
+        assert_eq!(checksum(1), [STATUS_OK, 0, 0, 0, 0]);
+
+        // the checksum covers the dimensions and the codes that a load would send
+        assert_eq!(
+            serve(&args, vec![OP_SAVE, 1, 1, 0, 3, 0, 0, 1, 2, 3]),
+            [0, 0]
+        );
+        let expected = crc32fast::hash(&[1, 0, 3, 0, 1, 2, 3]);
+        let mut reply = vec![STATUS_OK];
+        reply.extend_from_slice(&expected.to_le_bytes());
+        assert_eq!(checksum(1), reply);
+        assert_eq!(checksum(MOST_RECENT_SLOT), reply);
+
+        // identical images have the same checksum, whichever slot they are in
+        assert_eq!(
+            serve(&args, vec![OP_SAVE, 2, 1, 0, 3, 0, 0, 1, 2, 3]),
+            [0, 0]
+        );
+        assert_eq!(checksum(2), reply);
+
+        assert_eq!(
+            serve(&args, vec![OP_SAVE, 2, 1, 0, 3, 0, 0, 1, 2, 4]),
+            [0, 0]
+        );
+        assert_ne!(checksum(2), reply);
+        assert_eq!(
+            serve(&args, vec![OP_SAVE, 2, 3, 0, 1, 0, 0, 1, 0, 2, 0, 3]),
+            [0, 0]
+        );
+        assert_ne!(checksum(2), reply);
+        assert_eq!(checksum(1), reply);
+    }
+
+    #[test]
+    fn loads_are_transformed_but_saves_are_not() {
+        let dir = temp_dir("loads_are_transformed_but_saves_are_not");
+        let args = Args::parse_from(["canvas-server", "--image-dir", &dir]);
+        let save = vec![OP_SAVE, 1, 2, 0, 3, 0, 0, 1, 2, 3, 0, 4, 5, 6];
+        assert_eq!(serve(&args, save), [0, 0]);
+
+        let transformed = |transform: &str, height: u8, width: u8| {
+            let args = Args::parse_from([
+                "canvas-server",
+                "--image-dir",
+                &dir,
+                "--load-transform",
+                transform,
+            ]);
+            serve(&args, vec![OP_LOAD, 1, height, 0, width, 0, 0, 1, 1])
+        };
+        assert_eq!(transformed("rot90", 3, 2), [4, 1, 5, 2, 6, 3]);
+        assert_eq!(transformed("rot270", 3, 2), [3, 6, 2, 5, 1, 4]);
+        assert_eq!(transformed("rot180", 2, 3), [6, 5, 4, 3, 2, 1]);
+        assert_eq!(transformed("flip-h", 2, 3), [3, 2, 1, 6, 5, 4]);
+        assert_eq!(transformed("flip-v", 2, 3), [4, 5, 6, 1, 2, 3]);
+
+        // rotated images are only loaded at their rotated dimensions
+        assert_eq!(transformed("rot90", 2, 3), [8; 6]);
+
+        let stored = load_whole_bmp(&format!("{dir}/image_1"), &Palette::BUILTIN).unwrap();
+        assert_eq!((stored.len(), stored[0].len()), (2, 3));
+        assert_eq!(
+            serve(&args, vec![OP_LOAD, 1, 2, 0, 3, 0, 0, 1, 1]),
+            [1, 2, 3, 4, 5, 6]
+        );
+    }
+
+    #[test]
+    fn parallel_saves_store_the_same_image() {
+        let dir = temp_dir("parallel_saves_store_the_same_image");
+        let serial = Args::parse_from(["canvas-server", "--image-dir", &dir]);
+        let parallel = Args::parse_from(["canvas-server", "--image-dir", &dir, "--parallel"]);
+
+        // every row is different, and some codes are not in the palette
+        let save = |slot: u8| {
+            let mut input = vec![OP_SAVE, slot, 40, 0, 64, 0];
+            for row in 0..40u8 {
+                input.push(0);
+                input.extend((0..64u8).map(|col| (row + col) % 20));
+            }
+            input
+        };
+        assert_eq!(serve(&serial, save(1)), [0, 0]);
+        assert_eq!(serve(&parallel, save(2)), [0, 0]);
+
+        let stored =
+            |slot| load_whole_bmp(&format!("{dir}/image_{slot}"), &Palette::BUILTIN).unwrap();
+        assert_eq!(stored(1), stored(2));
+        let color = |code| serial.palette().code_2_color(code).unwrap();
+        assert_eq!(stored(2)[1][..3], [color(1), color(2), color(3)]);
+        assert_eq!(stored(2)[0][19], color(serial.fallback_code()));
+    }
+
+    #[test]
+    fn mismatched_loads_can_be_scaled() {
+        let dir = temp_dir("mismatched_loads_can_be_scaled");
+        let plain = Args::parse_from(["canvas-server", "--image-dir", &dir]);
+        let scaling =
+            Args::parse_from(["canvas-server", "--image-dir", &dir, "--scale-on-mismatch"]);
+        assert_eq!(
+            serve(&plain, vec![OP_SAVE, 1, 2, 0, 2, 0, 0, 0, 1, 0, 1, 0]),
+            [0, 0]
+        );
+        let load = |args: &Args, size: u8| serve(args, vec![OP_LOAD, 1, size, 0, size, 0, 0, 1, 1]);
+
+        let scaled_up = [0, 0, 1, 1, 0, 0, 1, 1, 1, 1, 0, 0, 1, 1, 0, 0];
+        assert_eq!(load(&scaling, 4), scaled_up);
+        assert_eq!(load(&plain, 4), [8; 16]);
+        assert_eq!(load(&scaling, 2), load(&plain, 2));
+        assert_eq!(load(&plain, 2), [0, 1, 1, 0]);
+
+        let mut save = vec![OP_SAVE, 2, 4, 0, 4, 0];
+        for row in scaled_up.chunks(4) {
+            save.push(0);
+            save.extend_from_slice(row);
+        }
+        assert_eq!(serve(&plain, save), [0, 0]);
+        assert_eq!(
+            serve(&scaling, vec![OP_LOAD, 2, 2, 0, 2, 0, 0, 1, 1]),
+            [0, 1, 1, 0]
+        );
+    }
+
+    #[test]
+    fn empty_slots_load_in_the_blank_color() {
+        let dir = temp_dir("empty_slots_load_in_the_blank_color");
+        let blank = |color: &str| {
+            Args::parse_from(["canvas-server", "--image-dir", &dir, "--blank-color", color])
+        };
+        let load = |args: &Args| serve(args, vec![OP_LOAD, 1, 1, 0, 3, 0, 0, 1, 1]);
+
+        let white = Args::parse_from(["canvas-server"])
+            .palette()
+            .color_2_code(0xFFFF)
+            .unwrap();
+        assert_eq!(load(&blank("3")), [3; 3]);
+        assert_eq!(load(&blank("0xFFFF")), [white; 3]);
+        assert_eq!(load(&blank("#ffff")), [white; 3]);
+
+        // images of other dimensions are blank too, while loads at the saved ones are not
+        let args = blank("3");
+        assert_eq!(serve(&args, vec![OP_SAVE, 1, 1, 0, 2, 0, 0, 1, 2]), [0, 0]);
+        assert_eq!(load(&args), [3; 3]);
+        assert_eq!(serve(&args, vec![OP_LOAD, 1, 1, 0, 2, 0, 0, 1, 1]), [1, 2]);
+
+        for invalid in ["0x10000", "#GGGG", "256", "red"] {
+            assert!(
+                Args::try_parse_from(["canvas-server", "--blank-color", invalid]).is_err(),
+                "{}",
+                invalid
+            );
+        }
+    }
+
+    #[test]
+    fn concurrent_saves_and_loads_never_tear_images() {
+        let dir = temp_dir("concurrent_saves_and_loads_never_tear_images");
+        let args = Args::parse_from([
+            "canvas-server",
+            "--image-dir",
+            &dir,
+            "--lock-wait-ms",
+            "10000",
+            "--cache-slots",
+            "0",
+        ]);
+        let (height, width) = (16u8, 32u8);
+        let save = |code: u8| {
+            let mut input = vec![OP_SAVE, 1, height, 0, width, 0];
+            let segment = SegmentFormat::DEFAULT.pack(code, width as usize) as u16;
+            for _ in 0..height {
+                input.push(1);
+                input.extend_from_slice(&segment.to_le_bytes());
+            }
+            input
+        };
+        let load = |slot: u8| {
+            let mut input = vec![OP_LOAD, slot, height, 0, width, 0, 0];
+            input.extend(std::iter::repeat_n(1, 2 * height as usize));
+            input
+        };
+
+        // every load is of a single whole image, or of the empty slot
+        let assert_whole = |output: Vec<u8>| {
+            assert_eq!(output.len(), height as usize * width as usize);
+            assert!(
+                [1, 2, 8]
+                    .iter()
+                    .any(|&code| output.iter().all(|&v| v == code)),
+                "torn image {:?}",
+                output
+            );
+        };
+
+        std::thread::scope(|scope| {
+            for thread in 0..8u8 {
+                let (args, save, load, assert_whole) = (&args, &save, &load, &assert_whole);
+                scope.spawn(move || {
+                    for i in 0..10 {
+                        // every save is served eventually, since saves of the slot wait for it
+                        let code = 1 + (thread + i) % 2;
+                        assert_eq!(serve(args, save(code)), [0, 0]);
+                        assert_whole(serve(args, load(1)));
+                    }
+                });
+            }
+
+            // copies out of the saved slot, and moves of the copies in both directions
+            let moves: [&[(u8, u8, u8)]; 2] =
+                [&[(OP_COPY, 1, 2), (OP_RENAME, 2, 3)], &[(OP_RENAME, 3, 2)]];
+            for moves in moves {
+                let (args, load, assert_whole) = (&args, &load, &assert_whole);
+                scope.spawn(move || {
+                    for _ in 0..10 {
+                        for &(op, from, to) in moves {
+                            let reply = serve(args, vec![op, from, 0, 0, 0, 0, to]);
+                            assert!(reply == [STATUS_OK] || reply == [STATUS_NOT_FOUND]);
+                            assert_whole(serve(args, load(to)));
+                        }
+                    }
+                });
+            }
+        });
+        let slots = list_slots(&dir);
+        assert!(slots.contains(&Slot::Number(1)));
+        for slot in slots {
+            assert_eq!(
+                checksums::verify_checksum(&dir, &slot).unwrap(),
+                checksums::Verification::Match
+            );
+        }
+    }
+
+    #[test]
+    fn transfers_are_logged() {
+        let dir = temp_dir("transfers_are_logged");
+        let log = format!("{dir}/transfers.csv");
+        let args = Args::parse_from(["canvas-server", "--image-dir", &dir, "--transfer-log", &log]);
+
+        assert_eq!(
+            serve(&args, vec![OP_SAVE, 1, 1, 0, 3, 0, 0, 1, 2, 3]),
+            [0, 0]
+        );
+        assert_eq!(
+            serve(&args, vec![OP_SAVE, 2, 2, 0, 3, 0, 1, 0x32, 0, 0, 4, 5, 6]),
+            [0, 0]
+        );
+        assert_eq!(
+            serve(&args, vec![OP_LOAD, 2, 2, 0, 3, 0, 0, 1, 1]),
+            [2, 2, 2, 4, 5, 6]
+        );
+        // pings and failed transfers are not logged
+        assert_eq!(serve(&args, vec![OP_PING, 0, 0, 0, 0, 0]), [STATUS_PONG]);
+        assert_eq!(
+            serve(&args, vec![OP_SAVE, 3, 1, 0, 2, 0, 1, 0x13, 0]),
+            [STATUS_BAD_REQUEST]
+        );
+
+        let contents = std::fs::read_to_string(&log).unwrap();
+        let rows: Vec<Vec<&str>> = contents
+            .lines()
+            .map(|line| line.split(',').collect())
+            .collect();
+        assert_eq!(rows[0].join(","), transfers::TRANSFER_LOG_HEADER);
+        assert_eq!(rows.len(), 4);
+        for (row, (opcode, slot, bytes)) in
+            rows[1..]
+                .iter()
+                .zip([("1", "1", "4"), ("1", "2", "7"), ("2", "2", "6")])
+        {
+            assert_eq!(row[1], "192.168.1.20");
+            assert_eq!((row[2], row[3], row[6]), (opcode, slot, bytes));
+            assert_eq!((row[4], row[5]), ("3", if slot == "1" { "1" } else { "2" }));
+        }
+    }
+
+    #[test]
+    fn regions_of_images_can_be_loaded() {
+        let dir = temp_dir("regions_of_images_can_be_loaded");
+        let args = Args::parse_from(["canvas-server", "--image-dir", &dir]);
+        let mut save = vec![OP_SAVE, 1, 3, 0, 4, 0];
+        for row in [[0, 1, 2, 3], [4, 5, 6, 7], [9, 10, 11, 12]] {
+            save.push(0);
+            save.extend_from_slice(&row);
+        }
+        assert_eq!(serve(&args, save), [0, 0]);
+
+        let crop = |args: &Args, slot: u8, (x, y): (u8, u8), (width, height): (u8, u8)| {
+            serve(
+                args,
+                vec![OP_CROP, slot, height, 0, width, 0, x, 0, y, 0, 0, 1, 1],
+            )
+        };
+        assert_eq!(crop(&args, 1, (1, 1), (2, 2)), [5, 6, 10, 11]);
+        assert_eq!(crop(&args, 1, (0, 0), (4, 1)), [0, 1, 2, 3]);
+        assert_eq!(crop(&args, 1, (3, 2), (1, 1)), [12]);
+
+        // the region must lie within the image, which must exist
+        assert_eq!(crop(&args, 1, (3, 0), (2, 1)), [STATUS_BAD_DIMENSIONS]);
+        assert_eq!(crop(&args, 1, (0, 1), (1, 3)), [STATUS_BAD_DIMENSIONS]);
+        assert_eq!(crop(&args, 1, (0, 0), (0, 1)), [STATUS_BAD_DIMENSIONS]);
+        assert_eq!(crop(&args, 2, (0, 0), (1, 1)), [STATUS_NOT_FOUND]);
+
+        // regions are of the image as it is loaded
+        let rotated = Args::parse_from([
+            "canvas-server",
+            "--image-dir",
+            &dir,
+            "--load-transform",
+            "rot90",
+        ]);
+        assert_eq!(crop(&rotated, 1, (0, 0), (3, 1)), [9, 4, 0]);
+    }
+
+    #[test]
+    fn loads_can_ask_to_be_mirrored() {
+        let dir = temp_dir("loads_can_ask_to_be_mirrored");
+        let args = Args::parse_from(["canvas-server", "--image-dir", &dir]);
+        let save = vec![OP_SAVE, 1, 2, 0, 3, 0, 0, 1, 2, 3, 0, 4, 5, 6];
+        assert_eq!(serve(&args, save), [0, 0]);
+
+        let load = |args: &Args, flags: u8, height: u8, width: u8| {
+            serve(args, vec![OP_LOAD | flags, 1, height, 0, width, 0, 0, 1, 1])
+        };
+        assert_eq!(load(&args, 0, 2, 3), [1, 2, 3, 4, 5, 6]);
+        assert_eq!(load(&args, LOAD_FLIP_HORIZONTAL, 2, 3), [3, 2, 1, 6, 5, 4]);
+        assert_eq!(load(&args, LOAD_FLIP_VERTICAL, 2, 3), [4, 5, 6, 1, 2, 3]);
+        assert_eq!(load(&args, LOAD_FLAGS, 2, 3), [6, 5, 4, 3, 2, 1]);
+
+        // the offset of a region is within the mirrored image
+        let crop = vec![
+            OP_CROP | LOAD_FLIP_HORIZONTAL,
+            1,
+            1,
+            0,
+            2,
+            0,
+            0,
+            0,
+            0,
+            0,
+            0,
+            1,
+            1,
+        ];
+        assert_eq!(serve(&args, crop), [3, 2]);
+
+        // the image is rotated by the server before it is mirrored for the client
+        let rotated = Args::parse_from([
+            "canvas-server",
+            "--image-dir",
+            &dir,
+            "--load-transform",
+            "rot90",
+        ]);
+        assert_eq!(load(&rotated, 0, 3, 2), [4, 1, 5, 2, 6, 3]);
+        assert_eq!(
+            load(&rotated, LOAD_FLIP_HORIZONTAL, 3, 2),
+            [1, 4, 2, 5, 3, 6]
+        );
+
+        // only loads can have flags
+        let ping = vec![OP_PING | LOAD_FLIP_HORIZONTAL, 0, 0, 0, 0, 0];
+        assert_eq!(serve(&args, ping), [STATUS_BAD_REQUEST]);
+    }
+
+    #[test]
+    fn mirrored_saves_are_stored_mirrored() {
+        let dir = temp_dir("mirrored_saves_are_stored_mirrored");
+        let save = vec![OP_SAVE, 1, 2, 0, 3, 0, 0, 1, 2, 3, 0, 4, 5, 6];
+        for (slot, mirror) in [(1, "horizontal"), (2, "vertical"), (3, "both")] {
+            let args = Args::parse_from(["canvas-server", "--image-dir", &dir, "--mirror", mirror]);
+            let mut save = save.clone();
+            save[1] = slot;
+            assert_eq!(serve(&args, save), [0, 0]);
+        }
+
+        let palette = Palette::BUILTIN;
+        let stored_codes = |slot: u8| -> Vec<Vec<u8>> {
+            load_whole_bmp(&format!("{dir}/image_{slot}"), &Palette::BUILTIN)
+                .unwrap()
+                .iter()
+                .map(|row| {
+                    row.iter()
+                        .map(|&color| palette.color_2_code(color).unwrap())
+                        .collect()
+                })
+                .collect()
+        };
+        assert_eq!(stored_codes(1), [[3, 2, 1], [6, 5, 4]]);
+        assert_eq!(stored_codes(2), [[4, 5, 6], [1, 2, 3]]);
+        assert_eq!(stored_codes(3), [[6, 5, 4], [3, 2, 1]]);
+    }
+
+    #[test]
+    fn merges_draw_over_the_image_of_the_slot() {
+        let dir = temp_dir("merges_draw_over_the_image_of_the_slot");
+        let args = Args::parse_from(["canvas-server", "--image-dir", &dir]);
+        let save = vec![OP_SAVE, 1, 2, 0, 3, 0, 0, 1, 2, 3, 0, 4, 5, 6];
+        assert_eq!(serve(&args, save), [0, 0]);
+        let load = || serve(&args, vec![OP_LOAD, 1, 2, 0, 3, 0, 0, 1, 1]);
+
+        // pixels of code 6 (white) are transparent, the first row is compressed
+        let mut merge = vec![OP_MERGE, 1, 2, 0, 3, 0, 6, 1];
+        merge.extend_from_slice(&(6u16 | (3 << 4)).to_le_bytes());
+        merge.extend_from_slice(&[0, 9, 6, 10]);
+        assert_eq!(serve(&args, merge), [0, 0]);
+        assert_eq!(load(), [1, 2, 3, 9, 5, 10]);
+
+        // a merge with nothing transparent replaces the image (both raw rows would have been
+        // smaller compressed)
+        let merge = vec![OP_MERGE, 1, 2, 0, 3, 0, 0, 0, 6, 6, 6, 0, 6, 6, 6];
+        assert_eq!(serve(&args, merge), [2, 0]);
+        assert_eq!(load(), [6; 6]);
+
+        // the image of the slot must exist and be of the same dimensions, which is checked before
+        // the rows are sent
+        let merge = |slot: u8, height: u8, width: u8, code: u8| {
+            serve(&args, vec![OP_MERGE, slot, height, 0, width, 0, code])
+        };
+        assert_eq!(merge(1, 3, 2, 6), [STATUS_BAD_DIMENSIONS]);
+        assert_eq!(merge(2, 2, 3, 6), [STATUS_NOT_FOUND]);
+        assert_eq!(merge(1, 2, 3, 200), [STATUS_BAD_REQUEST]);
+        assert_eq!(load(), [6; 6]);
+    }
+
+    #[test]
+    fn clearing_removes_every_image() {
+        let dir = temp_dir("clearing_removes_every_image");
+        let args = Args::parse_from([
+            "canvas-server",
+            "--image-dir",
+            &dir,
+            "--auth-token",
+            "secret",
+        ]);
+        for slot in [1, 2, 3] {
+            assert_eq!(serve(&args, vec![OP_SAVE, slot, 1, 0, 1, 0, 0, 1]), [0, 0]);
+        }
+        let clear = |token: &[u8]| {
+            let mut input = vec![OP_CLEAR, 0, 0, 0, 0, 0, token.len() as u8];
+            input.extend_from_slice(token);
+            serve(&args, input)
+        };
+
+        assert_eq!(clear(b"guess"), [STATUS_UNAUTHORIZED]);
+        assert_eq!(list_slots(&dir).len(), 3);
+
+        // slots that are being saved are kept
+        let lock = lock_slot(&dir, &Slot::Number(2)).unwrap();
+        assert_eq!(clear(b"secret"), [STATUS_OK, 2, 0]);
+        drop(lock);
+        assert_eq!(list_slots(&dir), [Slot::Number(2)]);
+        assert_eq!(trash::list_trash(&dir).len(), 2);
+        assert_eq!(serve(&args, vec![OP_LOAD, 1, 1, 0, 1, 0, 0]), [8]);
+
+        // the history is kept, so a cleared image can be reverted to
+        assert_eq!(list_history(&dir, &Slot::Number(1)).len(), 1);
+        assert_eq!(clear(b"secret"), [STATUS_OK, 1, 0]);
+        assert_eq!(clear(b"secret"), [STATUS_OK, 0, 0]);
+    }
+
+    #[test]
+    fn errors_are_reported_with_status_bytes() {
+        let dir = temp_dir("errors_are_reported_with_status_bytes");
+        let args = Args::parse_from(["canvas-server", "--image-dir", &dir]);
+
+        assert_eq!(serve(&args, vec![42, 0, 0, 0, 0, 0]), [STATUS_BAD_REQUEST]);
+        assert_eq!(
+            serve(&args, vec![OP_SAVE, 1, 0, 0, 4, 0]),
+            [STATUS_BAD_DIMENSIONS]
+        );
+        assert_eq!(
+            serve(&args, vec![OP_SAVE, 1, 4, 0, 0, 0]),
+            [STATUS_BAD_DIMENSIONS]
+        );
+        assert_eq!(
+            serve(&args, vec![OP_LOAD, 1, 0, 0, 0, 0, 0]),
+            [STATUS_BAD_DIMENSIONS]
+        );
+        assert!(!std::path::Path::new(&format!("{dir}/image_1.bmp")).exists());
+        assert_eq!(
+            serve(&args, vec![OP_RENAME, 1, 0, 0, 0, 0, 2]),
+            [STATUS_NOT_FOUND]
+        );
+        assert_eq!(
+            serve(&args, vec![OP_SHUTDOWN, 0, 0, 0, 0, 0, 0]),
+            [STATUS_UNAUTHORIZED]
+        );
+        assert!(!SHUTDOWN.load(Ordering::SeqCst));
+
+        std::fs::write(format!("{dir}/image_3.bmp"), b"BM").unwrap();
+        assert_eq!(
+            serve(&args, vec![OP_LOAD, 3, 1, 0, 1, 0, 0]),
+            [STATUS_CORRUPT_IMAGE]
+        );
+
+        let lock = lock_slot(&dir, &Slot::Number(5)).unwrap();
+        assert_eq!(
+            serve(&args, vec![OP_SAVE, 5, 1, 0, 1, 0, 0, 1]),
+            [STATUS_SLOT_BUSY]
+        );
+        drop(lock);
+        assert_eq!(serve(&args, vec![OP_SAVE, 5, 1, 0, 1, 0, 0, 1]), [0, 0]);
+
+        // the client can not be told about failures of the connection itself
+        let err = serve_request(
+            &mut MockStream {
+                input: std::io::Cursor::new(vec![OP_SAVE, 4, 2, 0, 2, 0, 0, 1]),
+                output: Vec::new(),
+            },
+            "192.168.1.20:50123".parse().unwrap(),
+            &args,
+        )
+        .unwrap_err();
+        assert!(matches!(
+            err,
+            ServeError::IncompleteRead {
+                received: 1,
+                expected: 2,
+                failure: ReadFailure::Disconnected,
+                ..
+            }
+        ));
+        assert_eq!(err.status(), None);
+        assert!(!std::path::Path::new(&format!("{dir}/image_4.bmp")).exists());
+    }
+
+    /// Reader which delivers some bytes and then fails as the given error
+    struct FailingReader {
+        input: std::io::Cursor<Vec<u8>>,
+        err: std::io::ErrorKind,
+    }
+
+    impl Read for FailingReader {
+        fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+            match self.input.read(buf)? {
+                0 => Err(self.err.into()),
+                len => Ok(len),
+            }
+        }
+    }
+
+    #[test]
+    fn incomplete_reads_report_what_arrived() {
+        let mut buffer = [0u8; 240];
+        for (input, err, failure, message) in [
+            (
+                vec![6; 37],
+                std::io::ErrorKind::WouldBlock,
+                ReadFailure::TimedOut,
+                "connection timed out while reading row 3, after 37 of 240 bytes arrived",
+            ),
+            (
+                vec![],
+                std::io::ErrorKind::ConnectionReset,
+                ReadFailure::Disconnected,
+                "client disconnected while reading row 3, after 0 of 240 bytes arrived",
+            ),
+        ] {
+            let err = read_counted(
+                FailingReader {
+                    input: std::io::Cursor::new(input),
+                    err,
+                },
+                &mut buffer,
+                "reading row 3",
+            )
+            .unwrap_err();
+            assert!(matches!(
+                err,
+                ServeError::IncompleteRead { failure: reported, .. } if reported == failure
+            ));
+            assert!(err.to_string().starts_with(message), "{}", err);
+        }
+
+        let mut input = std::io::Cursor::new(vec![8; 240]);
+        read_counted(&mut input, &mut buffer, "reading row 3").unwrap();
+        assert_eq!(buffer, [8; 240]);
+    }
+
+    #[test]
+    fn compressed_rows_must_cover_the_row() {
+        let dir = temp_dir("compressed_rows_must_cover_the_row");
+        let args = Args::parse_from(["canvas-server", "--image-dir", &dir]);
+
+        // a raw row, followed by a compressed row whose single segment only covers its first pixel
+        let mut input = vec![OP_SAVE, 1, 2, 0, 3, 0, 0, 1, 1, 1, 1];
+        input.extend_from_slice(&(2u16 | (1 << 4)).to_le_bytes());
+        assert_eq!(serve(&args, input), [STATUS_BAD_REQUEST]);
+
+        // a compressed row whose segments cover more pixels than the row has
+        let mut input = vec![OP_SAVE, 1, 1, 0, 3, 0, 2];
+        input.extend_from_slice(&(2u16 | (2 << 4)).to_le_bytes());
+        input.extend_from_slice(&(1u16 | (2 << 4)).to_le_bytes());
+        assert_eq!(serve(&args, input), [STATUS_BAD_REQUEST]);
+
+        assert!(!std::path::Path::new(&format!("{dir}/image_1.bmp")).exists());
+
+        let mut input = vec![OP_SAVE, 1, 1, 0, 3, 0, 2];
+        input.extend_from_slice(&(2u16 | (1 << 4)).to_le_bytes());
+        input.extend_from_slice(&(1u16 | (2 << 4)).to_le_bytes());
+        assert_eq!(serve(&args, input), [1, 0]);
+        assert_eq!(
+            load_slot(&dir, &Slot::Number(1), 3, 1, &Palette::BUILTIN).unwrap(),
+            vec![vec![0x001F, 0x07E0, 0x07E0]]
+        );
+    }
+
+    #[test]
+    fn lenient_saves_fill_the_rows_that_were_lost() {
+        let dir = temp_dir("lenient_saves_fill_the_rows_that_were_lost");
+        let args = Args::parse_from(["canvas-server", "--image-dir", &dir, "--lenient-save"]);
+        let palette = args.palette();
+        let background = palette
+            .code_2_color(palette.nearest_code(args.blank_color()))
+            .unwrap();
+        let row = |code: u8| vec![palette.code_2_color(code).unwrap(); 3];
+        let serve_status =
+            |args: &Args, input| serve_negotiated(args, Features::SAVE_STATUS, input);
+
+        // a malformed row is filled, and the rows after it are still received
+        let mut input = vec![OP_SAVE, 1, 3, 0, 3, 0, 0, 1, 1, 1, 1];
+        input.extend_from_slice(&(2u16 | (1 << 4)).to_le_bytes());
+        input.extend_from_slice(&[0, 2, 2, 2]);
+        // the feedback is still that of the rows that were received (which were sent raw)
+        assert_eq!(serve_status(&args, input), [STATUS_PARTIAL_SAVE, 2, 0]);
+        assert_eq!(
+            load_slot(&dir, &Slot::Number(1), 3, 3, &Palette::BUILTIN).unwrap(),
+            [row(1), vec![background; 3], row(2)]
+        );
+
+        // once the client disconnects, every row that was not received is filled
+        let input = vec![OP_SAVE, 2, 3, 0, 3, 0, 0, 1, 1, 1, 0, 2];
+        assert_eq!(serve_status(&args, input), [STATUS_PARTIAL_SAVE, 1, 0]);
+        assert_eq!(
+            load_slot(&dir, &Slot::Number(2), 3, 3, &Palette::BUILTIN).unwrap(),
+            [row(1), vec![background; 3], vec![background; 3]]
+        );
+
+        // complete saves are confirmed, and without the option nothing is saved from a lost save
+        let input = vec![OP_SAVE, 3, 1, 0, 3, 0, 0, 1, 2, 3];
+        assert_eq!(serve_status(&args, input), [STATUS_OK, 0, 0]);
+        let strict = Args::parse_from(["canvas-server", "--image-dir", &dir]);
+        let input = vec![OP_SAVE, 4, 3, 0, 3, 0, 0, 1, 1, 1];
+        assert_eq!(serve(&strict, input), Vec::<u8>::new());
+        assert!(!std::path::Path::new(&format!("{dir}/image_4.bmp")).exists());
+
+        // a save that loses every row is refused, instead of replacing the slot with the background
+        assert_eq!(
+            serve_status(&args, vec![OP_SAVE, 3, 1, 0, 3, 0]),
+            Vec::<u8>::new()
+        );
+        let input = vec![OP_SAVE, 3, 1, 0, 3, 0, 1, 0x21, 0];
+        assert_eq!(serve_status(&args, input), [STATUS_BAD_REQUEST]);
+        assert_eq!(
+            load_slot(&dir, &Slot::Number(3), 3, 1, &Palette::BUILTIN).unwrap(),
+            [[
+                palette.code_2_color(1).unwrap(),
+                palette.code_2_color(2).unwrap(),
+                palette.code_2_color(3).unwrap()
+            ]]
+        );
+
+        // clients that did not ask for the status byte are still replied to with the feedback alone
+        let input = vec![OP_SAVE, 5, 2, 0, 3, 0, 0, 1, 1, 1];
+        assert_eq!(serve(&args, input), [1, 0]);
+        assert_eq!(
+            load_slot(&dir, &Slot::Number(5), 3, 2, &Palette::BUILTIN).unwrap(),
+            [row(1), vec![background; 3]]
+        );
+    }
+
+    #[test]
+    fn wide_segments_survive_compression() {
+        // a run as wide as the panel, and more segments than a narrow row can have
+        let codes: Vec<u8> = std::iter::repeat_n(9, 800)
+            .chain((0..600).map(|pixel| (pixel % 2) as u8))
+            .collect();
+        let mut segments = vec![0u32; codes.len()];
+        let (num_segments, num_pixels) = compress(&mut segments, &codes, SegmentFormat::WIDE);
+        assert_eq!((num_segments, num_pixels), (601, codes.len()));
+        assert_eq!(segments[0], SegmentFormat::WIDE.pack(9, 800));
+
+        let mut uncompressed = vec![0u8; codes.len()];
+        assert_eq!(
+            uncompress(
+                &segments[..num_segments],
+                &mut uncompressed,
+                SegmentFormat::WIDE
+            ),
+            Ok(codes.len())
+        );
+        assert_eq!(uncompressed, codes);
+
+        // narrow segments only keep the bits of the wide format that fit in them
+        let mut narrow = vec![0u16; codes.len()];
+        assert_eq!(
+            compress(&mut narrow, &codes, SegmentFormat::DEFAULT),
+            (602, codes.len())
+        );
+    }
+
+    #[test]
+    fn wide_saves_are_loaded_like_narrow_ones() {
+        let dir = temp_dir("wide_saves_are_loaded_like_narrow_ones");
+        let args = Args::parse_from(["canvas-server", "--image-dir", &dir]);
+        let wide_save = |slot: u8, width: u16| {
+            let mut input = vec![OP_SAVE | SAVE_WIDE_SEGMENTS, slot, 1, 0];
+            input.extend_from_slice(&width.to_le_bytes());
+            input
+        };
+
+        // a single segment covers a whole row of the panel
+        let mut input = wide_save(1, 800);
+        input.extend_from_slice(&1u16.to_le_bytes());
+        input.extend_from_slice(&SegmentFormat::WIDE.pack(2, 800).to_le_bytes());
+        assert_eq!(serve(&args, input), [0, 0]);
+
+        // a row with more than 255 segments, which would be smaller if it was sent raw
+        let codes: Vec<u8> = (0..800).map(|pixel| (pixel / 2 % 2) as u8 + 1).collect();
+        let mut input = wide_save(2, 800);
+        input.extend_from_slice(&400u16.to_le_bytes());
+        for code in (0..400).map(|segment| (segment % 2) as u8 + 1) {
+            input.extend_from_slice(&SegmentFormat::WIDE.pack(code, 2).to_le_bytes());
+        }
+        assert_eq!(serve(&args, input), [1, 0]);
+
+        // raw rows only have a wider mode
+        let mut input = wide_save(3, 3);
+        input.extend_from_slice(&[0, 0, 1, 2, 3]);
+        assert_eq!(serve(&args, input), [0, 0]);
+
+        // the images are loaded (and saved) by older clients as if they were saved by them
+        let load = |slot: u8, width: u16| {
+            let mut input = vec![OP_LOAD, slot, 1, 0];
+            input.extend_from_slice(&width.to_le_bytes());
+            input.extend_from_slice(&[0, 1]);
+            serve(&args, input)
+        };
+        assert_eq!(load(1, 800), vec![2; 800]);
+        assert_eq!(load(2, 800), codes);
+        assert_eq!(load(3, 3), [1, 2, 3]);
+        let mut input = vec![OP_SAVE, 4, 1, 0, 0x20, 0x03, 0];
+        input.extend_from_slice(&codes);
+        assert_eq!(serve(&args, input), [0, 0]);
+        assert_eq!(
+            load_slot(&dir, &Slot::Number(4), 800, 1, &Palette::BUILTIN).unwrap(),
+            load_slot(&dir, &Slot::Number(2), 800, 1, &Palette::BUILTIN).unwrap()
+        );
+
+        // wide segments must cover the row exactly, like narrow ones
+        let mut input = wide_save(5, 800);
+        input.extend_from_slice(&1u16.to_le_bytes());
+        input.extend_from_slice(&SegmentFormat::WIDE.pack(2, 799).to_le_bytes());
+        assert_eq!(serve(&args, input), [STATUS_BAD_REQUEST]);
+
+        // only saves and merges can send wide rows
+        let rename = vec![OP_RENAME | SAVE_WIDE_SEGMENTS, 1, 0, 0, 0, 0, 6];
+        assert_eq!(serve(&args, rename), [STATUS_BAD_REQUEST]);
+    }
+
+    #[test]
+    fn negotiated_features_apply_to_the_request() {
+        let dir = temp_dir("negotiated_features_apply_to_the_request");
+        let args = Args::parse_from(["canvas-server", "--image-dir", &dir]);
+        let negotiate = |features: u32| {
+            let mut input = vec![OP_NEGOTIATE];
+            input.extend_from_slice(&features.to_le_bytes());
+            input.push(0);
+            input
+        };
+
+        // features that the server does not know about are left out of the reply
+        let mut input = negotiate(u32::MAX);
+        input.extend_from_slice(&[OP_SAVE, 1, 1, 0, 3, 0]);
+        input.extend_from_slice(&1u16.to_le_bytes());
+        input.extend_from_slice(&SegmentFormat::WIDE.pack(2, 3).to_le_bytes());
+        let output = serve(&args, input);
+        assert_eq!(output[..5], [STATUS_OK, 15, 0, 0, 0]);
+        // the save sends wide rows without setting the flag of its opcode (and its single segment
+        // is larger than the raw row), and is confirmed with a status byte before the feedback
+        assert_eq!(output[5..], [STATUS_OK, 1, 0]);
+        assert_eq!(
+            load_slot(&dir, &Slot::Number(1), 3, 1, &Palette::BUILTIN).unwrap(),
+            vec![vec![0x001F; 3]]
+        );
+
+        // without the feature, the request is served like that of a client that never negotiates
+        let mut input = negotiate(0);
+        input.extend_from_slice(&[OP_SAVE, 2, 1, 0, 3, 0, 0, 1, 2, 3]);
+        assert_eq!(serve(&args, input), [STATUS_OK, 0, 0, 0, 0, 0, 0]);
+        assert_eq!(serve(&args, vec![OP_LOAD, 2, 1, 0, 3, 0, 0, 1]), [1, 2, 3]);
+
+        // features are only negotiated once, at the start of the connection
+        let mut input = negotiate(1);
+        input.extend_from_slice(&negotiate(1));
+        assert_eq!(
+            serve(&args, input),
+            [STATUS_OK, 1, 0, 0, 0, STATUS_BAD_REQUEST]
+        );
+    }
+
+    #[test]
+    fn every_argument_has_an_environment_variable() {
+        // the fallback itself is tested on the binary (in tests/cli.rs), since changing the
+        // environment here would change it for every test that parses its arguments
+        use clap::CommandFactory;
+        for arg in Args::command().get_arguments() {
+            let expected = format!("CANVAS_{}", arg.get_id().as_str().to_uppercase());
+            assert_eq!(
+                arg.get_env(),
+                Some(std::ffi::OsStr::new(&expected)),
+                "--{}",
+                arg.get_id()
+            );
+        }
+    }
+
+    #[test]
+    fn daemon_files_require_the_daemon() {
+        assert!(Args::try_parse_from(["canvas-server", "--pid-file", "canvas.pid"]).is_err());
+        assert!(Args::try_parse_from(["canvas-server", "--log-file", "canvas.log"]).is_err());
+
+        let args = Args::parse_from(["canvas-server", "--daemon", "--pid-file", "canvas.pid"]);
+        assert!(args.daemon && !args.foreground);
+        assert_eq!(args.pid_file.as_deref(), Some("canvas.pid"));
+
+        // the flag given last wins, so scripts can turn the daemon off
+        let args = Args::parse_from(["canvas-server", "--daemon", "--foreground"]);
+        assert!(!args.daemon && args.foreground);
+    }
+
+    #[test]
+    fn the_directory_is_watched_while_the_cache_is_enabled() {
+        let watches = |flags: &[&str]| {
+            let mut args = vec!["canvas-server"];
+            args.extend_from_slice(flags);
+            Args::parse_from(args).watches()
+        };
+        assert!(watches(&[]));
+        assert!(!watches(&["--no-watch"]));
+        assert!(!watches(&["--cache-slots", "0"]));
+        assert!(watches(&["--cache-slots", "0", "--preload"]));
+        assert!(watches(&["--cache-slots", "0", "--watch"]));
+        assert!(Args::try_parse_from(["canvas-server", "--watch", "--no-watch"]).is_err());
+    }
+
+    #[test]
+    fn verbosity_is_set_by_the_flags() {
+        let verbosity = |flags: &[&str]| {
+            let mut args = vec!["canvas-server"];
+            args.extend_from_slice(flags);
+            Args::parse_from(args).verbosity()
+        };
+        assert_eq!(verbosity(&[]), Verbosity::Normal);
+        assert_eq!(verbosity(&["-q"]), Verbosity::Quiet);
+        assert_eq!(verbosity(&["-v"]), Verbosity::Debug);
+        assert_eq!(verbosity(&["-vv", "--verbose"]), Verbosity::Debug);
+        assert!(Args::try_parse_from(["canvas-server", "-q", "-v"]).is_err());
+
+        // the level only changes what is printed
+        let dir = temp_dir("verbosity_is_set_by_the_flags");
+        for flag in ["--quiet", "--verbose"] {
+            let args = Args::parse_from(["canvas-server", "--image-dir", &dir, flag]);
+            let save = vec![OP_SAVE, 1, 2, 0, 3, 0, 0, 1, 2, 3, 1, 0x34, 0x00];
+            assert_eq!(serve(&args, save), [0, 0]);
+            assert_eq!(
+                serve(&args, vec![OP_LOAD, 1, 2, 0, 3, 0, 0, 1, 1]),
+                [1, 2, 3, 4, 4, 4]
+            );
+        }
+    }
 }
@@ -0,0 +1,125 @@
+//! Metadata about the last save of each slot, stored as JSON next to the image
+
+use serde::{Deserialize, Serialize};
+
+use crate::image::TEMP_SUFFIX;
+use crate::slots::Slot;
+
+/// Version of the schema of the metadata, which is incremented whenever it changes incompatibly
+pub const METADATA_VERSION: u32 = 1;
+
+/// Details about how and when the image in a slot was last saved
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct SlotMetadata {
+    /// Version of the schema, always [`METADATA_VERSION`] when written by this server
+    pub v: u32,
+    /// Time at which the save finished, in milliseconds since the UNIX epoch
+    pub timestamp_ms: u64,
+    /// Address of the client which sent the image
+    pub peer: String,
+    /// Number of columns in the image
+    pub width: usize,
+    /// Number of rows in the image
+    pub height: usize,
+    /// Number of rows that were sent compressed
+    pub compressed_rows: usize,
+    /// Time taken to receive the image, in milliseconds
+    pub duration_ms: u64,
+}
+
+/// Gets the path of the metadata file of a slot
+///
+/// # Arguments
+///
+/// * `dir` - Directory where images are stored
+/// * `name` - The slot of the image
+///
+pub fn metadata_path(dir: &str, name: &Slot) -> String {
+    format!("{dir}/image_{name}.json")
+}
+
+/// Writes the metadata of a slot, replacing the metadata of its previous image
+///
+/// # Arguments
+///
+/// * `dir` - Directory where images are stored
+/// * `name` - The slot of the image
+/// * `metadata` - Metadata of the image that was just saved
+///
+pub fn write_metadata(dir: &str, name: &Slot, metadata: &SlotMetadata) -> std::io::Result<()> {
+    let path = metadata_path(dir, name);
+    let temp = format!("{path}{TEMP_SUFFIX}");
+
+    let result = serde_json::to_vec_pretty(metadata)
+        .map_err(std::io::Error::from)
+        .and_then(|json| std::fs::write(&temp, json))
+        .and_then(|()| std::fs::rename(&temp, &path));
+    if result.is_err() {
+        let _ = std::fs::remove_file(&temp);
+    }
+    result
+}
+
+/// Reads the metadata of a slot, if it exists and is valid
+///
+/// Metadata is only informational, so missing, malformed and unknown versions of metadata are all
+/// treated as if the slot had no metadata.
+///
+/// # Arguments
+///
+/// * `dir` - Directory where images are stored
+/// * `name` - The slot of the image
+///
+pub fn read_metadata(dir: &str, name: &Slot) -> Option<SlotMetadata> {
+    let json = std::fs::read(metadata_path(dir, name)).ok()?;
+    serde_json::from_slice::<SlotMetadata>(&json)
+        .ok()
+        .filter(|metadata| metadata.v == METADATA_VERSION)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::temp_dir;
+
+    fn sample() -> SlotMetadata {
+        SlotMetadata {
+            v: METADATA_VERSION,
+            timestamp_ms: 1_700_000_000_000,
+            peer: "192.168.1.20:50123".to_string(),
+            width: 320,
+            height: 240,
+            compressed_rows: 200,
+            duration_ms: 1500,
+        }
+    }
+
+    #[test]
+    fn metadata_round_trips() {
+        let dir = temp_dir("metadata_round_trips");
+
+        let json = serde_json::to_value(sample()).unwrap();
+        assert_eq!(json["v"], 1);
+        assert_eq!(
+            serde_json::from_value::<SlotMetadata>(json).unwrap(),
+            sample()
+        );
+
+        assert_eq!(read_metadata(&dir, &Slot::Number(1)), None);
+        write_metadata(&dir, &Slot::Number(1), &sample()).unwrap();
+        assert_eq!(read_metadata(&dir, &Slot::Number(1)), Some(sample()));
+    }
+
+    #[test]
+    fn invalid_metadata_is_ignored() {
+        let dir = temp_dir("invalid_metadata_is_ignored");
+
+        std::fs::write(metadata_path(&dir, &Slot::Number(1)), "{ not json").unwrap();
+        assert_eq!(read_metadata(&dir, &Slot::Number(1)), None);
+
+        let mut json = serde_json::to_value(sample()).unwrap();
+        json["v"] = 2.into();
+        std::fs::write(metadata_path(&dir, &Slot::Number(2)), json.to_string()).unwrap();
+        assert_eq!(read_metadata(&dir, &Slot::Number(2)), None);
+    }
+}
@@ -0,0 +1,407 @@
+//! Palettes of the colors that the codes sent by the canvas app stand for
+
+use clap::ValueEnum;
+use serde::Deserialize;
+
+use crate::image::{rgb565_2_rgb888, PALETTE};
+
+/// Largest number of colors in a palette, since codes only occupy the lower nibble of a byte
+pub const MAX_PALETTE_LEN: usize = 16;
+
+/// A code and the color that it stands for, as listed in a palette file
+///
+/// Both are read as wider integers than they are stored in, so that values which are out of range
+/// can be reported instead of failing to parse.
+#[derive(Deserialize)]
+struct PaletteEntry {
+    code: i64,
+    rgb565: i64,
+}
+
+/// Contents of a palette file
+#[derive(Deserialize)]
+struct PaletteFile {
+    colors: Vec<PaletteEntry>,
+}
+
+/// Reasons for which a palette could not be loaded
+///
+/// Entries are identified by their position in the file, starting from 0 (but are counted from 1
+/// when displayed).
+#[derive(Debug)]
+pub enum PaletteError {
+    /// The file could not be read
+    Io(std::io::Error),
+    /// The file is not valid TOML or JSON, or does not list the colors in the expected format
+    Parse(String),
+    /// The file does not list any colors
+    Empty,
+    /// The file lists more colors than there are codes
+    TooManyEntries(usize),
+    /// An entry has a code that does not fit in the lower nibble of a byte
+    CodeOutOfRange { entry: usize, code: i64 },
+    /// An entry has a color that does not fit in 16 bits
+    ColorOutOfRange { entry: usize, color: i64 },
+    /// An entry has the same code as an earlier entry
+    DuplicateCode { entry: usize, code: u8 },
+    /// An entry has the same color as an earlier entry (with a different code)
+    DuplicateColor { entry: usize, color: u16, code: u8 },
+}
+
+impl std::fmt::Display for PaletteError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            PaletteError::Io(err) => write!(f, "failed to read palette: {}", err),
+            PaletteError::Parse(err) => write!(f, "failed to parse palette: {}", err),
+            PaletteError::Empty => write!(f, "palette has no colors"),
+            PaletteError::TooManyEntries(len) => write!(
+                f,
+                "palette has {} colors, but at most {} are allowed",
+                len, MAX_PALETTE_LEN
+            ),
+            PaletteError::CodeOutOfRange { entry, code } => write!(
+                f,
+                "color {} has code {}, which is not in 0..={}",
+                entry + 1,
+                code,
+                MAX_PALETTE_LEN - 1
+            ),
+            PaletteError::ColorOutOfRange { entry, color } => write!(
+                f,
+                "color {} is {}, which is not a 16-bit (5-6-5) color",
+                entry + 1,
+                color
+            ),
+            PaletteError::DuplicateCode { entry, code } => {
+                write!(f, "color {} reuses code {}", entry + 1, code)
+            }
+            PaletteError::DuplicateColor { entry, color, code } => write!(
+                f,
+                "color {} is {:#06X}, which is already the color of code {}",
+                entry + 1,
+                color,
+                code
+            ),
+        }
+    }
+}
+
+impl std::error::Error for PaletteError {}
+
+/// Palettes that are built into the server, and can be picked with `--palette-preset`
+#[derive(ValueEnum, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum PalettePreset {
+    /// The colors of the canvas app
+    #[value(name = "color9")]
+    Color,
+    /// Four levels of gray, for e-paper displays
+    #[value(name = "gray4")]
+    Gray4,
+}
+
+impl PalettePreset {
+    /// Gets the palette of this preset
+    pub fn palette(self) -> &'static Palette {
+        match self {
+            Self::Color => &Palette::BUILTIN,
+            Self::Gray4 => &Palette::GRAY4,
+        }
+    }
+}
+
+/// The colors that each code stands for, and the code that each color is sent as
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Palette {
+    /// Color of each code, if the code is in the palette
+    colors: [Option<u16>; MAX_PALETTE_LEN],
+}
+
+impl Palette {
+    /// The palette of the canvas app, which is used unless another palette is loaded
+    pub const BUILTIN: Palette = Palette::from_pairs(&PALETTE);
+
+    /// Four levels of gray for e-paper builds of the canvas, from black (code 0) to white (code 3)
+    ///
+    /// The lowest bit of green matches the highest one in every level, so that they are kept
+    /// exactly by images saved in the 5-5-5 layout.
+    pub const GRAY4: Palette =
+        Palette::from_pairs(&[(0, 0x0000), (1, 0x528A), (2, 0xAD75), (3, 0xFFFF)]);
+
+    /// Builds a palette from pairs of codes and colors that are known to be valid
+    ///
+    /// # Arguments
+    ///
+    /// * `pairs` - The 4-bit code and the 16-bit (5-6-5) color of every entry
+    ///
+    const fn from_pairs(pairs: &[(u8, u16)]) -> Palette {
+        let mut colors = [None; MAX_PALETTE_LEN];
+        let mut i = 0;
+        while i < pairs.len() {
+            colors[pairs[i].0 as usize] = Some(pairs[i].1);
+            i += 1;
+        }
+        Palette { colors }
+    }
+
+    /// Builds a palette from pairs of codes and colors, in the order in which they were listed
+    ///
+    /// # Arguments
+    ///
+    /// * `entries` - The code and the 16-bit (5-6-5) color of every entry
+    ///
+    /// # Errors
+    ///
+    /// * [`PaletteError::Empty`] when there are no entries
+    /// * [`PaletteError::TooManyEntries`] when there are more than [`MAX_PALETTE_LEN`] entries
+    /// * [`PaletteError::CodeOutOfRange`] or [`PaletteError::ColorOutOfRange`] when an entry does
+    ///   not fit in a code or a color
+    /// * [`PaletteError::DuplicateCode`] or [`PaletteError::DuplicateColor`] when an entry repeats
+    ///   the code or the color of an earlier entry
+    ///
+    pub fn from_entries(entries: &[(i64, i64)]) -> Result<Self, PaletteError> {
+        if entries.is_empty() {
+            return Err(PaletteError::Empty);
+        }
+        if entries.len() > MAX_PALETTE_LEN {
+            return Err(PaletteError::TooManyEntries(entries.len()));
+        }
+
+        let mut palette = Palette {
+            colors: [None; MAX_PALETTE_LEN],
+        };
+        for (entry, &(code, color)) in entries.iter().enumerate() {
+            let Some(code) = u8::try_from(code)
+                .ok()
+                .filter(|&code| (code as usize) < MAX_PALETTE_LEN)
+            else {
+                return Err(PaletteError::CodeOutOfRange { entry, code });
+            };
+            let Ok(color) = u16::try_from(color) else {
+                return Err(PaletteError::ColorOutOfRange { entry, color });
+            };
+
+            if palette.colors[code as usize].is_some() {
+                return Err(PaletteError::DuplicateCode { entry, code });
+            }
+            if let Some(existing) = palette.color_2_code(color) {
+                return Err(PaletteError::DuplicateColor {
+                    entry,
+                    color,
+                    code: existing,
+                });
+            }
+            palette.colors[code as usize] = Some(color);
+        }
+        Ok(palette)
+    }
+
+    /// Loads a palette from a file, which lists every color as a table with its `code` and its
+    /// `rgb565` color under `colors`
+    ///
+    /// Files whose names end with `.toml` are read as TOML, and all other files are read as JSON.
+    ///
+    /// # Arguments
+    ///
+    /// * `path` - Path of the palette file
+    ///
+    /// # Errors
+    ///
+    /// * [`PaletteError::Io`] when the file can not be read
+    /// * [`PaletteError::Parse`] when the file is not in the expected format
+    /// * The same errors as [`Palette::from_entries`]
+    ///
+    pub fn load(path: &str) -> Result<Self, PaletteError> {
+        let contents = std::fs::read_to_string(path).map_err(PaletteError::Io)?;
+
+        let file: PaletteFile = match path.ends_with(".toml") {
+            true => {
+                toml::from_str(&contents).map_err(|err| PaletteError::Parse(err.to_string()))?
+            }
+            false => serde_json::from_str(&contents)
+                .map_err(|err| PaletteError::Parse(err.to_string()))?,
+        };
+        let entries: Vec<(i64, i64)> = file
+            .colors
+            .iter()
+            .map(|entry| (entry.code, entry.rgb565))
+            .collect();
+        Self::from_entries(&entries)
+    }
+
+    /// Gets the number of codes that are in the palette
+    pub fn color_count(&self) -> usize {
+        self.colors.iter().flatten().count()
+    }
+
+    /// Converts a 4-bit code to a 16-bit color
+    ///
+    /// # Arguments
+    ///
+    /// * `code` - The 4-bit code to convert to its color
+    ///
+    /// # Errors
+    ///
+    /// * When the supplied code does not map to any color
+    ///
+    pub fn code_2_color(&self, code: u8) -> Option<u16> {
+        self.colors.get(code as usize).copied().flatten()
+    }
+
+    /// Converts a 16-bit color to a 4-bit code
+    ///
+    /// # Arguments
+    ///
+    /// * `color` - The 16-bit color to convert to its code
+    ///
+    /// # Errors
+    ///
+    /// * When the supplied color does not map to any code
+    ///
+    pub fn color_2_code(&self, color: u16) -> Option<u8> {
+        self.colors
+            .iter()
+            .position(|&c| c == Some(color))
+            .map(|code| code as u8)
+    }
+
+    /// Finds the code of the color of the palette that is nearest to a 16-bit color, by the
+    /// distance between their 8-bit channels
+    ///
+    /// # Arguments
+    ///
+    /// * `color` - The 16-bit color to find the nearest code for
+    ///
+    pub fn nearest_code(&self, color: u16) -> u8 {
+        let [r, g, b] = rgb565_2_rgb888(color);
+
+        (0..MAX_PALETTE_LEN as u8)
+            .filter_map(|code| Some((code, self.code_2_color(code)?)))
+            .min_by_key(|&(_, palette_color)| {
+                let [pr, pg, pb] = rgb565_2_rgb888(palette_color);
+                [(r, pr), (g, pg), (b, pb)]
+                    .iter()
+                    .map(|&(a, b)| (a as i32 - b as i32).pow(2))
+                    .sum::<i32>()
+            })
+            .map(|(code, _)| code)
+            .unwrap()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::image::ColorFormat;
+    use crate::testing::temp_dir;
+
+    /// Path of a palette file that is shipped with the server
+    fn palette_file(name: &str) -> String {
+        format!("{}/palettes/{}", env!("CARGO_MANIFEST_DIR"), name)
+    }
+
+    #[test]
+    fn builtin_palette_matches_its_file() {
+        assert_eq!(
+            Palette::load(&palette_file("builtin.toml")).unwrap(),
+            Palette::BUILTIN
+        );
+
+        for code in 0..=0xFFu8 {
+            let color = PALETTE
+                .iter()
+                .find(|&&(c, _)| c == code)
+                .map(|&(_, color)| color);
+            assert_eq!(Palette::BUILTIN.code_2_color(code), color);
+        }
+        for &(code, color) in PALETTE.iter() {
+            assert_eq!(Palette::BUILTIN.color_2_code(color), Some(code));
+        }
+        for &color in &[0x1234, 0xE800, 0x0600, 0x2104, 0xEF7D] {
+            assert_eq!(Palette::BUILTIN.color_2_code(color), None);
+        }
+    }
+
+    #[test]
+    fn gray_palette_matches_its_file() {
+        assert_eq!(
+            Palette::load(&palette_file("gray4.toml")).unwrap(),
+            Palette::GRAY4
+        );
+        assert_eq!(PalettePreset::Gray4.palette().color_count(), 4);
+
+        // every level is gray, and is kept by the 5-5-5 layout
+        for code in 0..4 {
+            let color = Palette::GRAY4.code_2_color(code).unwrap();
+            let [r, g, b] = rgb565_2_rgb888(color);
+            assert!(r.abs_diff(g) <= 4 && r == b);
+            assert_eq!(
+                ColorFormat::Rgb555.decode(ColorFormat::Rgb555.encode(color), &Palette::GRAY4),
+                color
+            );
+        }
+    }
+
+    #[test]
+    fn palettes_are_read_from_json() {
+        let dir = temp_dir("palettes_are_read_from_json");
+        let path = format!("{dir}/palette.json");
+        std::fs::write(
+            &path,
+            r#"{ "colors": [{ "code": 3, "rgb565": 65535 }, { "code": 15, "rgb565": 0 }] }"#,
+        )
+        .unwrap();
+
+        let palette = Palette::load(&path).unwrap();
+        assert_eq!(palette.code_2_color(3), Some(0xFFFF));
+        assert_eq!(palette.code_2_color(15), Some(0x0000));
+        assert_eq!(palette.code_2_color(0), None);
+        assert_eq!(palette.nearest_code(0xFFE0), 3);
+        assert_eq!(palette.nearest_code(0x1082), 15);
+    }
+
+    #[test]
+    fn invalid_palettes_are_refused() {
+        assert!(matches!(
+            Palette::from_entries(&[]),
+            Err(PaletteError::Empty)
+        ));
+        let entries: Vec<(i64, i64)> = (0..17).map(|code| (code, code)).collect();
+        assert!(matches!(
+            Palette::from_entries(&entries),
+            Err(PaletteError::TooManyEntries(17))
+        ));
+        assert!(matches!(
+            Palette::from_entries(&[(0, 0), (16, 1)]),
+            Err(PaletteError::CodeOutOfRange { entry: 1, code: 16 })
+        ));
+        assert!(matches!(
+            Palette::from_entries(&[(-1, 0)]),
+            Err(PaletteError::CodeOutOfRange { entry: 0, code: -1 })
+        ));
+        assert!(matches!(
+            Palette::from_entries(&[(0, 0x10000)]),
+            Err(PaletteError::ColorOutOfRange { entry: 0, .. })
+        ));
+        assert!(matches!(
+            Palette::from_entries(&[(2, 0), (2, 1)]),
+            Err(PaletteError::DuplicateCode { entry: 1, code: 2 })
+        ));
+        assert!(matches!(
+            Palette::from_entries(&[(0, 0xF800), (1, 0x07E0), (2, 0xF800)]),
+            Err(PaletteError::DuplicateColor {
+                entry: 2,
+                color: 0xF800,
+                code: 0
+            })
+        ));
+
+        let dir = temp_dir("invalid_palettes_are_refused");
+        let path = format!("{dir}/palette.toml");
+        std::fs::write(&path, "[[colors]]\ncode = 0\n").unwrap();
+        assert!(matches!(Palette::load(&path), Err(PaletteError::Parse(_))));
+        assert!(matches!(
+            Palette::load(&format!("{dir}/missing.toml")),
+            Err(PaletteError::Io(_))
+        ));
+    }
+}
@@ -0,0 +1,411 @@
+//! Constants describing the wire protocol spoken with the canvas app
+//!
+//! Status bytes may be sent in place of pixel data. Every error status is at least `0x10`, so it
+//! can never be mistaken for a color code (which only occupies the lower nibble of a byte).
+//!
+//! Administrative requests (such as [`OP_SHUTDOWN`] and [`OP_CLEAR`]) are followed by the authentication token of
+//! the client, as a single length byte followed by the bytes of the token.
+//!
+//! On a connection that negotiated [`Features::NAMED_SLOTS`] (see [`OP_NEGOTIATE`]), requests that
+//! refer to a slot may name it instead of numbering it, by sending [`NAMED_SLOT`] as the slot
+//! number, and then a single length byte followed by the UTF-8 encoded name (after the device ID,
+//! if any). Names that could refer to files outside of the slot are refused with
+//! [`STATUS_BAD_REQUEST`].
+//!
+//! Similarly, on a connection that negotiated [`Features::WIDE_SLOTS`], slots above 255 are
+//! requested by sending [`WIDE_SLOT`] as the slot number, and then the slot number as a
+//! little-endian `u16`. Both forms refer to the same slots, so slot 3 requested by older firmware
+//! (as a single byte) is the same image as slot 3 requested as a `u16`. Without the features,
+//! [`NAMED_SLOT`] and [`WIDE_SLOT`] are ordinary slots, as they are for firmware that predates them.
+//!
+//! When the server runs with `--multi-device`, every request header is followed by a single byte
+//! which identifies the device, and the images of each device are kept in a separate subdirectory.
+//!
+//! Loads (with [`OP_LOAD`] or [`OP_CROP`]) may set [`LOAD_FLIP_HORIZONTAL`] and
+//! [`LOAD_FLIP_VERTICAL`] in the opcode byte, to be sent the image mirrored (after any
+//! `--load-transform` of the server, so the offset of a region is within the mirrored image). The
+//! flags of other requests are not stripped, so they are refused as unknown opcodes, like servers
+//! that do not know about the flags refuse flagged loads.
+//!
+//! Before an image (or a region of one, with [`OP_CROP`]) is loaded, the client sends a single byte with the number of rows after which it
+//! acknowledges the rows it has received (0 for [`DEFAULT_ACK_INTERVAL`]). Intervals above
+//! [`MAX_ACK_INTERVAL`] are clamped to it.
+//!
+//! After an image has been saved, the server replies with a little-endian `u16`, which is the
+//! number of rows that would have been smaller if they were sent in the other mode (raw instead
+//! of compressed, or vice versa). Clients can use this to tune how they pick the mode of each row.
+//! On a connection that negotiated [`Features::SAVE_STATUS`], the `u16` is preceded by
+//! [`STATUS_OK`], or by [`STATUS_PARTIAL_SAVE`] when the server runs with `--lenient-save` and rows
+//! that could not be received were saved as the background.
+//!
+//! A request with [`OP_CAPABILITIES`] (whose header is otherwise ignored) is answered with
+//! [`STATUS_OK`] followed by [`CAPABILITIES_LEN`] bytes describing the server, so that clients can
+//! enable the features that it supports:
+//!
+//! | Bytes | Contents                                                                    |
+//! |-------|-----------------------------------------------------------------------------|
+//! | 0..3  | Major, minor and patch version of the server                                |
+//! | 3..7  | Little-endian `u32` with bit `n` set for every supported opcode `n`         |
+//! | 7     | Number of codes in the palette of the server                                |
+//! | 8..12 | Largest height and width of an image that is accepted, as little-endian `u16`s |
+//! | 12    | Number of bits of the code in each segment of a compressed row               |
+//! | 13    | Number of bits of the count in each segment of a compressed row              |
+//! | 14    | Every flag that the opcode byte of a save (or a merge) can have              |
+//!
+//! The checksum of an image (as replied to [`OP_CHECKSUM`]) is the CRC-32 (as used by zip and PNG)
+//! of its height and width as little-endian `u16`s, followed by the codes that a load of the image
+//! would send, row by row. Clients can keep the checksum of every image they have cached, and only
+//! load an image again when the checksum of its slot changes. An image whose CRC-32 happens to be
+//! [`EMPTY_CHECKSUM`] is reported with a checksum of 1 instead, so that it is not mistaken for an
+//! empty slot.
+//!
+//! Each segment of a compressed row is a little-endian `u16` that packs a run of pixels of the same
+//! code, with the code in its lowest bits and the number of pixels in the run in the bits above
+//! them (as described by [`SegmentFormat`]). Any bits above the count are ignored. By default the
+//! code takes 4 bits and the count 9 bits, which is what clients that do not ask for the
+//! capabilities of the server must use.
+//!
+//! Saves (with [`OP_SAVE`] or [`OP_MERGE`]) may set [`SAVE_WIDE_SEGMENTS`] in the opcode byte, to
+//! send their rows in the wide layout (for displays whose runs or palettes do not fit the default
+//! format). The mode of each row is then a little-endian `u16` (0 for a raw row, otherwise the
+//! number of segments), and each segment is a little-endian `u32` in the format of
+//! [`SegmentFormat::WIDE`], whatever the format of the server. Clients should only set the flag
+//! when the reply to [`OP_CAPABILITIES`] reports it, since older servers refuse it as an unknown
+//! opcode. Loads are sent as before.
+//!
+//! A connection may start with [`OP_NEGOTIATE`] in place of a request header, followed by the
+//! [`Features`] that the client wants to use as a little-endian `u32` and a reserved byte (so that
+//! it is as long as a header, and servers without negotiation refuse it as an unknown opcode right
+//! away). The server replies with [`STATUS_OK`] followed by the features that both sides support,
+//! as a little-endian `u32`, and the client then sends its request as usual, using only the
+//! features of the reply. Bits that the server does not know are left out of the reply, so clients
+//! may ask for features of newer servers. Connections that start with a request header get none of
+//! the features.
+
+/// Opcode of a request for the version and capabilities of the server
+pub const OP_CAPABILITIES: u8 = 0;
+/// Opcode of a request to save an image sent by the client
+pub const OP_SAVE: u8 = 1;
+/// Opcode of a request to load an image to the client
+pub const OP_LOAD: u8 = 2;
+/// Opcode of an authenticated request to shut the server down gracefully
+pub const OP_SHUTDOWN: u8 = 8;
+/// Opcode of a request to move the image in a slot (the slot of the header) to another slot (the
+/// byte after the header)
+pub const OP_RENAME: u8 = 9;
+/// Opcode of a request that is only answered with [`STATUS_PONG`], for checking that the server can
+/// be reached (and how long a round trip takes)
+pub const OP_PING: u8 = 10;
+/// Opcode of an authenticated request to remove the image of every slot (of the device, with
+/// `--multi-device`), which is answered with [`STATUS_OK`] followed by the number of images that
+/// were removed, as a little-endian `u16`
+pub const OP_CLEAR: u8 = 11;
+/// Opcode of a request for the checksum of the image in a slot (whose dimensions in the header are
+/// ignored), which is answered with [`STATUS_OK`] followed by the checksum as a little-endian `u32`
+/// (or [`EMPTY_CHECKSUM`] when the slot has no image)
+pub const OP_CHECKSUM: u8 = 12;
+/// Opcode of a request to load a region of an image to the client, whose dimensions are those of
+/// the header, and whose offset follows the slot (as the column and the row of its top left corner,
+/// both little-endian `u16`s)
+pub const OP_CROP: u8 = 13;
+/// Opcode of a request to draw an image sent by the client over the image of a slot (of the same
+/// dimensions), whose transparent code follows the slot as a single byte (before the rows, which
+/// are sent like those of a save). Pixels of the transparent code are left as they were.
+pub const OP_MERGE: u8 = 14;
+/// Opcode of a request to copy the image in a slot (the slot of the header) to another slot (the
+/// byte after the header, which may name or widen the slot like the slot of a header), so that a
+/// drawing can be branched without loading and saving it again
+pub const OP_COPY: u8 = 15;
+/// Opcode of the negotiation of the [`Features`] that the rest of a connection uses, which is only
+/// served as the first message of a connection
+pub const OP_NEGOTIATE: u8 = 16;
+
+/// Flag of the opcode byte of a load, to mirror the image horizontally (swapping its left and right
+/// edges)
+pub const LOAD_FLIP_HORIZONTAL: u8 = 0x80;
+/// Flag of the opcode byte of a load, to mirror the image vertically (swapping its top and bottom
+/// edges)
+pub const LOAD_FLIP_VERTICAL: u8 = 0x40;
+/// Every flag that the opcode byte of a load can have
+pub const LOAD_FLAGS: u8 = LOAD_FLIP_HORIZONTAL | LOAD_FLIP_VERTICAL;
+/// Flag of the opcode byte of a save (or a merge), to send its rows in [`RowLayout::Wide`]
+pub const SAVE_WIDE_SEGMENTS: u8 = 0x80;
+/// Every flag that the opcode byte of a save (or a merge) can have
+pub const SAVE_FLAGS: u8 = SAVE_WIDE_SEGMENTS;
+
+/// Every opcode that the server serves, as reported to [`OP_CAPABILITIES`]
+pub const SUPPORTED_OPCODES: [u8; 12] = [
+    OP_CAPABILITIES,
+    OP_SAVE,
+    OP_LOAD,
+    OP_SHUTDOWN,
+    OP_RENAME,
+    OP_PING,
+    OP_CLEAR,
+    OP_CHECKSUM,
+    OP_CROP,
+    OP_MERGE,
+    OP_COPY,
+    OP_NEGOTIATE,
+];
+/// Number of bytes that follow the status byte of the reply to [`OP_CAPABILITIES`]
+pub const CAPABILITIES_LEN: usize = 15;
+
+/// Slot byte of the header which, when loading, refers to the most recently saved image instead
+/// (slot 255 requested as a `u16`, after [`WIDE_SLOT`], is an ordinary slot)
+pub const MOST_RECENT_SLOT: u8 = 255;
+/// Slot number which indicates that the name of the slot follows the header, with
+/// [`Features::NAMED_SLOTS`]
+pub const NAMED_SLOT: u8 = 254;
+/// Slot number which indicates that a 16-bit slot number follows the header, with
+/// [`Features::WIDE_SLOTS`]
+pub const WIDE_SLOT: u8 = 253;
+
+/// Checksum that [`OP_CHECKSUM`] replies with for a slot that has no image
+pub const EMPTY_CHECKSUM: u32 = 0;
+
+/// Number of rows after which the client acknowledges the rows of a load, when it has no preference
+pub const DEFAULT_ACK_INTERVAL: usize = 10;
+/// Largest number of rows that the client can receive before it has to acknowledge them
+pub const MAX_ACK_INTERVAL: usize = 64;
+
+/// The request was served successfully
+pub const STATUS_OK: u8 = 0x00;
+/// Reply to a save (or a merge) with [`Features::SAVE_STATUS`] when the server runs with
+/// `--lenient-save` and some of the rows could not be received, which were saved as the background
+/// instead
+pub const STATUS_PARTIAL_SAVE: u8 = 0x01;
+/// Reply to [`OP_PING`]
+pub const STATUS_PONG: u8 = 0x50;
+/// The request was malformed, and was refused without being served
+pub const STATUS_BAD_REQUEST: u8 = 0xF0;
+/// The request is for an image with no rows or no columns (or for a region that is not within its
+/// image, or to merge an image of other dimensions than the image of the slot)
+pub const STATUS_BAD_DIMENSIONS: u8 = 0xF1;
+/// The requested image exists but could not be read because it is corrupt
+pub const STATUS_CORRUPT_IMAGE: u8 = 0xF2;
+/// The requested image exists but is stored in a format that can not be read
+pub const STATUS_UNSUPPORTED_IMAGE: u8 = 0xF3;
+/// The request requires authentication, and the client did not present the correct token
+pub const STATUS_UNAUTHORIZED: u8 = 0xF4;
+/// The slot that the request refers to has no image
+pub const STATUS_NOT_FOUND: u8 = 0xF5;
+/// The slot that the request would write to already has an image, and may not be overwritten
+pub const STATUS_SLOT_OCCUPIED: u8 = 0xF6;
+/// The request could not be served because of an error on the server
+pub const STATUS_SERVER_ERROR: u8 = 0xF7;
+/// The slot that the request would write to is being written by someone else, and the request
+/// can be retried later
+pub const STATUS_SLOT_BUSY: u8 = 0xF8;
+/// The image was not saved, because the images on the server would then take more space than
+/// allowed
+pub const STATUS_QUOTA_EXCEEDED: u8 = 0xF9;
+/// The image was not saved, because the disk of the server does not have enough free space for it
+pub const STATUS_SERVER_FULL: u8 = 0xFA;
+
+/// Features that a client and the server agree to use for a connection with [`OP_NEGOTIATE`], as
+/// a bitmask
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct Features(u32);
+
+impl Features {
+    /// No features, which is what connections without a negotiation use
+    pub const NONE: Self = Self(0);
+    /// Every save (and merge) sends its rows in [`RowLayout::Wide`], as if it set
+    /// [`SAVE_WIDE_SEGMENTS`]
+    pub const WIDE_SEGMENTS: Self = Self(1 << 0);
+    /// Slots may be named, by sending [`NAMED_SLOT`] as the slot number
+    pub const NAMED_SLOTS: Self = Self(1 << 1);
+    /// Slots may be numbered with a `u16`, by sending [`WIDE_SLOT`] as the slot number
+    pub const WIDE_SLOTS: Self = Self(1 << 2);
+    /// Saves (and merges) are answered with a status byte before their feedback, which tells
+    /// whether every row was received
+    pub const SAVE_STATUS: Self = Self(1 << 3);
+    /// Every feature that the server supports
+    pub const SUPPORTED: Self = Self(
+        Self::WIDE_SEGMENTS.0 | Self::NAMED_SLOTS.0 | Self::WIDE_SLOTS.0 | Self::SAVE_STATUS.0,
+    );
+
+    /// Gets the features of a bitmask, including the bits of features that the server does not know
+    /// about (which are left out by an intersection with [`Features::SUPPORTED`])
+    pub fn from_bits(bits: u32) -> Self {
+        Self(bits)
+    }
+
+    /// Gets the bitmask of the features
+    pub fn bits(self) -> u32 {
+        self.0
+    }
+
+    /// Gets the features that are in both sets of features
+    pub fn intersection(self, other: Self) -> Self {
+        Self(self.0 & other.0)
+    }
+
+    /// Checks whether every feature of `other` is one of the features
+    pub fn contains(self, other: Self) -> bool {
+        self.0 & other.0 == other.0
+    }
+}
+
+/// Split of the bits of each segment of a compressed row between its code and its count
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct SegmentFormat {
+    /// Number of bits of the code, which are the lowest bits of the segment
+    pub code_bits: u32,
+    /// Number of bits of the count, which are the bits right above the code
+    pub count_bits: u32,
+}
+
+impl SegmentFormat {
+    /// The format of the segments of clients that do not know about other formats
+    pub const DEFAULT: Self = Self {
+        code_bits: 4,
+        count_bits: 9,
+    };
+    /// The format of the segments of rows that are sent in [`RowLayout::Wide`]
+    pub const WIDE: Self = Self {
+        code_bits: 8,
+        count_bits: 24,
+    };
+
+    /// Gets the format with the given number of bits of the code and the count, if they fit in a
+    /// segment (and the code fits in a byte)
+    ///
+    /// # Arguments
+    ///
+    /// * `code_bits` - Number of bits of the code
+    /// * `count_bits` - Number of bits of the count
+    ///
+    pub fn new(code_bits: u32, count_bits: u32) -> Option<Self> {
+        match (1..=8).contains(&code_bits) && count_bits >= 1 && code_bits + count_bits <= 16 {
+            true => Some(Self {
+                code_bits,
+                count_bits,
+            }),
+            false => None,
+        }
+    }
+
+    /// Gets the largest count that a segment can hold
+    pub fn max_count(self) -> usize {
+        (1 << self.count_bits) - 1
+    }
+
+    /// Checks whether a code can be held by a segment
+    pub fn fits_code(self, code: u8) -> bool {
+        (code as u32) < (1 << self.code_bits)
+    }
+
+    /// Packs a code and a count into a segment, keeping only the bits of each that fit
+    pub fn pack(self, code: u8, count: usize) -> u32 {
+        let code = code as u32 & ((1 << self.code_bits) - 1);
+        let count = (count & self.max_count()) as u32;
+        (count << self.code_bits) | code
+    }
+
+    /// Unpacks the code and the count of a segment
+    pub fn unpack(self, segment: u32) -> (u8, usize) {
+        let code = (segment & ((1 << self.code_bits) - 1)) as u8;
+        let count = (segment >> self.code_bits) as usize & self.max_count();
+        (code, count)
+    }
+}
+
+/// Integer that holds a segment of a compressed row, whose format must fit in its bits
+pub trait Segment: Copy {
+    /// Gets the segment with the given bits, keeping only the bits that fit
+    fn from_bits(bits: u32) -> Self;
+    /// Gets the bits of the segment
+    fn bits(self) -> u32;
+}
+
+impl Segment for u16 {
+    fn from_bits(bits: u32) -> Self {
+        bits as u16
+    }
+
+    fn bits(self) -> u32 {
+        self.into()
+    }
+}
+
+impl Segment for u32 {
+    fn from_bits(bits: u32) -> Self {
+        bits
+    }
+
+    fn bits(self) -> u32 {
+        self
+    }
+}
+
+/// How the rows of a save are sent
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum RowLayout {
+    /// A single byte of mode, and segments of 16 bits in the format of the server
+    Narrow,
+    /// A little-endian `u16` of mode, and segments of 32 bits in [`SegmentFormat::WIDE`]
+    Wide,
+}
+
+impl RowLayout {
+    /// Gets the layout of the rows of a save with the given flags in its opcode byte
+    pub fn from_flags(flags: u8) -> Self {
+        match flags & SAVE_WIDE_SEGMENTS {
+            0 => Self::Narrow,
+            _ => Self::Wide,
+        }
+    }
+
+    /// Gets the number of bytes of the mode of each row
+    pub fn mode_len(self) -> usize {
+        match self {
+            Self::Narrow => 1,
+            Self::Wide => 2,
+        }
+    }
+
+    /// Gets the number of bytes of each segment
+    pub fn segment_len(self) -> usize {
+        match self {
+            Self::Narrow => 2,
+            Self::Wide => 4,
+        }
+    }
+
+    /// Gets the largest number of segments that a compressed row can have
+    pub fn max_segments(self) -> usize {
+        match self {
+            Self::Narrow => u8::MAX as usize,
+            Self::Wide => u16::MAX as usize,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn unknown_features_are_left_out() {
+        // a newer client may ask for features that this server has never heard of
+        let requested = Features::from_bits(u32::MAX);
+        assert_eq!(requested.bits(), u32::MAX);
+        assert_eq!(
+            Features::SUPPORTED.intersection(requested),
+            Features::SUPPORTED
+        );
+        assert_eq!(
+            Features::SUPPORTED
+                .intersection(Features::from_bits(0b1111_0000))
+                .bits(),
+            0
+        );
+
+        assert!(requested.contains(Features::WIDE_SEGMENTS));
+        assert!(requested.contains(Features::NONE));
+        assert!(!Features::NONE.contains(Features::WIDE_SEGMENTS));
+        assert_eq!(Features::default(), Features::NONE);
+    }
+}
@@ -0,0 +1,1324 @@
+//! Functions to manage the files of the slots in the image directory
+
+use crate::checksums::{checksum_path, write_checksum};
+use crate::image::*;
+use crate::instance::INSTANCE_LOCK_NAME;
+use crate::metadata::metadata_path;
+use crate::palette::Palette;
+
+/// Removes temporary files and locks left behind in a directory by saves that were interrupted
+///
+/// # Arguments
+///
+/// * `dir` - Directory where images are stored
+///
+pub fn remove_temp_files(dir: &str) {
+    let Ok(entries) = std::fs::read_dir(dir) else {
+        return;
+    };
+
+    for entry in entries.filter_map(|entry| entry.ok()) {
+        let file_name = entry.file_name().to_string_lossy().into_owned();
+        // the lock of the server itself is held by the running server, rather than left behind
+        if (!file_name.ends_with(TEMP_SUFFIX) && !file_name.ends_with(LOCK_SUFFIX))
+            || file_name == INSTANCE_LOCK_NAME
+        {
+            continue;
+        }
+        match std::fs::remove_file(entry.path()) {
+            Ok(()) => println!("Removed leftover temporary file {:?}", entry.path()),
+            Err(err) => eprintln!(
+                "Failed to remove temporary file {:?}: {}",
+                entry.path(),
+                err
+            ),
+        }
+    }
+}
+
+/// Lists the directories of the devices in an image directory that is shared by multiple devices
+///
+/// Only subdirectories named after a device ID are listed, so that the history, trash and
+/// thumbnails of the image directory itself are not mistaken for devices.
+///
+/// # Arguments
+///
+/// * `image_dir` - Directory where the directory of each device is created
+///
+pub fn device_dirs(image_dir: &str) -> Vec<String> {
+    let Ok(entries) = std::fs::read_dir(image_dir) else {
+        return Vec::new();
+    };
+
+    let mut dirs: Vec<String> = entries
+        .filter_map(|entry| entry.ok())
+        .filter(|entry| entry.path().is_dir())
+        .filter(|entry| entry.file_name().to_string_lossy().parse::<u8>().is_ok())
+        .map(|entry| entry.path().to_string_lossy().into_owned())
+        .collect();
+    dirs.sort();
+    dirs
+}
+
+/// Suffix of the file that marks a slot as locked, while its image is being replaced
+pub const LOCK_SUFFIX: &str = ".lock";
+
+/// Lock on a slot, which is released when dropped
+#[derive(Debug)]
+pub struct SlotLock {
+    path: String,
+}
+
+impl Drop for SlotLock {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_file(&self.path);
+    }
+}
+
+/// Locks a slot, so that the server and the subcommands never replace its image at the same time
+///
+/// # Arguments
+///
+/// * `dir` - Directory where images are stored
+/// * `name` - The slot to lock
+///
+/// # Errors
+///
+/// * With [`std::io::ErrorKind::AlreadyExists`] when the slot is already locked
+/// * When the lock file can not be created
+///
+pub fn lock_slot(dir: &str, name: &Slot) -> std::io::Result<SlotLock> {
+    let path = format!("{dir}/image_{name}{LOCK_SUFFIX}");
+    std::fs::OpenOptions::new()
+        .write(true)
+        .create_new(true)
+        .open(&path)?;
+    Ok(SlotLock { path })
+}
+
+/// Time to wait between attempts to lock a slot that is locked by someone else
+const LOCK_RETRY_INTERVAL: std::time::Duration = std::time::Duration::from_millis(5);
+
+/// Locks a slot like [`lock_slot`], but waits for it while it is locked by someone else (such as a
+/// save of the same slot from another connection)
+///
+/// # Arguments
+///
+/// * `dir` - Directory where images are stored
+/// * `name` - The slot to lock
+/// * `wait` - Longest time to wait for the slot to be unlocked
+///
+/// # Errors
+///
+/// * With [`std::io::ErrorKind::AlreadyExists`] when the slot is still locked after the wait
+/// * When the lock file can not be created
+///
+pub fn lock_slot_waiting(
+    dir: &str,
+    name: &Slot,
+    wait: std::time::Duration,
+) -> std::io::Result<SlotLock> {
+    let deadline = std::time::Instant::now() + wait;
+    loop {
+        match lock_slot(dir, name) {
+            Err(err)
+                if err.kind() == std::io::ErrorKind::AlreadyExists
+                    && std::time::Instant::now() < deadline =>
+            {
+                std::thread::sleep(LOCK_RETRY_INTERVAL)
+            }
+            result => return result,
+        }
+    }
+}
+
+/// Longest name of a slot (in bytes), which keeps the file names of its images within the limits
+/// of common filesystems
+pub const MAX_SLOT_NAME_LEN: usize = 64;
+
+/// A slot in which an image is stored, identified either by a number or by a name
+///
+/// The images of a slot are stored in `image_{slot}.bmp`, so a slot named `7` is the same slot as
+/// slot number 7.
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord, Hash, serde::Serialize)]
+#[serde(untagged)]
+pub enum Slot {
+    /// A slot identified by a number, as sent in the header of a request (or after it, for slots
+    /// that do not fit in a byte)
+    Number(u16),
+    /// A slot identified by a name, which is always valid (see [`Slot::named`])
+    Name(String),
+}
+
+/// Reasons for which a name can not be used as the name of a slot
+#[derive(Debug, PartialEq, Eq)]
+pub enum SlotNameError {
+    /// The name has no characters
+    Empty,
+    /// The name is longer than [`MAX_SLOT_NAME_LEN`] bytes
+    TooLong(usize),
+    /// The name is not valid UTF-8
+    NotUtf8,
+    /// The name contains a character which could make it refer to a file outside of the slot
+    InvalidCharacter(char),
+}
+
+impl std::fmt::Display for SlotNameError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Empty => write!(f, "slot name is empty"),
+            Self::TooLong(len) => write!(
+                f,
+                "slot name is {} bytes long, at most {} bytes are allowed",
+                len, MAX_SLOT_NAME_LEN
+            ),
+            Self::NotUtf8 => write!(f, "slot name is not valid UTF-8"),
+            Self::InvalidCharacter(c) => write!(f, "slot name contains invalid character {:?}", c),
+        }
+    }
+}
+
+impl std::error::Error for SlotNameError {}
+
+impl Slot {
+    /// Validates the name of a slot, as sent by the client
+    ///
+    /// Names may not contain path separators, control characters or dots, so that they can never
+    /// refer to a file outside of the image directory, or to the backup and temporary files of
+    /// another slot (which are named by adding extensions to the name of its image).
+    ///
+    /// # Arguments
+    ///
+    /// * `name` - The name of the slot, as UTF-8 encoded bytes
+    ///
+    pub fn named(name: &[u8]) -> Result<Self, SlotNameError> {
+        let name = std::str::from_utf8(name).map_err(|_| SlotNameError::NotUtf8)?;
+
+        if name.is_empty() {
+            return Err(SlotNameError::Empty);
+        }
+        if name.len() > MAX_SLOT_NAME_LEN {
+            return Err(SlotNameError::TooLong(name.len()));
+        }
+        if let Some(c) = name
+            .chars()
+            .find(|&c| matches!(c, '/' | '\\' | '.') || c.is_control())
+        {
+            return Err(SlotNameError::InvalidCharacter(c));
+        }
+
+        // names which are slot numbers refer to the numbered slots, so that every file has one slot
+        Ok(match name.parse::<u16>() {
+            Ok(number) if number.to_string() == name => Self::Number(number),
+            _ => Self::Name(name.to_string()),
+        })
+    }
+}
+
+impl std::str::FromStr for Slot {
+    type Err = SlotNameError;
+
+    fn from_str(name: &str) -> Result<Self, Self::Err> {
+        Self::named(name.as_bytes())
+    }
+}
+
+impl std::fmt::Display for Slot {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Number(number) => f.pad(&number.to_string()),
+            Self::Name(name) => f.pad(name),
+        }
+    }
+}
+
+/// Gets the slot of an image from its file name, if it is the name of an image
+///
+/// # Arguments
+///
+/// * `file_name` - Name of the file (with extension), of the form `image_{slot}.bmp` (or
+///   `image_{slot}.bmp.gz` or `image_{slot}.bmp.zst`, if the image is compressed)
+///
+pub fn parse_image_slot(file_name: &str) -> Option<Slot> {
+    let file_name = file_name
+        .strip_suffix(stored_suffix(file_name))
+        .unwrap_or(file_name);
+    let name = file_name.strip_prefix("image_")?.strip_suffix(".bmp")?;
+    Slot::named(name.as_bytes()).ok()
+}
+
+/// Gets the path of the image file of a slot, which is the newest of `image_{name}.bmp`,
+/// `image_{name}.bmp.gz` and `image_{name}.bmp.zst`, and `image_{name}.bmp` if the slot has no image
+///
+/// # Arguments
+///
+/// * `dir` - Directory where images are stored
+/// * `name` - The slot of the image
+///
+pub fn image_path(dir: &str, name: &Slot) -> String {
+    let filename = format!("{dir}/image_{name}");
+    stored_bmp_path(&filename).unwrap_or_else(|| format!("{filename}.bmp"))
+}
+
+/// Removes the image files of a slot in every other form than the given file (compressed or not),
+/// after the image has been replaced by that file
+///
+/// The newest form of an image is the one that is served, so a stale image could otherwise hide
+/// the new one (or come back once the new one is removed).
+///
+/// # Arguments
+///
+/// * `kept` - Path of the image file that replaced the image of the slot
+///
+pub fn remove_stale_image(kept: &str) -> std::io::Result<()> {
+    let kept_suffix = stored_suffix(kept);
+    let plain = kept.strip_suffix(kept_suffix).unwrap_or(kept);
+    for suffix in STORED_SUFFIXES {
+        if suffix == kept_suffix {
+            continue;
+        }
+        match std::fs::remove_file(format!("{plain}{suffix}")) {
+            Err(err) if err.kind() != std::io::ErrorKind::NotFound => return Err(err),
+            _ => {}
+        }
+    }
+    Ok(())
+}
+
+/// Gets the slots of every image in a directory, numbered slots first (in ascending order) and
+/// then named slots (in alphabetical order)
+///
+/// # Arguments
+///
+/// * `dir` - Directory to search for images
+///
+pub fn list_slots(dir: &str) -> Vec<Slot> {
+    let Ok(entries) = std::fs::read_dir(dir) else {
+        return Vec::new();
+    };
+
+    let mut slots: Vec<Slot> = entries
+        .filter_map(|entry| entry.ok())
+        .filter_map(|entry| parse_image_slot(&entry.file_name().to_string_lossy()))
+        .collect();
+    slots.sort_unstable();
+    // a slot that is stored in more than one form is only listed once
+    slots.dedup();
+    slots
+}
+
+/// Details about the image file of a slot, as found in the image directory
+#[derive(Debug, Clone, PartialEq, Eq, serde::Serialize)]
+pub struct SlotInfo {
+    /// The slot of the image
+    pub slot: Slot,
+    /// Number of columns in the image, if its header could be read
+    pub width: Option<usize>,
+    /// Number of rows in the image, if its header could be read
+    pub height: Option<usize>,
+    /// Size of the file, in bytes
+    pub bytes: u64,
+    /// Time at which the file was last modified, in milliseconds since the UNIX epoch
+    pub modified_ms: u64,
+}
+
+/// Gets the details of the image file of every slot in a directory, in the same order as
+/// [`list_slots`]
+///
+/// Only the headers of the images are read, and images whose headers can not be read are still
+/// listed (without dimensions).
+///
+/// # Arguments
+///
+/// * `dir` - Directory to search for images
+///
+pub fn slot_inventory(dir: &str) -> Vec<SlotInfo> {
+    list_slots(dir)
+        .into_iter()
+        .filter_map(|slot| {
+            let filename = format!("{dir}/image_{slot}");
+            let metadata = std::fs::metadata(image_path(dir, &slot)).ok()?;
+            let dimensions = read_bmp_dimensions(&filename).ok();
+            let modified_ms = metadata
+                .modified()
+                .ok()
+                .and_then(|time| time.duration_since(std::time::UNIX_EPOCH).ok())
+                .map_or(0, |duration| duration.as_millis() as u64);
+
+            Some(SlotInfo {
+                slot,
+                width: dimensions.map(|(width, _)| width),
+                height: dimensions.map(|(_, height)| height),
+                bytes: metadata.len(),
+                modified_ms,
+            })
+        })
+        .collect()
+}
+
+/// Finds the most recently modified image in a directory, and gets its slot
+///
+/// # Arguments
+///
+/// * `dir` - Directory to search for images
+///
+pub fn most_recent_slot(dir: &str) -> Option<Slot> {
+    std::fs::read_dir(dir)
+        .ok()?
+        .filter_map(|entry| entry.ok())
+        .filter_map(|entry| {
+            let slot = parse_image_slot(&entry.file_name().to_string_lossy())?;
+            Some((entry.metadata().ok()?.modified().ok()?, slot))
+        })
+        .max_by_key(|(modified, _)| *modified)
+        .map(|(_, slot)| slot)
+}
+
+/// Loads the image stored in a slot, from whichever supported file format it is stored in
+///
+/// The BMP file (`image_{name}.bmp`) takes precedence, and the PNG file (`image_{name}.png`) is
+/// only loaded when there is no BMP file. The colors of PNG files are mapped to the nearest colors
+/// of the palette.
+///
+/// # Arguments
+///
+/// * `dir` - Directory to retrieve the image from
+/// * `name` - The slot of the image
+/// * `expected_width` - Number of columns in the image as expected by the client
+/// * `expected_height` - Number of rows in the image as expected by the client
+/// * `palette` - The palette that the colors of the image are mapped to
+///
+pub fn load_slot(
+    dir: &str,
+    name: &Slot,
+    expected_width: usize,
+    expected_height: usize,
+    palette: &Palette,
+) -> Result<Vec<Vec<u16>>, LoadError> {
+    let filename = format!("{dir}/image_{name}");
+
+    match load_bmp_image(&filename, expected_width, expected_height, palette) {
+        Err(LoadError::NotFound) => {
+            load_png_image(&filename, expected_width, expected_height, palette)
+        }
+        result => result,
+    }
+}
+
+/// Gets the path of the file that keeps the previous image of a slot
+///
+/// # Arguments
+///
+/// * `dir` - Directory where images are stored
+/// * `name` - The slot of the image
+///
+pub fn backup_path(dir: &str, name: &Slot) -> String {
+    format!("{dir}/image_{name}.bak.bmp")
+}
+
+/// Copies the image stored in a slot to its backup file, replacing the previous backup
+///
+/// The copy is written to a temporary file first, so the previous backup is only replaced once the
+/// copy is complete. Saving an image after backing it up can never lose both copies, as the save
+/// itself replaces the image atomically.
+///
+/// # Arguments
+///
+/// * `dir` - Directory where images are stored
+/// * `name` - The slot of the image
+///
+/// # Returns
+///
+/// Whether the slot had an image to back up
+///
+pub fn backup_slot(dir: &str, name: &Slot) -> std::io::Result<bool> {
+    match copy_atomically(&image_path(dir, name), &backup_path(dir, name)) {
+        Ok(()) => Ok(true),
+        Err(err) if err.kind() == std::io::ErrorKind::NotFound => Ok(false),
+        Err(err) => Err(err),
+    }
+}
+
+/// Copies a file by first copying it to a temporary file and then renaming the temporary file, so
+/// that the destination is either left untouched or replaced with a complete copy
+///
+/// # Arguments
+///
+/// * `from` - Path of the file to copy
+/// * `to` - Path to copy the file to
+///
+fn copy_atomically(from: &str, to: &str) -> std::io::Result<()> {
+    let temp = format!("{to}{TEMP_SUFFIX}");
+
+    let result = std::fs::copy(from, &temp).and_then(|_| std::fs::rename(&temp, to));
+    if result.is_err() {
+        let _ = std::fs::remove_file(&temp);
+    }
+    result
+}
+
+/// Swaps the image stored in a slot with its backup
+///
+/// Every step is a rename, so both images remain on disk even if the swap is interrupted. If it is
+/// interrupted, the image that was in the slot is left in `image_{name}.swap.bmp`.
+///
+/// # Arguments
+///
+/// * `dir` - Directory where images are stored
+/// * `name` - The slot of the image
+///
+/// # Errors
+///
+/// * When the slot has no backup (with [`std::io::ErrorKind::NotFound`])
+/// * When any of the files can not be renamed
+///
+pub fn restore_slot(dir: &str, name: &Slot) -> std::io::Result<()> {
+    let image = image_path(dir, name);
+    let backup = backup_path(dir, name);
+    let swap = format!("{dir}/image_{name}.swap.bmp");
+
+    if !std::path::Path::new(&backup).exists() {
+        return Err(std::io::Error::new(
+            std::io::ErrorKind::NotFound,
+            "slot has no backup",
+        ));
+    }
+
+    let had_image = match std::fs::rename(&image, &swap) {
+        Ok(()) => true,
+        Err(err) if err.kind() == std::io::ErrorKind::NotFound => false,
+        Err(err) => return Err(err),
+    };
+    std::fs::rename(&backup, &image)?;
+    if had_image {
+        std::fs::rename(&swap, &backup)?;
+    }
+
+    if let Err(err) = write_checksum(dir, name) {
+        eprintln!("Failed to write checksum of image_{}.bmp: {}", name, err);
+    }
+    Ok(())
+}
+
+/// Checks that the image of a slot can be moved or copied to another slot, and backs up the image
+/// of the destination if it is replaced
+///
+/// # Arguments
+///
+/// * `dir` - Directory where images are stored
+/// * `from` - The slot of the image to move or copy
+/// * `to` - The slot to move or copy the image to
+/// * `overwrite` - Whether to replace the image of the destination, if it has one
+///
+/// # Returns
+///
+/// The path of the image of the source, and the path that it is moved or copied to (in the same
+/// form), or `None` when both are the same slot
+///
+/// # Errors
+///
+/// * When the source has no image (with [`std::io::ErrorKind::NotFound`])
+/// * When the destination has an image and may not be overwritten (with
+///   [`std::io::ErrorKind::AlreadyExists`])
+/// * When the destination can not be backed up
+///
+fn prepare_transfer(
+    dir: &str,
+    from: &Slot,
+    to: &Slot,
+    overwrite: bool,
+) -> std::io::Result<Option<(String, String)>> {
+    let source = image_path(dir, from);
+    let suffix = stored_suffix(&source);
+    let destination = format!("{dir}/image_{to}.bmp{suffix}");
+
+    if !std::path::Path::new(&source).exists() {
+        return Err(std::io::Error::new(
+            std::io::ErrorKind::NotFound,
+            "source slot has no image",
+        ));
+    }
+    if from == to {
+        return Ok(None);
+    }
+    if std::path::Path::new(&image_path(dir, to)).exists() {
+        if !overwrite {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::AlreadyExists,
+                "destination slot already has an image",
+            ));
+        }
+        backup_slot(dir, to)?;
+    }
+    Ok(Some((source, destination)))
+}
+
+/// Moves the image stored in a slot to another slot, along with its metadata
+///
+/// The image is moved with a single rename, so it is never missing from both slots. When the
+/// destination is overwritten, its image is backed up first (like when saving over it).
+///
+/// # Arguments
+///
+/// * `dir` - Directory where images are stored
+/// * `from` - The slot of the image to move
+/// * `to` - The slot to move the image to
+/// * `overwrite` - Whether to replace the image of the destination, if it has one
+///
+/// # Errors
+///
+/// * When the source has no image (with [`std::io::ErrorKind::NotFound`])
+/// * When the destination has an image and may not be overwritten (with
+///   [`std::io::ErrorKind::AlreadyExists`])
+/// * When the destination can not be backed up, or the image can not be renamed
+///
+pub fn rename_slot(dir: &str, from: &Slot, to: &Slot, overwrite: bool) -> std::io::Result<()> {
+    let Some((source, destination)) = prepare_transfer(dir, from, to, overwrite)? else {
+        return Ok(());
+    };
+
+    std::fs::rename(&source, &destination)?;
+    if let Err(err) = remove_stale_image(&destination) {
+        eprintln!("Failed to remove the previous image_{}.bmp: {}", to, err);
+    }
+
+    // the metadata describes the image, so it follows the image (or is dropped if it has none)
+    let metadata = metadata_path(dir, from);
+    let result = if std::path::Path::new(&metadata).exists() {
+        std::fs::rename(&metadata, metadata_path(dir, to))
+    } else {
+        std::fs::remove_file(metadata_path(dir, to))
+    };
+    if let Err(err) = result {
+        if err.kind() != std::io::ErrorKind::NotFound {
+            eprintln!("Failed to move metadata of image_{}.bmp: {}", from, err);
+        }
+    }
+
+    // the checksum file names the image, so it is written again instead of being moved
+    let _ = std::fs::remove_file(checksum_path(dir, from));
+    if let Err(err) = write_checksum(dir, to) {
+        eprintln!("Failed to write checksum of image_{}.bmp: {}", to, err);
+    }
+    Ok(())
+}
+
+/// Copies the image stored in a slot to another slot, along with its metadata
+///
+/// The image is copied to a temporary file, which is then renamed over the destination, so the
+/// destination is never left with a partially copied image. When the destination is overwritten,
+/// its image is backed up first (like when saving over it).
+///
+/// # Arguments
+///
+/// * `dir` - Directory where images are stored
+/// * `from` - The slot of the image to copy
+/// * `to` - The slot to copy the image to
+/// * `overwrite` - Whether to replace the image of the destination, if it has one
+///
+/// # Errors
+///
+/// * The same errors as [`rename_slot`], and also when the image can not be copied
+///
+pub fn copy_slot(dir: &str, from: &Slot, to: &Slot, overwrite: bool) -> std::io::Result<()> {
+    let Some((source, destination)) = prepare_transfer(dir, from, to, overwrite)? else {
+        return Ok(());
+    };
+
+    let temp = format!("{destination}{TEMP_SUFFIX}");
+    let result = std::fs::copy(&source, &temp).and_then(|_| std::fs::rename(&temp, &destination));
+    if result.is_err() {
+        let _ = std::fs::remove_file(&temp);
+    }
+    result?;
+    if let Err(err) = remove_stale_image(&destination) {
+        eprintln!("Failed to remove the previous image_{}.bmp: {}", to, err);
+    }
+
+    // the metadata describes the image, so it is copied with the image (or is dropped if it has none)
+    let metadata = metadata_path(dir, from);
+    let result = match std::path::Path::new(&metadata).exists() {
+        true => std::fs::copy(&metadata, metadata_path(dir, to)).map(|_| ()),
+        false => std::fs::remove_file(metadata_path(dir, to)),
+    };
+    if let Err(err) = result {
+        if err.kind() != std::io::ErrorKind::NotFound {
+            eprintln!("Failed to copy metadata of image_{}.bmp: {}", from, err);
+        }
+    }
+
+    if let Err(err) = write_checksum(dir, to) {
+        eprintln!("Failed to write checksum of image_{}.bmp: {}", to, err);
+    }
+    Ok(())
+}
+
+/// Finds another slot whose image is identical to the given image
+///
+/// # Arguments
+///
+/// * `dir` - Directory where images are stored
+/// * `name` - The slot of the image, which is never returned
+/// * `data` - The image to find a duplicate of
+/// * `palette` - The palette that the images were saved with
+///
+pub fn find_duplicate(
+    dir: &str,
+    name: &Slot,
+    data: &[Vec<u16>],
+    palette: &Palette,
+) -> Option<Slot> {
+    let height = data.len();
+    let width = data.first().map_or(0, |row| row.len());
+    let hash = content_hash(data);
+
+    list_slots(dir)
+        .into_iter()
+        .filter(|slot| slot != name)
+        .find(|slot| {
+            load_bmp_image(&format!("{dir}/image_{slot}"), width, height, palette)
+                .is_ok_and(|other| content_hash(&other) == hash && other == data)
+        })
+}
+
+/// Makes a slot share the file of another slot (as a hard link), replacing its current image
+///
+/// Since images are always replaced by renaming new files over them, saving either slot later
+/// only replaces the image of that slot, and deleting either slot leaves the other one intact.
+///
+/// # Arguments
+///
+/// * `dir` - Directory where images are stored
+/// * `from` - The slot of the image to share
+/// * `to` - The slot of the image to replace
+///
+/// # Errors
+///
+/// * When the filesystem does not support hard links (such as FAT)
+///
+pub fn link_slot(dir: &str, from: &Slot, to: &Slot) -> std::io::Result<()> {
+    let from = image_path(dir, from);
+    let suffix = stored_suffix(&from);
+    let to = format!("{dir}/image_{to}.bmp{suffix}");
+    let temp = format!("{to}{TEMP_SUFFIX}");
+
+    let _ = std::fs::remove_file(&temp);
+    let result = std::fs::hard_link(&from, &temp)
+        .and_then(|()| std::fs::rename(&temp, &to))
+        .and_then(|()| remove_stale_image(&to));
+    if result.is_err() {
+        let _ = std::fs::remove_file(&temp);
+    }
+    result
+}
+
+/// Gets the path of the directory that keeps every saved version of the image stored in a slot
+///
+/// # Arguments
+///
+/// * `dir` - Directory where images are stored
+/// * `name` - The slot of the image
+///
+pub fn history_dir(dir: &str, name: &Slot) -> String {
+    format!("{dir}/history/{name}")
+}
+
+/// Copies the image stored in a slot into its history, as a version named after the current time
+///
+/// # Arguments
+///
+/// * `dir` - Directory where images are stored
+/// * `name` - The slot of the image
+///
+/// # Returns
+///
+/// The version (milliseconds since the UNIX epoch) that the image was archived as
+///
+pub fn archive_slot(dir: &str, name: &Slot) -> std::io::Result<u128> {
+    let version = std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .map_err(std::io::Error::other)?
+        .as_millis();
+
+    let history = history_dir(dir, name);
+    std::fs::create_dir_all(&history)?;
+    copy_atomically(&image_path(dir, name), &format!("{history}/{version}.bmp"))?;
+
+    Ok(version)
+}
+
+/// Gets every version in the history of a slot, from the oldest to the newest
+///
+/// Files in the history directory that are not versions are ignored, and a missing history
+/// directory is the same as an empty history
+///
+/// # Arguments
+///
+/// * `dir` - Directory where images are stored
+/// * `name` - The slot of the image
+///
+pub fn list_history(dir: &str, name: &Slot) -> Vec<u128> {
+    let Ok(entries) = std::fs::read_dir(history_dir(dir, name)) else {
+        return Vec::new();
+    };
+
+    let mut versions: Vec<u128> = entries
+        .filter_map(|entry| entry.ok())
+        .filter_map(|entry| {
+            entry
+                .file_name()
+                .to_string_lossy()
+                .strip_suffix(".bmp")?
+                .parse()
+                .ok()
+        })
+        .collect();
+    versions.sort_unstable();
+    versions
+}
+
+/// Deletes the oldest versions in the history of a slot, until at most `keep` versions are left
+///
+/// # Arguments
+///
+/// * `dir` - Directory where images are stored
+/// * `name` - The slot of the image
+/// * `keep` - Number of versions to keep
+///
+pub fn prune_history(dir: &str, name: &Slot, keep: usize) -> std::io::Result<()> {
+    let versions = list_history(dir, name);
+    let excess = versions.len().saturating_sub(keep);
+
+    for version in &versions[..excess] {
+        match std::fs::remove_file(format!("{}/{version}.bmp", history_dir(dir, name))) {
+            Err(err) if err.kind() != std::io::ErrorKind::NotFound => return Err(err),
+            _ => {}
+        }
+    }
+    Ok(())
+}
+
+/// Replaces the image stored in a slot with a version from its history
+///
+/// The image that is replaced is backed up first, just like when a new image is saved
+///
+/// # Arguments
+///
+/// * `dir` - Directory where images are stored
+/// * `name` - The slot of the image
+/// * `version` - The version to revert to
+///
+pub fn revert_slot(dir: &str, name: &Slot, version: u128) -> std::io::Result<()> {
+    let path = format!("{}/{version}.bmp", history_dir(dir, name));
+    if !std::path::Path::new(&path).exists() {
+        return Err(std::io::Error::new(
+            std::io::ErrorKind::NotFound,
+            "slot has no such version",
+        ));
+    }
+
+    backup_slot(dir, name)?;
+    copy_atomically(&path, &image_path(dir, name))?;
+
+    if let Err(err) = write_checksum(dir, name) {
+        eprintln!("Failed to write checksum of image_{}.bmp: {}", name, err);
+    }
+    Ok(())
+}
+
+/// Loads every version in the history of a slot, from the oldest to the newest
+///
+/// Versions whose dimensions differ from those of the newest version (or that can not be loaded)
+/// are skipped with a warning, so that every image has the same dimensions.
+///
+/// # Arguments
+///
+/// * `dir` - Directory where images are stored
+/// * `name` - The slot of the image
+/// * `palette` - The palette that the versions were saved with
+///
+pub fn load_history(dir: &str, name: &Slot, palette: &Palette) -> Vec<Vec<Vec<u16>>> {
+    let history = history_dir(dir, name);
+    let versions = list_history(dir, name);
+
+    let Some((width, height)) = versions
+        .last()
+        .and_then(|newest| read_bmp_dimensions(&format!("{history}/{newest}")).ok())
+    else {
+        return Vec::new();
+    };
+
+    versions
+        .iter()
+        .filter_map(|version| {
+            match load_bmp_image(&format!("{history}/{version}"), width, height, palette) {
+                Ok(img) => Some(img),
+                Err(err) => {
+                    eprintln!(
+                        "Skipping version {} of image_{}.bmp: {}",
+                        version, name, err
+                    );
+                    None
+                }
+            }
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::palette::Palette;
+    use crate::testing::temp_dir;
+
+    #[test]
+    fn inventory_flags_unreadable_images() {
+        let dir = temp_dir("inventory_flags_unreadable_images");
+        save_bmp_image(&vec![vec![0xF800; 3]; 2], &format!("{dir}/image_1")).unwrap();
+        std::fs::write(format!("{dir}/image_card.bmp"), b"BM").unwrap();
+        std::fs::write(format!("{dir}/image_2.png"), b"not a bmp").unwrap();
+        std::fs::write(format!("{dir}/notes.bmp"), b"not a slot").unwrap();
+        std::fs::write(format!("{dir}/image_3.bmp.tmp"), b"partial").unwrap();
+
+        let inventory = slot_inventory(&dir);
+        assert_eq!(inventory.len(), 2);
+
+        assert_eq!(inventory[0].slot, Slot::Number(1));
+        assert_eq!(
+            (inventory[0].width, inventory[0].height),
+            (Some(3), Some(2))
+        );
+        assert_eq!(
+            inventory[0].bytes,
+            std::fs::metadata(format!("{dir}/image_1.bmp"))
+                .unwrap()
+                .len()
+        );
+        assert!(inventory[0].modified_ms > 0);
+
+        assert_eq!(inventory[1].slot, Slot::Name("card".to_string()));
+        assert_eq!((inventory[1].width, inventory[1].height), (None, None));
+        assert_eq!(inventory[1].bytes, 2);
+
+        let json = serde_json::to_value(&inventory).unwrap();
+        assert_eq!(json[0]["slot"], 1);
+        assert_eq!(json[1]["slot"], "card");
+        assert_eq!(json[1]["width"], serde_json::Value::Null);
+    }
+
+    #[test]
+    fn slots_are_locked_exclusively() {
+        let dir = temp_dir("slots_are_locked_exclusively");
+
+        let lock = lock_slot(&dir, &Slot::Number(1)).unwrap();
+        assert_eq!(
+            lock_slot(&dir, &Slot::Number(1)).unwrap_err().kind(),
+            std::io::ErrorKind::AlreadyExists
+        );
+        lock_slot(&dir, &Slot::Number(2)).unwrap();
+        drop(lock);
+        let lock = lock_slot(&dir, &Slot::Number(1)).unwrap();
+
+        // locks left behind by a server that was killed are removed when it starts again, but not
+        // the lock of the server itself
+        std::mem::forget(lock);
+        std::fs::write(format!("{dir}/{INSTANCE_LOCK_NAME}"), "1\n").unwrap();
+        remove_temp_files(&dir);
+        lock_slot(&dir, &Slot::Number(1)).unwrap();
+        assert!(std::path::Path::new(&format!("{dir}/{INSTANCE_LOCK_NAME}")).exists());
+    }
+
+    #[test]
+    fn only_directories_of_devices_are_listed() {
+        let dir = temp_dir("only_directories_of_devices_are_listed");
+        for name in ["0", "12", "255", "256", "history", "trash", "thumbnails"] {
+            std::fs::create_dir(format!("{dir}/{name}")).unwrap();
+        }
+        std::fs::write(format!("{dir}/3"), b"not a directory").unwrap();
+
+        assert_eq!(
+            device_dirs(&dir),
+            [
+                format!("{dir}/0"),
+                format!("{dir}/12"),
+                format!("{dir}/255")
+            ]
+        );
+        assert!(device_dirs(&format!("{dir}/missing")).is_empty());
+    }
+
+    #[test]
+    fn locks_can_be_waited_for() {
+        let dir = temp_dir("locks_can_be_waited_for");
+        let wait = std::time::Duration::from_millis;
+
+        let lock = lock_slot(&dir, &Slot::Number(1)).unwrap();
+        assert_eq!(
+            lock_slot_waiting(&dir, &Slot::Number(1), wait(0))
+                .unwrap_err()
+                .kind(),
+            std::io::ErrorKind::AlreadyExists
+        );
+        let started = std::time::Instant::now();
+        assert!(lock_slot_waiting(&dir, &Slot::Number(1), wait(50)).is_err());
+        assert!(started.elapsed() >= wait(50));
+
+        // the lock is taken as soon as it is released
+        let waiter = {
+            let dir = dir.to_string();
+            std::thread::spawn(move || lock_slot_waiting(&dir, &Slot::Number(1), wait(5_000)))
+        };
+        std::thread::sleep(wait(50));
+        drop(lock);
+        let _lock = waiter.join().unwrap().unwrap();
+        assert!(lock_slot(&dir, &Slot::Number(1)).is_err());
+    }
+
+    #[test]
+    fn load_slot_prefers_bmp_over_png() {
+        let dir = temp_dir("load_slot_prefers_bmp_over_png");
+        let bmp = vec![vec![0xF800, 0x07E0], vec![0x001F, 0xFFFF]];
+        let png = vec![vec![0x0000, 0x0000], vec![0x07FF, 0x07FF]];
+
+        save_png_image(&png, &format!("{dir}/image_4")).unwrap();
+        assert_eq!(
+            load_slot(&dir, &Slot::Number(4), 2, 2, &Palette::BUILTIN).unwrap(),
+            png
+        );
+
+        save_bmp_image(&bmp, &format!("{dir}/image_4")).unwrap();
+        assert_eq!(
+            load_slot(&dir, &Slot::Number(4), 2, 2, &Palette::BUILTIN).unwrap(),
+            bmp
+        );
+
+        assert!(matches!(
+            load_slot(&dir, &Slot::Number(5), 2, 2, &Palette::BUILTIN).unwrap_err(),
+            LoadError::NotFound
+        ));
+    }
+
+    #[test]
+    fn overwrite_and_restore_slot() {
+        let dir = temp_dir("overwrite_and_restore_slot");
+        let first = vec![vec![0xF800, 0x07E0], vec![0x001F, 0xFFFF]];
+        let second = vec![vec![0x0000, 0x0000], vec![0x0000, 0x0000]];
+
+        assert!(!backup_slot(&dir, &Slot::Number(3)).unwrap());
+        save_bmp_image(&first, &format!("{dir}/image_3")).unwrap();
+
+        // interrupted after the backup, before the new image was written
+        assert!(backup_slot(&dir, &Slot::Number(3)).unwrap());
+        assert_eq!(
+            load_slot(&dir, &Slot::Number(3), 2, 2, &Palette::BUILTIN).unwrap(),
+            first
+        );
+        assert_eq!(
+            std::fs::read(backup_path(&dir, &Slot::Number(3))).unwrap(),
+            std::fs::read(format!("{dir}/image_3.bmp")).unwrap()
+        );
+
+        save_bmp_image(&second, &format!("{dir}/image_3")).unwrap();
+        assert_eq!(
+            load_slot(&dir, &Slot::Number(3), 2, 2, &Palette::BUILTIN).unwrap(),
+            second
+        );
+
+        restore_slot(&dir, &Slot::Number(3)).unwrap();
+        assert_eq!(
+            load_slot(&dir, &Slot::Number(3), 2, 2, &Palette::BUILTIN).unwrap(),
+            first
+        );
+        restore_slot(&dir, &Slot::Number(3)).unwrap();
+        assert_eq!(
+            load_slot(&dir, &Slot::Number(3), 2, 2, &Palette::BUILTIN).unwrap(),
+            second
+        );
+
+        let err = restore_slot(&dir, &Slot::Number(4)).unwrap_err();
+        assert_eq!(err.kind(), std::io::ErrorKind::NotFound);
+    }
+
+    #[test]
+    fn archive_prune_and_revert_history() {
+        let dir = temp_dir("archive_prune_and_revert_history");
+        let images: Vec<Vec<Vec<u16>>> = PALETTE
+            .iter()
+            .take(3)
+            .map(|&(_, color)| vec![vec![color; 2]; 2])
+            .collect();
+
+        let mut versions = Vec::new();
+        for img in &images {
+            save_bmp_image(img, &format!("{dir}/image_1")).unwrap();
+            versions.push(archive_slot(&dir, &Slot::Number(1)).unwrap());
+            std::thread::sleep(std::time::Duration::from_millis(2));
+        }
+        assert_eq!(list_history(&dir, &Slot::Number(1)), versions);
+
+        // stray files and versions deleted by hand are skipped
+        std::fs::write(
+            format!("{}/notes.txt", history_dir(&dir, &Slot::Number(1))),
+            b"",
+        )
+        .unwrap();
+        assert_eq!(list_history(&dir, &Slot::Number(1)), versions);
+        assert_eq!(list_history(&dir, &Slot::Number(2)), vec![]);
+
+        prune_history(&dir, &Slot::Number(1), 2).unwrap();
+        assert_eq!(list_history(&dir, &Slot::Number(1)), versions[1..]);
+
+        revert_slot(&dir, &Slot::Number(1), versions[1]).unwrap();
+        assert_eq!(
+            load_slot(&dir, &Slot::Number(1), 2, 2, &Palette::BUILTIN).unwrap(),
+            images[1]
+        );
+        assert!(revert_slot(&dir, &Slot::Number(1), versions[0]).is_err());
+    }
+
+    #[test]
+    fn deduplicated_slots_stay_independent() {
+        let dir = temp_dir("deduplicated_slots_stay_independent");
+        let img = vec![vec![0xF800, 0x07E0], vec![0x001F, 0xFFFF]];
+        let other = vec![vec![0x0000; 2]; 2];
+
+        save_bmp_image(&img, &format!("{dir}/image_1")).unwrap();
+        save_bmp_image(&other, &format!("{dir}/image_2")).unwrap();
+        assert_eq!(
+            find_duplicate(&dir, &Slot::Number(3), &img, &Palette::BUILTIN),
+            Some(Slot::Number(1))
+        );
+        assert_eq!(
+            find_duplicate(&dir, &Slot::Number(1), &img, &Palette::BUILTIN),
+            None
+        );
+        assert_eq!(
+            find_duplicate(
+                &dir,
+                &Slot::Number(3),
+                &vec![vec![0xF800; 2]; 2],
+                &Palette::BUILTIN
+            ),
+            None
+        );
+
+        link_slot(&dir, &Slot::Number(1), &Slot::Number(3)).unwrap();
+        assert_eq!(
+            load_slot(&dir, &Slot::Number(3), 2, 2, &Palette::BUILTIN).unwrap(),
+            img
+        );
+
+        // overwriting or deleting the original does not affect the duplicate
+        save_bmp_image(&other, &format!("{dir}/image_1")).unwrap();
+        assert_eq!(
+            load_slot(&dir, &Slot::Number(3), 2, 2, &Palette::BUILTIN).unwrap(),
+            img
+        );
+        std::fs::remove_file(format!("{dir}/image_1.bmp")).unwrap();
+        assert_eq!(
+            load_slot(&dir, &Slot::Number(3), 2, 2, &Palette::BUILTIN).unwrap(),
+            img
+        );
+    }
+
+    #[test]
+    fn copy_slot_keeps_the_source() {
+        let dir = temp_dir("copy_slot_keeps_the_source");
+        let first = vec![vec![0xF800, 0x07E0], vec![0x001F, 0xFFFF]];
+        let second = vec![vec![0x0000; 2]; 2];
+
+        assert_eq!(
+            copy_slot(&dir, &Slot::Number(3), &Slot::Number(7), true)
+                .unwrap_err()
+                .kind(),
+            std::io::ErrorKind::NotFound
+        );
+
+        save_bmp_image(&first, &format!("{dir}/image_3")).unwrap();
+        copy_slot(&dir, &Slot::Number(3), &Slot::Number(7), false).unwrap();
+        assert_eq!(
+            load_slot(&dir, &Slot::Number(3), 2, 2, &Palette::BUILTIN).unwrap(),
+            first
+        );
+        assert_eq!(
+            load_slot(&dir, &Slot::Number(7), 2, 2, &Palette::BUILTIN).unwrap(),
+            first
+        );
+        assert!(std::path::Path::new(&checksum_path(&dir, &Slot::Number(7))).exists());
+        assert!(!std::path::Path::new(&format!("{dir}/image_7.bmp{TEMP_SUFFIX}")).exists());
+
+        // the copies are separate files, so changing one leaves the other as it was
+        save_bmp_image(&second, &format!("{dir}/image_7")).unwrap();
+        assert_eq!(
+            load_slot(&dir, &Slot::Number(3), 2, 2, &Palette::BUILTIN).unwrap(),
+            first
+        );
+
+        // an occupied destination is only replaced when overwriting, and is backed up first
+        assert_eq!(
+            copy_slot(&dir, &Slot::Number(3), &Slot::Number(7), false)
+                .unwrap_err()
+                .kind(),
+            std::io::ErrorKind::AlreadyExists
+        );
+        assert_eq!(
+            load_slot(&dir, &Slot::Number(7), 2, 2, &Palette::BUILTIN).unwrap(),
+            second
+        );
+        copy_slot(&dir, &Slot::Number(3), &Slot::Number(7), true).unwrap();
+        assert_eq!(
+            load_slot(&dir, &Slot::Number(7), 2, 2, &Palette::BUILTIN).unwrap(),
+            first
+        );
+        assert_eq!(
+            load_bmp_image(&format!("{dir}/image_7.bak"), 2, 2, &Palette::BUILTIN).unwrap(),
+            second
+        );
+    }
+
+    #[test]
+    fn rename_slot_moves_image() {
+        let dir = temp_dir("rename_slot_moves_image");
+        let first = vec![vec![0xF800, 0x07E0], vec![0x001F, 0xFFFF]];
+        let second = vec![vec![0x0000; 2]; 2];
+
+        assert_eq!(
+            rename_slot(&dir, &Slot::Number(1), &Slot::Number(2), true)
+                .unwrap_err()
+                .kind(),
+            std::io::ErrorKind::NotFound
+        );
+
+        save_bmp_image(&first, &format!("{dir}/image_1")).unwrap();
+        rename_slot(&dir, &Slot::Number(1), &Slot::Number(2), false).unwrap();
+        assert!(!std::path::Path::new(&format!("{dir}/image_1.bmp")).exists());
+        assert_eq!(
+            load_slot(&dir, &Slot::Number(2), 2, 2, &Palette::BUILTIN).unwrap(),
+            first
+        );
+
+        // an occupied destination is only replaced when overwriting, and is backed up first
+        save_bmp_image(&second, &format!("{dir}/image_3")).unwrap();
+        let moved = std::fs::read(format!("{dir}/image_2.bmp")).unwrap();
+        assert_eq!(
+            rename_slot(&dir, &Slot::Number(3), &Slot::Number(2), false)
+                .unwrap_err()
+                .kind(),
+            std::io::ErrorKind::AlreadyExists
+        );
+        assert_eq!(
+            load_slot(&dir, &Slot::Number(2), 2, 2, &Palette::BUILTIN).unwrap(),
+            first
+        );
+
+        rename_slot(&dir, &Slot::Number(3), &Slot::Number(2), true).unwrap();
+        assert_eq!(
+            load_slot(&dir, &Slot::Number(2), 2, 2, &Palette::BUILTIN).unwrap(),
+            second
+        );
+        assert_eq!(
+            std::fs::read(backup_path(&dir, &Slot::Number(2))).unwrap(),
+            moved
+        );
+    }
+
+    #[test]
+    fn slot_names_are_validated() {
+        assert_eq!(
+            Slot::named(b"birthday-card"),
+            Ok(Slot::Name("birthday-card".to_string()))
+        );
+        assert_eq!(
+            Slot::named("café".as_bytes()),
+            Ok(Slot::Name("café".to_string()))
+        );
+        assert_eq!(Slot::named(b"7"), Ok(Slot::Number(7)));
+        assert_eq!(Slot::named(b"256"), Ok(Slot::Number(256)));
+        assert_eq!(Slot::named(b"65536"), Ok(Slot::Name("65536".to_string())));
+        assert_eq!(Slot::named(b"007"), Ok(Slot::Name("007".to_string())));
+
+        assert_eq!(Slot::named(b""), Err(SlotNameError::Empty));
+        assert_eq!(Slot::named(&[b'a'; 65]), Err(SlotNameError::TooLong(65)));
+        assert_eq!(Slot::named(&[0xFF, 0xFE]), Err(SlotNameError::NotUtf8));
+        for name in [
+            "../../etc/passwd",
+            "..",
+            ".",
+            "a/b",
+            "a\\b",
+            "/etc",
+            "line\nbreak",
+            "nul\0",
+            "3.bak",
+        ] {
+            assert!(
+                matches!(
+                    Slot::named(name.as_bytes()),
+                    Err(SlotNameError::InvalidCharacter(_))
+                ),
+                "{:?} was accepted",
+                name
+            );
+        }
+    }
+
+    #[test]
+    fn list_slots_includes_named_slots() {
+        let dir = temp_dir("list_slots_includes_named_slots");
+        let img = vec![vec![0xF800, 0x07E0], vec![0x001F, 0xFFFF]];
+
+        for slot in ["card", "10", "2", "art"] {
+            save_bmp_image(&img, &format!("{dir}/image_{slot}")).unwrap();
+        }
+        backup_slot(&dir, &Slot::Number(2)).unwrap();
+
+        assert_eq!(
+            list_slots(&dir),
+            vec![
+                Slot::Number(2),
+                Slot::Number(10),
+                Slot::Name("art".to_string()),
+                Slot::Name("card".to_string())
+            ]
+        );
+        assert_eq!(
+            load_slot(
+                &dir,
+                &Slot::Name("card".to_string()),
+                2,
+                2,
+                &Palette::BUILTIN
+            )
+            .unwrap(),
+            img
+        );
+    }
+
+    #[test]
+    fn history_exports_as_animation() {
+        let dir = temp_dir("history_exports_as_animation");
+        let history = history_dir(&dir, &Slot::Number(1));
+        std::fs::create_dir_all(&history).unwrap();
+
+        // the version with other dimensions is skipped
+        for (version, &(_, color)) in PALETTE.iter().enumerate().take(4) {
+            let size = if version == 1 { 3 } else { 2 };
+            save_bmp_image(&vec![vec![color; size]; 2], &format!("{history}/{version}")).unwrap();
+        }
+        let frames = load_history(&dir, &Slot::Number(1), &Palette::BUILTIN);
+        assert_eq!(frames.len(), 3);
+
+        save_gif_animation(&frames, 250, &format!("{dir}/timelapse"), &Palette::BUILTIN).unwrap();
+        let mut decoder = gif::DecodeOptions::new()
+            .read_info(std::fs::File::open(format!("{dir}/timelapse.gif")).unwrap())
+            .unwrap();
+        let mut count = 0;
+        while let Some(frame) = decoder.read_next_frame().unwrap() {
+            assert_eq!((frame.width, frame.height, frame.delay), (2, 2, 25));
+            count += 1;
+        }
+        assert_eq!(count, 3);
+
+        assert!(load_history(&dir, &Slot::Number(2), &Palette::BUILTIN).is_empty());
+    }
+}
@@ -0,0 +1,832 @@
+//! Backends that the images of the slots are stored in
+//!
+//! By default, every image is a BMP file in the image directory, next to the files derived from it
+//! (backups, history, checksums, thumbnails and metadata). With `--store sqlite`, the pixels and
+//! metadata of the images are kept in a single SQLite database instead, which suits filesystems
+//! (such as FAT on an SD card) that waste space on small files. The database keeps the images of
+//! every image directory (of each device, with `--multi-device`) apart by the path of the
+//! directory, and does not keep any derived files.
+
+use std::collections::BTreeMap;
+use std::sync::{Arc, Mutex};
+
+use rusqlite::{params, Connection, OptionalExtension};
+
+use crate::checksums::{checksum_path, write_checksum};
+use crate::error::{storage, ServeError};
+use crate::image::*;
+use crate::metadata::{metadata_path, read_metadata, write_metadata, SlotMetadata};
+use crate::palette::Palette;
+use crate::slots::*;
+use crate::thumbnails::{thumbnail_path, write_thumbnail};
+use crate::trash::trash_slot;
+use crate::usage;
+use crate::watch::record_own_change;
+
+/// Backends that images can be stored in
+#[derive(clap::ValueEnum, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Backend {
+    /// A BMP file for every image, in the image directory
+    Files,
+    /// A single SQLite database (given by `--db`)
+    Sqlite,
+}
+
+/// Storage of the images of the slots
+///
+/// Every operation takes the image directory that the slot belongs to, which the filesystem store
+/// keeps the image in, and which the other stores use to keep the images of each device apart.
+pub trait Store {
+    /// Loads the image of a slot
+    ///
+    /// # Errors
+    ///
+    /// * [`LoadError::NotFound`] when the slot has no image
+    /// * [`LoadError::DimensionMismatch`] when the image is not of the expected dimensions
+    /// * Any other [`LoadError`] when the image can not be read
+    ///
+    fn read_slot(
+        &self,
+        dir: &str,
+        name: &Slot,
+        expected_width: usize,
+        expected_height: usize,
+    ) -> Result<Vec<Vec<u16>>, LoadError>;
+
+    /// Replaces the image of a slot (and its metadata), so that no partially written image is ever
+    /// loaded
+    fn write_slot(
+        &self,
+        dir: &str,
+        name: &Slot,
+        img: &[Vec<u16>],
+        metadata: &SlotMetadata,
+    ) -> Result<(), ServeError>;
+
+    /// Gets the slots which have an image, in order
+    fn list(&self, dir: &str) -> Vec<Slot>;
+
+    /// Removes the image of a slot (and its metadata)
+    ///
+    /// # Errors
+    ///
+    /// * When the slot has no image (with [`std::io::ErrorKind::NotFound`])
+    ///
+    fn delete(&self, dir: &str, name: &Slot) -> std::io::Result<()>;
+
+    /// Moves the image of a slot into the trash, from where it can be restored, with the same
+    /// errors as [`Store::delete`]
+    ///
+    /// Stores that do not keep a trash remove the image instead.
+    fn trash(&self, dir: &str, name: &Slot) -> std::io::Result<()> {
+        self.delete(dir, name)
+    }
+
+    /// Moves the image of a slot to another slot, with the same errors as [`rename_slot`]
+    fn rename(&self, dir: &str, from: &Slot, to: &Slot, overwrite: bool) -> std::io::Result<()>;
+
+    /// Copies the image of a slot to another slot, with the same errors as [`copy_slot`]
+    fn copy(&self, dir: &str, from: &Slot, to: &Slot, overwrite: bool) -> std::io::Result<()>;
+
+    /// Gets the slot whose image was saved most recently
+    fn most_recent(&self, dir: &str) -> Option<Slot>;
+
+    /// Gets the width and height of the image of a slot, without loading it
+    fn dimensions(&self, dir: &str, name: &Slot) -> Result<(usize, usize), LoadError>;
+
+    /// Gets the details of the last save of a slot, if they were recorded
+    fn metadata(&self, dir: &str, name: &Slot) -> Option<SlotMetadata>;
+}
+
+/// Store which keeps every image as a BMP file in its image directory
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct FileStore {
+    /// Layout of the colors of written images
+    pub color_depth: ColorFormat,
+    /// Whether black and white images are written as monochrome (1-bit) images instead
+    pub mono: bool,
+    /// Compression of written images
+    pub compression: StoreCompression,
+    /// Level that written images are compressed at with zstd
+    pub compression_level: i32,
+    /// Whether images identical to the image of another slot are linked to its file
+    pub dedupe: bool,
+    /// Largest total size that the images of a directory may take
+    pub max_dir_size: Option<u64>,
+    /// Number of versions kept in the history of each slot
+    pub history_keep: Option<usize>,
+    /// Whether a PNG copy is written next to every image
+    pub save_png: bool,
+    /// The palette that read images are mapped to
+    pub palette: Palette,
+}
+
+impl Default for FileStore {
+    fn default() -> Self {
+        Self {
+            color_depth: ColorFormat::Rgb565,
+            mono: false,
+            compression: StoreCompression::None,
+            compression_level: DEFAULT_ZSTD_LEVEL,
+            dedupe: false,
+            max_dir_size: None,
+            history_keep: None,
+            save_png: false,
+            palette: Palette::BUILTIN,
+        }
+    }
+}
+
+impl Store for FileStore {
+    fn read_slot(
+        &self,
+        dir: &str,
+        name: &Slot,
+        expected_width: usize,
+        expected_height: usize,
+    ) -> Result<Vec<Vec<u16>>, LoadError> {
+        load_slot(dir, name, expected_width, expected_height, &self.palette)
+    }
+
+    /// Writes the image file of a slot, after backing up its previous image, and then the files
+    /// derived from it
+    ///
+    /// The slot must be locked. Only failing to write the image itself fails the save, since the
+    /// derived files are only conveniences.
+    fn write_slot(
+        &self,
+        dir: &str,
+        name: &Slot,
+        img: &[Vec<u16>],
+        metadata: &SlotMetadata,
+    ) -> Result<(), ServeError> {
+        let mono = self.mono && is_monochrome(img);
+        if let Some(quota) = self.max_dir_size {
+            let bytes = match mono {
+                true => mono_bmp_file_size(metadata.width, metadata.height),
+                false => bmp_file_size(metadata.width, metadata.height, self.color_depth),
+            };
+            if let Err(total) = usage::reserve(dir, name, bytes, quota) {
+                return Err(ServeError::QuotaExceeded { total, quota });
+            }
+        }
+
+        // keep the previous image of the slot, so that it can be restored if it is overwritten by
+        // mistake (only once the save is known to fit, since a refused save overwrites nothing)
+        if let Err(err) = backup_slot(dir, name) {
+            // the space reserved for the image was never taken
+            usage::invalidate(dir);
+            return Err(storage(format!(
+                "backing up image_{}.bmp (refusing to overwrite it)",
+                name
+            ))(err));
+        }
+
+        // share the file of an identical image instead of writing another copy (if the filesystem can)
+        let deduplicated = self.dedupe
+            && find_duplicate(dir, name, img, &self.palette).is_some_and(|slot| {
+                match link_slot(dir, &slot, name) {
+                    Ok(()) => {
+                        println!(
+                            "image_{}.bmp is identical to image_{}.bmp, linked",
+                            name, slot
+                        );
+                        true
+                    }
+                    Err(err) => {
+                        eprintln!(
+                            "Failed to link image_{}.bmp to image_{}.bmp: {}",
+                            name, slot, err
+                        );
+                        false
+                    }
+                }
+            });
+
+        if !deduplicated {
+            let filename = format!("{dir}/image_{name}");
+            let result = match self.compression {
+                _ if mono => {
+                    save_bmp_image_mono(img, &filename, self.compression, self.compression_level)
+                }
+                StoreCompression::None => save_bmp_image_as(img, &filename, self.color_depth),
+                StoreCompression::Gzip => {
+                    save_compressed_bmp_image_as(img, &filename, self.color_depth)
+                }
+                StoreCompression::Zstd => {
+                    save_zstd_bmp_image_as(img, &filename, self.color_depth, self.compression_level)
+                }
+            };
+            if let Err(err) = result {
+                // the space reserved for the image was never taken
+                usage::invalidate(dir);
+                return Err(err.into());
+            }
+
+            // the image may have been stored in the other form before, which must not be served instead
+            let saved = format!("{filename}.bmp{}", self.compression.suffix());
+            remove_stale_image(&saved)
+                .map_err(storage(format!("removing the previous image_{}.bmp", name)))?;
+        }
+        record_own_change(dir, name);
+
+        // the checksum is only written once the image has been replaced, so it describes the final file
+        if let Err(err) = write_checksum(dir, name) {
+            eprintln!("Failed to write checksum of image_{}.bmp: {}", name, err);
+        }
+
+        // the image was replaced atomically, so the history never contains a partially written image
+        match archive_slot(dir, name) {
+            Ok(_) => {
+                if let Some(keep) = self.history_keep {
+                    if let Err(err) = prune_history(dir, name, keep) {
+                        eprintln!("Failed to prune history of image_{}.bmp: {}", name, err);
+                    }
+                }
+            }
+            Err(err) => eprintln!("Failed to archive image_{}.bmp: {}", name, err),
+        }
+
+        // the BMP file is the primary copy of the image, so failing to write the PNG is not fatal
+        if self.save_png {
+            if let Err(err) = save_png_image(img, &format!("{dir}/image_{name}")) {
+                eprintln!("Failed to save image_{}.png: {}", name, err);
+            }
+        }
+
+        // the thumbnail is only a convenience, so it is written without delaying the reply
+        let (thumbnail_dir, thumbnail_name) = (dir.to_string(), name.clone());
+        let thumbnail_img = img.to_vec();
+        std::thread::spawn(move || {
+            if let Err(err) = write_thumbnail(&thumbnail_dir, &thumbnail_name, &thumbnail_img) {
+                eprintln!(
+                    "Failed to write thumbnail of image_{}.bmp: {}",
+                    thumbnail_name, err
+                );
+            }
+        });
+
+        if let Err(err) = write_metadata(dir, name, metadata) {
+            eprintln!("Failed to write metadata of image_{}.bmp: {}", name, err);
+        }
+        Ok(())
+    }
+
+    fn list(&self, dir: &str) -> Vec<Slot> {
+        list_slots(dir)
+    }
+
+    /// Removes the image file of a slot (in either form), with its PNG copy, checksum, metadata and
+    /// thumbnail
+    ///
+    /// Backups and the history of the slot are kept, so that the image can still be restored.
+    fn delete(&self, dir: &str, name: &Slot) -> std::io::Result<()> {
+        let path = image_path(dir, name);
+        std::fs::remove_file(&path)?;
+        remove_stale_image(&path)?;
+        for path in [
+            format!("{dir}/image_{name}.png"),
+            checksum_path(dir, name),
+            metadata_path(dir, name),
+            thumbnail_path(dir, name),
+        ] {
+            match std::fs::remove_file(path) {
+                Err(err) if err.kind() != std::io::ErrorKind::NotFound => return Err(err),
+                _ => {}
+            }
+        }
+        record_own_change(dir, name);
+        Ok(())
+    }
+
+    fn trash(&self, dir: &str, name: &Slot) -> std::io::Result<()> {
+        trash_slot(dir, name)?;
+        record_own_change(dir, name);
+        // the thumbnail stays behind when the image is moved to the trash, and would otherwise
+        // still show the image in the gallery
+        match std::fs::remove_file(thumbnail_path(dir, name)) {
+            Err(err) if err.kind() != std::io::ErrorKind::NotFound => Err(err),
+            _ => Ok(()),
+        }
+    }
+
+    fn rename(&self, dir: &str, from: &Slot, to: &Slot, overwrite: bool) -> std::io::Result<()> {
+        rename_slot(dir, from, to, overwrite)?;
+        record_own_change(dir, from);
+        record_own_change(dir, to);
+        Ok(())
+    }
+
+    fn copy(&self, dir: &str, from: &Slot, to: &Slot, overwrite: bool) -> std::io::Result<()> {
+        copy_slot(dir, from, to, overwrite)?;
+        record_own_change(dir, to);
+        Ok(())
+    }
+
+    fn most_recent(&self, dir: &str) -> Option<Slot> {
+        most_recent_slot(dir)
+    }
+
+    fn dimensions(&self, dir: &str, name: &Slot) -> Result<(usize, usize), LoadError> {
+        read_bmp_dimensions(&format!("{dir}/image_{name}"))
+    }
+
+    fn metadata(&self, dir: &str, name: &Slot) -> Option<SlotMetadata> {
+        read_metadata(dir, name)
+    }
+}
+
+/// Schema of the database, created when it is first opened
+const SCHEMA: &str = "
+    CREATE TABLE IF NOT EXISTS images (
+        dir TEXT NOT NULL,
+        slot TEXT NOT NULL,
+        width INTEGER NOT NULL,
+        height INTEGER NOT NULL,
+        pixels BLOB NOT NULL,
+        PRIMARY KEY (dir, slot)
+    );
+    CREATE TABLE IF NOT EXISTS metadata (
+        dir TEXT NOT NULL,
+        slot TEXT NOT NULL,
+        v INTEGER NOT NULL,
+        timestamp_ms INTEGER NOT NULL,
+        peer TEXT NOT NULL,
+        width INTEGER NOT NULL,
+        height INTEGER NOT NULL,
+        compressed_rows INTEGER NOT NULL,
+        duration_ms INTEGER NOT NULL,
+        PRIMARY KEY (dir, slot)
+    );
+";
+
+/// Connections to every database that has been opened, by its path, so that every request shares
+/// the same connection (and its transactions are serialized)
+static CONNECTIONS: Mutex<BTreeMap<String, Arc<Mutex<Connection>>>> = Mutex::new(BTreeMap::new());
+
+/// Store which keeps the pixels and metadata of every image in a SQLite database
+///
+/// The pixels of an image are kept as a single blob of little-endian 5-6-5 colors, row by row.
+#[derive(Debug, Clone)]
+pub struct SqliteStore {
+    conn: Arc<Mutex<Connection>>,
+}
+
+/// Wraps an error of the database as an I/O error, as the filesystem store would have raised
+fn db_error(err: rusqlite::Error) -> std::io::Error {
+    std::io::Error::other(err)
+}
+
+impl SqliteStore {
+    /// Opens the database at the given path (creating it if it does not exist)
+    ///
+    /// # Arguments
+    ///
+    /// * `path` - Path of the database file
+    ///
+    pub fn open(path: &str) -> rusqlite::Result<Self> {
+        let mut connections = CONNECTIONS.lock().unwrap_or_else(|err| err.into_inner());
+        if let Some(conn) = connections.get(path) {
+            return Ok(Self { conn: conn.clone() });
+        }
+
+        let conn = Connection::open(path)?;
+        conn.execute_batch(SCHEMA)?;
+        let conn = Arc::new(Mutex::new(conn));
+        connections.insert(path.to_string(), conn.clone());
+        Ok(Self { conn })
+    }
+
+    /// Locks the connection to the database
+    fn conn(&self) -> std::sync::MutexGuard<'_, Connection> {
+        self.conn.lock().unwrap_or_else(|err| err.into_inner())
+    }
+}
+
+impl Store for SqliteStore {
+    fn read_slot(
+        &self,
+        dir: &str,
+        name: &Slot,
+        expected_width: usize,
+        expected_height: usize,
+    ) -> Result<Vec<Vec<u16>>, LoadError> {
+        let (width, height, pixels): (usize, usize, Vec<u8>) = self
+            .conn()
+            .query_row(
+                "SELECT width, height, pixels FROM images WHERE dir = ?1 AND slot = ?2",
+                params![dir, name.to_string()],
+                |row| {
+                    let width: u32 = row.get(0)?;
+                    let height: u32 = row.get(1)?;
+                    Ok((width as usize, height as usize, row.get(2)?))
+                },
+            )
+            .optional()
+            .map_err(|err| LoadError::Io(db_error(err)))?
+            .ok_or(LoadError::NotFound)?;
+
+        if width != expected_width || height != expected_height {
+            return Err(LoadError::DimensionMismatch { width, height });
+        }
+        if pixels.len() != width * height * 2 {
+            return Err(LoadError::Truncated);
+        }
+        Ok(pixels
+            .chunks_exact(width * 2)
+            .map(|row| {
+                row.chunks_exact(2)
+                    .map(|pair| u16::from_le_bytes([pair[0], pair[1]]))
+                    .collect()
+            })
+            .collect())
+    }
+
+    /// Replaces the image and metadata of a slot in a single transaction
+    fn write_slot(
+        &self,
+        dir: &str,
+        name: &Slot,
+        img: &[Vec<u16>],
+        metadata: &SlotMetadata,
+    ) -> Result<(), ServeError> {
+        let pixels: Vec<u8> = img.iter().flatten().flat_map(|v| v.to_le_bytes()).collect();
+        let slot = name.to_string();
+
+        let mut conn = self.conn();
+        let result = conn.transaction().and_then(|tx| {
+            tx.execute(
+                "INSERT OR REPLACE INTO images (dir, slot, width, height, pixels)
+                 VALUES (?1, ?2, ?3, ?4, ?5)",
+                params![
+                    dir,
+                    slot,
+                    metadata.width as u32,
+                    metadata.height as u32,
+                    pixels
+                ],
+            )?;
+            tx.execute(
+                "INSERT OR REPLACE INTO metadata
+                 (dir, slot, v, timestamp_ms, peer, width, height, compressed_rows, duration_ms)
+                 VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9)",
+                params![
+                    dir,
+                    slot,
+                    metadata.v,
+                    metadata.timestamp_ms as i64,
+                    metadata.peer,
+                    metadata.width as u32,
+                    metadata.height as u32,
+                    metadata.compressed_rows as u32,
+                    metadata.duration_ms as i64,
+                ],
+            )?;
+            tx.commit()
+        });
+        result.map_err(|err| {
+            storage(format!("writing image_{}.bmp to the database", name))(db_error(err))
+        })
+    }
+
+    fn list(&self, dir: &str) -> Vec<Slot> {
+        let conn = self.conn();
+        let Ok(mut stmt) = conn.prepare("SELECT slot FROM images WHERE dir = ?1") else {
+            return Vec::new();
+        };
+        let mut slots: Vec<Slot> = stmt
+            .query_map(params![dir], |row| row.get::<_, String>(0))
+            .map(|rows| {
+                rows.filter_map(|slot| slot.ok()?.parse().ok())
+                    .collect::<Vec<_>>()
+            })
+            .unwrap_or_default();
+        slots.sort_unstable();
+        slots
+    }
+
+    fn delete(&self, dir: &str, name: &Slot) -> std::io::Result<()> {
+        let slot = name.to_string();
+        let mut conn = self.conn();
+        let tx = conn.transaction().map_err(db_error)?;
+        let deleted = tx
+            .execute(
+                "DELETE FROM images WHERE dir = ?1 AND slot = ?2",
+                params![dir, slot],
+            )
+            .map_err(db_error)?;
+        if deleted == 0 {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::NotFound,
+                "slot has no image",
+            ));
+        }
+        tx.execute(
+            "DELETE FROM metadata WHERE dir = ?1 AND slot = ?2",
+            params![dir, slot],
+        )
+        .map_err(db_error)?;
+        tx.commit().map_err(db_error)
+    }
+
+    fn rename(&self, dir: &str, from: &Slot, to: &Slot, overwrite: bool) -> std::io::Result<()> {
+        let (from, to) = (from.to_string(), to.to_string());
+        let mut conn = self.conn();
+        let tx = conn.transaction().map_err(db_error)?;
+        let exists = |slot: &str| {
+            tx.query_row(
+                "SELECT 1 FROM images WHERE dir = ?1 AND slot = ?2",
+                params![dir, slot],
+                |_| Ok(()),
+            )
+            .optional()
+            .map(|found| found.is_some())
+            .map_err(db_error)
+        };
+
+        if !exists(&from)? {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::NotFound,
+                "source slot has no image",
+            ));
+        }
+        if from == to {
+            return Ok(());
+        }
+        if !overwrite && exists(&to)? {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::AlreadyExists,
+                "destination slot already has an image",
+            ));
+        }
+
+        for table in ["images", "metadata"] {
+            tx.execute(
+                &format!("DELETE FROM {table} WHERE dir = ?1 AND slot = ?2"),
+                params![dir, to],
+            )
+            .and_then(|_| {
+                tx.execute(
+                    &format!("UPDATE {table} SET slot = ?3 WHERE dir = ?1 AND slot = ?2"),
+                    params![dir, from, to],
+                )
+            })
+            .map_err(db_error)?;
+        }
+        tx.commit().map_err(db_error)
+    }
+
+    fn copy(&self, dir: &str, from: &Slot, to: &Slot, overwrite: bool) -> std::io::Result<()> {
+        let (from, to) = (from.to_string(), to.to_string());
+        let mut conn = self.conn();
+        let tx = conn.transaction().map_err(db_error)?;
+        let exists = |slot: &str| {
+            tx.query_row(
+                "SELECT 1 FROM images WHERE dir = ?1 AND slot = ?2",
+                params![dir, slot],
+                |_| Ok(()),
+            )
+            .optional()
+            .map(|found| found.is_some())
+            .map_err(db_error)
+        };
+
+        if !exists(&from)? {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::NotFound,
+                "source slot has no image",
+            ));
+        }
+        if from == to {
+            return Ok(());
+        }
+        if !overwrite && exists(&to)? {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::AlreadyExists,
+                "destination slot already has an image",
+            ));
+        }
+
+        for (table, columns) in [
+            ("images", "width, height, pixels"),
+            (
+                "metadata",
+                "v, timestamp_ms, peer, width, height, compressed_rows, duration_ms",
+            ),
+        ] {
+            tx.execute(
+                &format!("DELETE FROM {table} WHERE dir = ?1 AND slot = ?2"),
+                params![dir, to],
+            )
+            .and_then(|_| {
+                tx.execute(
+                    &format!(
+                        "INSERT INTO {table} (dir, slot, {columns})
+                         SELECT dir, ?3, {columns} FROM {table} WHERE dir = ?1 AND slot = ?2"
+                    ),
+                    params![dir, from, to],
+                )
+            })
+            .map_err(db_error)?;
+        }
+        tx.commit().map_err(db_error)
+    }
+
+    fn most_recent(&self, dir: &str) -> Option<Slot> {
+        // saves within the same millisecond are told apart by the order their metadata was written
+        self.conn()
+            .query_row(
+                "SELECT images.slot FROM images JOIN metadata USING (dir, slot)
+                 WHERE dir = ?1 ORDER BY timestamp_ms DESC, metadata.rowid DESC LIMIT 1",
+                params![dir],
+                |row| row.get::<_, String>(0),
+            )
+            .ok()?
+            .parse()
+            .ok()
+    }
+
+    fn dimensions(&self, dir: &str, name: &Slot) -> Result<(usize, usize), LoadError> {
+        self.conn()
+            .query_row(
+                "SELECT width, height FROM images WHERE dir = ?1 AND slot = ?2",
+                params![dir, name.to_string()],
+                |row| {
+                    let width: u32 = row.get(0)?;
+                    let height: u32 = row.get(1)?;
+                    Ok((width as usize, height as usize))
+                },
+            )
+            .optional()
+            .map_err(|err| LoadError::Io(db_error(err)))?
+            .ok_or(LoadError::NotFound)
+    }
+
+    fn metadata(&self, dir: &str, name: &Slot) -> Option<SlotMetadata> {
+        self.conn()
+            .query_row(
+                "SELECT v, timestamp_ms, peer, width, height, compressed_rows, duration_ms
+                 FROM metadata WHERE dir = ?1 AND slot = ?2",
+                params![dir, name.to_string()],
+                |row| {
+                    Ok(SlotMetadata {
+                        v: row.get(0)?,
+                        timestamp_ms: row.get::<_, i64>(1)? as u64,
+                        peer: row.get(2)?,
+                        width: row.get::<_, u32>(3)? as usize,
+                        height: row.get::<_, u32>(4)? as usize,
+                        compressed_rows: row.get::<_, u32>(5)? as usize,
+                        duration_ms: row.get::<_, i64>(6)? as u64,
+                    })
+                },
+            )
+            .ok()
+    }
+}
+
+/// Moves the image (and metadata) of a slot from one store to another
+///
+/// The image is only removed from the source once it has been written to the destination. Images
+/// without metadata are given metadata describing them, as saved now by no client.
+///
+/// # Arguments
+///
+/// * `from` - Store to take the image from
+/// * `to` - Store to move the image to
+/// * `dir` - Image directory of the slot
+/// * `name` - The slot of the image
+///
+pub fn migrate_slot(
+    from: &dyn Store,
+    to: &dyn Store,
+    dir: &str,
+    name: &Slot,
+) -> Result<(), ServeError> {
+    let (width, height) = from.dimensions(dir, name)?;
+    let img = from.read_slot(dir, name, width, height)?;
+    let metadata = from.metadata(dir, name).unwrap_or_else(|| SlotMetadata {
+        v: crate::metadata::METADATA_VERSION,
+        timestamp_ms: std::time::SystemTime::now()
+            .duration_since(std::time::UNIX_EPOCH)
+            .map_or(0, |since| since.as_millis() as u64),
+        peer: String::new(),
+        width,
+        height,
+        compressed_rows: 0,
+        duration_ms: 0,
+    });
+
+    to.write_slot(dir, name, &img, &metadata)?;
+    from.delete(dir, name).map_err(storage(format!(
+        "removing image_{}.bmp after moving it",
+        name
+    )))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::temp_dir;
+
+    /// Metadata of an image saved at the given time
+    fn metadata(timestamp_ms: u64, width: usize, height: usize) -> SlotMetadata {
+        SlotMetadata {
+            v: crate::metadata::METADATA_VERSION,
+            timestamp_ms,
+            peer: "192.168.1.20:50123".to_string(),
+            width,
+            height,
+            compressed_rows: 1,
+            duration_ms: 12,
+        }
+    }
+
+    /// Checks the behavior that every store must share
+    fn check_store(store: &dyn Store, dir: &str) {
+        let red = vec![vec![0xF800; 3]; 2];
+        let green = vec![vec![0x07E0; 1]];
+        let (one, two, named) = (
+            Slot::Number(1),
+            Slot::Number(300),
+            Slot::Name("sketch".to_string()),
+        );
+
+        assert!(matches!(
+            store.read_slot(dir, &one, 3, 2),
+            Err(LoadError::NotFound)
+        ));
+        assert_eq!(store.list(dir), []);
+        assert_eq!(store.most_recent(dir), None);
+
+        store
+            .write_slot(dir, &one, &red, &metadata(1000, 3, 2))
+            .unwrap();
+        store
+            .write_slot(dir, &named, &green, &metadata(3000, 1, 1))
+            .unwrap();
+        store
+            .write_slot(dir, &two, &green, &metadata(2000, 1, 1))
+            .unwrap();
+        assert_eq!(store.read_slot(dir, &one, 3, 2).unwrap(), red);
+        assert!(matches!(
+            store.read_slot(dir, &one, 2, 3),
+            Err(LoadError::DimensionMismatch {
+                width: 3,
+                height: 2
+            })
+        ));
+        assert_eq!(store.dimensions(dir, &one).unwrap(), (3, 2));
+        assert_eq!(store.metadata(dir, &one), Some(metadata(1000, 3, 2)));
+        assert_eq!(store.list(dir), [one.clone(), two.clone(), named.clone()]);
+
+        // replacing an image replaces its metadata too
+        store
+            .write_slot(dir, &one, &green, &metadata(4000, 1, 1))
+            .unwrap();
+        assert_eq!(store.read_slot(dir, &one, 1, 1).unwrap(), green);
+        assert_eq!(store.metadata(dir, &one), Some(metadata(4000, 1, 1)));
+
+        assert_eq!(
+            store.rename(dir, &two, &one, false).unwrap_err().kind(),
+            std::io::ErrorKind::AlreadyExists
+        );
+        store.rename(dir, &two, &one, true).unwrap();
+        assert_eq!(store.metadata(dir, &one), Some(metadata(2000, 1, 1)));
+        assert_eq!(
+            store.rename(dir, &two, &one, true).unwrap_err().kind(),
+            std::io::ErrorKind::NotFound
+        );
+
+        store.delete(dir, &one).unwrap();
+        assert!(matches!(
+            store.read_slot(dir, &one, 1, 1),
+            Err(LoadError::NotFound)
+        ));
+        assert_eq!(store.metadata(dir, &one), None);
+        assert_eq!(
+            store.delete(dir, &one).unwrap_err().kind(),
+            std::io::ErrorKind::NotFound
+        );
+        assert_eq!(store.list(dir), [named]);
+    }
+
+    #[test]
+    fn file_store_behaves_like_a_store() {
+        let dir = temp_dir("file_store_behaves_like_a_store");
+        check_store(&FileStore::default(), &dir);
+    }
+
+    #[test]
+    fn sqlite_store_behaves_like_a_store() {
+        let dir = temp_dir("sqlite_store_behaves_like_a_store");
+        let store = SqliteStore::open(&format!("{dir}/canvas.db")).unwrap();
+        check_store(&store, &dir);
+        assert_eq!(
+            store.most_recent(&dir),
+            Some(Slot::Name("sketch".to_string()))
+        );
+
+        // the images of other directories are kept apart
+        assert_eq!(store.list(&format!("{dir}/3")), []);
+    }
+}
@@ -0,0 +1,61 @@
+//! Helpers shared by the tests of every module
+
+/// Empty directory for a single test, which is removed (with everything in it) when dropped
+///
+/// It dereferences to its path, so that it can be passed wherever an image directory is expected.
+pub struct TempDir {
+    path: String,
+}
+
+impl std::ops::Deref for TempDir {
+    type Target = str;
+
+    fn deref(&self) -> &str {
+        &self.path
+    }
+}
+
+impl AsRef<std::path::Path> for TempDir {
+    fn as_ref(&self) -> &std::path::Path {
+        self.path.as_ref()
+    }
+}
+
+impl AsRef<std::ffi::OsStr> for TempDir {
+    fn as_ref(&self) -> &std::ffi::OsStr {
+        self.path.as_ref()
+    }
+}
+
+impl std::fmt::Display for TempDir {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(&self.path)
+    }
+}
+
+impl Drop for TempDir {
+    fn drop(&mut self) {
+        // thumbnails are written by threads that outlive the save, so one may still be writing
+        // into the directory while it is removed
+        for _ in 0..10 {
+            match std::fs::remove_dir_all(&self.path) {
+                Err(err) if err.kind() != std::io::ErrorKind::NotFound => {
+                    std::thread::sleep(std::time::Duration::from_millis(10))
+                }
+                _ => return,
+            }
+        }
+    }
+}
+
+/// Creates an empty directory for a single test, whose name starts with the name of the test
+pub fn temp_dir(name: &str) -> TempDir {
+    let dir = tempfile::Builder::new()
+        .prefix(&format!("canvas-server-{name}-"))
+        .tempdir()
+        .unwrap()
+        .keep();
+    TempDir {
+        path: dir.to_string_lossy().into_owned(),
+    }
+}
@@ -0,0 +1,355 @@
+//! Functions to keep a downscaled PNG copy of the image in every slot, for quickly listing slots
+
+use crate::image::*;
+use crate::palette::Palette;
+use crate::slots::*;
+
+/// Length of the longer edge of a thumbnail in pixels (smaller images are not scaled up)
+pub const THUMBNAIL_SIZE: usize = 96;
+
+/// Gets the path of the thumbnail of a slot
+///
+/// # Arguments
+///
+/// * `dir` - Directory where images are stored
+/// * `name` - The slot of the image
+///
+pub fn thumbnail_path(dir: &str, name: &Slot) -> String {
+    format!("{dir}/thumbnails/image_{name}.png")
+}
+
+/// Scales an image down with nearest-neighbor sampling, so that its longer edge is at most
+/// `max_edge` pixels long, while keeping its aspect ratio
+///
+/// # Arguments
+///
+/// * `data` - A 16-bit color bitmap
+/// * `max_edge` - Largest number of rows or columns of the scaled image
+///
+pub fn downscale(data: &[Vec<u16>], max_edge: usize) -> Vec<Vec<u16>> {
+    let height = data.len();
+    let width = data.first().map_or(0, |row| row.len());
+    let longer = width.max(height);
+
+    if longer <= max_edge {
+        return data.to_vec();
+    }
+
+    // round to the nearest size, but never collapse the shorter edge entirely
+    let scaled_width = ((width * max_edge + longer / 2) / longer).max(1);
+    let scaled_height = ((height * max_edge + longer / 2) / longer).max(1);
+
+    (0..scaled_height)
+        .map(|y| {
+            let row = &data[y * height / scaled_height];
+            (0..scaled_width)
+                .map(|x| row[x * width / scaled_width])
+                .collect()
+        })
+        .collect()
+}
+
+/// Writes the thumbnail of a slot, replacing its previous thumbnail
+///
+/// # Arguments
+///
+/// * `dir` - Directory where images are stored
+/// * `name` - The slot of the image
+/// * `data` - The image stored in the slot
+///
+pub fn write_thumbnail(
+    dir: &str,
+    name: &Slot,
+    data: &[Vec<u16>],
+) -> Result<(), png::EncodingError> {
+    // the image directory itself is never created, so that a thumbnail written after the directory
+    // was removed does not bring it back
+    match std::fs::create_dir(format!("{dir}/thumbnails")) {
+        Err(err) if err.kind() != std::io::ErrorKind::AlreadyExists => return Err(err.into()),
+        _ => {}
+    }
+    save_png_image(
+        &downscale(data, THUMBNAIL_SIZE),
+        &format!("{dir}/thumbnails/image_{name}"),
+    )
+}
+
+/// Writes the thumbnail of every slot whose thumbnail is missing, or older than its image
+///
+/// # Arguments
+///
+/// * `dir` - Directory where images are stored
+/// * `palette` - The palette that the images were saved with
+///
+pub fn refresh_thumbnails(dir: &str, palette: &Palette) {
+    for slot in list_slots(dir) {
+        let image = format!("{dir}/image_{slot}");
+
+        let modified = |path: &str| std::fs::metadata(path).and_then(|m| m.modified()).ok();
+        let Some(image_modified) = modified(&image_path(dir, &slot)) else {
+            continue;
+        };
+        if modified(&thumbnail_path(dir, &slot)).is_some_and(|t| t >= image_modified) {
+            continue;
+        }
+
+        match load_whole_bmp(&image, palette) {
+            Ok(img) => match write_thumbnail(dir, &slot, &img) {
+                Ok(()) => println!("Generated thumbnail of image_{}.bmp", slot),
+                Err(err) => eprintln!("Failed to write thumbnail of image_{}.bmp: {}", slot, err),
+            },
+            Err(err) => eprintln!(
+                "Failed to load image_{}.bmp for its thumbnail: {}",
+                slot, err
+            ),
+        }
+    }
+}
+
+/// Height of the strip below each thumbnail of a contact sheet, which holds the label of the slot
+const LABEL_HEIGHT: usize = 14;
+/// Factor by which the glyphs of labels are scaled up
+const LABEL_SCALE: usize = 2;
+
+/// Glyphs of a 3 x 5 pixel font for labels, as 5 rows of 3 bits (the most significant bit is the
+/// leftmost pixel)
+const GLYPHS: [(char, [u8; 5]); 38] = [
+    ('0', [0b111, 0b101, 0b101, 0b101, 0b111]),
+    ('1', [0b010, 0b110, 0b010, 0b010, 0b111]),
+    ('2', [0b111, 0b001, 0b111, 0b100, 0b111]),
+    ('3', [0b111, 0b001, 0b111, 0b001, 0b111]),
+    ('4', [0b101, 0b101, 0b111, 0b001, 0b001]),
+    ('5', [0b111, 0b100, 0b111, 0b001, 0b111]),
+    ('6', [0b111, 0b100, 0b111, 0b101, 0b111]),
+    ('7', [0b111, 0b001, 0b001, 0b001, 0b001]),
+    ('8', [0b111, 0b101, 0b111, 0b101, 0b111]),
+    ('9', [0b111, 0b101, 0b111, 0b001, 0b111]),
+    ('A', [0b010, 0b101, 0b111, 0b101, 0b101]),
+    ('B', [0b110, 0b101, 0b110, 0b101, 0b110]),
+    ('C', [0b011, 0b100, 0b100, 0b100, 0b011]),
+    ('D', [0b110, 0b101, 0b101, 0b101, 0b110]),
+    ('E', [0b111, 0b100, 0b110, 0b100, 0b111]),
+    ('F', [0b111, 0b100, 0b110, 0b100, 0b100]),
+    ('G', [0b011, 0b100, 0b101, 0b101, 0b011]),
+    ('H', [0b101, 0b101, 0b111, 0b101, 0b101]),
+    ('I', [0b111, 0b010, 0b010, 0b010, 0b111]),
+    ('J', [0b001, 0b001, 0b001, 0b101, 0b010]),
+    ('K', [0b101, 0b101, 0b110, 0b101, 0b101]),
+    ('L', [0b100, 0b100, 0b100, 0b100, 0b111]),
+    ('M', [0b101, 0b111, 0b111, 0b101, 0b101]),
+    ('N', [0b110, 0b101, 0b101, 0b101, 0b101]),
+    ('O', [0b010, 0b101, 0b101, 0b101, 0b010]),
+    ('P', [0b110, 0b101, 0b110, 0b100, 0b100]),
+    ('Q', [0b010, 0b101, 0b101, 0b110, 0b011]),
+    ('R', [0b110, 0b101, 0b110, 0b101, 0b101]),
+    ('S', [0b011, 0b100, 0b010, 0b001, 0b110]),
+    ('T', [0b111, 0b010, 0b010, 0b010, 0b010]),
+    ('U', [0b101, 0b101, 0b101, 0b101, 0b111]),
+    ('V', [0b101, 0b101, 0b101, 0b101, 0b010]),
+    ('W', [0b101, 0b101, 0b111, 0b111, 0b101]),
+    ('X', [0b101, 0b101, 0b010, 0b101, 0b101]),
+    ('Y', [0b101, 0b101, 0b010, 0b010, 0b010]),
+    ('Z', [0b111, 0b001, 0b010, 0b100, 0b111]),
+    ('-', [0b000, 0b000, 0b111, 0b000, 0b000]),
+    ('_', [0b000, 0b000, 0b000, 0b000, 0b111]),
+];
+
+/// Draws a line of text onto an image with the glyphs of [`GLYPHS`], clipping what does not fit
+///
+/// Letters are drawn in upper case, and characters without a glyph are drawn as filled boxes.
+///
+/// # Arguments
+///
+/// * `img` - The image to draw onto
+/// * `x` - Column of the top left corner of the text
+/// * `y` - Row of the top left corner of the text
+/// * `text` - The text to draw
+/// * `color` - Color of the text
+///
+fn draw_label(img: &mut [Vec<u16>], x: usize, y: usize, text: &str, color: u16) {
+    for (i, c) in text.chars().enumerate() {
+        let glyph = GLYPHS
+            .iter()
+            .find(|&&(glyph, _)| glyph == c.to_ascii_uppercase())
+            .map_or([0b111; 5], |&(_, rows)| rows);
+        let left = x + i * 4 * LABEL_SCALE;
+
+        for (row, bits) in glyph.iter().enumerate() {
+            for col in (0..3).filter(|&col| bits & (0b100 >> col) != 0) {
+                for dy in 0..LABEL_SCALE {
+                    for dx in 0..LABEL_SCALE {
+                        let line = img.get_mut(y + row * LABEL_SCALE + dy);
+                        if let Some(pixel) =
+                            line.and_then(|line| line.get_mut(left + col * LABEL_SCALE + dx))
+                        {
+                            *pixel = color;
+                        }
+                    }
+                }
+            }
+        }
+    }
+}
+
+/// Builds a single image with the thumbnail of every slot in a directory, arranged in a grid, each
+/// labeled with its slot
+///
+/// Cells are [`THUMBNAIL_SIZE`] pixels wide, and slots whose image can not be loaded are left
+/// empty (but still labeled).
+///
+/// # Arguments
+///
+/// * `dir` - Directory where images are stored
+/// * `palette` - The palette that the images were saved with
+///
+/// # Returns
+///
+/// The contact sheet, or `None` if the directory has no images
+///
+pub fn contact_sheet(dir: &str, palette: &Palette) -> Option<Vec<Vec<u16>>> {
+    let slots = list_slots(dir);
+    if slots.is_empty() {
+        return None;
+    }
+
+    // as square a grid as possible, which is never taller than it is wide
+    let columns = (1..).find(|&columns| columns * columns >= slots.len())?;
+    let rows = slots.len().div_ceil(columns);
+
+    let cell_height = THUMBNAIL_SIZE + LABEL_HEIGHT;
+    let mut sheet = vec![vec![0x0000; columns * THUMBNAIL_SIZE]; rows * cell_height];
+
+    for (i, slot) in slots.iter().enumerate() {
+        let (left, top) = ((i % columns) * THUMBNAIL_SIZE, (i / columns) * cell_height);
+
+        match load_whole_bmp(&format!("{dir}/image_{slot}"), palette) {
+            Ok(img) => {
+                // center the thumbnail within its cell
+                let thumbnail = downscale(&img, THUMBNAIL_SIZE);
+                let width = thumbnail.first().map_or(0, |row| row.len());
+                let (x, y) = (
+                    left + (THUMBNAIL_SIZE - width) / 2,
+                    top + (THUMBNAIL_SIZE - thumbnail.len()) / 2,
+                );
+                for (row, line) in thumbnail.iter().enumerate() {
+                    sheet[y + row][x..x + width].copy_from_slice(line);
+                }
+            }
+            Err(err) => eprintln!("Failed to load image_{}.bmp: {}", slot, err),
+        }
+
+        // long names are cut off at the edge of the cell, instead of running into the next cell
+        let label: String = slot
+            .to_string()
+            .chars()
+            .take((THUMBNAIL_SIZE - LABEL_SCALE) / (4 * LABEL_SCALE))
+            .collect();
+        draw_label(
+            &mut sheet[top + THUMBNAIL_SIZE..top + cell_height],
+            left + LABEL_SCALE,
+            (LABEL_HEIGHT - 5 * LABEL_SCALE) / 2,
+            &label,
+            0xFFFF,
+        );
+    }
+
+    Some(sheet)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::temp_dir;
+
+    /// Gets the width and height of an image
+    fn dimensions(data: &[Vec<u16>]) -> (usize, usize) {
+        (data.first().map_or(0, |row| row.len()), data.len())
+    }
+
+    #[test]
+    fn downscale_preserves_aspect_ratio() {
+        let dimensions_of =
+            |width, height| dimensions(&downscale(&vec![vec![0; width]; height], 96));
+
+        assert_eq!(dimensions_of(320, 240), (96, 72));
+        assert_eq!(dimensions_of(240, 320), (72, 96));
+        assert_eq!(dimensions_of(480, 480), (96, 96));
+        assert_eq!(dimensions_of(1000, 3), (96, 1));
+        assert_eq!(dimensions_of(50, 20), (50, 20));
+
+        // every pixel of the thumbnail is sampled from the corresponding area of the image
+        let img: Vec<Vec<u16>> = (0..200).map(|y| vec![y as u16 / 100; 400]).collect();
+        let scaled = downscale(&img, 96);
+        assert_eq!(scaled[0][0], 0);
+        assert_eq!(scaled[23][95], 0);
+        assert_eq!(scaled[24][0], 1);
+    }
+
+    #[test]
+    fn refresh_writes_missing_and_stale_thumbnails() {
+        let dir = temp_dir("refresh_writes_missing_and_stale_thumbnails");
+        let img = vec![vec![0xF800; 320]; 240];
+
+        save_bmp_image(&img, &format!("{dir}/image_1")).unwrap();
+        refresh_thumbnails(&dir, &Palette::BUILTIN);
+
+        let thumbnail = format!("{dir}/thumbnails/image_1");
+        assert_eq!(
+            load_png_image(&thumbnail, 96, 72, &Palette::BUILTIN).unwrap(),
+            vec![vec![0xF800; 96]; 72]
+        );
+
+        // a thumbnail that is older than its image is regenerated
+        let stale = std::time::SystemTime::UNIX_EPOCH;
+        std::fs::File::options()
+            .write(true)
+            .open(thumbnail_path(&dir, &Slot::Number(1)))
+            .unwrap()
+            .set_modified(stale)
+            .unwrap();
+        refresh_thumbnails(&dir, &Palette::BUILTIN);
+        let modified = std::fs::metadata(thumbnail_path(&dir, &Slot::Number(1)))
+            .unwrap()
+            .modified()
+            .unwrap();
+        assert!(modified > stale);
+    }
+
+    #[test]
+    fn contact_sheet_tiles_every_slot() {
+        let dir = temp_dir("contact_sheet_tiles_every_slot");
+        assert!(contact_sheet(&dir, &Palette::BUILTIN).is_none());
+
+        save_bmp_image(&vec![vec![0xF800; 320]; 240], &format!("{dir}/image_1")).unwrap();
+        save_bmp_image(&vec![vec![0x07E0; 10]; 10], &format!("{dir}/image_2")).unwrap();
+        save_bmp_image(
+            &vec![vec![0x001F; 2]; 4],
+            &format!("{dir}/image_birthday-card"),
+        )
+        .unwrap();
+
+        let sheet = contact_sheet(&dir, &Palette::BUILTIN).unwrap();
+        let cell_height = THUMBNAIL_SIZE + LABEL_HEIGHT;
+        assert_eq!(dimensions(&sheet), (2 * THUMBNAIL_SIZE, 2 * cell_height));
+
+        // the centers of the cells show the slots in order
+        let center = THUMBNAIL_SIZE / 2;
+        assert_eq!(sheet[center][center], 0xF800);
+        assert_eq!(sheet[center][THUMBNAIL_SIZE + center], 0x07E0);
+        assert_eq!(sheet[cell_height + center][center], 0x001F);
+        assert_eq!(sheet[cell_height + center][THUMBNAIL_SIZE + center], 0x0000);
+
+        // every occupied cell has a label
+        let labeled = |left: usize, top: usize| {
+            sheet[top + THUMBNAIL_SIZE..top + cell_height]
+                .iter()
+                .any(|row| row[left..left + THUMBNAIL_SIZE].contains(&0xFFFF))
+        };
+        assert!(labeled(0, 0));
+        assert!(labeled(THUMBNAIL_SIZE, 0));
+        assert!(labeled(0, cell_height));
+
+        // the long name of the last slot does not run into the empty cell
+        assert!(!labeled(THUMBNAIL_SIZE, cell_height));
+    }
+}
@@ -0,0 +1,33 @@
+//! Functions to set up TLS for encrypted transfers
+
+use std::sync::Arc;
+
+use rustls::pki_types::pem::PemObject;
+use rustls::pki_types::{CertificateDer, PrivateKeyDer};
+use rustls::ServerConfig;
+
+/// Builds the TLS configuration of the server from PEM encoded certificate and key files
+///
+/// # Arguments
+///
+/// * `cert_path` - Path to the certificate chain, starting with the certificate of the server
+/// * `key_path` - Path to the private key of the certificate
+///
+/// # Errors
+///
+/// * When either file can not be read or does not contain valid PEM data
+/// * When the private key does not match the certificate
+///
+pub fn load_server_config(
+    cert_path: &str,
+    key_path: &str,
+) -> Result<Arc<ServerConfig>, Box<dyn std::error::Error>> {
+    let certs = CertificateDer::pem_file_iter(cert_path)?.collect::<Result<Vec<_>, _>>()?;
+    let key = PrivateKeyDer::from_pem_file(key_path)?;
+
+    let config = ServerConfig::builder()
+        .with_no_client_auth()
+        .with_single_cert(certs, key)?;
+
+    Ok(Arc::new(config))
+}
@@ -0,0 +1,132 @@
+//! Log of every completed save and load, appended to a CSV file for `--transfer-log`
+
+use std::io::Write;
+use std::net::IpAddr;
+use std::sync::Mutex;
+
+use crate::slots::Slot;
+
+/// First line of a transfer log, naming the columns of the rows below it
+pub const TRANSFER_LOG_HEADER: &str =
+    "timestamp_ms,peer,opcode,slot,width,height,bytes,duration_ms";
+
+/// Held while a row is appended, so that the rows of concurrent transfers are never interleaved
+/// (and the header is only written once)
+static TRANSFER_LOG: Mutex<()> = Mutex::new(());
+
+/// A save or load that was completed
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Transfer {
+    /// When the transfer was completed, in milliseconds since the Unix epoch
+    pub timestamp_ms: u64,
+    /// Address of the client
+    pub peer: IpAddr,
+    /// Opcode of the request
+    pub opcode: u8,
+    /// The slot that the image was saved to or loaded from
+    pub slot: Slot,
+    /// Number of columns of the image
+    pub width: usize,
+    /// Number of rows of the image
+    pub height: usize,
+    /// Number of bytes of the rows that were received or sent (excluding the header of the request
+    /// and the acknowledgements)
+    pub bytes: u64,
+    /// Time taken by the transfer of the rows, in milliseconds
+    pub duration_ms: u64,
+}
+
+/// Quotes a field of a CSV row if it contains a separator or a quote
+fn csv_field(field: &str) -> String {
+    match field.contains([',', '"']) {
+        true => format!("\"{}\"", field.replace('"', "\"\"")),
+        false => field.to_string(),
+    }
+}
+
+impl Transfer {
+    /// Formats the transfer as a row of a transfer log (without a line break)
+    pub fn csv_row(&self) -> String {
+        format!(
+            "{},{},{},{},{},{},{},{}",
+            self.timestamp_ms,
+            self.peer,
+            self.opcode,
+            csv_field(&self.slot.to_string()),
+            self.width,
+            self.height,
+            self.bytes,
+            self.duration_ms
+        )
+    }
+}
+
+/// Appends a transfer to a transfer log, which is created (with a header) if it does not exist
+///
+/// Every row is written with a single write, so rows that were appended are kept even if the server
+/// is killed right after.
+///
+/// # Arguments
+///
+/// * `path` - Path of the transfer log
+/// * `transfer` - The transfer that was completed
+///
+/// # Errors
+///
+/// * When the log can not be opened or written
+///
+pub fn append_transfer(path: &str, transfer: &Transfer) -> std::io::Result<()> {
+    let _guard = TRANSFER_LOG.lock().unwrap_or_else(|err| err.into_inner());
+    let mut file = std::fs::OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(path)?;
+
+    let mut record = String::new();
+    if file.metadata()?.len() == 0 {
+        record.push_str(TRANSFER_LOG_HEADER);
+        record.push('\n');
+    }
+    record.push_str(&transfer.csv_row());
+    record.push('\n');
+    file.write_all(record.as_bytes())?;
+    file.flush()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::temp_dir;
+
+    #[test]
+    fn transfers_are_appended_below_a_header() {
+        let dir = temp_dir("transfers_are_appended_below_a_header");
+        let path = format!("{dir}/transfers.csv");
+        let mut transfer = Transfer {
+            timestamp_ms: 1_700_000_000_000,
+            peer: "192.168.1.20".parse().unwrap(),
+            opcode: 1,
+            slot: Slot::Number(3),
+            width: 320,
+            height: 240,
+            bytes: 4_080,
+            duration_ms: 812,
+        };
+        append_transfer(&path, &transfer).unwrap();
+
+        // names that would break up the row are quoted
+        transfer.opcode = 2;
+        transfer.slot = Slot::Name("cards, \"old\"".to_string());
+        append_transfer(&path, &transfer).unwrap();
+
+        assert_eq!(
+            std::fs::read_to_string(&path).unwrap(),
+            format!(
+                "{}\n{}\n{}\n",
+                TRANSFER_LOG_HEADER,
+                "1700000000000,192.168.1.20,1,3,320,240,4080,812",
+                "1700000000000,192.168.1.20,2,\"cards, \"\"old\"\"\",320,240,4080,812"
+            )
+        );
+    }
+}
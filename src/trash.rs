@@ -0,0 +1,325 @@
+//! Trash that the images of removed slots are moved into, so that removing a slot (such as with
+//! [`OP_CLEAR`](crate::protocol::OP_CLEAR)) can be undone
+//!
+//! Every removed slot becomes an entry of the trash, named `{timestamp}_image_{slot}` after the time
+//! of its removal (in milliseconds since the Unix epoch). The files of the slot (its image in every
+//! form, and its checksum, metadata and PNG copy) are moved into the `trash` subdirectory of the
+//! image directory, with the name of the entry in place of `image_{slot}`.
+
+use crate::image::TEMP_SUFFIX;
+use crate::slots::{parse_image_slot, Slot};
+
+/// Name of the subdirectory of an image directory that keeps the removed slots
+pub const TRASH_DIR_NAME: &str = "trash";
+
+/// Suffixes of every file of a slot that is moved into the trash with its image
+const TRASHED_SUFFIXES: [&str; 6] = [
+    ".bmp",
+    ".bmp.gz",
+    ".bmp.zst",
+    ".bmp.sha256",
+    ".json",
+    ".png",
+];
+
+/// A slot that was moved into the trash
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TrashEntry {
+    /// Name of the entry, which is the name of its files without their suffixes
+    pub name: String,
+    /// The slot that the image was removed from
+    pub slot: Slot,
+    /// When the slot was removed, in milliseconds since the Unix epoch
+    pub deleted_ms: u64,
+}
+
+/// Gets the path of the directory that keeps the removed slots of an image directory
+///
+/// # Arguments
+///
+/// * `dir` - Directory where images are stored
+///
+pub fn trash_dir(dir: &str) -> String {
+    format!("{dir}/{TRASH_DIR_NAME}")
+}
+
+/// Gets the entry of the trash that a file belongs to, if it is the image of an entry
+///
+/// # Arguments
+///
+/// * `file_name` - Name of the file (with extension), of the form `{timestamp}_image_{slot}.bmp`
+///   (or `.bmp.gz` or `.bmp.zst`, if the image is compressed)
+///
+fn parse_trash_entry(file_name: &str) -> Option<TrashEntry> {
+    let (deleted_ms, image) = file_name.split_once('_')?;
+    let slot = parse_image_slot(image)?;
+    let deleted_ms = deleted_ms.parse().ok()?;
+    Some(TrashEntry {
+        name: format!("{deleted_ms}_image_{slot}"),
+        slot,
+        deleted_ms,
+    })
+}
+
+/// Moves a file, copying it (and then removing it) when it can not be renamed because the
+/// destination is on another filesystem (such as a trash directory that is mounted elsewhere)
+///
+/// # Arguments
+///
+/// * `from` - Path of the file to move
+/// * `to` - Path to move the file to
+///
+fn move_file(from: &str, to: &str) -> std::io::Result<()> {
+    match std::fs::rename(from, to) {
+        Err(err) if err.kind() == std::io::ErrorKind::CrossesDevices => copy_and_remove(from, to),
+        result => result,
+    }
+}
+
+/// Moves a file by copying it to a temporary file next to the destination, renaming the temporary
+/// file and only then removing the original, so that the file is never lost if the move fails
+///
+/// # Arguments
+///
+/// * `from` - Path of the file to move
+/// * `to` - Path to move the file to
+///
+fn copy_and_remove(from: &str, to: &str) -> std::io::Result<()> {
+    let temp = format!("{to}{TEMP_SUFFIX}");
+    let result = std::fs::copy(from, &temp).and_then(|_| std::fs::rename(&temp, to));
+    if result.is_err() {
+        let _ = std::fs::remove_file(&temp);
+    }
+    result.and_then(|()| std::fs::remove_file(from))
+}
+
+/// Moves every file of a slot whose name starts with `from` to the same name starting with `to`
+fn move_slot_files(from: &str, to: &str) -> std::io::Result<()> {
+    for suffix in TRASHED_SUFFIXES {
+        match move_file(&format!("{from}{suffix}"), &format!("{to}{suffix}")) {
+            Err(err) if err.kind() != std::io::ErrorKind::NotFound => return Err(err),
+            _ => {}
+        }
+    }
+    Ok(())
+}
+
+/// Moves the image of a slot (and the files derived from it) into the trash, instead of removing
+/// it
+///
+/// Backups and the history of the slot are left where they are, so that they can still be used
+/// once a new image is saved to the slot.
+///
+/// # Arguments
+///
+/// * `dir` - Directory where images are stored
+/// * `name` - The slot of the image, which must be locked
+///
+/// # Errors
+///
+/// * When the slot has no image (with [`std::io::ErrorKind::NotFound`])
+/// * When any of the files can not be moved
+///
+pub fn trash_slot(dir: &str, name: &Slot) -> std::io::Result<TrashEntry> {
+    if crate::image::stored_bmp_path(&format!("{dir}/image_{name}")).is_none() {
+        return Err(std::io::Error::new(
+            std::io::ErrorKind::NotFound,
+            "slot has no image",
+        ));
+    }
+
+    let deleted_ms = std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .map_err(std::io::Error::other)?
+        .as_millis() as u64;
+    let entry = TrashEntry {
+        name: format!("{deleted_ms}_image_{name}"),
+        slot: name.clone(),
+        deleted_ms,
+    };
+
+    let trash = trash_dir(dir);
+    std::fs::create_dir_all(&trash)?;
+    move_slot_files(
+        &format!("{dir}/image_{name}"),
+        &format!("{trash}/{}", entry.name),
+    )?;
+    Ok(entry)
+}
+
+/// Gets every entry of the trash, from the oldest to the newest
+///
+/// # Arguments
+///
+/// * `dir` - Directory where images are stored
+///
+pub fn list_trash(dir: &str) -> Vec<TrashEntry> {
+    let Ok(entries) = std::fs::read_dir(trash_dir(dir)) else {
+        return Vec::new();
+    };
+
+    let mut trashed: Vec<TrashEntry> = entries
+        .filter_map(|entry| entry.ok())
+        .filter_map(|entry| parse_trash_entry(&entry.file_name().to_string_lossy()))
+        .collect();
+    trashed.sort_by(|a, b| (a.deleted_ms, &a.name).cmp(&(b.deleted_ms, &b.name)));
+    // an image that was stored in several forms is still a single entry
+    trashed.dedup();
+    trashed
+}
+
+/// Moves an entry of the trash back into its slot
+///
+/// # Arguments
+///
+/// * `dir` - Directory where images are stored
+/// * `entry` - Name of the entry, as listed by [`list_trash`]
+///
+/// # Returns
+///
+/// The slot that the image was moved back into
+///
+/// # Errors
+///
+/// * When the trash has no such entry (with [`std::io::ErrorKind::NotFound`])
+/// * When the slot already has an image again (with [`std::io::ErrorKind::AlreadyExists`])
+/// * When any of the files can not be moved
+///
+pub fn restore_trash(dir: &str, entry: &str) -> std::io::Result<Slot> {
+    let Some(trashed) = list_trash(dir)
+        .into_iter()
+        .find(|trashed| trashed.name == entry)
+    else {
+        return Err(std::io::Error::new(
+            std::io::ErrorKind::NotFound,
+            "trash has no such entry",
+        ));
+    };
+
+    let slot = trashed.slot;
+    if crate::image::stored_bmp_path(&format!("{dir}/image_{slot}")).is_some() {
+        return Err(std::io::Error::new(
+            std::io::ErrorKind::AlreadyExists,
+            "slot already has an image",
+        ));
+    }
+    move_slot_files(
+        &format!("{}/{entry}", trash_dir(dir)),
+        &format!("{dir}/image_{slot}"),
+    )?;
+    Ok(slot)
+}
+
+/// Removes the entries of the trash that were removed longer ago than the given age
+///
+/// # Arguments
+///
+/// * `dir` - Directory where images are stored
+/// * `keep` - Age of the oldest entries that are kept
+///
+/// # Returns
+///
+/// The number of entries that were removed
+///
+pub fn prune_trash(dir: &str, keep: std::time::Duration) -> std::io::Result<usize> {
+    let now_ms = std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .map_err(std::io::Error::other)?
+        .as_millis() as u64;
+    let oldest_ms = now_ms.saturating_sub(keep.as_millis() as u64);
+
+    let trash = trash_dir(dir);
+    let mut pruned = 0;
+    for entry in list_trash(dir) {
+        if entry.deleted_ms >= oldest_ms {
+            continue;
+        }
+        for suffix in TRASHED_SUFFIXES {
+            match std::fs::remove_file(format!("{trash}/{}{suffix}", entry.name)) {
+                Err(err) if err.kind() != std::io::ErrorKind::NotFound => return Err(err),
+                _ => {}
+            }
+        }
+        pruned += 1;
+    }
+    Ok(pruned)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::checksums::{checksum_path, verify_checksum, write_checksum, Verification};
+    use crate::image::{load_whole_bmp, save_bmp_image};
+    use crate::palette::Palette;
+    use crate::testing::temp_dir;
+
+    #[test]
+    fn trashed_slots_can_be_restored() {
+        let dir = temp_dir("trashed_slots_can_be_restored");
+        let img = vec![vec![0xF800, 0x07E0, 0x001F], vec![0xFFFF, 0x0000, 0x520A]];
+        let slot = Slot::Name("sketch".to_string());
+        save_bmp_image(&img, &format!("{dir}/image_{slot}")).unwrap();
+        write_checksum(&dir, &slot).unwrap();
+
+        let entry = trash_slot(&dir, &slot).unwrap();
+        assert_eq!(entry.slot, slot);
+        assert!(!std::path::Path::new(&format!("{dir}/image_{slot}.bmp")).exists());
+        assert!(!std::path::Path::new(&checksum_path(&dir, &slot)).exists());
+        assert_eq!(list_trash(&dir), std::slice::from_ref(&entry));
+        assert_eq!(
+            trash_slot(&dir, &slot).unwrap_err().kind(),
+            std::io::ErrorKind::NotFound
+        );
+
+        // the image comes back as it was, along with its checksum
+        assert_eq!(restore_trash(&dir, &entry.name).unwrap(), slot);
+        assert_eq!(
+            load_whole_bmp(&format!("{dir}/image_{slot}"), &Palette::BUILTIN).unwrap(),
+            img
+        );
+        assert!(matches!(
+            verify_checksum(&dir, &slot),
+            Ok(Verification::Match)
+        ));
+        assert_eq!(list_trash(&dir), []);
+        assert_eq!(
+            restore_trash(&dir, &entry.name).unwrap_err().kind(),
+            std::io::ErrorKind::NotFound
+        );
+
+        // entries are not restored over a new image of their slot
+        let entry = trash_slot(&dir, &slot).unwrap();
+        save_bmp_image(&img, &format!("{dir}/image_{slot}")).unwrap();
+        assert_eq!(
+            restore_trash(&dir, &entry.name).unwrap_err().kind(),
+            std::io::ErrorKind::AlreadyExists
+        );
+    }
+
+    #[test]
+    fn old_entries_are_pruned() {
+        let dir = temp_dir("old_entries_are_pruned");
+        std::fs::create_dir_all(trash_dir(&dir)).unwrap();
+        let img = vec![vec![0xF800; 2]; 2];
+        save_bmp_image(&img, &format!("{}/1000_image_1", trash_dir(&dir))).unwrap();
+        save_bmp_image(&img, &format!("{dir}/image_2")).unwrap();
+        let recent = trash_slot(&dir, &Slot::Number(2)).unwrap();
+
+        let day = std::time::Duration::from_secs(24 * 60 * 60);
+        assert_eq!(prune_trash(&dir, day).unwrap(), 1);
+        assert_eq!(list_trash(&dir), [recent]);
+        assert_eq!(prune_trash(&dir, day).unwrap(), 0);
+    }
+
+    #[test]
+    fn moved_files_are_copied_when_they_can_not_be_renamed() {
+        let dir = temp_dir("moved_files_are_copied_when_they_can_not_be_renamed");
+        let (from, to) = (format!("{dir}/from.bmp"), format!("{dir}/to.bmp"));
+        std::fs::write(&from, b"pixels").unwrap();
+
+        copy_and_remove(&from, &to).unwrap();
+        assert_eq!(std::fs::read(&to).unwrap(), b"pixels");
+        assert!(!std::path::Path::new(&from).exists());
+        assert!(!std::path::Path::new(&format!("{to}{TEMP_SUFFIX}")).exists());
+    }
+}
@@ -0,0 +1,119 @@
+//! Accounting of the space taken by the images in each image directory, for `--max-dir-size`
+
+use std::collections::BTreeMap;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use crate::slots::{image_path, list_slots, Slot};
+
+/// Time after which the images of a directory are counted again, so that changes made by other
+/// processes (such as the subcommands) are noticed without counting them for every save
+pub const RESCAN_INTERVAL: Duration = Duration::from_secs(60);
+
+/// Total size of the images of a directory, as of when it was last counted or changed
+struct Total {
+    bytes: u64,
+    counted_at: Instant,
+}
+
+/// Totals of every directory that has been saved to, by the path of the directory
+static TOTALS: Mutex<BTreeMap<String, Total>> = Mutex::new(BTreeMap::new());
+
+/// Gets the size of the image file of a slot, in bytes (0 if it has no image)
+fn image_size(dir: &str, name: &Slot) -> u64 {
+    std::fs::metadata(image_path(dir, name)).map_or(0, |metadata| metadata.len())
+}
+
+/// Counts the total size of the image files of every slot in a directory, in bytes
+///
+/// Backups and the history of the slots are not counted, since they are bounded by the number of
+/// slots (and by `--history-keep`).
+///
+/// # Arguments
+///
+/// * `dir` - Directory where images are stored
+///
+pub fn images_size(dir: &str) -> u64 {
+    list_slots(dir)
+        .iter()
+        .map(|slot| image_size(dir, slot))
+        .sum()
+}
+
+/// Reserves space for replacing the image of a slot with an image of the given size, unless the
+/// images of the directory would then take more space than the quota
+///
+/// The slot must be locked, so that the size of its current image does not change until the new
+/// image has been written.
+///
+/// # Arguments
+///
+/// * `dir` - Directory where images are stored
+/// * `name` - The slot whose image is replaced
+/// * `bytes` - Size of the new image file
+/// * `quota` - Largest total size that the images of the directory may take
+///
+/// # Errors
+///
+/// * With the current total size of the images when the new image does not fit in the quota
+///
+pub fn reserve(dir: &str, name: &Slot, bytes: u64, quota: u64) -> Result<(), u64> {
+    let mut totals = TOTALS.lock().unwrap_or_else(|err| err.into_inner());
+
+    if totals
+        .get(dir)
+        .is_none_or(|total| total.counted_at.elapsed() >= RESCAN_INTERVAL)
+    {
+        let total = Total {
+            bytes: images_size(dir),
+            counted_at: Instant::now(),
+        };
+        totals.insert(dir.to_string(), total);
+    }
+    let total = totals.get_mut(dir).unwrap();
+
+    let new_total = total.bytes - image_size(dir, name).min(total.bytes) + bytes;
+    if new_total > quota {
+        return Err(total.bytes);
+    }
+    total.bytes = new_total;
+    Ok(())
+}
+
+/// Forgets the total size of the images of a directory, so that they are counted again before the
+/// next save (after a save failed, or after images were moved)
+///
+/// # Arguments
+///
+/// * `dir` - Directory where images are stored
+///
+pub fn invalidate(dir: &str) {
+    TOTALS
+        .lock()
+        .unwrap_or_else(|err| err.into_inner())
+        .remove(dir);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::image::save_bmp_image;
+    use crate::testing::temp_dir;
+
+    #[test]
+    fn reservations_respect_the_quota() {
+        let dir = temp_dir("reservations_respect_the_quota");
+        save_bmp_image(&[vec![0xF800; 2]], &format!("{dir}/image_1")).unwrap();
+        save_bmp_image(&[vec![0xF800; 2]], &format!("{dir}/image_1.bak")).unwrap();
+        assert_eq!(images_size(&dir), 70);
+
+        // replacing an image only takes the difference in size
+        assert_eq!(reserve(&dir, &Slot::Number(1), 100, 100), Ok(()));
+        assert_eq!(reserve(&dir, &Slot::Number(2), 70, 100), Err(100));
+
+        // the reservation is kept until the images are counted again
+        assert_eq!(reserve(&dir, &Slot::Number(2), 1, 100), Err(100));
+        invalidate(&dir);
+        assert_eq!(reserve(&dir, &Slot::Number(2), 30, 100), Ok(()));
+    }
+}
@@ -0,0 +1,176 @@
+//! Watching of the image directory, so that images copied into it (or changed by the subcommands)
+//! are noticed while the server runs, instead of serving stale cached images and thumbnails
+
+use std::collections::BTreeMap;
+use std::path::Path;
+use std::sync::Mutex;
+
+use notify::{RecommendedWatcher, RecursiveMode, Watcher};
+
+use crate::cache::{self, Stamp};
+use crate::image::{load_whole_bmp, LoadError};
+use crate::palette::Palette;
+use crate::slots::{image_path, parse_image_slot, Slot};
+use crate::thumbnails::{thumbnail_path, write_thumbnail};
+use crate::usage;
+
+/// Image files of the slots that the server itself last changed, by the directory and slot of the
+/// image, with the modification time and size that the change left the file with (`None` once the
+/// image was moved away)
+static OWN_CHANGES: Mutex<BTreeMap<(String, Slot), Option<Stamp>>> = Mutex::new(BTreeMap::new());
+
+/// Records that the server changed the image file of a slot, so that the events caused by the
+/// change are ignored
+///
+/// # Arguments
+///
+/// * `dir` - Directory where images are stored
+/// * `name` - The slot of the image
+///
+pub fn record_own_change(dir: &str, name: &Slot) {
+    let stamp = cache::stamp(&image_path(dir, name));
+    OWN_CHANGES
+        .lock()
+        .unwrap_or_else(|err| err.into_inner())
+        .insert((dir.to_string(), name.clone()), stamp);
+}
+
+/// Starts watching an image directory (and the directories of the devices within it), until the
+/// returned watcher is dropped
+///
+/// Filesystems which can not be watched only make the server log why, since images changed
+/// outside of the server are still noticed by the cache when they are loaded.
+///
+/// # Arguments
+///
+/// * `dir` - Directory where images are stored
+/// * `multi_device` - Whether the images of each device are stored in a subdirectory
+/// * `palette` - The palette that the images were saved with
+///
+pub fn watch(dir: &str, multi_device: bool, palette: &Palette) -> Option<RecommendedWatcher> {
+    let palette = *palette;
+    let handler = move |event: notify::Result<notify::Event>| match event {
+        Ok(event) if !event.kind.is_access() => {
+            for path in &event.paths {
+                handle_change(path, &palette);
+            }
+        }
+        Ok(_) => {}
+        Err(err) => eprintln!("Failed to watch the image directory: {}", err),
+    };
+    let mode = match multi_device {
+        true => RecursiveMode::Recursive,
+        false => RecursiveMode::NonRecursive,
+    };
+
+    let result = notify::recommended_watcher(handler)
+        .and_then(|mut watcher| watcher.watch(Path::new(dir), mode).map(|()| watcher));
+    match result {
+        Ok(watcher) => {
+            println!("Watching {} for changes", dir);
+            Some(watcher)
+        }
+        Err(err) => {
+            eprintln!("Not watching {} for changes: {}", dir, err);
+            None
+        }
+    }
+}
+
+/// Reacts to a change of a file in an image directory, if it is the image of a slot and was not
+/// changed by the server itself
+///
+/// # Arguments
+///
+/// * `path` - Path of the file that changed
+/// * `palette` - The palette that the images were saved with
+///
+/// # Returns
+///
+/// Whether the image of a slot was changed outside of the server
+///
+pub fn handle_change(path: &Path, palette: &Palette) -> bool {
+    let (Some(file_name), Some(dir)) = (path.file_name(), path.parent()) else {
+        return false;
+    };
+    let Some(slot) = parse_image_slot(&file_name.to_string_lossy()) else {
+        return false;
+    };
+    let dir = dir.to_string_lossy();
+
+    let stamp = cache::stamp(&image_path(&dir, &slot));
+    let own_changes = OWN_CHANGES.lock().unwrap_or_else(|err| err.into_inner());
+    if own_changes.get(&(dir.to_string(), slot.clone())) == Some(&stamp) {
+        return false;
+    }
+    drop(own_changes);
+
+    cache::invalidate(&dir, &slot);
+    usage::invalidate(&dir);
+
+    if stamp.is_none() {
+        println!("image_{}.bmp was removed from {}", slot, dir);
+        let _ = std::fs::remove_file(thumbnail_path(&dir, &slot));
+        return true;
+    }
+
+    println!("image_{}.bmp was changed in {}", slot, dir);
+    match load_whole_bmp(&format!("{dir}/image_{slot}"), palette) {
+        Ok(img) => {
+            if let Err(err) = write_thumbnail(&dir, &slot, &img) {
+                eprintln!("Failed to write thumbnail of image_{}.bmp: {}", slot, err);
+            }
+        }
+        // the file is still being copied, and changes again once it is complete
+        Err(LoadError::BadHeader | LoadError::Truncated) => {}
+        Err(err) => eprintln!("Failed to load image_{}.bmp: {}", slot, err),
+    }
+    true
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::image::save_bmp_image;
+    use crate::store::{FileStore, Store};
+    use crate::testing::temp_dir;
+
+    #[test]
+    fn only_external_changes_are_handled() {
+        let dir = temp_dir("only_external_changes_are_handled");
+        let slot = Slot::Number(1);
+        let path = format!("{dir}/image_1.bmp");
+
+        save_bmp_image(&[vec![0xF800; 2]], &format!("{dir}/image_1")).unwrap();
+        record_own_change(&dir, &slot);
+        assert!(!handle_change(Path::new(&path), &Palette::BUILTIN));
+        assert!(!Path::new(&thumbnail_path(&dir, &slot)).exists());
+
+        // copied in by someone else
+        save_bmp_image(&vec![vec![0x07E0; 4]; 2], &format!("{dir}/image_1")).unwrap();
+        assert!(handle_change(Path::new(&path), &Palette::BUILTIN));
+        assert!(Path::new(&thumbnail_path(&dir, &slot)).exists());
+
+        std::fs::remove_file(&path).unwrap();
+        assert!(handle_change(Path::new(&path), &Palette::BUILTIN));
+        assert!(!Path::new(&thumbnail_path(&dir, &slot)).exists());
+
+        // moved to the trash by the server itself
+        let store = FileStore::default();
+        save_bmp_image(&[vec![0xF800; 2]], &format!("{dir}/image_1")).unwrap();
+        write_thumbnail(&dir, &slot, &[vec![0xF800; 2]]).unwrap();
+        store.trash(&dir, &slot).unwrap();
+        assert!(!handle_change(Path::new(&path), &Palette::BUILTIN));
+        assert!(!Path::new(&thumbnail_path(&dir, &slot)).exists());
+
+        // other files of the directory are not images
+        assert!(!handle_change(
+            Path::new(&format!("{dir}/image_1.bmp.bak")),
+            &Palette::BUILTIN
+        ));
+        assert!(!handle_change(
+            Path::new(&format!("{dir}/notes.txt")),
+            &Palette::BUILTIN
+        ));
+    }
+}
@@ -0,0 +1,107 @@
+//! Tests that run the server binary, for behavior that depends on the environment of the process
+//! (which tests running in the same process can not change safely)
+
+use std::process::{Command, Output};
+
+/// Gets a command that runs the server binary, without the `CANVAS_` variables of the environment
+/// of the tests
+fn server() -> Command {
+    let mut command = Command::new(env!("CARGO_BIN_EXE_dumblebots-canvas-server"));
+    for (key, _) in std::env::vars_os() {
+        if key.to_string_lossy().starts_with("CANVAS_") {
+            command.env_remove(key);
+        }
+    }
+    command
+}
+
+/// Gets what a command printed to its standard output
+fn stdout(output: &Output) -> String {
+    String::from_utf8_lossy(&output.stdout).into_owned()
+}
+
+#[test]
+fn arguments_fall_back_to_environment_variables() {
+    let from_env = tempfile::tempdir().unwrap();
+    let from_command_line = tempfile::tempdir().unwrap();
+    let (from_env, from_command_line) = (
+        from_env.path().to_str().unwrap(),
+        from_command_line.path().to_str().unwrap(),
+    );
+
+    let output = server()
+        .arg("--list")
+        .env("CANVAS_IMAGE_DIR", from_env)
+        .output()
+        .unwrap();
+    assert!(output.status.success());
+    assert_eq!(stdout(&output), format!("{from_env} has no images\n"));
+
+    // options given on the command line take precedence over the environment
+    let output = server()
+        .args(["--list", "--image-dir", from_command_line])
+        .env("CANVAS_IMAGE_DIR", from_env)
+        .output()
+        .unwrap();
+    assert!(output.status.success());
+    assert_eq!(
+        stdout(&output),
+        format!("{from_command_line} has no images\n")
+    );
+
+    // values from the environment are checked like those from the command line
+    let output = server()
+        .arg("--list")
+        .env("CANVAS_PORT", "not-a-port")
+        .output()
+        .unwrap();
+    assert_eq!(output.status.code(), Some(2));
+    assert!(String::from_utf8_lossy(&output.stderr).contains("not-a-port"));
+}
+
+// directories are only locked on unix
+#[cfg(unix)]
+#[test]
+fn second_servers_on_the_same_directory_are_refused() {
+    let dir = tempfile::tempdir().unwrap();
+    let dir = dir.path().to_str().unwrap();
+
+    let mut first = server()
+        .args(["--image-dir", dir, "--port", "0"])
+        .stdout(std::process::Stdio::null())
+        .stderr(std::process::Stdio::null())
+        .spawn()
+        .unwrap();
+    // the lock file holds the process ID of the server once it has locked the directory
+    let lock_file = format!("{dir}/.canvas-server.lock");
+    let started = std::time::Instant::now();
+    while std::fs::read_to_string(&lock_file)
+        .ok()
+        .as_deref()
+        .map(str::trim)
+        != Some(&first.id().to_string())
+    {
+        assert!(
+            started.elapsed() < std::time::Duration::from_secs(10),
+            "the first server did not lock {dir}"
+        );
+        std::thread::sleep(std::time::Duration::from_millis(10));
+    }
+
+    let second = server()
+        .args(["--image-dir", dir, "--port", "0"])
+        .output()
+        .unwrap();
+    first.kill().unwrap();
+    first.wait().unwrap();
+
+    assert_eq!(second.status.code(), Some(1));
+    assert!(
+        String::from_utf8_lossy(&second.stderr).contains(&format!(
+            "Can not use image directory {dir}: another server (process {}) is using it",
+            first.id()
+        )),
+        "{}",
+        String::from_utf8_lossy(&second.stderr)
+    );
+}